@@ -0,0 +1,303 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::cmds::{Cmds, CommandSpec, GetCmds, HELP_JSON};
+
+/// Response of an invalid or failed request
+pub const INVALID_REQUEST: &str = "Invalid request.";
+
+/// Default response timeout
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Response of a request, `name: value`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub name: String,
+    pub value: String,
+}
+
+impl Response {
+    /// Parse a response line, Err of `Invalid request.`, a json array of a batch has an empty name
+    pub fn parse_line(line: &str) -> Result<Self> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line == INVALID_REQUEST {
+            bail!("{}", INVALID_REQUEST);
+        }
+        if line.starts_with('[') {
+            return Ok(Self {
+                name: String::new(),
+                value: line.to_string(),
+            });
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid response: {}", line))?;
+        Ok(Self {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+
+    /// Value of the response, e.g. f32 of `battery: 85.5`
+    pub fn value_as<T>(&self) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.value
+            .parse()
+            .map_err(|e| anyhow!("Invalid value of {}: {}, {}", self.name, self.value, e))
+    }
+}
+
+enum Transport {
+    Stream {
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+        writer: Box<dyn AsyncWrite + Send + Unpin>,
+    },
+    Ws(Box<WebSocketStream<MaybeTlsStream<TcpStream>>>),
+}
+
+impl Transport {
+    async fn send(&mut self, req: String) -> Result<()> {
+        match self {
+            Transport::Stream { writer, .. } => {
+                writer.write_all(req.as_bytes()).await?;
+                writer.flush().await?;
+            }
+            Transport::Ws(ws) => ws.send(Message::Text(req)).await?,
+        }
+        Ok(())
+    }
+
+    /// Append received data to pending
+    async fn recv(&mut self, pending: &mut String) -> Result<()> {
+        match self {
+            Transport::Stream { reader, .. } => {
+                let mut buf = [0; 1024];
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    bail!("Connection closed");
+                }
+                pending.push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+            Transport::Ws(ws) => match ws.next().await {
+                Some(Ok(msg)) if msg.is_text() || msg.is_binary() => {
+                    pending.push_str(msg.to_text()?);
+                    if !pending.ends_with('\n') {
+                        pending.push('\n');
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => bail!("Connection closed"),
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Async connection to pisugar-server, over tcp, uds or ws.
+///
+/// Requests are tagged with `#id`, so responses are told apart from events and `watch` updates,
+/// which are kept for [`Client::next_event`].
+pub struct Client {
+    transport: Transport,
+    timeout: Duration,
+    next_id: u64,
+    pending: String,
+    events: VecDeque<String>,
+}
+
+impl Client {
+    fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            timeout: DEFAULT_TIMEOUT,
+            next_id: 1,
+            pending: String::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Connect to tcp address, e.g. 127.0.0.1:8423
+    pub async fn connect_tcp(addr: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| anyhow!("Connect {}: {}", addr, e))?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self::new(Transport::Stream {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        }))
+    }
+
+    /// Connect to unix domain socket, e.g. /tmp/pisugar-server.sock
+    pub async fn connect_uds(path: &str) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .map_err(|e| anyhow!("Connect {}: {}", path, e))?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self::new(Transport::Stream {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        }))
+    }
+
+    /// Connect to websocket, e.g. ws://127.0.0.1:8422
+    pub async fn connect_ws(url: &str) -> Result<Self> {
+        let (ws, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .map_err(|e| anyhow!("Connect {}: {}", url, e))?;
+        Ok(Self::new(Transport::Ws(Box::new(ws))))
+    }
+
+    /// Response timeout, default 5s
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Next received line
+    async fn read_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(i) = self.pending.find('\n') {
+                let line: String = self.pending.drain(..=i).collect();
+                return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+            }
+            self.transport.recv(&mut self.pending).await?;
+        }
+    }
+
+    /// Send a request of the protocol as is, e.g. `get battery`, returns its response
+    pub async fn request(&mut self, req: &str) -> Result<Response> {
+        let id = self.next_id.to_string();
+        self.next_id += 1;
+        self.transport.send(format!("#{} {}\n", id, req.trim())).await?;
+
+        let tag = format!("#{} ", id);
+        let timeout = self.timeout;
+        let wait = async {
+            loop {
+                let line = self.read_line().await?;
+                match line.strip_prefix(&tag) {
+                    Some(resp) => return Response::parse_line(resp),
+                    None => self.events.push_back(line),
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| anyhow!("No response: {}", req.trim()))?
+    }
+
+    /// Get a field, e.g. `GetCmds::Battery`
+    pub async fn get(&mut self, cmd: GetCmds) -> Result<String> {
+        Ok(self.request(&cmd.to_string()).await?.value)
+    }
+
+    /// Get a field and parse its value, e.g. f32 of `GetCmds::Battery`
+    pub async fn get_as<T>(&mut self, cmd: GetCmds) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.request(&cmd.to_string()).await?.value_as()
+    }
+
+    /// Protocol version of the server, 0 of servers without `get protocol_version`
+    pub async fn protocol_version(&mut self) -> Result<u32> {
+        match self.request(&GetCmds::ProtocolVersion.to_string()).await {
+            Ok(resp) => resp.value_as(),
+            Err(e) if e.to_string() == INVALID_REQUEST => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Commands of the server with argument signatures, e.g. `get button_enable <mode>`
+    pub async fn commands(&mut self) -> Result<Vec<String>> {
+        let value = self.get(GetCmds::Commands).await?;
+        Ok(value.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect())
+    }
+
+    /// Command catalogue of the server, of protocol version 6 or later
+    pub async fn command_catalogue(&mut self) -> Result<Vec<CommandSpec>> {
+        let resp = self.request(HELP_JSON).await?;
+        serde_json::from_str(&resp.value).map_err(|e| anyhow!("Invalid command catalogue: {}", e))
+    }
+
+    /// Run a cmd, e.g. `set_allow_charging true`, which is validated before sending
+    pub async fn run(&mut self, req: &str) -> Result<()> {
+        match Cmds::from_str(req)? {
+            Cmds::Get(_) | Cmds::Watch { .. } | Cmds::Unwatch => bail!("Not a set cmd: {}", req),
+            _ => (),
+        }
+        let resp = self.request(req).await?;
+        match resp.value.as_str() {
+            "done" => Ok(()),
+            value => bail!("{}: {}", resp.name, value),
+        }
+    }
+
+    /// Next line that is not a response, e.g. a button event or a `watch` update, waits until one arrives.
+    /// `watch` updates are tagged with the id of the `watch` request, the tag is stripped.
+    pub async fn next_event(&mut self) -> Result<String> {
+        let line = match self.events.pop_front() {
+            Some(event) => event,
+            None => self.read_line().await?,
+        };
+        match line.strip_prefix('#').and_then(|l| l.split_once(' ')) {
+            Some((_, event)) => Ok(event.to_string()),
+            None => Ok(line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::UnixListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_client() -> Result<()> {
+        let path = std::env::temp_dir().join("pisugar-client-test.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(req)) = lines.next_line().await {
+                let resp = match req.as_str() {
+                    "#1 get battery" => "single\n#1 battery: 85.5\n",
+                    "#2 set_allow_charging true" => "#2 set_allow_charging: done\n#1 battery: 85.4\n",
+                    "#4 get protocol_version" => "#4 Invalid request.\n",
+                    _ => "#3 Invalid request.\n",
+                };
+                writer.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+
+        let mut client = Client::connect_uds(path.to_str().unwrap()).await?;
+        assert_eq!(client.get_as::<f32>(GetCmds::Battery).await?, 85.5);
+        assert_eq!(client.next_event().await?, "single");
+        client.run("set_allow_charging true").await?;
+        assert_eq!(client.next_event().await?, "battery: 85.4");
+        assert!(client.run("get battery").await.is_err());
+        assert!(client.request("get model").await.is_err());
+        assert_eq!(client.protocol_version().await?, 0);
+        let batch = Response::parse_line("[\"battery: 85.5\",\"model: PiSugar 3\"]")?;
+        assert_eq!(batch.name, "");
+        let _ = std::fs::remove_file(path);
+        Ok(())
+    }
+}
@@ -0,0 +1,1107 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Error as AnyError;
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, NaiveTime, TimeZone};
+use clap::{builder::PossibleValue, ArgAction, Args, CommandFactory, Parser, Subcommand};
+use enum_variants_strings::EnumVariantsStrings;
+use pisugar_core::{BatteryCapabilities, LedMode, PowerSave, RtcCapabilities, RtcIntOutput, REDACTED};
+use serde::{Deserialize, Serialize};
+
+/// Version of the command protocol, bumped when commands are added or changed, servers without
+/// `get protocol_version` are version 0
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// Max off time of `set_output_cycle` (ms)
+pub const OUTPUT_CYCLE_MAX_MS: u64 = 10_000;
+
+#[derive(Debug, Parser, PartialEq)]
+#[command(multicall = true)]
+#[clap(rename_all = "snake_case")]
+pub enum Cmds {
+    /// Get a value, e.g. `get battery`
+    #[command(subcommand)]
+    Get(GetCmds),
+
+    /// Set a value of several arguments, e.g. `set transport http off`
+    #[command(subcommand)]
+    Set(SetCmds),
+
+    /// Reset config to defaults, except i2c bus and addresses
+    FactoryReset,
+
+    /// Charging range restart_point% and stop_point%, e.g. `60,100`
+    SetBatteryChargingRange {
+        #[arg(value_delimiter = ',')]
+        range: Vec<f32>,
+    },
+
+    /// Enable or disable BAT input protect
+    SetBatteryInputProtect(BoolArg),
+
+    /// Enable or disable battery output
+    SetBatteryOutput(BoolArg),
+
+    /// Turn battery output off for ms (1-10000) and back on, e.g. to power cycle usb devices
+    SetOutputCycle {
+        #[arg(value_parser = clap::value_parser!(u64).range(1..=OUTPUT_CYCLE_MAX_MS))]
+        ms: u64,
+    },
+
+    /// Seconds of charging hold after fully charged
+    SetFullChargeDuration { seconds: u64 },
+
+    /// Enable or disable charging
+    SetAllowCharging(BoolArg),
+
+    /// Clear the rtc alarm flag
+    RtcClearFlag,
+
+    /// Sync time pi => rtc
+    RtcPi2rtc,
+
+    /// Sync time rtc => pi
+    RtcRtc2pi,
+
+    /// Sync time web => rtc & pi
+    RtcWeb,
+
+    /// Set rtc wakeup alarm, repeat in weekdays (127=1111111)
+    RtcAlarmSet {
+        datetime: DateTime<FixedOffset>,
+        weekdays: u8,
+    },
+
+    /// Disable rtc wakeup alarm
+    RtcAlarmDisable,
+
+    /// Adjust rtc ppm, -500.0 to 500.0
+    RtcAdjustPpm { ppm: f64 },
+
+    /// Auto shutdown level %
+    SetSafeShutdownLevel { level: f64 },
+
+    /// Auto shutdown delay in seconds
+    SetSafeShutdownDelay { delay: f64 },
+
+    /// Shutdown delay in seconds after power loss, empty to disable
+    SetOutageShutdownDelay { delay: Option<f64> },
+
+    /// Reboot at a 5 field cron expression of local time or a datetime, empty to cancel
+    ScheduleReboot { schedule: Vec<String> },
+
+    /// Wake up after 1min 30sec, please power off
+    RtcTestWake,
+
+    /// Enable or disable the custom button shell of a tap
+    SetButtonEnable { mode: ButtonMode, enable: BoolValue },
+
+    /// Shell script of a tap
+    SetButtonShell { mode: ButtonMode, shell: Vec<String> },
+
+    /// Power on when external power is plugged
+    SetAutoPowerOn(BoolArg),
+
+    /// Set http auth, or clear it with no arguments
+    SetAuth {
+        username: Option<String>,
+        password: Option<String>,
+    },
+
+    /// Cut power immediately
+    ForceShutdown,
+
+    /// Enable or disable anti-mistouch
+    SetAntiMistouch(BoolArg),
+
+    /// Enable or disable software poweroff
+    SetSoftPoweroff(BoolArg),
+
+    /// Shell script of software poweroff
+    SetSoftPoweroffShell { shell: Vec<String> },
+
+    /// Cancel a pending soft poweroff countdown
+    CancelPoweroff,
+
+    /// Enable or disable battery hardware protect
+    SetInputProtect(BoolArg),
+
+    /// Rebind battery and rtc on an i2c bus
+    SetI2cBus { bus: u8 },
+
+    /// Rebind battery on an i2c address
+    SetI2cAddr {
+        #[arg(value_parser = parse_i2c_addr)]
+        addr: u16,
+    },
+
+    /// Rebind rtc on an i2c address
+    SetRtcI2cAddr {
+        #[arg(value_parser = parse_i2c_addr)]
+        addr: u16,
+    },
+
+    /// Pause charging above chip temperature, disable with no arguments
+    SetThermalProtect { max: Option<f32> },
+
+    /// Charging range 40-80%, input protect and 30s full charge hold for 24/7 UPS duty
+    SetLongevityMode(BoolArg),
+
+    /// Apply a named power profile of config
+    SetProfile { name: String },
+
+    /// Output boost current limit (A), 1.0-3.15, empty to reset
+    SetOutputCurrentLimit { limit: Option<f32> },
+
+    /// SD3078 INT pin output, empty to restore alarm and auto power on
+    SetRtcIntOutput { output: Option<RtcIntOutput> },
+
+    /// Battery LED indicators off, low brightness or normal, newer PiSugar 3 firmware
+    SetLedMode { mode: LedMode },
+
+    /// Power save of the daemon, auto when unplugged
+    SetPowerSave { mode: PowerSave },
+
+    /// Write hex user data in rtc battery backed ram
+    SetRtcUserdata {
+        // fully qualified, so that clap takes it as a single value
+        #[arg(value_parser = parse_hex)]
+        data: ::std::vec::Vec<u8>,
+    },
+
+    /// Write a raw i2c register, requires `--enable-raw-i2c`
+    SetRegister {
+        #[arg(value_parser = parse_i2c_addr)]
+        addr: u16,
+        #[arg(value_parser = parse_u8)]
+        reg: u8,
+        #[arg(value_parser = parse_u8)]
+        value: u8,
+    },
+
+    /// Allow charging only in a time window, disable with no arguments
+    SetChargingWindow {
+        #[arg(value_parser = parse_time, requires = "end")]
+        begin: Option<NaiveTime>,
+        #[arg(value_parser = parse_time)]
+        end: Option<NaiveTime>,
+    },
+
+    /// Close a connection or end an http auth session of `get sessions`
+    RevokeSession { id: u64 },
+
+    /// `watch [field...] [interval]`, only on tcp/uds/ws connections
+    Watch { args: Vec<String> },
+
+    /// Stop `watch`
+    Unwatch,
+}
+
+impl FromStr for Cmds {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // json of `set config` is taken as is, shlex would strip its quotes
+        let mut words = s.trim().splitn(3, ' ');
+        if let (Some("set"), Some("config"), Some(json)) = (words.next(), words.next(), words.next()) {
+            return Ok(Cmds::Set(SetCmds::Config {
+                json: json.trim().to_string(),
+            }));
+        }
+        let mut args = shlex::split(s).ok_or(anyhow!("Invalid args"))?;
+        if args.as_slice()[1..].iter().any(|a| a.starts_with("-")) {
+            args.insert(1, "--".to_string());
+        }
+        Ok(Self::try_parse_from(args)?)
+    }
+}
+
+impl Cmds {
+    /// Whether the battery supports this cmd
+    pub fn battery_supported(&self, caps: &BatteryCapabilities) -> bool {
+        match self {
+            Cmds::Get(GetCmds::BatteryInputProtectEnabled | GetCmds::InputProtect)
+            | Cmds::SetBatteryInputProtect(_)
+            | Cmds::SetInputProtect(_) => caps.input_protect,
+            Cmds::Get(GetCmds::OutputCurrentLimit) | Cmds::SetOutputCurrentLimit { .. } => caps.output_current_limit,
+            Cmds::SetOutputCycle { .. } => caps.output_toggle,
+            Cmds::SetAntiMistouch(_) => caps.anti_mistouch,
+            Cmds::SetSoftPoweroff(_) => caps.soft_poweroff,
+            Cmds::Get(GetCmds::Temperature) => caps.temperature,
+            Cmds::Get(GetCmds::LedMode) | Cmds::SetLedMode { .. } => caps.led_mode,
+            Cmds::SetAllowCharging(_) | Cmds::SetBatteryChargingRange { .. } => caps.keep_input,
+            _ => true,
+        }
+    }
+
+    /// Whether the rtc supports this cmd
+    pub fn rtc_supported(&self, caps: &RtcCapabilities) -> bool {
+        match self {
+            Cmds::Get(GetCmds::RtcAlarmFlag) | Cmds::RtcClearFlag => caps.alarm_flag,
+            Cmds::RtcAdjustPpm { .. } => caps.adjust_ppm,
+            Cmds::Get(GetCmds::RtcBattery) => caps.battery_voltage,
+            Cmds::Get(GetCmds::RtcTemperature) => caps.temperature,
+            Cmds::Get(GetCmds::RtcUserdata) | Cmds::SetRtcUserdata { .. } => caps.user_data,
+            Cmds::Get(GetCmds::RtcIntOutput) | Cmds::SetRtcIntOutput { .. } => caps.int_output,
+            Cmds::SetRtcI2cAddr { .. } => caps.addr_change,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand, PartialEq, Eq)]
+#[clap(rename_all = "snake_case")]
+pub enum SetCmds {
+    /// Full config json, as of `get config`, redacted secrets are kept
+    Config { json: String },
+
+    /// Start or stop a listener, e.g. `set transport http off`
+    Transport {
+        transport: Transport,
+        #[arg(action = ArgAction::Set)]
+        enable: BoolValue,
+    },
+}
+
+#[derive(Debug, Subcommand, PartialEq, Eq)]
+#[clap(rename_all = "snake_case")]
+pub enum GetCmds {
+    /// Server version
+    Version,
+    /// Command protocol version
+    ProtocolVersion,
+    /// Available commands with arguments
+    Commands,
+    /// PiSugar model
+    Model,
+    /// Firmware version
+    FirmwareVersion,
+    /// Newer published firmware version, empty if none
+    FirmwareUpdateAvailable,
+    /// Battery level %
+    Battery,
+    /// Battery level % of the chip fuel gauge
+    BatteryChipLevel,
+    /// BAT current in A
+    BatteryI,
+    /// BAT voltage in V
+    BatteryV,
+    /// Charging led amount
+    BatteryLedAmount,
+    /// Whether external power is plugged
+    BatteryPowerPlugged,
+    /// Whether charging is allowed when power is plugged
+    BatteryAllowCharging,
+    /// Charging range restart_point% and stop_point%
+    BatteryChargingRange,
+    /// Charging status, deprecated
+    BatteryCharging,
+    /// BAT input protect enabled
+    BatteryInputProtectEnabled,
+    /// Battery output enabled
+    BatteryOutputEnabled,
+    /// Seconds of charging hold after fully charged
+    FullChargeDuration,
+    /// System time
+    SystemTime,
+    /// Rtc time
+    RtcTime,
+    /// Rtc time registers
+    RtcTimeList,
+    /// Rtc alarm flag
+    RtcAlarmFlag,
+    /// Rtc wakeup alarm time
+    RtcAlarmTime,
+    /// Rtc wakeup alarm time registers
+    RtcAlarmTimeList,
+    /// Rtc wakeup alarm enabled
+    RtcAlarmEnabled,
+    /// Rtc ppm adjustment
+    RtcAdjustPpm,
+    /// Rtc wakeup alarm repeat in weekdays
+    AlarmRepeat,
+    /// Auto shutdown level %
+    SafeShutdownLevel,
+    /// Auto shutdown delay in seconds
+    SafeShutdownDelay,
+    /// Shutdown delay after power loss, empty if disabled
+    OutageShutdownDelay,
+    /// Reboot schedule, empty if disabled
+    ScheduledReboot,
+    /// Next scheduled reboot, empty if none
+    NextReboot,
+    /// Whether the custom button shell of a tap is enabled
+    ButtonEnable { mode: ButtonMode },
+    /// Shell script of a tap
+    ButtonShell { mode: ButtonMode },
+    /// Power on when external power is plugged
+    AutoPowerOn,
+    /// Http auth username
+    AuthUsername,
+    /// Anti-mistouch enabled
+    AntiMistouch,
+    /// Software poweroff enabled
+    SoftPoweroff,
+    /// Shell script of software poweroff
+    SoftPoweroffShell,
+    /// Seconds before a pending soft poweroff, empty if none
+    SoftPoweroffCountdown,
+    /// Chip temperature
+    Temperature,
+    /// Battery hardware protect enabled
+    InputProtect,
+    /// Self-test report json for bug filing
+    Diagnostics,
+    /// Seconds since the last successful poll
+    DataAge,
+    /// Persistent stats json
+    Stats,
+    /// I2c bus
+    I2cBus,
+    /// Battery i2c address
+    I2cAddr,
+    /// Rtc i2c address
+    RtcI2cAddr,
+    /// Max chip temperature of charging
+    ThermalProtect,
+    /// Charging time window
+    ChargingWindow,
+    /// Active power profile
+    PowerProfile,
+    /// Output boost current limit (A)
+    OutputCurrentLimit,
+    /// Battery LED indicators
+    LedMode,
+    /// Power save mode of the daemon
+    PowerSave,
+    /// Whether the daemon is saving power now
+    PowerSaving,
+    /// Wake reason of this boot
+    WakeReason,
+    /// Rtc backup battery voltage (V)
+    RtcBattery,
+    /// Rtc chip temperature
+    RtcTemperature,
+    /// Hex user data in rtc battery backed ram
+    RtcUserdata,
+    /// SD3078 INT pin output, empty if alarm or auto power on
+    RtcIntOutput,
+    /// Supported rtc features
+    RtcCapabilities,
+    /// Supported battery features
+    Capabilities,
+    /// Config json, secrets redacted
+    Config,
+    /// Listeners started, e.g. `tcp=on,ws=off,http=on`
+    Transports,
+    /// Connections and http auth sessions json
+    Sessions,
+    /// Connection stats of transports json
+    Connections,
+    /// Recent log lines json, 50 by default
+    Logs { n: Option<usize> },
+    /// Read a raw i2c register, requires `--enable-raw-i2c`
+    Register {
+        #[arg(value_parser = parse_i2c_addr)]
+        addr: u16,
+        #[arg(value_parser = parse_u8)]
+        reg: u8,
+    },
+}
+
+impl fmt::Display for GetCmds {
+    /// Request of the cmd, e.g. `get battery_v`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GetCmds::ButtonEnable { mode } => write!(f, "get button_enable {}", mode.to_str()),
+            GetCmds::ButtonShell { mode } => write!(f, "get button_shell {}", mode.to_str()),
+            GetCmds::Logs { n: Some(n) } => write!(f, "get logs {}", n),
+            GetCmds::Logs { n: None } => write!(f, "get logs"),
+            GetCmds::Register { addr, reg } => write!(f, "get register {:#04x} {:#04x}", addr, reg),
+            _ => write!(f, "get {}", snake_case(&format!("{:?}", self))),
+        }
+    }
+}
+
+impl GetCmds {
+    /// Name in the response, e.g. `battery_v` of `battery_v: 4.1`
+    pub fn name(&self) -> String {
+        let req = self.to_string();
+        req.split(' ').nth(1).unwrap_or_default().to_string()
+    }
+}
+
+/// Request of the command catalogue json, `help` of other forms is the help text
+pub const HELP_JSON: &str = "help json";
+
+/// Protocol versions that added commands, commands not listed are of version 0 (servers without
+/// `get protocol_version`), add new commands here when `PROTOCOL_VERSION` is bumped
+const COMMANDS_SINCE: &[(&str, u32)] = &[
+    ("get protocol_version", 1),
+    ("get commands", 1),
+    ("get firmware_update_available", 1),
+    ("get battery_chip_level", 1),
+    ("get outage_shutdown_delay", 1),
+    ("get scheduled_reboot", 1),
+    ("get next_reboot", 1),
+    ("get soft_poweroff_countdown", 1),
+    ("get diagnostics", 1),
+    ("get data_age", 1),
+    ("get stats", 1),
+    ("get i2c_bus", 1),
+    ("get i2c_addr", 1),
+    ("get rtc_i2c_addr", 1),
+    ("get thermal_protect", 1),
+    ("get charging_window", 1),
+    ("get power_profile", 1),
+    ("get output_current_limit", 1),
+    ("get power_save", 1),
+    ("get power_saving", 1),
+    ("get wake_reason", 1),
+    ("get rtc_battery", 1),
+    ("get rtc_temperature", 1),
+    ("get rtc_userdata", 1),
+    ("get rtc_int_output", 1),
+    ("get rtc_capabilities", 1),
+    ("get capabilities", 1),
+    ("get logs", 1),
+    ("get register", 1),
+    ("set_outage_shutdown_delay", 1),
+    ("schedule_reboot", 1),
+    ("cancel_poweroff", 1),
+    ("set_i2c_bus", 1),
+    ("set_i2c_addr", 1),
+    ("set_rtc_i2c_addr", 1),
+    ("set_thermal_protect", 1),
+    ("set_longevity_mode", 1),
+    ("set_profile", 1),
+    ("set_output_current_limit", 1),
+    ("set_rtc_int_output", 1),
+    ("set_power_save", 1),
+    ("set_rtc_userdata", 1),
+    ("set_register", 1),
+    ("set_charging_window", 1),
+    ("watch", 1),
+    ("unwatch", 1),
+    ("factory_reset", 2),
+    ("get config", 2),
+    ("set config", 2),
+    ("set transport", 3),
+    ("get transports", 3),
+    ("revoke_session", 4),
+    ("get sessions", 4),
+    ("get connections", 5),
+    ("get led_mode", 7),
+    ("set_led_mode", 7),
+    ("set_output_cycle", 8),
+];
+
+/// Argument of a command of the catalogue
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArgSpec {
+    pub name: String,
+    pub required: bool,
+    /// Takes the rest of the request, e.g. a shell script
+    pub rest: bool,
+    /// Possible values, empty if any
+    pub values: Vec<String>,
+}
+
+/// Command of the catalogue of `help json`, for clients building forms of commands
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandSpec {
+    /// e.g. `get battery` or `rtc_alarm_set`
+    pub name: String,
+    pub args: Vec<ArgSpec>,
+    /// Doc of the command, empty if there is none
+    pub description: String,
+    /// Protocol version that added the command
+    pub since: u32,
+}
+
+impl CommandSpec {
+    fn new(prefix: &str, cmd: &clap::Command) -> Self {
+        let name = format!("{}{}", prefix, cmd.get_name());
+        let args = cmd
+            .get_positionals()
+            .map(|arg| ArgSpec {
+                name: arg.get_id().to_string(),
+                required: arg.is_required_set(),
+                rest: matches!(arg.get_action(), ArgAction::Append),
+                values: arg
+                    .get_possible_values()
+                    .iter()
+                    .map(|v| v.get_name().to_string())
+                    .collect(),
+            })
+            .collect();
+        let since = COMMANDS_SINCE
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, since)| *since)
+            .unwrap_or_default();
+        Self {
+            name,
+            args,
+            description: cmd.get_about().map(|a| a.to_string()).unwrap_or_default(),
+            since,
+        }
+    }
+
+    /// Signature of the command, e.g. `rtc_alarm_set <datetime> <weekdays>`, `[arg]` is optional,
+    /// `<arg>...` takes the rest of the request
+    pub fn signature(&self) -> String {
+        let mut signature = self.name.clone();
+        for arg in &self.args {
+            let (open, close) = if arg.required { ('<', '>') } else { ('[', ']') };
+            let rest = if arg.rest { "..." } else { "" };
+            signature += &format!(" {}{}{}{}", open, arg.name, close, rest);
+        }
+        signature
+    }
+}
+
+/// Catalogue of all commands, `get` fields included
+pub fn command_catalogue() -> Vec<CommandSpec> {
+    let mut cmds = Cmds::command();
+    cmds.build();
+    let mut catalogue = Vec::new();
+    for cmd in cmds.get_subcommands().filter(|c| c.get_name() != "help") {
+        if cmd.has_subcommands() {
+            let prefix = format!("{} ", cmd.get_name());
+            catalogue.extend(
+                cmd.get_subcommands()
+                    .filter(|c| c.get_name() != "help")
+                    .map(|c| CommandSpec::new(&prefix, c)),
+            );
+        } else {
+            catalogue.push(CommandSpec::new("", cmd));
+        }
+    }
+    catalogue
+}
+
+/// Signatures of all commands, `get` fields included, e.g. `get button_enable <mode>`
+pub fn command_signatures() -> Vec<String> {
+    command_catalogue().iter().map(CommandSpec::signature).collect()
+}
+
+/// `BatteryV` => `battery_v`, same as clap `rename_all = "snake_case"` of the variants
+fn snake_case(s: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in s.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+#[derive(Debug, EnumVariantsStrings, PartialEq, Eq, Clone, Copy)]
+pub enum ButtonMode {
+    Single,
+    Double,
+    Long,
+}
+
+impl clap::ValueEnum for ButtonMode {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Single, Self::Double, Self::Long]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(PossibleValue::new(self.to_str()))
+    }
+}
+
+/// Listener of `set transport`, uds is the local control socket and always on
+#[derive(Debug, EnumVariantsStrings, PartialEq, Eq, Clone, Copy)]
+pub enum Transport {
+    Tcp,
+    Ws,
+    Http,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_str())
+    }
+}
+
+impl clap::ValueEnum for Transport {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Tcp, Self::Ws, Self::Http]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(PossibleValue::new(self.to_str()))
+    }
+}
+
+#[derive(Debug, Args, PartialEq)]
+pub struct BatteryRangeArgs {
+    pub min: f32,
+    pub max: f32,
+}
+
+#[derive(Debug, Args, PartialEq, Clone)]
+pub struct BoolArg {
+    #[arg(action = ArgAction::Set)]
+    pub enable: BoolValue,
+}
+
+impl BoolArg {
+    const TRUE: Self = Self {
+        enable: BoolValue(true),
+    };
+    const FALSE: Self = Self {
+        enable: BoolValue(false),
+    };
+
+    pub fn value(&self) -> bool {
+        *self == Self::TRUE
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BoolValue(pub bool);
+
+impl From<String> for BoolValue {
+    fn from(value: String) -> Self {
+        if let Ok(b) = bool::from_str(&value) {
+            return Self(b);
+        }
+        match value.as_str() {
+            "on" => return Self(true),
+            "off" => return Self(false),
+            _ => {}
+        }
+        if let Ok(n) = u32::from_str(&value) {
+            return Self(n != 0);
+        } 
+        return Self(false);
+    }
+}
+
+/// Parse i2c address, hex (0x57) or decimal (87)
+fn parse_i2c_addr(s: &str) -> Result<u16, String> {
+    let addr = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => u16::from_str(s),
+    }
+    .map_err(|e| e.to_string())?;
+    if addr > 0x7f {
+        return Err(format!("Invalid i2c address: {}", s));
+    }
+    Ok(addr)
+}
+
+/// Parse byte, hex (0x prefixed) or decimal
+fn parse_u8(s: &str) -> Result<u8, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => u8::from_str(s),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Parse hex bytes, e.g. 0a0b or 0x0a0b
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    hex::decode(s.strip_prefix("0x").unwrap_or(s)).map_err(|e| e.to_string())
+}
+
+/// Parse time of day, e.g. 01:00 or 01:00:00
+fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .map_err(|e| e.to_string())
+}
+
+/// Legacy spellings of commands, e.g. of older client scripts, and their current ones
+pub const LEGACY_ALIASES: &[(&str, &str)] = &[
+    ("get battery_input_protect_enable", "get battery_input_protect_enabled"),
+    ("get battery_output_enable", "get battery_output_enabled"),
+    ("get rtc_alarm_enable", "get rtc_alarm_enabled"),
+    ("get rtc_alarm_repeat", "get alarm_repeat"),
+    ("set_battery_allow_charging", "set_allow_charging"),
+];
+
+/// Commands that are still served but deprecated, and their replacements
+pub const DEPRECATED_CMDS: &[(&str, &str)] = &[(
+    "get battery_charging",
+    "get battery_power_plugged and get battery_allow_charging",
+)];
+
+/// Legacy request resolved to its current spelling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alias {
+    /// Current request, same as the legacy one of a deprecated cmd
+    pub request: String,
+    /// Name in the response of the current request, e.g. `battery_output_enabled`
+    pub name: String,
+    /// Name in the response of the legacy request, e.g. `battery_output_enable`
+    pub legacy_name: String,
+    /// Deprecation warning
+    pub warning: String,
+}
+
+/// Name in the response of a request, e.g. `battery_v` of `get battery_v`
+fn response_name(req: &str) -> &str {
+    let mut parts = req.split(' ');
+    match parts.next() {
+        Some("get") => parts.next().unwrap_or_default(),
+        name => name.unwrap_or_default(),
+    }
+}
+
+/// Legacy `rtc_alarm_set` datetime, unix seconds or local time without offset, in rfc3339
+fn legacy_alarm_datetime(args: &[&str]) -> Option<(String, usize)> {
+    let first = *args.first()?;
+    if first.parse::<DateTime<FixedOffset>>().is_ok() {
+        return None;
+    }
+    if let Ok(secs) = first.parse::<i64>() {
+        return Some((Local.timestamp_opt(secs, 0).single()?.to_rfc3339(), 1));
+    }
+    let (naive, used) = match NaiveDateTime::parse_from_str(first, "%Y-%m-%dT%H:%M:%S") {
+        Ok(naive) => (naive, 1),
+        Err(_) => {
+            let datetime = format!("{} {}", first, args.get(1)?);
+            (NaiveDateTime::parse_from_str(&datetime, "%Y-%m-%d %H:%M:%S").ok()?, 2)
+        }
+    };
+    Some((Local.from_local_datetime(&naive).earliest()?.to_rfc3339(), used))
+}
+
+/// Resolve a legacy request to the current one, None if it's current
+pub fn resolve_alias(req: &str) -> Option<Alias> {
+    let req = req.trim();
+    let alias = |request: String, warning: String| Alias {
+        name: response_name(&request).to_string(),
+        legacy_name: response_name(req).to_string(),
+        request,
+        warning,
+    };
+    for (legacy, current) in LEGACY_ALIASES {
+        if let Some(rest) = req.strip_prefix(legacy).filter(|r| r.is_empty() || r.starts_with(' ')) {
+            let warning = format!("`{}` is deprecated, use `{}`", legacy, current);
+            return Some(alias(format!("{}{}", current, rest), warning));
+        }
+    }
+    if let Some((cmd, instead)) = DEPRECATED_CMDS.iter().find(|(cmd, _)| req == *cmd) {
+        return Some(alias(
+            req.to_string(),
+            format!("`{}` is deprecated, use {}", cmd, instead),
+        ));
+    }
+    if let Some(rest) = req.strip_prefix("rtc_alarm_set ") {
+        let args: Vec<&str> = rest.split(' ').filter(|a| !a.is_empty()).collect();
+        let (datetime, used) = legacy_alarm_datetime(&args)?;
+        let request = [vec!["rtc_alarm_set", datetime.as_str()], args[used..].to_vec()]
+            .concat()
+            .join(" ");
+        let warning = "`rtc_alarm_set` without utc offset is deprecated, use an ISO8601 time string".to_string();
+        return Some(alias(request, warning));
+    }
+    None
+}
+
+/// Prefix of a batch request, other requests are never split, `;` of their arguments included
+pub const BATCH_PREFIX: &str = "batch ";
+
+/// Commands whose last argument takes the rest of a batch, `;` of shell scripts, passwords and json included
+const BATCH_REST_CMDS: &[&str] = &["set_button_shell", "set_soft_poweroff_shell", "set_auth", "set config"];
+
+/// Split `;` separated requests of a batch, None if it's not a batch, `;` in quotes or escaped by `\` is kept,
+/// e.g. `batch get battery; get battery_v` => [`get battery`, `get battery_v`]
+pub fn split_batch(req: &str) -> Option<Vec<&str>> {
+    let req = req.trim_start().strip_prefix(BATCH_PREFIX)?;
+    let mut reqs = Vec::new();
+    let (mut start, mut quote, mut escaped) = (0, None, false);
+    for (i, c) in req.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (c, quote) {
+            ('\\', _) => escaped = true,
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (';', None) => {
+                let part = req[start..i].trim();
+                let rest_cmd = |cmd: &&str| part == *cmd || part.starts_with(&format!("{} ", cmd));
+                if BATCH_REST_CMDS.iter().any(rest_cmd) {
+                    break;
+                }
+                reqs.push(part);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    reqs.push(req[start..].trim());
+    reqs.retain(|r| !r.is_empty());
+    Some(reqs)
+}
+
+/// Commands of secrets, passwords and tokens of their arguments are not logged
+const SECRET_CMDS: &[&str] = &["set_auth", "set config"];
+
+/// Request for logs, arguments of `set_auth` and `set config` (batched too) are redacted,
+/// e.g. `set_auth <redacted>`
+pub fn redact_request(req: &str) -> Cow<'_, str> {
+    let end = SECRET_CMDS
+        .iter()
+        .filter_map(|cmd| req.find(cmd).map(|i| i + cmd.len()))
+        .min();
+    match end {
+        Some(end) if !req[end..].trim().is_empty() => Cow::Owned(format!("{} {}", &req[..end], REDACTED)),
+        _ => Cow::Borrowed(req),
+    }
+}
+
+/// Split device id from request, e.g. `get battery@dev1` => (`get battery`, `battery`, `dev1`)
+pub fn split_device(req: &str) -> Option<(String, &str, &str)> {
+    let mut parts: Vec<&str> = req.split(' ').collect();
+    let i = if parts.first() == Some(&"get") { 1 } else { 0 };
+    let (name, device) = parts.get(i)?.split_once('@')?;
+    parts[i] = name;
+    Some((parts.join(" "), name, device))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Result;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("get version", Cmds::Get(GetCmds::Version))]
+    #[case("get model", Cmds::Get(GetCmds::Model))]
+    #[case("get firmware_version", Cmds::Get(GetCmds::FirmwareVersion))]
+    #[case("get button_enable single", Cmds::Get(GetCmds::ButtonEnable{ mode: ButtonMode::Single }))]
+    #[case("get button_shell long", Cmds::Get(GetCmds::ButtonShell { mode: ButtonMode::Long } ))]
+    #[case("set_battery_charging_range 30.0,80.0", Cmds::SetBatteryChargingRange{ range: vec![30.0, 80.0]})]
+    #[case("set_battery_output true", Cmds::SetBatteryOutput(BoolArg::TRUE))]
+    #[case("set_battery_output false", Cmds::SetBatteryOutput(BoolArg::FALSE))]
+    #[case("set_output_cycle 500", Cmds::SetOutputCycle { ms: 500 })]
+    #[case("set_button_enable single 1", Cmds::SetButtonEnable { mode: ButtonMode::Single, enable: BoolValue(true) })]
+    #[case("set_button_shell single echo hello", Cmds::SetButtonShell { mode: ButtonMode::Single, shell: vec!["echo".to_string(), "hello".to_string()] })]
+    #[case("set_soft_poweroff_shell shutdown -a", Cmds::SetSoftPoweroffShell { shell: vec!["shutdown".to_string(), "-a".to_string()] })]
+    #[case("set_soft_poweroff_shell bash \"shutdown -a\"", Cmds::SetSoftPoweroffShell { shell: vec!["bash".to_string(), "shutdown -a".to_string()] })]
+    #[case("set_i2c_addr 0x57", Cmds::SetI2cAddr { addr: 0x57 })]
+    #[case("set_charging_window 01:00 06:00:00", Cmds::SetChargingWindow { begin: NaiveTime::from_hms_opt(1, 0, 0), end: NaiveTime::from_hms_opt(6, 0, 0) })]
+    #[case("set_charging_window", Cmds::SetChargingWindow { begin: None, end: None })]
+    #[case("set_longevity_mode true", Cmds::SetLongevityMode(BoolArg::TRUE))]
+    #[case("set_profile ups", Cmds::SetProfile { name: "ups".to_string() })]
+    #[case("set_output_current_limit 2.1", Cmds::SetOutputCurrentLimit { limit: Some(2.1) })]
+    #[case("set_output_current_limit", Cmds::SetOutputCurrentLimit { limit: None })]
+    #[case("set_led_mode off", Cmds::SetLedMode { mode: LedMode::Off })]
+    #[case("get led_mode", Cmds::Get(GetCmds::LedMode))]
+    #[case("set_outage_shutdown_delay 60", Cmds::SetOutageShutdownDelay { delay: Some(60.0) })]
+    #[case("set_outage_shutdown_delay", Cmds::SetOutageShutdownDelay { delay: None })]
+    #[case("get outage_shutdown_delay", Cmds::Get(GetCmds::OutageShutdownDelay))]
+    #[case("get config", Cmds::Get(GetCmds::Config))]
+    #[case("set config {\"auth_user\": \"pi sugar\"}", Cmds::Set(SetCmds::Config { json: "{\"auth_user\": \"pi sugar\"}".to_string() }))]
+    #[case("factory_reset", Cmds::FactoryReset)]
+    #[case("set transport http off", Cmds::Set(SetCmds::Transport { transport: Transport::Http, enable: BoolValue(false) }))]
+    #[case("set transport tcp on", Cmds::Set(SetCmds::Transport { transport: Transport::Tcp, enable: BoolValue(true) }))]
+    #[case("get transports", Cmds::Get(GetCmds::Transports))]
+    #[case("get sessions", Cmds::Get(GetCmds::Sessions))]
+    #[case("get connections", Cmds::Get(GetCmds::Connections))]
+    #[case("revoke_session 3", Cmds::RevokeSession { id: 3 })]
+    #[case("schedule_reboot 0 3 * * *", Cmds::ScheduleReboot { schedule: vec!["0".to_string(), "3".to_string(), "*".to_string(), "*".to_string(), "*".to_string()] })]
+    #[case("schedule_reboot", Cmds::ScheduleReboot { schedule: vec![] })]
+    #[case("get next_reboot", Cmds::Get(GetCmds::NextReboot))]
+    #[case("set_power_save auto", Cmds::SetPowerSave { mode: PowerSave::Auto })]
+    #[case("get power_save", Cmds::Get(GetCmds::PowerSave))]
+    #[case("get power_saving", Cmds::Get(GetCmds::PowerSaving))]
+    #[case("cancel_poweroff", Cmds::CancelPoweroff)]
+    #[case("get soft_poweroff_countdown", Cmds::Get(GetCmds::SoftPoweroffCountdown))]
+    #[case("get wake_reason", Cmds::Get(GetCmds::WakeReason))]
+    #[case("get rtc_battery", Cmds::Get(GetCmds::RtcBattery))]
+    #[case("get rtc_userdata", Cmds::Get(GetCmds::RtcUserdata))]
+    #[case("get rtc_capabilities", Cmds::Get(GetCmds::RtcCapabilities))]
+    #[case("get capabilities", Cmds::Get(GetCmds::Capabilities))]
+    #[case("get battery_chip_level", Cmds::Get(GetCmds::BatteryChipLevel))]
+    #[case("get data_age", Cmds::Get(GetCmds::DataAge))]
+    #[case("get stats", Cmds::Get(GetCmds::Stats))]
+    #[case("get logs 20", Cmds::Get(GetCmds::Logs { n: Some(20) }))]
+    #[case("get firmware_update_available", Cmds::Get(GetCmds::FirmwareUpdateAvailable))]
+    #[case("set_rtc_int_output 1/2hz", Cmds::SetRtcIntOutput { output: Some(RtcIntOutput::HzHalf) })]
+    #[case("set_rtc_int_output per_minute", Cmds::SetRtcIntOutput { output: Some(RtcIntOutput::PerMinute) })]
+    #[case("set_rtc_userdata 0x0a0b", Cmds::SetRtcUserdata { data: vec![0x0a, 0x0b] })]
+    #[case("get register 0x75 0x30", Cmds::Get(GetCmds::Register { addr: 0x75, reg: 0x30 }))]
+    #[case("set_register 0x57 2 0xff", Cmds::SetRegister { addr: 0x57, reg: 2, value: 0xff })]
+    #[case("set_rtc_i2c_addr 50", Cmds::SetRtcI2cAddr { addr: 0x32 })]
+    #[case("watch battery,battery_v 5", Cmds::Watch { args: vec!["battery,battery_v".to_string(), "5".to_string()] })]
+    #[case("unwatch", Cmds::Unwatch)]
+    #[case("get protocol_version", Cmds::Get(GetCmds::ProtocolVersion))]
+    #[case("get commands", Cmds::Get(GetCmds::Commands))]
+    fn test_cmds(#[case] repl: &str, #[case] cmd: Cmds) -> Result<()> {
+        assert!(cmd == Cmds::from_str(repl)?);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("set_output_cycle 0")]
+    #[case("set_output_cycle 10001")]
+    fn test_output_cycle_range(#[case] repl: &str) {
+        assert!(Cmds::from_str(repl).is_err());
+    }
+
+    #[rstest]
+    #[case(GetCmds::BatteryV, "battery_v")]
+    #[case(GetCmds::I2cBus, "i2c_bus")]
+    #[case(GetCmds::RtcI2cAddr, "rtc_i2c_addr")]
+    #[case(GetCmds::FirmwareUpdateAvailable, "firmware_update_available")]
+    #[case(GetCmds::ButtonShell { mode: ButtonMode::Double }, "button_shell")]
+    #[case(GetCmds::Logs { n: Some(20) }, "logs")]
+    #[case(GetCmds::Logs { n: None }, "logs")]
+    #[case(GetCmds::Register { addr: 0x57, reg: 2 }, "register")]
+    fn test_get_cmds_display(#[case] cmd: GetCmds, #[case] name: &str) -> Result<()> {
+        assert_eq!(cmd.name(), name);
+        assert_eq!(Cmds::from_str(&cmd.to_string())?, Cmds::Get(cmd));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("get battery@dev1", Some(("get battery".to_string(), "battery", "dev1")))]
+    #[case("set_allow_charging@dev1 true", Some(("set_allow_charging true".to_string(), "set_allow_charging", "dev1")))]
+    #[case("set_button_shell single echo a@b", None)]
+    #[case("get battery", None)]
+    fn test_split_device(#[case] req: &str, #[case] expected: Option<(String, &str, &str)>) {
+        assert_eq!(split_device(req), expected);
+    }
+
+    #[rstest]
+    fn test_command_signatures() {
+        let signatures = command_signatures();
+        for signature in [
+            "get battery",
+            "get button_enable <mode>",
+            "get logs [n]",
+            "rtc_alarm_set <datetime> <weekdays>",
+            "set_allow_charging <enable>",
+            "set_button_shell <mode> [shell]...",
+            "set config <json>",
+            "unwatch",
+        ] {
+            assert!(signatures.iter().any(|s| s == signature), "{}", signature);
+        }
+        assert!(!signatures.iter().any(|s| s.contains("help")));
+
+        let catalogue = command_catalogue();
+        let button_enable = catalogue.iter().find(|c| c.name == "get button_enable").unwrap();
+        assert_eq!(button_enable.args[0].values, vec!["single", "double", "long"]);
+        let factory_reset = catalogue.iter().find(|c| c.name == "factory_reset").unwrap();
+        assert_eq!(factory_reset.since, 2);
+        assert!(factory_reset.description.starts_with("Reset config"));
+        assert!(catalogue.iter().all(|c| c.since <= PROTOCOL_VERSION));
+        for (name, _) in COMMANDS_SINCE {
+            assert!(catalogue.iter().any(|c| c.name == *name), "{}", name);
+        }
+        let since = |name: &str| catalogue.iter().find(|c| c.name == name).map(|c| c.since);
+        assert_eq!(since("set_power_save"), Some(1));
+        assert_eq!(since("get battery"), Some(0));
+        for cmd in &catalogue {
+            assert!(!cmd.description.is_empty(), "{}", cmd.name);
+        }
+    }
+
+    #[rstest]
+    #[case("get battery_output_enable", Some(("get battery_output_enabled", "battery_output_enabled", "battery_output_enable")))]
+    #[case("set_battery_allow_charging true", Some(("set_allow_charging true", "set_allow_charging", "set_battery_allow_charging")))]
+    #[case("get battery_charging", Some(("get battery_charging", "battery_charging", "battery_charging")))]
+    #[case("get battery_output_enabled", None)]
+    #[case("rtc_alarm_set 2020-06-26T16:09:34+08:00 127", None)]
+    fn test_resolve_alias(#[case] req: &str, #[case] expected: Option<(&str, &str, &str)>) {
+        let alias = resolve_alias(req);
+        assert_eq!(
+            alias
+                .as_ref()
+                .map(|a| (a.request.as_str(), a.name.as_str(), a.legacy_name.as_str())),
+            expected
+        );
+        if let Some(alias) = alias {
+            assert!(Cmds::from_str(&alias.request).is_ok());
+            assert!(alias.warning.contains("deprecated"));
+        }
+    }
+
+    #[rstest]
+    #[case("rtc_alarm_set 2020-06-26T16:09:34 127")]
+    #[case("rtc_alarm_set 2020-06-26 16:09:34 127")]
+    fn test_resolve_alarm_alias(#[case] req: &str) -> Result<()> {
+        let alias = resolve_alias(req).unwrap();
+        let expected = Local.with_ymd_and_hms(2020, 6, 26, 16, 9, 34).unwrap();
+        assert_eq!(
+            Cmds::from_str(&alias.request)?,
+            Cmds::RtcAlarmSet {
+                datetime: expected.fixed_offset(),
+                weekdays: 127
+            }
+        );
+        let alias = resolve_alias(&format!("rtc_alarm_set {} 127", expected.timestamp())).unwrap();
+        assert!(matches!(Cmds::from_str(&alias.request)?, Cmds::RtcAlarmSet { datetime, .. } if datetime == expected));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("get battery", None)]
+    #[case("get battery; get battery_v", None)]
+    #[case("set_button_shell single echo a; echo b", None)]
+    #[case("batch get battery", Some(vec!["get battery"]))]
+    #[case("batch get battery; get battery_v;get model;", Some(vec!["get battery", "get battery_v", "get model"]))]
+    #[case("batch get battery; set_button_shell single echo a; echo b", Some(vec!["get battery", "set_button_shell single echo a; echo b"]))]
+    #[case("batch set_soft_poweroff_shell \"a; b\"; get battery", Some(vec!["set_soft_poweroff_shell \"a; b\"; get battery"]))]
+    #[case("batch rtc_alarm_set \"2020-06-26 16:09:34;\" 127; get battery", Some(vec!["rtc_alarm_set \"2020-06-26 16:09:34;\" 127", "get battery"]))]
+    #[case("batch rtc_alarm_set \"a\\\";b\" 127; get model\\;x; get battery", Some(vec!["rtc_alarm_set \"a\\\";b\" 127", "get model\\;x", "get battery"]))]
+    #[case("batch get battery; set config {\"button_shell\":\"echo \\\"a;b\\\"\"}", Some(vec!["get battery", "set config {\"button_shell\":\"echo \\\"a;b\\\"\"}"]))]
+    #[case("batch get battery; set configx; get model", Some(vec!["get battery", "set configx", "get model"]))]
+    fn test_split_batch(#[case] req: &str, #[case] expected: Option<Vec<&str>>) {
+        assert_eq!(split_batch(req), expected);
+    }
+
+    #[rstest]
+    #[case("set_auth admin secret", "set_auth <redacted>")]
+    #[case("#42 set config {\"auth_password\":\"secret\"}", "#42 set config <redacted>")]
+    #[case("batch get battery; set config {\"auth_password\":\"secret\"}", "batch get battery; set config <redacted>")]
+    #[case("set_auth", "set_auth")]
+    #[case("get config", "get config")]
+    fn test_redact_request(#[case] req: &str, #[case] expected: &str) {
+        assert_eq!(redact_request(req), expected);
+    }
+
+    #[rstest]
+    #[case("set_led_mode off", false)]
+    #[case("get led_mode", false)]
+    #[case("get battery", true)]
+    fn test_battery_supported(#[case] req: &str, #[case] supported: bool) {
+        let caps = BatteryCapabilities::default();
+        assert_eq!(Cmds::from_str(req).unwrap().battery_supported(&caps), supported);
+        let caps = BatteryCapabilities {
+            led_mode: true,
+            ..Default::default()
+        };
+        assert!(Cmds::from_str(req).unwrap().battery_supported(&caps));
+    }
+
+    #[rstest]
+    fn test_help() {
+        let h = Cmds::from_str("help");
+        assert!(format!("{:?}", h).contains("help"));
+    }
+
+    #[rstest]
+    fn test_help_get() {
+        let h = Cmds::from_str("help get");
+        println!("{:?}", h);
+        assert!(format!("{:?}", h).contains("version"));
+    }
+}
@@ -0,0 +1,19 @@
+//! Command protocol of pisugar-server, and an async client of tcp, uds and ws connections.
+//!
+//! ```no_run
+//! use pisugar_client::{cmds::GetCmds, Client};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let mut client = Client::connect_uds("/tmp/pisugar-server.sock").await?;
+//! let level: f32 = client.get_as(GetCmds::Battery).await?;
+//! client.run("set_allow_charging true").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub use client::{Client, Response, DEFAULT_TIMEOUT, INVALID_REQUEST};
+
+pub mod cmds;
+pub mod metrics;
+
+mod client;
@@ -0,0 +1,158 @@
+/// Measurement, or metric prefix, of exported metrics
+pub const MEASUREMENT: &str = "pisugar";
+
+/// Metrics of a battery snapshot and their get commands, same as the telemetry of pisugar-server
+pub const METRIC_FIELDS: &[(&str, &str)] = &[
+    ("battery", "battery"),
+    ("battery_v", "battery_v"),
+    ("battery_i", "battery_i"),
+    ("battery_charging", "battery_charging"),
+    ("battery_power_plugged", "battery_power_plugged"),
+    ("battery_allow_charging", "battery_allow_charging"),
+    ("temperature", "temperature"),
+];
+
+/// Metric of a battery snapshot, booleans are 0 or 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metric {
+    /// e.g. battery_v
+    pub name: &'static str,
+    pub value: f64,
+}
+
+impl Metric {
+    pub fn new(name: &'static str, value: impl Into<f64>) -> Self {
+        Self {
+            name,
+            value: value.into(),
+        }
+    }
+
+    /// Metric of a response value, booleans are 0 or 1, None if it's not a number
+    pub fn parse(name: &'static str, value: &str) -> Option<Self> {
+        let value = match value.trim() {
+            "true" => 1.0,
+            "false" => 0.0,
+            v => v.parse().ok()?,
+        };
+        Some(Self { name, value })
+    }
+}
+
+/// Escape commas, equals signs and spaces of influx tags and field keys
+fn influx_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// InfluxDB line protocol, e.g. `pisugar,model=PiSugar\ 3 battery=80,battery_v=4.1 1717200000`,
+/// timestamp in seconds, None without metrics
+pub fn influx_line(measurement: &str, tags: &[(&str, &str)], metrics: &[Metric], timestamp: i64) -> Option<String> {
+    if metrics.is_empty() {
+        return None;
+    }
+    let mut line = measurement.replace(',', "\\,").replace(' ', "\\ ");
+    for (key, value) in tags.iter().filter(|(_, v)| !v.is_empty()) {
+        line += &format!(",{}={}", influx_escape(key), influx_escape(value));
+    }
+    let fields: Vec<String> = metrics
+        .iter()
+        .map(|m| format!("{}={}", influx_escape(m.name), m.value))
+        .collect();
+    Some(format!("{} {} {}", line, fields.join(","), timestamp))
+}
+
+/// Graphite plaintext, `<prefix>.<name> <value> <timestamp>` lines, timestamp in seconds
+pub fn graphite_lines(prefix: &str, metrics: &[Metric], timestamp: i64) -> String {
+    metrics
+        .iter()
+        .map(|m| format!("{}.{} {} {}\n", prefix, m.name, m.value, timestamp))
+        .collect()
+}
+
+/// StatsD gauges, `<prefix>.<name>:<value>|g` lines
+pub fn statsd_lines(prefix: &str, metrics: &[Metric]) -> String {
+    metrics
+        .iter()
+        .map(|m| {
+            // a signed gauge is a delta, set it to 0 first
+            let reset = if m.value < 0.0 {
+                format!("{}.{}:0|g\n", prefix, m.name)
+            } else {
+                String::new()
+            };
+            format!("{}{}.{}:{}|g\n", reset, prefix, m.name, m.value)
+        })
+        .collect()
+}
+
+/// collectd exec plugin gauges, `PUTVAL "<host>/pisugar/gauge-<name>" interval=<s> N:<value>` lines
+pub fn collectd_lines(host: &str, metrics: &[Metric], interval: Option<f64>) -> String {
+    let interval = interval.map(|i| format!(" interval={}", i)).unwrap_or_default();
+    metrics
+        .iter()
+        .map(|m| {
+            format!(
+                "PUTVAL \"{}/{}/gauge-{}\"{} N:{}\n",
+                host, MEASUREMENT, m.name, interval, m.value
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_influx_line() {
+        let metrics = [Metric::new("battery", 80.5), Metric::new("power_plugged", 1)];
+        assert_eq!(
+            influx_line(
+                MEASUREMENT,
+                &[("model", "PiSugar 3"), ("host", "")],
+                &metrics,
+                1717200000
+            )
+            .unwrap(),
+            "pisugar,model=PiSugar\\ 3 battery=80.5,power_plugged=1 1717200000"
+        );
+        assert_eq!(influx_line(MEASUREMENT, &[], &[], 0), None);
+    }
+
+    #[test]
+    fn test_graphite_lines() {
+        let metrics = [Metric::new("battery", 80.5), Metric::new("battery_i", -0.25)];
+        assert_eq!(
+            graphite_lines(MEASUREMENT, &metrics, 1717200000),
+            "pisugar.battery 80.5 1717200000\npisugar.battery_i -0.25 1717200000\n"
+        );
+        assert_eq!(
+            statsd_lines(MEASUREMENT, &metrics),
+            "pisugar.battery:80.5|g\npisugar.battery_i:0|g\npisugar.battery_i:-0.25|g\n"
+        );
+    }
+
+    #[test]
+    fn test_collectd_lines() {
+        let metrics = [
+            Metric::parse("battery", "80.5").unwrap(),
+            Metric::parse("battery_charging", "true").unwrap(),
+        ];
+        assert_eq!(Metric::parse("temperature", ""), None);
+        assert_eq!(
+            collectd_lines("pi", &metrics, Some(10.0)),
+            "PUTVAL \"pi/pisugar/gauge-battery\" interval=10 N:80.5\nPUTVAL \"pi/pisugar/gauge-battery_charging\" interval=10 N:1\n"
+        );
+        assert_eq!(
+            collectd_lines("pi", &metrics[..1], None),
+            "PUTVAL \"pi/pisugar/gauge-battery\" N:80.5\n"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{Error, Result};
+
+/// Min calibration interval of drift factor, same as hwclock
+pub const DRIFT_CALIBRATION_MIN_SECS: i64 = 4 * 3600;
+
+/// Larger rtc errors are not drift, e.g. rtc lost power
+pub const DRIFT_ERROR_MAX_SECS: f64 = 3600.0;
+
+/// Rtc drift record, in /etc/adjtime format:
+/// `<drift factor (s/day)> <last adjust time> 0.000000`, `<last calibration time>`, `UTC`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Adjtime {
+    /// Seconds per day the rtc loses, negative if it gains
+    pub drift_factor: f64,
+    /// Unix timestamp of the last rtc write
+    pub last_adjust: i64,
+    /// Unix timestamp of the last calibration
+    pub last_calibration: i64,
+}
+
+impl Adjtime {
+    /// Parse adjtime file content
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || Error::Other(format!("Invalid adjtime: {:?}", s));
+        let mut lines = s.lines();
+        let mut line1 = lines.next().ok_or_else(invalid)?.split_whitespace();
+        let drift_factor = line1.next().and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+        let last_adjust = line1.next().and_then(|v| v.parse().ok()).ok_or_else(invalid)?;
+        let last_calibration = lines.next().and_then(|v| v.trim().parse().ok()).ok_or_else(invalid)?;
+        Ok(Self {
+            drift_factor,
+            last_adjust,
+            last_calibration,
+        })
+    }
+
+    /// Load from file
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&read_to_string(path)?)
+    }
+
+    /// Save to file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write(path, self.to_string())?;
+        Ok(())
+    }
+
+    /// Predicted rtc error (s) at time `now`
+    pub fn predicted_error(&self, now: DateTime<Utc>) -> f64 {
+        if self.last_adjust <= 0 {
+            return 0.0;
+        }
+        let days = (now.timestamp() - self.last_adjust) as f64 / 86400.0;
+        self.drift_factor * days
+    }
+
+    /// Predicted correction to rtc time
+    pub fn correction(&self, rtc_time: DateTime<Utc>) -> Duration {
+        Duration::milliseconds((self.predicted_error(rtc_time) * 1000.0) as i64)
+    }
+
+    /// Calibrate with reference time and rtc time, returns whether the rtc should be written
+    pub fn calibrate(&mut self, reference: DateTime<Utc>, rtc_time: Option<DateTime<Utc>>) -> bool {
+        let error = rtc_time.map(|t| (reference - t).num_milliseconds() as f64 / 1000.0);
+        let elapsed = reference.timestamp() - self.last_adjust;
+        let write = match error {
+            _ if self.last_adjust <= 0 => true,
+            Some(error) if error.abs() < DRIFT_ERROR_MAX_SECS => {
+                if elapsed < DRIFT_CALIBRATION_MIN_SECS {
+                    // keep measuring, unless the rtc is obviously off
+                    error.abs() >= 1.0
+                } else {
+                    self.drift_factor += error / (elapsed as f64 / 86400.0);
+                    self.last_calibration = reference.timestamp();
+                    true
+                }
+            }
+            _ => true,
+        };
+        if write {
+            self.last_adjust = reference.timestamp();
+        }
+        write
+    }
+}
+
+impl std::fmt::Display for Adjtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:.6} {} 0.000000", self.drift_factor, self.last_adjust)?;
+        writeln!(f, "{}", self.last_calibration)?;
+        writeln!(f, "UTC")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_adjtime() {
+        let mut adjtime = Adjtime::parse("0.500000 1609459200 0.000000\n1609459200\nUTC\n").unwrap();
+        assert_eq!(Adjtime::parse(&adjtime.to_string()).unwrap(), adjtime);
+        assert!(Adjtime::parse("").is_err());
+
+        // lost 2s in 2 days
+        let t0 = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let now = t0 + Duration::days(2);
+        assert_eq!(adjtime.correction(now).num_seconds(), 1);
+        assert!(adjtime.calibrate(now, Some(now - Duration::seconds(2))));
+        assert!((adjtime.drift_factor - 1.5).abs() < 1e-6);
+        assert_eq!(adjtime.last_adjust, now.timestamp());
+
+        // too early to calibrate
+        let later = now + Duration::hours(1);
+        assert!(!adjtime.calibrate(later, Some(later)));
+        assert_eq!(adjtime.last_adjust, now.timestamp());
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::VecDeque;
 use std::time::Instant;
 
-use crate::{PiSugarConfig, Result, TapType};
+use crate::sample::Timestamped;
+use crate::{Error, LedMode, PiSugarConfig, Result, TapType};
 
 /// Battery event
 pub enum BatteryEvent {
@@ -9,8 +10,55 @@ pub enum BatteryEvent {
     SoftPowerOff,
 }
 
+/// Battery capabilities
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatteryCapabilities {
+    /// Input protect
+    pub input_protect: bool,
+    /// Output on/off
+    pub output_toggle: bool,
+    /// Output current limit
+    pub output_current_limit: bool,
+    /// Power restore, auto power on of PiSugar 3
+    pub power_restore: bool,
+    /// Anti-mistouch of power button
+    pub anti_mistouch: bool,
+    /// Soft poweroff
+    pub soft_poweroff: bool,
+    /// Chip temperature
+    pub temperature: bool,
+    /// LED indicators off or low brightness, probed on init
+    pub led_mode: bool,
+    /// Charging off while usb input keeps powering the pi, `set_allow_charging` and charging range
+    pub keep_input: bool,
+}
+
+impl BatteryCapabilities {
+    /// Names of supported capabilities
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            ("input_protect", self.input_protect),
+            ("output_toggle", self.output_toggle),
+            ("output_current_limit", self.output_current_limit),
+            ("power_restore", self.power_restore),
+            ("anti_mistouch", self.anti_mistouch),
+            ("soft_poweroff", self.soft_poweroff),
+            ("temperature", self.temperature),
+            ("led_mode", self.led_mode),
+            ("keep_input", self.keep_input),
+        ]
+        .iter()
+        .filter(|(_, supported)| *supported)
+        .map(|(name, _)| *name)
+        .collect()
+    }
+}
+
 /// Battery chip controller
 pub trait Battery {
+    /// Supported features
+    fn capabilities(&self) -> BatteryCapabilities;
+
     /// Init battery chip
     fn init(&mut self, config: &PiSugarConfig) -> Result<()>;
 
@@ -38,6 +86,12 @@ pub trait Battery {
     /// Battery average current intensity (A)
     fn intensity_avg(&self) -> Result<f32>;
 
+    /// Recent voltage samples (V), oldest first
+    fn voltage_history(&self) -> Vec<Timestamped<f32>>;
+
+    /// Recent current intensity samples (A), oldest first
+    fn intensity_history(&self) -> Vec<Timestamped<f32>>;
+
     /// Is power cable plugged in
     fn is_power_plugged(&self) -> Result<bool>;
 
@@ -73,6 +127,31 @@ pub trait Battery {
         self.toggle_output_enabled(false)
     }
 
+    /// Battery level of the chip fuel gauge
+    fn chip_level(&self) -> Result<f32> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    /// Output boost current limit (A)
+    fn output_current_limit(&self) -> Result<f32> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    /// Set output boost current limit (A), None to reset to the chip default
+    fn set_output_current_limit(&self, _limit: Option<f32>) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    /// Battery LED indicators
+    fn led_mode(&self) -> Result<LedMode> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    /// Set battery LED indicators
+    fn set_led_mode(&self, _mode: LedMode) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
     /// Enable/disable light load shutdown
     fn toggle_light_load_shutdown(&self, enable: bool) -> Result<()>;
 
@@ -1,15 +1,108 @@
 use std::{
+    collections::BTreeMap,
+    fmt,
     fs::{File, OpenOptions},
     io::{self, Read, Write},
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
     path::Path,
+    str::FromStr,
 };
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, NaiveTime};
 use serde::{Deserialize, Serialize};
 
+use crate::{Schedule, LONGEVITY_CHARGING_RANGE, LONGEVITY_FULL_CHARGE_DURATION};
+
 /// Battery voltage threshold, (low, percentage at low)
 pub type BatteryThreshold = (f32, f32);
 
+/// Standard LiPo/Li-ion cell, 4.2V full
+pub const LIPO_CURVE: [BatteryThreshold; 10] = [
+    (4.20, 100.0),
+    (4.10, 90.0),
+    (4.00, 80.0),
+    (3.90, 65.0),
+    (3.80, 50.0),
+    (3.75, 40.0),
+    (3.70, 25.0),
+    (3.60, 10.0),
+    (3.50, 5.0),
+    (3.30, 0.0),
+];
+
+/// LiFePO4 cell, 3.6V full, flat in the middle
+pub const LIFEPO4_CURVE: [BatteryThreshold; 10] = [
+    (3.60, 100.0),
+    (3.40, 95.0),
+    (3.35, 90.0),
+    (3.32, 70.0),
+    (3.30, 50.0),
+    (3.27, 30.0),
+    (3.25, 20.0),
+    (3.20, 10.0),
+    (3.00, 5.0),
+    (2.60, 0.0),
+];
+
+/// High-capacity pack, e.g. 5000mAh, larger voltage sag under load
+pub const HIGH_CAPACITY_CURVE: [BatteryThreshold; 10] = [
+    (4.10, 100.0),
+    (4.00, 90.0),
+    (3.90, 78.0),
+    (3.80, 64.0),
+    (3.72, 50.0),
+    (3.66, 38.0),
+    (3.60, 25.0),
+    (3.50, 12.0),
+    (3.40, 4.0),
+    (3.10, 0.0),
+];
+
+/// Secret of config with `${NAME}` expanded from environment, e.g. `${PISUGAR_AUTH_PASSWORD}`,
+/// unset variables are empty
+pub fn resolve_secret(value: &str) -> String {
+    let mut resolved = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        resolved += &rest[..start];
+        let name = &rest[start + 2..end];
+        match std::env::var(name) {
+            Ok(value) => resolved += &value,
+            Err(_) => log::warn!("Environment variable {} of config is not set", name),
+        }
+        rest = &rest[end + 1..];
+    }
+    resolved + rest
+}
+
+/// Placeholder of secrets in an exported config, a redacted secret pushed back keeps the current one
+pub const REDACTED: &str = "<redacted>";
+
+/// Battery cell chemistry, selects a built-in battery curve
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryChemistry {
+    Lipo,
+    Lifepo4,
+    HighCapacity,
+}
+
+impl BatteryChemistry {
+    /// Built-in battery curve
+    pub fn curve(&self) -> &'static [BatteryThreshold] {
+        match self {
+            BatteryChemistry::Lipo => &LIPO_CURVE,
+            BatteryChemistry::Lifepo4 => &LIFEPO4_CURVE,
+            BatteryChemistry::HighCapacity => &HIGH_CAPACITY_CURVE,
+        }
+    }
+}
+
 fn default_i2c_bus() -> u8 {
     1
 }
@@ -19,6 +112,471 @@ fn default_session_timeout() -> u32 {
     60 * 60
 }
 
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_telemetry_interval() -> u64 {
+    60
+}
+
+fn default_snmp_listen() -> String {
+    "0.0.0.0:161".to_string()
+}
+
+fn default_modbus_listen() -> String {
+    "127.0.0.1:502".to_string()
+}
+
+fn default_modbus_read_only() -> bool {
+    true
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+/// Notification backend
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierBackend {
+    /// Broadcast to terminals with wall
+    Wall,
+    /// Desktop notification with notify-send
+    NotifySend,
+    /// Http POST json, `{"title": "...", "message": "..."}`
+    Webhook { url: String },
+    /// MQTT publish, QoS 0
+    Mqtt {
+        host: String,
+        #[serde(default = "default_mqtt_port")]
+        port: u16,
+        topic: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// Telegram bot
+    Telegram { token: String, chat_id: String },
+    /// Gotify server
+    Gotify { url: String, token: String },
+    /// ntfy topic url, e.g. https://ntfy.sh/<topic>
+    Ntfy { url: String },
+}
+
+/// Notifier configuration
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NotifierConfig {
+    #[serde(flatten)]
+    pub backend: NotifierBackend,
+
+    /// Message template, placeholders: {message} {level} {seconds} {model} {hostname}
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+impl Default for NotifierConfig {
+    fn default() -> Self {
+        Self {
+            backend: NotifierBackend::Wall,
+            template: None,
+        }
+    }
+}
+
+/// Webhook configuration
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// Http(s) url, POST json `{"event": "...", "time": "...", "model": "...", "data": {...}}`
+    pub url: String,
+
+    /// HMAC-SHA256 secret, signature of body in `X-PiSugar-Signature: sha256=<hex>` header
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    /// Subscribed events, default all: tap, low_battery, power_loss, power_restore, shutdown,
+    /// thermal_protect_paused, thermal_protect_resumed, missed_alarm, under_voltage,
+    /// outage_countdown, outage_cancelled, soft_poweroff_countdown, soft_poweroff_cancelled,
+    /// degraded, recovered
+    #[serde(default)]
+    pub events: Option<Vec<String>>,
+
+    /// Retries on failure, default 3
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+}
+
+impl WebhookConfig {
+    /// Whether the event is subscribed
+    pub fn subscribed(&self, event: &str) -> bool {
+        match &self.events {
+            Some(events) => events.iter().any(|e| e == event),
+            None => true,
+        }
+    }
+}
+
+/// InfluxDB v2 line protocol push, VictoriaMetrics takes it as well
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InfluxDbConfig {
+    /// Server url, e.g. http://127.0.0.1:8086, written to `<url>/api/v2/write`
+    pub url: String,
+
+    /// Api token, `Authorization: Token <token>`
+    #[serde(default)]
+    pub token: Option<String>,
+
+    /// Organization
+    #[serde(default)]
+    pub org: Option<String>,
+
+    /// Bucket
+    pub bucket: String,
+
+    /// Push interval (s), default 60
+    #[serde(default = "default_telemetry_interval")]
+    pub interval: u64,
+}
+
+/// Read-only SNMP v1/v2c agent of battery status
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SnmpConfig {
+    /// Udp listen address, default 0.0.0.0:161
+    #[serde(default = "default_snmp_listen")]
+    pub listen: String,
+
+    /// Community, requests of other communities are dropped
+    pub community: String,
+
+    /// Base oid of PISUGAR-MIB, default 1.3.6.1.4.1.8072.9999.9999.1 (netSnmpPlaypen.1)
+    #[serde(default)]
+    pub base_oid: Option<String>,
+}
+
+/// Modbus TCP server of battery registers and control coils
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModbusConfig {
+    /// Tcp listen address, default 127.0.0.1:502
+    #[serde(default = "default_modbus_listen")]
+    pub listen: String,
+
+    /// Unit id, requests of other units are dropped, default any
+    #[serde(default)]
+    pub unit_id: Option<u8>,
+
+    /// Reject coil writes (allow charging, battery output), default true, modbus has no auth and the battery
+    /// output powers the pi
+    #[serde(default = "default_modbus_read_only")]
+    pub read_only: bool,
+}
+
+/// Protocol of the graphite emitter
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphiteProtocol {
+    /// Graphite plaintext over tcp, `<path> <value> <timestamp>`
+    #[default]
+    Graphite,
+    /// StatsD gauges over udp, `<path>:<value>|g`
+    Statsd,
+}
+
+impl GraphiteProtocol {
+    /// Default port, 2003 of carbon, 8125 of statsd
+    pub fn default_port(&self) -> u16 {
+        match self {
+            GraphiteProtocol::Graphite => 2003,
+            GraphiteProtocol::Statsd => 8125,
+        }
+    }
+}
+
+/// Graphite plaintext or StatsD emitter of battery metrics
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GraphiteConfig {
+    /// graphite (default) or statsd
+    #[serde(default)]
+    pub protocol: GraphiteProtocol,
+
+    /// Server host
+    pub host: String,
+
+    /// Server port, default of the protocol
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// Metric path prefix, default `pisugar`, e.g. `pisugar.battery_v`
+    #[serde(default)]
+    pub prefix: Option<String>,
+
+    /// Emit interval (s), default 60
+    #[serde(default = "default_telemetry_interval")]
+    pub interval: u64,
+}
+
+/// SD3078 INT pin output, in place of alarm and auto power on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RtcIntOutput {
+    /// No output
+    Off,
+    /// Square wave
+    #[serde(rename = "4096hz")]
+    Hz4096,
+    #[serde(rename = "1024hz")]
+    Hz1024,
+    #[serde(rename = "64hz")]
+    Hz64,
+    #[serde(rename = "32hz")]
+    Hz32,
+    #[serde(rename = "16hz")]
+    Hz16,
+    #[serde(rename = "8hz")]
+    Hz8,
+    #[serde(rename = "4hz")]
+    Hz4,
+    #[serde(rename = "2hz")]
+    Hz2,
+    #[serde(rename = "1hz")]
+    Hz1,
+    #[serde(rename = "1/2hz")]
+    HzHalf,
+    #[serde(rename = "1/4hz")]
+    HzQuarter,
+    #[serde(rename = "1/8hz")]
+    HzEighth,
+    #[serde(rename = "1/16hz")]
+    HzSixteenth,
+    /// Interrupt every minute, by countdown timer
+    PerMinute,
+}
+
+impl FromStr for RtcIntOutput {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
+
+impl fmt::Display for RtcIntOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => write!(f, "{}", s),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+/// What to do with an alarm missed while the pi was off
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedAlarmPolicy {
+    /// Run `missed_alarm_shell` immediately
+    Run,
+    /// Re-program the alarm for its next occurrence
+    Reschedule,
+}
+
+/// Battery LED indicators, PiSugar 3 with newer firmware
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedMode {
+    Off,
+    Low,
+    Normal,
+}
+
+impl FromStr for LedMode {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
+
+impl fmt::Display for LedMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => write!(f, "{}", s),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+/// Power save of the daemon
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerSave {
+    Off,
+    On,
+    /// On when the power is unplugged
+    Auto,
+}
+
+impl FromStr for PowerSave {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
+
+impl fmt::Display for PowerSave {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(s)) => write!(f, "{}", s),
+            _ => Err(fmt::Error),
+        }
+    }
+}
+
+/// How http requests are authenticated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// Digest auth of `auth_user` and `auth_password`
+    #[default]
+    Digest,
+    /// Basic auth of `auth_user` and `auth_password`, only behind https
+    Basic,
+    /// User authenticated by a reverse proxy, in `auth_proxy_header` of a request from `auth_proxy_addrs`
+    Proxy,
+}
+
+/// How the system is powered off
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownMethod {
+    /// `soft_poweroff_shell`, or logind and then reboot(2) when its binary is missing
+    #[default]
+    Shell,
+    /// PowerOff of systemd-logind over D-Bus, reboot(2) if it fails
+    Logind,
+    /// reboot(2) after sync, services are not stopped
+    Syscall,
+}
+
+/// Source of battery level
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LevelSource {
+    /// Fuel gauge of the chip if valid, otherwise voltage curve
+    #[default]
+    Auto,
+    /// Fuel gauge of the chip
+    Chip,
+    /// Voltage curve, `battery_curve` or built-in
+    Curve,
+}
+
+/// Power profile, unset fields are left unchanged when it's applied
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PowerProfile {
+    #[serde(default)]
+    pub auto_charging_range: Option<(f32, f32)>,
+
+    #[serde(default)]
+    pub full_charge_duration: Option<u64>,
+
+    #[serde(default)]
+    pub bat_protect: Option<bool>,
+
+    #[serde(default)]
+    pub auto_shutdown_level: Option<f64>,
+
+    #[serde(default)]
+    pub auto_shutdown_delay: Option<f64>,
+
+    #[serde(default)]
+    pub auto_power_on: Option<bool>,
+
+    /// Tap shell scripts, empty to disable
+    #[serde(default)]
+    pub single_tap_shell: Option<String>,
+
+    #[serde(default)]
+    pub double_tap_shell: Option<String>,
+
+    #[serde(default)]
+    pub long_tap_shell: Option<String>,
+}
+
+/// Charging settings of before a power profile was applied, restored when longevity mode is turned off
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProfileBackup {
+    #[serde(default)]
+    pub auto_charging_range: Option<(f32, f32)>,
+
+    #[serde(default)]
+    pub full_charge_duration: Option<u64>,
+
+    #[serde(default)]
+    pub bat_protect: Option<bool>,
+}
+
+impl PowerProfile {
+    /// Built-in longevity profile, for 24/7 UPS duty
+    pub fn longevity() -> Self {
+        Self {
+            auto_charging_range: Some(LONGEVITY_CHARGING_RANGE),
+            full_charge_duration: Some(LONGEVITY_FULL_CHARGE_DURATION),
+            bat_protect: Some(true),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the config is in this profile
+    pub fn matches(&self, config: &PiSugarConfig) -> bool {
+        fn tap_matches(shell: &Option<String>, enable: bool, config_shell: &str) -> bool {
+            match shell {
+                Some(shell) if shell.is_empty() => !enable,
+                Some(shell) => enable && shell == config_shell,
+                None => true,
+            }
+        }
+        (self.auto_charging_range.is_none() || self.auto_charging_range == config.auto_charging_range)
+            && (self.full_charge_duration.is_none() || self.full_charge_duration == config.full_charge_duration)
+            && (self.bat_protect.is_none() || self.bat_protect == config.bat_protect)
+            && (self.auto_shutdown_level.is_none() || self.auto_shutdown_level == config.auto_shutdown_level)
+            && (self.auto_shutdown_delay.is_none() || self.auto_shutdown_delay == config.auto_shutdown_delay)
+            && (self.auto_power_on.is_none() || self.auto_power_on == config.auto_power_on)
+            && tap_matches(&self.single_tap_shell, config.single_tap_enable, &config.single_tap_shell)
+            && tap_matches(&self.double_tap_shell, config.double_tap_enable, &config.double_tap_shell)
+            && tap_matches(&self.long_tap_shell, config.long_tap_enable, &config.long_tap_shell)
+    }
+}
+
+/// Additional PiSugar device
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    /// Device id, e.g. `get battery@<id>`
+    pub id: String,
+
+    /// Model, e.g. PiSugar 3
+    pub model: String,
+
+    /// I2C bus, default same as main device
+    #[serde(default)]
+    pub i2c_bus: Option<u8>,
+
+    /// I2C addr, default address of the model
+    #[serde(default)]
+    pub i2c_addr: Option<u16>,
+}
+
+impl DeviceConfig {
+    /// Whether the id is of `[A-Za-z0-9_-]+`, it names the config dir `devices/<id>`
+    pub fn valid_id(&self) -> bool {
+        !self.id.is_empty()
+            && self
+                .id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+}
+
 /// PiSugar configuration
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PiSugarConfig {
@@ -29,10 +587,36 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub auth_password: Option<String>,
 
+    /// File of the http auth password instead of `auth_password`, e.g. `${CREDENTIALS_DIRECTORY}/auth_password`
+    /// of systemd `LoadCredential=`
+    #[serde(default)]
+    pub auth_password_file: Option<String>,
+
     /// Auth session timeout in seconds
     #[serde(default = "default_session_timeout")]
     pub session_timeout: u32,
 
+    /// Http auth mode, default digest
+    #[serde(default)]
+    pub auth_mode: Option<AuthMode>,
+
+    /// Header of the user authenticated by a reverse proxy, default `X-Remote-User`
+    #[serde(default)]
+    pub auth_proxy_header: Option<String>,
+
+    /// Addresses of reverse proxies trusted to set `auth_proxy_header`, default loopback only
+    #[serde(default)]
+    pub auth_proxy_addrs: Option<Vec<String>>,
+
+    /// Origins of web pages allowed to call the http api and websocket cross-origin, e.g. `https://dash.local`,
+    /// `*` for any without auth, same-origin only by default
+    #[serde(default)]
+    pub cors_origins: Option<Vec<String>>,
+
+    /// PiSugar model, used when pisugar-server or pisugar-poweroff runs with `--model auto`
+    #[serde(default)]
+    pub model: Option<String>,
+
     /// I2C bus, default 1 (/dev/i2c-1)
     #[serde(default = "default_i2c_bus")]
     pub i2c_bus: u8,
@@ -41,6 +625,18 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub i2c_addr: Option<u16>,
 
+    /// RTC I2C addr, default 0x32 (50) in PiSugar 2, 0x57 (87) in PiSugar 3
+    #[serde(default)]
+    pub rtc_i2c_addr: Option<u16>,
+
+    /// Flock the i2c bus device around every driver operation, for other i2c users that flock it too
+    #[serde(default)]
+    pub i2c_lock: bool,
+
+    /// Delay between i2c transactions (ms), for slow devices sharing the bus
+    #[serde(default)]
+    pub i2c_delay: Option<u64>,
+
     /// Alarm time
     #[serde(default)]
     pub auto_wake_time: Option<DateTime<Local>>,
@@ -49,6 +645,26 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub auto_wake_repeat: u8,
 
+    /// Detect alarms missed while the pi was off at startup, and what to do with them
+    #[serde(default)]
+    pub missed_alarm_policy: Option<MissedAlarmPolicy>,
+
+    /// Shell script of missed alarm, with `missed_alarm_policy` run
+    #[serde(default)]
+    pub missed_alarm_shell: Option<String>,
+
+    /// SD3078 INT pin output, in place of alarm and auto power on
+    #[serde(default)]
+    pub rtc_int_output: Option<RtcIntOutput>,
+
+    /// Check published PiSugar 3 firmware of channel daily, `stable` or `beta`
+    #[serde(default)]
+    pub firmware_update_channel: Option<String>,
+
+    /// Alarm local time of day, the rtc is re-programmed when local utc offset changes (DST)
+    #[serde(default)]
+    pub auto_wake_local_time: Option<NaiveTime>,
+
     /// Single tap enable
     #[serde(default)]
     pub single_tap_enable: bool,
@@ -73,6 +689,14 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub long_tap_shell: String,
 
+    /// Bcm pin of a button wired to pi gpio, taps are detected besides the i2c tap registers
+    #[serde(default)]
+    pub gpio_button_pin: Option<u8>,
+
+    /// Gpio button is pressed on low level, default true
+    #[serde(default)]
+    pub gpio_button_active_low: Option<bool>,
+
     /// Auto shutdown when battery level is low
     #[serde(default)]
     pub auto_shutdown_level: Option<f64>,
@@ -81,6 +705,34 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub auto_shutdown_delay: Option<f64>,
 
+    /// Shutdown after power loss of this duration (seconds), cancelled when power returns
+    #[serde(default)]
+    pub outage_shutdown_delay: Option<f64>,
+
+    /// Low battery notifiers, default wall, empty to disable wall
+    #[serde(default)]
+    pub notifiers: Option<Vec<NotifierConfig>>,
+
+    /// Event webhooks
+    #[serde(default)]
+    pub webhooks: Option<Vec<WebhookConfig>>,
+
+    /// InfluxDB push of battery metrics
+    #[serde(default)]
+    pub influxdb: Option<InfluxDbConfig>,
+
+    /// Graphite or StatsD emitter of battery metrics
+    #[serde(default)]
+    pub graphite: Option<GraphiteConfig>,
+
+    /// SNMP agent of battery status
+    #[serde(default)]
+    pub snmp: Option<SnmpConfig>,
+
+    /// Modbus TCP server for PLCs
+    #[serde(default)]
+    pub modbus: Option<ModbusConfig>,
+
     /// Charging range
     #[serde(default)]
     pub auto_charging_range: Option<(f32, f32)>,
@@ -89,6 +741,26 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub full_charge_duration: Option<u64>,
 
+    /// Allow charging only in this time window (rtc time), e.g. ("01:00:00", "06:00:00")
+    #[serde(default)]
+    pub charging_window: Option<(NaiveTime, NaiveTime)>,
+
+    /// Pause charging above this chip temperature (°C)
+    #[serde(default)]
+    pub thermal_protect: Option<f32>,
+
+    /// Resume charging below thermal_protect - hysteresis (°C), default 5
+    #[serde(default)]
+    pub thermal_protect_hysteresis: Option<f32>,
+
+    /// Voltage drop (V) within 2s that counts as an under voltage, default 0.3
+    #[serde(default)]
+    pub under_voltage_sag: Option<f32>,
+
+    /// Output boost current limit (A), IP5312 only, default 3.15
+    #[serde(default)]
+    pub output_current_limit: Option<f32>,
+
     /// UPS automatically power on when power recovered
     #[serde(default)]
     pub auto_power_on: Option<bool>,
@@ -101,10 +773,62 @@ pub struct PiSugarConfig {
     #[serde(default)]
     pub soft_poweroff_shell: Option<String>,
 
+    /// Countdown (seconds) before soft poweroff shell, could be cancelled by `cancel_poweroff`
+    #[serde(default)]
+    pub soft_poweroff_delay: Option<u64>,
+
+    /// Power off through systemd-logind before running soft poweroff shell
+    #[serde(default)]
+    pub shutdown_logind: Option<bool>,
+
+    /// Grace period (seconds) of logind power off, other services flush meanwhile, before soft poweroff shell
+    #[serde(default)]
+    pub shutdown_grace_period: Option<u64>,
+
+    /// How the system is powered off, default shell
+    #[serde(default)]
+    pub shutdown_method: Option<ShutdownMethod>,
+
+    /// Interpreter of tap, poweroff and alarm scripts, run as `<hook_shell> -c <script>`, default /bin/sh
+    #[serde(default)]
+    pub hook_shell: Option<String>,
+
+    /// Working directory of tap, poweroff and alarm scripts
+    #[serde(default)]
+    pub hook_dir: Option<String>,
+
+    /// Run tap, poweroff and alarm scripts as this user (name or uid), instead of the daemon user
+    #[serde(default)]
+    pub hook_user: Option<String>,
+
+    /// Run the scripts as this group (name or gid), default the group of `hook_user`
+    #[serde(default)]
+    pub hook_group: Option<String>,
+
+    /// Kill tap, poweroff and alarm scripts running longer than this (seconds), 0 or none for no limit
+    #[serde(default)]
+    pub hook_timeout: Option<u64>,
+
+    /// Timeouts of scripts of events (seconds), e.g. {"double_tap": 10}, instead of `hook_timeout`
+    #[serde(default)]
+    pub hook_timeouts: Option<BTreeMap<String, u64>>,
+
+    /// Reboot schedule, a 5 field cron expression of local time or a rfc3339 datetime
+    #[serde(default)]
+    pub scheduled_reboot: Option<String>,
+
     /// Auto rtc sync
     #[serde(default)]
     pub auto_rtc_sync: Option<bool>,
 
+    /// Rtc drift record in /etc/adjtime format, updated on ntp sync
+    #[serde(default)]
+    pub rtc_drift_file: Option<String>,
+
+    /// Audit file, panics are appended with backtrace
+    #[serde(default)]
+    pub audit_file: Option<String>,
+
     /// RTC ppm adjust comm (every second)
     #[serde(default)]
     pub adj_comm: Option<u8>,
@@ -128,9 +852,132 @@ pub struct PiSugarConfig {
     /// User defined battery curve
     #[serde(default)]
     pub battery_curve: Option<Vec<BatteryThreshold>>,
+
+    /// Battery cell chemistry, built-in curve used without `battery_curve`
+    #[serde(default)]
+    pub battery_chemistry: Option<BatteryChemistry>,
+
+    /// Source of battery level, fuel gauge is available in newer PiSugar 3 firmware
+    #[serde(default)]
+    pub level_source: Option<LevelSource>,
+
+    /// Battery LED indicators, PiSugar 3 with newer firmware
+    #[serde(default)]
+    pub led_mode: Option<LedMode>,
+
+    /// Power save of the daemon: slower polls, no averaging and no web UI, default off
+    #[serde(default)]
+    pub power_save: Option<PowerSave>,
+
+    /// Poll interval in power save (s), default 10
+    #[serde(default)]
+    pub power_save_interval: Option<u64>,
+
+    /// Hand the rtc over to its kernel driver, e.g. rtc0
+    #[serde(default)]
+    pub rtc_kernel: Option<String>,
+
+    /// Additional devices
+    #[serde(default)]
+    pub devices: Option<Vec<DeviceConfig>>,
+
+    /// Named power profiles
+    #[serde(default)]
+    pub profiles: Option<BTreeMap<String, PowerProfile>>,
+
+    /// Active power profile
+    #[serde(default)]
+    pub power_profile: Option<String>,
+
+    /// Charging settings replaced by power profiles, kept by the daemon
+    #[serde(default)]
+    pub profile_backup: Option<ProfileBackup>,
 }
 
 impl PiSugarConfig {
+    /// Battery curve of `battery_curve`, or `battery_chemistry`, or the default of the model
+    pub fn battery_curve_or<'a>(&'a self, default: &'a [BatteryThreshold]) -> &'a [BatteryThreshold] {
+        match (&self.battery_curve, self.battery_chemistry) {
+            (Some(curve), _) => curve,
+            (None, Some(chemistry)) => chemistry.curve(),
+            (None, None) => default,
+        }
+    }
+
+    /// Http auth password of `auth_password_file` or `auth_password`, `${NAME}` expanded, none if auth is off
+    pub fn resolved_auth_password(&self) -> io::Result<Option<String>> {
+        if let Some(path) = &self.auth_password_file {
+            let password = std::fs::read_to_string(resolve_secret(path))?;
+            return Ok(Some(password.trim_end_matches(['\r', '\n']).to_string()));
+        }
+        Ok(self.auth_password.as_deref().map(resolve_secret))
+    }
+
+    /// Secrets with their paths, e.g. `webhooks.0.secret`
+    fn secrets_mut(&mut self) -> Vec<(String, &mut String)> {
+        let mut secrets = Vec::new();
+        if let Some(password) = self.auth_password.as_mut() {
+            secrets.push(("auth_password".to_string(), password));
+        }
+        // list secrets are keyed by the destination too, a reordered or retyped entry never gets another's secret
+        for (i, webhook) in self.webhooks.iter_mut().flatten().enumerate() {
+            if let Some(secret) = webhook.secret.as_mut() {
+                secrets.push((format!("webhooks.{}.{}.secret", i, webhook.url), secret));
+            }
+        }
+        for (i, notifier) in self.notifiers.iter_mut().flatten().enumerate() {
+            let (destination, secret) = match &mut notifier.backend {
+                NotifierBackend::Mqtt {
+                    host,
+                    port,
+                    password: Some(password),
+                    ..
+                } => (format!("mqtt.{}:{}", host, port), password),
+                NotifierBackend::Telegram { token, .. } => ("telegram".to_string(), token),
+                NotifierBackend::Gotify { url, token } => (format!("gotify.{}", url), token),
+                _ => continue,
+            };
+            secrets.push((format!("notifiers.{}.{}.secret", i, destination), secret));
+        }
+        if let Some(token) = self.influxdb.as_mut().and_then(|influxdb| influxdb.token.as_mut()) {
+            secrets.push(("influxdb.token".to_string(), token));
+        }
+        if let Some(snmp) = self.snmp.as_mut() {
+            secrets.push(("snmp.community".to_string(), &mut snmp.community));
+        }
+        secrets
+    }
+
+    /// Copy for export, passwords, tokens, webhook secrets and snmp community are `REDACTED`
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        for (_, secret) in config.secrets_mut() {
+            *secret = REDACTED.to_string();
+        }
+        config
+    }
+
+    /// Whether secrets are kept in plain text, `${NAME}` references are not
+    pub fn has_secrets(&self) -> bool {
+        self.clone()
+            .secrets_mut()
+            .iter()
+            .any(|(_, secret)| !secret.is_empty() && (!secret.starts_with("${") || !secret.ends_with('}')))
+    }
+
+    /// Take secrets still `REDACTED` from the current config, so an exported config can be pushed back
+    pub fn unredact(&mut self, current: &PiSugarConfig) {
+        let mut current = current.clone();
+        let current: BTreeMap<String, &mut String> = current.secrets_mut().into_iter().collect();
+        for (path, secret) in self.secrets_mut() {
+            if secret == REDACTED {
+                if let Some(current) = current.get(&path) {
+                    *secret = current.to_string();
+                }
+            }
+        }
+    }
+
     fn _validate_battery_curve(cfg: &PiSugarConfig) -> bool {
         let mut curve = cfg.battery_curve.clone().unwrap_or_default();
         curve.sort_by(|x, y| x.0.total_cmp(&y.0));
@@ -143,6 +990,155 @@ impl PiSugarConfig {
         true
     }
 
+    /// Validate configuration, returns issues
+    pub fn validate(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+        let auth_password_set =
+            !self.auth_password.as_deref().unwrap_or_default().is_empty() || self.auth_password_file.is_some();
+        if self.auth_user.as_deref().unwrap_or_default().is_empty() == auth_password_set {
+            issues.push("auth_user and auth_password (or auth_password_file) should be set together".to_string());
+        }
+        if let Some(header) = self.auth_proxy_header.as_deref() {
+            if header.is_empty()
+                || !header
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            {
+                issues.push(format!("auth_proxy_header {} is not a header name", header));
+            }
+        }
+        for addr in self.auth_proxy_addrs.iter().flatten() {
+            if addr.parse::<IpAddr>().is_err() {
+                issues.push(format!("auth_proxy_addrs {} is not an ip address", addr));
+            }
+        }
+        for device in self.devices.iter().flatten().filter(|d| !d.valid_id()) {
+            issues.push(format!("device id {:?} is not of [A-Za-z0-9_-]", device.id));
+        }
+        let auth = self.auth_mode == Some(AuthMode::Proxy) || auth_password_set;
+        if auth && self.cors_origins.iter().flatten().any(|origin| origin == "*") {
+            issues.push("cors_origins \"*\" is refused with auth, list the origins instead".to_string());
+        }
+        for origin in self.cors_origins.iter().flatten() {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                issues.push(format!(
+                    "cors_origins {} is not an origin, e.g. https://host:port",
+                    origin
+                ));
+            }
+        }
+        if let Some(pin) = self.gpio_button_pin {
+            if pin > 27 {
+                issues.push(format!("gpio_button_pin {} out of range 0-27", pin));
+            }
+        }
+        if let Some(level) = self.auto_shutdown_level {
+            if !(0.0..=100.0).contains(&level) {
+                issues.push(format!("auto_shutdown_level {} out of range 0-100", level));
+            }
+        }
+        if let Some(delay) = self.auto_shutdown_delay {
+            if delay < 0.0 {
+                issues.push(format!("auto_shutdown_delay {} is negative", delay));
+            }
+        }
+        if let Some(delay) = self.i2c_delay {
+            if delay > 100 {
+                issues.push(format!("i2c_delay {}ms is more than 100ms", delay));
+            }
+        }
+        if matches!(&self.hook_shell, Some(shell) if shell.trim().is_empty()) {
+            issues.push("hook_shell is empty".to_string());
+        }
+        if let Some(dir) = &self.hook_dir {
+            if !std::path::Path::new(dir).is_dir() {
+                issues.push(format!("hook_dir {} is not a directory", dir));
+            }
+        }
+        if let Some(Err(e)) = self.hook_user.as_deref().map(crate::hook::lookup_user) {
+            issues.push(format!("hook_user: {}", e));
+        }
+        if let Some(Err(e)) = self.hook_group.as_deref().map(crate::hook::lookup_group) {
+            issues.push(format!("hook_group: {}", e));
+        }
+        if let Some(interval) = self.power_save_interval {
+            if !(1..=60).contains(&interval) {
+                issues.push(format!("power_save_interval {} out of range 1-60", interval));
+            }
+        }
+        if let Some(delay) = self.outage_shutdown_delay {
+            if delay < 0.0 {
+                issues.push(format!("outage_shutdown_delay {} is negative", delay));
+            }
+        }
+        if let Some(schedule) = &self.scheduled_reboot {
+            if let Err(e) = schedule.parse::<Schedule>() {
+                issues.push(format!("scheduled_reboot: {}", e));
+            }
+        }
+        if let Some((begin, end)) = self.auto_charging_range {
+            if begin < 0.0 || end < begin || end > 100.0 {
+                issues.push(format!("auto_charging_range ({}, {}) is invalid", begin, end));
+            }
+        }
+        if let Some(ppm) = self.rtc_adj_ppm {
+            if !(-500.0..=500.0).contains(&ppm) {
+                issues.push(format!("rtc_adj_ppm {} out of range -500-500", ppm));
+            }
+        }
+        if self.auto_wake_repeat > 0b0111_1111 {
+            issues.push(format!("auto_wake_repeat {} is invalid", self.auto_wake_repeat));
+        }
+        if self.auto_power_on == Some(true) && self.auto_wake_time.is_some() {
+            issues.push("auto_power_on is in conflict with auto_wake_time".to_string());
+        }
+        if self.rtc_int_output.is_some() && (self.auto_power_on == Some(true) || self.auto_wake_time.is_some()) {
+            issues.push("rtc_int_output is in conflict with auto_power_on and auto_wake_time".to_string());
+        }
+        if !PiSugarConfig::_validate_battery_curve(self) {
+            issues.push("battery_curve is invalid".to_string());
+        }
+        for webhook in self.webhooks.iter().flatten() {
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                issues.push(format!("webhook url {} is invalid", webhook.url));
+            }
+        }
+        if let Some(influxdb) = &self.influxdb {
+            if !influxdb.url.starts_with("http://") && !influxdb.url.starts_with("https://") {
+                issues.push(format!("influxdb url {} is invalid", influxdb.url));
+            }
+            if influxdb.interval == 0 {
+                issues.push("influxdb interval is 0".to_string());
+            }
+        }
+        if let Some(graphite) = &self.graphite {
+            if graphite.host.is_empty() {
+                issues.push("graphite host is empty".to_string());
+            }
+            if graphite.interval == 0 {
+                issues.push("graphite interval is 0".to_string());
+            }
+        }
+        if let Some(snmp) = &self.snmp {
+            if snmp.listen.parse::<std::net::SocketAddr>().is_err() {
+                issues.push(format!("snmp listen {} is invalid", snmp.listen));
+            }
+            if snmp.community.is_empty() {
+                issues.push("snmp community is empty".to_string());
+            }
+            let oid_valid = |oid: &str| oid.split('.').all(|n| n.parse::<u32>().is_ok());
+            if let Some(oid) = snmp.base_oid.as_deref().filter(|oid| !oid_valid(oid)) {
+                issues.push(format!("snmp base_oid {} is invalid", oid));
+            }
+        }
+        if let Some(modbus) = &self.modbus {
+            if modbus.listen.parse::<std::net::SocketAddr>().is_err() {
+                issues.push(format!("modbus listen {} is invalid", modbus.listen));
+            }
+        }
+        issues
+    }
+
     pub fn load(&mut self, path: &Path) -> io::Result<()> {
         let mut f = File::open(path)?;
         let mut buff = String::new();
@@ -155,12 +1151,13 @@ impl PiSugarConfig {
         Ok(())
     }
 
+    /// Save to a file, a new one is created 0600 as it has secrets, an existing one keeps its mode
     pub fn save_to(&self, path: &Path) -> io::Result<()> {
         let mut options = OpenOptions::new();
-        options.write(true).create(true);
+        options.write(true).create(true).mode(0o600);
         let mut f = options.open(path)?;
         let s = serde_json::to_string_pretty(self)?;
-        log::info!("Dump config:\n{}", s);
+        log::info!("Dump config:\n{}", serde_json::to_string_pretty(&self.redacted())?);
         f.set_len(0)?;
         f.write_all(s.as_bytes())
     }
@@ -171,31 +1168,82 @@ impl Default for PiSugarConfig {
         Self {
             auth_user: Default::default(),
             auth_password: Default::default(),
+            auth_password_file: Default::default(),
             session_timeout: default_session_timeout(),
+            auth_mode: Default::default(),
+            auth_proxy_header: Default::default(),
+            auth_proxy_addrs: Default::default(),
+            cors_origins: Default::default(),
+            model: Default::default(),
             i2c_bus: default_i2c_bus(),
             i2c_addr: Default::default(),
+            rtc_i2c_addr: Default::default(),
+            i2c_lock: Default::default(),
+            i2c_delay: Default::default(),
             auto_wake_time: Default::default(),
             auto_wake_repeat: Default::default(),
+            auto_wake_local_time: Default::default(),
+            rtc_int_output: Default::default(),
+            missed_alarm_policy: Default::default(),
+            missed_alarm_shell: Default::default(),
+            firmware_update_channel: Default::default(),
             single_tap_enable: Default::default(),
             single_tap_shell: Default::default(),
             double_tap_enable: Default::default(),
             double_tap_shell: Default::default(),
             long_tap_enable: Default::default(),
             long_tap_shell: Default::default(),
+            gpio_button_pin: Default::default(),
+            gpio_button_active_low: Default::default(),
             auto_shutdown_level: Default::default(),
             auto_shutdown_delay: Default::default(),
+            outage_shutdown_delay: Default::default(),
+            notifiers: Default::default(),
+            webhooks: Default::default(),
+            influxdb: Default::default(),
+            graphite: Default::default(),
+            snmp: Default::default(),
+            modbus: Default::default(),
             auto_charging_range: Default::default(),
             full_charge_duration: Default::default(),
+            charging_window: Default::default(),
+            thermal_protect: Default::default(),
+            thermal_protect_hysteresis: Default::default(),
+            under_voltage_sag: Default::default(),
+            output_current_limit: Default::default(),
             auto_power_on: Default::default(),
             soft_poweroff: Default::default(),
             soft_poweroff_shell: Default::default(),
+            soft_poweroff_delay: Default::default(),
+            shutdown_logind: Default::default(),
+            shutdown_grace_period: Default::default(),
+            shutdown_method: Default::default(),
+            hook_shell: Default::default(),
+            hook_dir: Default::default(),
+            hook_user: Default::default(),
+            hook_group: Default::default(),
+            hook_timeout: Default::default(),
+            hook_timeouts: Default::default(),
+            scheduled_reboot: Default::default(),
             auto_rtc_sync: Default::default(),
+            rtc_drift_file: Default::default(),
+            audit_file: Default::default(),
             adj_comm: Default::default(),
             adj_diff: Default::default(),
             rtc_adj_ppm: Default::default(),
             anti_mistouch: Default::default(),
             bat_protect: Default::default(),
             battery_curve: Default::default(),
+            battery_chemistry: Default::default(),
+            level_source: Default::default(),
+            led_mode: Default::default(),
+            power_save: Default::default(),
+            power_save_interval: Default::default(),
+            rtc_kernel: Default::default(),
+            devices: Default::default(),
+            profiles: Default::default(),
+            power_profile: Default::default(),
+            profile_backup: Default::default(),
         }
     }
 }
@@ -0,0 +1,122 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use tokio::runtime::Runtime;
+
+use crate::i2c::with_retry;
+use crate::{Model, PiSugarConfig, PiSugarCore, RTCRawTime, Result};
+
+/// Attempts of model detection and status reads
+pub const FACADE_ATTEMPTS: u32 = 3;
+
+/// Backoff before the first retry, doubled on every retry
+pub const FACADE_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Battery status
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Status {
+    pub model: String,
+    /// Battery level (%)
+    pub level: f32,
+    /// Battery voltage (V)
+    pub voltage: f32,
+    /// Battery current (A)
+    pub current: f32,
+    pub charging: bool,
+    pub power_plugged: bool,
+    pub allow_charging: bool,
+}
+
+/// Blocking PiSugar, for embedding in other daemons without pisugar-server.
+///
+/// Model is taken from the config or detected, chips are initialized as pisugar-server does,
+/// reads are retried. Don't run it along with pisugar-server, they would fight over the chips.
+///
+/// ```no_run
+/// use pisugar_core::{PiSugar, PiSugarConfig};
+///
+/// let mut pisugar = PiSugar::new(PiSugarConfig::default())?;
+/// let status = pisugar.status()?;
+/// println!("{}: {}%", status.model, status.level);
+/// # Ok::<(), pisugar_core::Error>(())
+/// ```
+pub struct PiSugar {
+    core: PiSugarCore,
+    runtime: Runtime,
+}
+
+impl PiSugar {
+    pub fn new(config: PiSugarConfig) -> Result<Self> {
+        let model = Self::model_of(&config)?;
+        Self::with_core(PiSugarCore::new(config, model)?)
+    }
+
+    /// Open with a config file, which is updated on `set_alarm`
+    pub fn with_config_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config = PiSugarConfig::default();
+        config.load(path)?;
+        let model = Self::model_of(&config)?;
+        Self::with_core(PiSugarCore::new_with_path(&path.to_string_lossy(), false, model)?)
+    }
+
+    fn with_core(core: PiSugarCore) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        Ok(Self { core, runtime })
+    }
+
+    /// Model of the config, or detected on the i2c bus
+    fn model_of(config: &PiSugarConfig) -> Result<Model> {
+        if let Some(model) = config.model.as_ref().and_then(|m| m.parse().ok()) {
+            return Ok(model);
+        }
+        let model = with_retry(FACADE_ATTEMPTS, FACADE_RETRY_BACKOFF, || Model::detect(config.i2c_bus))?;
+        log::info!("Detected model: {}", model);
+        Ok(model)
+    }
+
+    /// Core, for what the façade doesn't cover
+    pub fn core(&mut self) -> &mut PiSugarCore {
+        &mut self.core
+    }
+
+    /// Poll and read the status
+    pub fn status(&mut self) -> Result<Status> {
+        let Self { core, runtime } = self;
+        with_retry(FACADE_ATTEMPTS, FACADE_RETRY_BACKOFF, || {
+            runtime.block_on(core.poll(Instant::now()))?;
+            Ok(Status {
+                model: core.model(),
+                level: core.level()?,
+                voltage: core.voltage_avg()?,
+                current: core.intensity_avg()?,
+                charging: core.charging()?,
+                power_plugged: core.power_plugged()?,
+                allow_charging: core.allow_charging()?,
+            })
+        })
+    }
+
+    /// Cut the power of the battery output after syncing file systems, the system should be halted before
+    pub fn shutdown(&self) -> Result<()> {
+        self.core.force_shutdown()
+    }
+
+    /// Wake up at time, repeated on weekdays of the bitmask as `rtc_alarm_set`, e.g. 127 for every day
+    pub fn set_alarm(&mut self, time: DateTime<Local>, weekday_repeat: u8) -> Result<()> {
+        let raw: RTCRawTime = time.into();
+        with_retry(FACADE_ATTEMPTS, FACADE_RETRY_BACKOFF, || {
+            self.core.write_alarm(raw, weekday_repeat)
+        })?;
+        let config = self.core.config_mut();
+        config.auto_wake_repeat = weekday_repeat;
+        config.auto_wake_time = Some(time);
+        config.auto_wake_local_time = Some(time.time());
+        if let Err(e) = self.core.save_config() {
+            log::debug!("Alarm not saved: {}", e);
+        }
+        Ok(())
+    }
+}
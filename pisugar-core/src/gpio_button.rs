@@ -0,0 +1,102 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rppal::gpio::{Gpio, InputPin, Level, Trigger};
+
+use crate::{gpio_detect_tap, Error, Result, TapType};
+
+/// Samples of tap history, same as the i2c tap registers
+const TAP_HISTORY_SIZE: usize = 30;
+
+/// Interval of tap history samples, the tap patterns are of 100ms samples
+const TAP_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Max edges recorded between polls, the oldest are dropped of a bouncing button
+const EDGES_SIZE: usize = 256;
+
+/// Button wired to a pi gpio. Edges are recorded by an interrupt and replayed as 100ms samples on polls,
+/// so taps don't depend on the poll interval
+pub struct GpioButton {
+    /// Kept for the interrupt, which is cleared on drop
+    _pin: InputPin,
+    /// Edges of the interrupt, (time, pressed)
+    edges: Arc<Mutex<VecDeque<(Instant, bool)>>>,
+    pressed: bool,
+    sampled_at: Option<Instant>,
+    tap_history: String,
+    taps: VecDeque<TapType>,
+}
+
+impl GpioButton {
+    /// Bcm pin, pulled up if the button is active low, otherwise pulled down
+    pub fn new(pin: u8, active_low: bool) -> Result<Self> {
+        let gpio_err = |e| Error::Other(format!("Gpio {}: {}", pin, e));
+        let pin = Gpio::new().and_then(|gpio| gpio.get(pin)).map_err(gpio_err)?;
+        let mut pin = if active_low {
+            pin.into_input_pullup()
+        } else {
+            pin.into_input_pulldown()
+        };
+        let pressed = if active_low { pin.is_low() } else { pin.is_high() };
+        let edges = Arc::new(Mutex::new(VecDeque::new()));
+        let recorded = edges.clone();
+        pin.set_async_interrupt(Trigger::Both, move |level| {
+            let pressed = (level == Level::Low) == active_low;
+            if let Ok(mut edges) = recorded.lock() {
+                if edges.len() >= EDGES_SIZE {
+                    edges.pop_front();
+                }
+                edges.push_back((Instant::now(), pressed));
+            }
+        })
+        .map_err(gpio_err)?;
+        Ok(Self {
+            _pin: pin,
+            edges,
+            pressed,
+            sampled_at: None,
+            tap_history: String::with_capacity(TAP_HISTORY_SIZE),
+            taps: VecDeque::new(),
+        })
+    }
+
+    /// Whether a tap is in progress or pending, polls should be fast till it's detected
+    pub fn busy(&self) -> bool {
+        self.pressed
+            || !self.taps.is_empty()
+            || self.tap_history.contains('1')
+            || self.edges.lock().is_ok_and(|edges| !edges.is_empty())
+    }
+
+    /// Replay edges since the last poll as samples and detect taps, one tap is returned per poll
+    pub fn poll(&mut self, now: Instant) -> Option<TapType> {
+        let mut t = *self.sampled_at.get_or_insert(now);
+        if let Ok(mut edges) = self.edges.lock() {
+            while t + TAP_SAMPLE_INTERVAL <= now {
+                // idle, nothing to replay
+                if edges.is_empty() && !self.pressed && !self.tap_history.contains('1') {
+                    t = now;
+                    break;
+                }
+                t += TAP_SAMPLE_INTERVAL;
+                // a press shorter than a sample still counts
+                let mut pressed = self.pressed;
+                while let Some((_, p)) = edges.front().filter(|(at, _)| *at <= t).copied() {
+                    edges.pop_front();
+                    self.pressed = p;
+                    pressed |= p;
+                }
+                if self.tap_history.len() >= TAP_HISTORY_SIZE {
+                    self.tap_history.remove(0);
+                }
+                self.tap_history.push(if pressed { '1' } else { '0' });
+                if let Some(tap) = gpio_detect_tap(&mut self.tap_history) {
+                    self.taps.push_back(tap);
+                }
+            }
+        }
+        self.sampled_at = Some(t);
+        self.taps.pop_front()
+    }
+}
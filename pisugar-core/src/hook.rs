@@ -0,0 +1,278 @@
+use std::ffi::{CStr, CString};
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::ptr;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use crate::PiSugarConfig;
+
+/// Interpreter of hook scripts without `hook_shell`
+pub const DEFAULT_HOOK_SHELL: &str = "/bin/sh";
+
+/// Interval of checking whether a hook with timeout exited
+const HOOK_WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How hook scripts are run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HookOptions {
+    /// Interpreter, run as `<shell> -c <script>`
+    pub shell: Option<String>,
+    /// Working directory
+    pub dir: Option<String>,
+    /// Run as this user (name or uid), the daemon needs to be root
+    pub user: Option<String>,
+    /// Run as this group (name or gid), default the group of `user`
+    pub group: Option<String>,
+    /// Kill the script and its children after this
+    pub timeout: Option<Duration>,
+}
+
+impl HookOptions {
+    /// Options of the hook of event, timeout of `hook_timeouts`, or `hook_timeout`
+    pub fn new(config: &PiSugarConfig, event: &str) -> Self {
+        let timeout = config
+            .hook_timeouts
+            .as_ref()
+            .and_then(|timeouts| timeouts.get(event).copied())
+            .or(config.hook_timeout)
+            .filter(|secs| *secs > 0);
+        Self {
+            shell: config.hook_shell.clone(),
+            dir: config.hook_dir.clone(),
+            user: config.hook_user.clone(),
+            group: config.hook_group.clone(),
+            timeout: timeout.map(Duration::from_secs),
+        }
+    }
+}
+
+/// Context of a hook, passed to the script as PISUGAR_EVENT, PISUGAR_LEVEL and PISUGAR_VOLTAGE
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HookEnv {
+    /// e.g. single_tap, soft_poweroff
+    pub event: String,
+    /// Battery level (%)
+    pub level: Option<f32>,
+    /// Battery voltage (V)
+    pub voltage: Option<f32>,
+}
+
+impl HookEnv {
+    /// Env vars, unknown values are empty
+    pub fn vars(&self) -> Vec<(&'static str, String)> {
+        let value = |v: Option<f32>| v.map(|v| v.to_string()).unwrap_or_default();
+        vec![
+            ("PISUGAR_EVENT", self.event.clone()),
+            ("PISUGAR_LEVEL", value(self.level)),
+            ("PISUGAR_VOLTAGE", value(self.voltage)),
+        ]
+    }
+}
+
+/// User of passwd, by name or uid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+fn lookup_error(r: libc::c_int, what: &str) -> io::Error {
+    if r != 0 {
+        return io::Error::from_raw_os_error(r);
+    }
+    io::Error::new(io::ErrorKind::NotFound, format!("Unknown {}", what))
+}
+
+/// Look up a user of passwd by name or uid
+pub fn lookup_user(user: &str) -> io::Result<HookUser> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = ptr::null_mut();
+    let r = match user.parse::<u32>() {
+        Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) },
+        Err(_) => {
+            let name = CString::new(user)?;
+            unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) }
+        }
+    };
+    if result.is_null() {
+        return Err(lookup_error(r, &format!("user: {}", user)));
+    }
+    let string = |s: *const libc::c_char| unsafe { CStr::from_ptr(s) }.to_string_lossy().to_string();
+    Ok(HookUser {
+        name: string(pwd.pw_name),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: string(pwd.pw_dir),
+    })
+}
+
+/// Look up a group id by name or gid
+pub fn lookup_group(group: &str) -> io::Result<u32> {
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = ptr::null_mut();
+    let r = match group.parse::<u32>() {
+        Ok(gid) => unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) },
+        Err(_) => {
+            let name = CString::new(group)?;
+            unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) }
+        }
+    };
+    if result.is_null() {
+        return Err(lookup_error(r, &format!("group: {}", group)));
+    }
+    Ok(grp.gr_gid)
+}
+
+/// Command of a hook script, Err of an unknown user or group
+pub fn hook_command(options: &HookOptions, script: &str, env: &HookEnv) -> io::Result<Command> {
+    let shell = options.shell.as_deref().unwrap_or(DEFAULT_HOOK_SHELL);
+    let mut cmd = Command::new(shell);
+    // own process group, so that children are killed along on timeout
+    cmd.args(["-c", script]).envs(env.vars()).process_group(0);
+    if let Some(dir) = &options.dir {
+        cmd.current_dir(dir);
+    }
+    let user = options.user.as_deref().map(lookup_user).transpose()?;
+    let gid = options.group.as_deref().map(lookup_group).transpose()?;
+    if let Some(gid) = gid.or(user.as_ref().map(|u| u.gid)) {
+        cmd.gid(gid);
+    }
+    if let Some(user) = user {
+        // supplementary groups of the daemon are dropped along
+        cmd.uid(user.uid)
+            .env("USER", &user.name)
+            .env("LOGNAME", &user.name)
+            .env("HOME", &user.home);
+    }
+    Ok(cmd)
+}
+
+/// Log lines of a script output with the event, until closed
+fn capture(output: impl Read + Send + 'static, event: String, stream: &'static str) {
+    // not joined, background children of the script could hold the output
+    thread::spawn(move || {
+        for line in BufReader::new(output).split(b'\n').map_while(|l| l.ok()) {
+            let line = String::from_utf8_lossy(&line);
+            match stream {
+                "stderr" => log::warn!("Hook of {} {}: {}", event, stream, line.trim_end()),
+                _ => log::info!("Hook of {} {}: {}", event, stream, line.trim_end()),
+            }
+        }
+    });
+}
+
+/// Run a hook script until it exits, or killed on timeout, stdout and stderr are logged with the event
+pub fn execute_hook(options: &HookOptions, script: &str, env: &HookEnv) -> io::Result<ExitStatus> {
+    log::info!("Execute hook of {}: {}", env.event, script);
+    let mut child = hook_command(options, script, env)?
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(stdout) = child.stdout.take() {
+        capture(stdout, env.event.clone(), "stdout");
+    }
+    if let Some(stderr) = child.stderr.take() {
+        capture(stderr, env.event.clone(), "stderr");
+    }
+
+    let status = match options.timeout {
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                    let _ = child.wait();
+                    log::error!("Hook of {} killed after timeout {:?}", env.event, timeout);
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Hook of {} timed out", env.event),
+                    ));
+                }
+                sleep(HOOK_WAIT_INTERVAL);
+            }
+        }
+        None => child.wait()?,
+    };
+    if !status.success() {
+        log::warn!("Hook of {} exited with {}", env.event, status);
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_command() {
+        let options = HookOptions {
+            shell: Some("sh".to_string()),
+            dir: Some("/".to_string()),
+            ..Default::default()
+        };
+        let env = HookEnv {
+            event: "double_tap".to_string(),
+            level: Some(85.5),
+            voltage: None,
+        };
+        let script = "echo \"$PISUGAR_EVENT $PISUGAR_LEVEL [$PISUGAR_VOLTAGE] $(pwd)\"";
+        let output = hook_command(&options, script, &env).unwrap().output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "double_tap 85.5 [] /\n");
+
+        let options = HookOptions {
+            user: Some("no-such-user".to_string()),
+            ..Default::default()
+        };
+        assert!(hook_command(&options, script, &env).is_err());
+    }
+
+    #[test]
+    fn test_execute_hook() {
+        let env = HookEnv {
+            event: "long_tap".to_string(),
+            ..Default::default()
+        };
+        let mut options = HookOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let status = execute_hook(&options, "echo out; echo err >&2; exit 3", &env).unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let start = Instant::now();
+        let r = execute_hook(&options, "sleep 5 & sleep 5", &env);
+        assert_eq!(r.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let mut config = PiSugarConfig {
+            hook_timeout: Some(30),
+            ..Default::default()
+        };
+        config.hook_timeouts = Some(vec![("long_tap".to_string(), 5)].into_iter().collect());
+        options = HookOptions::new(&config, "long_tap");
+        assert_eq!(options.timeout, Some(Duration::from_secs(5)));
+        assert_eq!(
+            HookOptions::new(&config, "single_tap").timeout,
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert_eq!(lookup_user("0").unwrap(), root);
+        assert_eq!(lookup_group("0").unwrap(), 0);
+        assert!(lookup_group("no-such-group").is_err());
+    }
+}
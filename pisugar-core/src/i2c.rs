@@ -0,0 +1,268 @@
+use std::cell::Cell;
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rppal::i2c::{I2c, Result};
+
+/// Attempts of an i2c transaction, a noisy bus NACKs once in a while
+pub const I2C_ATTEMPTS: u32 = 3;
+
+/// Backoff before the first retry, doubled on every retry
+pub const I2C_RETRY_BACKOFF: Duration = Duration::from_millis(2);
+
+/// Attempts to flock the bus without blocking, a script holding the lock must not hang the daemon
+pub const I2C_LOCK_ATTEMPTS: u32 = 50;
+
+/// Interval between attempts to flock the bus
+pub const I2C_LOCK_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Retried i2c transactions since startup
+static I2C_RETRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Flock the bus device around every transaction
+static I2C_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Delay between transactions (ms)
+static I2C_DELAY: AtomicU64 = AtomicU64::new(0);
+
+/// End of the last transaction
+static I2C_LAST_TRANSACTION: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Bus device files of opened i2c devices, one per bus
+static I2C_BUS_DEVS: Mutex<Vec<(u8, Arc<File>)>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// Bus locks held by the thread, nested operations and transactions don't lock again
+    static I2C_LOCK_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Coexist with other i2c users, e.g. the kernel rtc driver or scripts:
+/// flock `/dev/i2c-N` around every driver operation, and keep a delay between transactions
+pub fn set_i2c_options(lock: bool, delay: Option<Duration>) {
+    I2C_LOCK.store(lock, Ordering::Relaxed);
+    I2C_DELAY.store(delay.unwrap_or_default().as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Exclusive flock of a bus device, without blocking, Err if it's still locked by others after all attempts
+fn flock(dev: &File) -> io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        if unsafe { libc::flock(dev.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            log::debug!("I2c flock error: {}", e);
+            return Ok(());
+        }
+        if attempt >= I2C_LOCK_ATTEMPTS {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "I2c bus is locked by another process",
+            ));
+        }
+        sleep(I2C_LOCK_INTERVAL);
+        attempt += 1;
+    }
+}
+
+/// Exclusive flocks of bus devices, released on drop, only the outermost lock of the thread flocks
+struct BusLock(Vec<Arc<File>>);
+
+impl BusLock {
+    fn new(devs: &[Arc<File>]) -> io::Result<Self> {
+        let depth = I2C_LOCK_DEPTH.with(|d| {
+            d.set(d.get() + 1);
+            d.get()
+        });
+        let mut lock = Self(Vec::new());
+        if depth == 1 {
+            for dev in devs {
+                flock(dev)?;
+                lock.0.push(dev.clone());
+            }
+        }
+        Ok(lock)
+    }
+}
+
+impl Drop for BusLock {
+    fn drop(&mut self) {
+        for dev in &self.0 {
+            unsafe { libc::flock(dev.as_raw_fd(), libc::LOCK_UN) };
+        }
+        I2C_LOCK_DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+/// Run a whole driver operation with all buses locked if enabled, e.g. write enable, write and write protect,
+/// so other i2c users can't interleave
+pub fn with_bus_locked<T, E: From<io::Error>>(
+    f: impl FnOnce() -> std::result::Result<T, E>,
+) -> std::result::Result<T, E> {
+    if !I2C_LOCK.load(Ordering::Relaxed) {
+        return f();
+    }
+    let devs: Vec<_> = I2C_BUS_DEVS
+        .lock()
+        .expect("unexpected lock failed")
+        .iter()
+        .map(|(_, dev)| dev.clone())
+        .collect();
+    let _lock = BusLock::new(&devs)?;
+    f()
+}
+
+/// Bus device file to flock, shared by devices of the bus
+fn bus_dev(bus: u8) -> Option<Arc<File>> {
+    let mut devs = I2C_BUS_DEVS.lock().expect("unexpected lock failed");
+    if let Some((_, dev)) = devs.iter().find(|(b, _)| *b == bus) {
+        return Some(dev.clone());
+    }
+    let dev = Arc::new(File::open(format!("/dev/i2c-{}", bus)).ok()?);
+    devs.push((bus, dev.clone()));
+    Some(dev)
+}
+
+/// Run a transaction with the bus locked if enabled and not locked by the operation, after the delay since the last one
+fn transact<T>(dev: Option<&Arc<File>>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let _lock = match dev.filter(|_| I2C_LOCK.load(Ordering::Relaxed)) {
+        Some(dev) => Some(BusLock::new(std::slice::from_ref(dev))?),
+        None => None,
+    };
+    let delay = Duration::from_millis(I2C_DELAY.load(Ordering::Relaxed));
+    let mut last = I2C_LAST_TRANSACTION.lock().expect("unexpected lock failed");
+    if let Some(elapsed) = last.map(|t| t.elapsed()) {
+        if elapsed < delay {
+            sleep(delay - elapsed);
+        }
+    }
+    let r = f();
+    *last = Some(Instant::now());
+    r
+}
+
+/// Retried i2c transactions since startup
+pub fn i2c_retries() -> u64 {
+    I2C_RETRIES.load(Ordering::Relaxed)
+}
+
+/// Run a transaction, retry with exponential backoff on error
+pub fn with_retry<T, E: Display>(
+    attempts: u32,
+    backoff: Duration,
+    mut f: impl FnMut() -> std::result::Result<T, E>,
+) -> std::result::Result<T, E> {
+    let mut backoff = backoff;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(e) if attempt < attempts => {
+                log::debug!("I2c error: {}, retry in {:?}", e, backoff);
+                I2C_RETRIES.fetch_add(1, Ordering::Relaxed);
+                sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            r => return r,
+        }
+    }
+}
+
+/// I2c device of a slave address, transactions are retried on error
+pub struct I2cDevice {
+    i2c: I2c,
+    /// Bus device file to flock
+    dev: Option<Arc<File>>,
+}
+
+impl I2cDevice {
+    pub fn new(bus: u8, addr: u16) -> Result<Self> {
+        let mut i2c = I2c::with_bus(bus)?;
+        i2c.set_slave_address(addr)?;
+        let dev = bus_dev(bus);
+        Ok(Self { i2c, dev })
+    }
+
+    fn retry<T>(&self, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        with_retry(I2C_ATTEMPTS, I2C_RETRY_BACKOFF, || transact(self.dev.as_ref(), &mut f))
+    }
+
+    pub fn smbus_read_byte(&self, command: u8) -> Result<u8> {
+        self.retry(|| self.i2c.smbus_read_byte(command))
+    }
+
+    pub fn smbus_write_byte(&self, command: u8, value: u8) -> Result<()> {
+        self.retry(|| self.i2c.smbus_write_byte(command, value))
+    }
+
+    pub fn block_read(&self, command: u8, buffer: &mut [u8]) -> Result<()> {
+        self.retry(|| self.i2c.block_read(command, buffer))
+    }
+
+    pub fn block_write(&self, command: u8, buffer: &[u8]) -> Result<()> {
+        self.retry(|| self.i2c.block_write(command, buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_retry() {
+        let mut calls = 0;
+        let r: std::result::Result<u8, String> = with_retry(3, Duration::ZERO, || {
+            calls += 1;
+            if calls < 3 {
+                Err("nack".to_string())
+            } else {
+                Ok(1)
+            }
+        });
+        assert_eq!(r, Ok(1));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let r: std::result::Result<u8, String> = with_retry(3, Duration::ZERO, || {
+            calls += 1;
+            Err("nack".to_string())
+        });
+        assert!(r.is_err());
+        assert_eq!(calls, 3);
+        assert!(i2c_retries() >= 4);
+    }
+
+    #[test]
+    fn test_transact() {
+        let path = std::env::temp_dir().join("pisugar-i2c-lock-test");
+        let dev = Arc::new(File::create(&path).unwrap());
+        set_i2c_options(true, Some(Duration::from_millis(20)));
+        let start = Instant::now();
+        assert_eq!(transact(Some(&dev), || Ok(1)).unwrap(), 1);
+        assert_eq!(transact(Some(&dev), || Ok(2)).unwrap(), 2);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        // released after a transaction
+        let other = File::open(&path).unwrap();
+        let flock_other = |op| unsafe { libc::flock(other.as_raw_fd(), op) };
+        assert_eq!(flock_other(libc::LOCK_EX | libc::LOCK_NB), 0);
+        // locked by others, gives up instead of blocking
+        assert!(transact(Some(&dev), || Ok(3)).is_err());
+        assert_eq!(flock_other(libc::LOCK_UN), 0);
+        // held for a whole operation, nested transactions don't unlock it
+        {
+            let _lock = BusLock::new(std::slice::from_ref(&dev)).unwrap();
+            assert_eq!(transact(Some(&dev), || Ok(4)).unwrap(), 4);
+            assert_ne!(flock_other(libc::LOCK_EX | libc::LOCK_NB), 0);
+        }
+        assert_eq!(flock_other(libc::LOCK_EX | libc::LOCK_NB), 0);
+        set_i2c_options(false, None);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,11 +1,11 @@
 use std::collections::VecDeque;
 use std::time::Instant;
 
-use rppal::i2c::I2c;
-
 use crate::config::BatteryThreshold;
+use crate::i2c::I2cDevice;
+use crate::sample::Timestamped;
 use crate::{
-    battery::{Battery, BatteryEvent},
+    battery::{Battery, BatteryCapabilities, BatteryEvent},
     I2C_ADDR_BAT,
 };
 use crate::{convert_battery_voltage_to_level, gpio_detect_tap, Error, Model, PiSugarConfig, Result};
@@ -29,14 +29,13 @@ const PI_ZERO_IDLE_INTENSITY: f64 = 0.11;
 
 /// IP5209, pi-zero bat chip
 pub struct IP5209 {
-    i2c: I2c,
+    i2c: I2cDevice,
 }
 
 impl IP5209 {
     /// Create new IP5209
     pub fn new(i2c_bus: u8, i2c_addr: u16) -> Result<Self> {
-        let mut i2c = I2c::with_bus(i2c_bus)?;
-        i2c.set_slave_address(i2c_addr)?;
+        let i2c = I2cDevice::new(i2c_bus, i2c_addr)?;
         Ok(Self { i2c })
     }
 
@@ -245,9 +244,9 @@ impl IP5209 {
 pub struct IP5209Battery {
     ip5209: IP5209,
     model: Model,
-    voltages: VecDeque<(Instant, f32)>,
+    voltages: VecDeque<Timestamped<f32>>,
     levels: VecDeque<f32>,
-    intensities: VecDeque<(Instant, f32)>,
+    intensities: VecDeque<Timestamped<f32>>,
     tap_history: String,
     cfg: PiSugarConfig,
 }
@@ -268,6 +267,13 @@ impl IP5209Battery {
 }
 
 impl Battery for IP5209Battery {
+    fn capabilities(&self) -> BatteryCapabilities {
+        BatteryCapabilities {
+            keep_input: self.model.led_amount() == 2,
+            ..Default::default()
+        }
+    }
+
     fn init(&mut self, config: &PiSugarConfig) -> Result<()> {
         if self.model.led_amount() == 2 {
             self.ip5209.init_gpio_2led()?;
@@ -285,12 +291,12 @@ impl Battery for IP5209Battery {
         let v = self.voltage()?;
         let now = Instant::now();
         while self.voltages.len() < self.voltages.capacity() {
-            self.voltages.push_back((now, v));
+            self.voltages.push_back(Timestamped::new(now, v));
         }
 
         let i = self.intensity()?;
         while self.intensities.len() > self.intensities.capacity() {
-            self.intensities.push_back((now, i));
+            self.intensities.push_back(Timestamped::new(now, i));
         }
 
         Ok(())
@@ -314,7 +320,7 @@ impl Battery for IP5209Battery {
 
     fn voltage_avg(&self) -> Result<f32> {
         let mut total = 0.0;
-        self.voltages.iter().for_each(|v| total += v.1);
+        self.voltages.iter().for_each(|v| total += v.value);
         if !self.voltages.is_empty() {
             Ok(total / self.voltages.len() as f32)
         } else {
@@ -323,12 +329,7 @@ impl Battery for IP5209Battery {
     }
 
     fn level(&self) -> Result<f32> {
-        let curve = self
-            .cfg
-            .battery_curve
-            .as_ref()
-            .map(|x| &x[..])
-            .unwrap_or(BATTERY_CURVE.as_ref());
+        let curve = self.cfg.battery_curve_or(&BATTERY_CURVE);
         self.voltage_avg().map(|x| IP5209::parse_voltage_level(x, curve))
     }
 
@@ -338,7 +339,7 @@ impl Battery for IP5209Battery {
 
     fn intensity_avg(&self) -> Result<f32> {
         let mut total = 0.0;
-        self.intensities.iter().for_each(|i| total += i.1);
+        self.intensities.iter().for_each(|i| total += i.value);
         if !self.intensities.is_empty() {
             Ok(total / self.intensities.len() as f32)
         } else {
@@ -346,6 +347,14 @@ impl Battery for IP5209Battery {
         }
     }
 
+    fn voltage_history(&self) -> Vec<Timestamped<f32>> {
+        self.voltages.iter().copied().collect()
+    }
+
+    fn intensity_history(&self) -> Vec<Timestamped<f32>> {
+        self.intensities.iter().copied().collect()
+    }
+
     fn is_power_plugged(&self) -> Result<bool> {
         if self.model.led_amount() == 2 {
             self.ip5209.is_power_plugged_2led()
@@ -377,7 +386,7 @@ impl Battery for IP5209Battery {
     fn is_charging(&self) -> Result<bool> {
         if self.levels.len() > 2 {
             if let Ok(avg) = self.voltage_avg() {
-                return Ok(self.voltages[0].1 < avg && avg < self.voltages[self.voltages.len() - 1].1);
+                return Ok(self.voltages[0].value < avg && avg < self.voltages[self.voltages.len() - 1].value);
             }
         }
         Ok(false)
@@ -407,7 +416,7 @@ impl Battery for IP5209Battery {
         if self.voltages.len() >= self.voltages.capacity() {
             self.voltages.pop_front();
         }
-        self.voltages.push_back((now, voltage));
+        self.voltages.push_back(Timestamped::new(now, voltage));
 
         let level = self.level()?;
         if self.levels.len() >= self.levels.capacity() {
@@ -419,7 +428,7 @@ impl Battery for IP5209Battery {
         if self.intensities.len() >= self.intensities.capacity() {
             self.intensities.pop_front();
         }
-        self.intensities.push_back((now, intensity));
+        self.intensities.push_back(Timestamped::new(now, intensity));
 
         let gpio_value = self.ip5209.read_gpio_tap()?;
         let tapped = if self.model.led_amount() == 2 {
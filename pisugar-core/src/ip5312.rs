@@ -1,11 +1,11 @@
 use std::collections::VecDeque;
 use std::time::Instant;
 
-use rppal::i2c::I2c;
-
+use crate::i2c::I2cDevice;
+use crate::sample::Timestamped;
 use crate::Error;
 use crate::{
-    battery::{Battery, BatteryEvent},
+    battery::{Battery, BatteryCapabilities, BatteryEvent},
     config::BatteryThreshold,
 };
 use crate::{convert_battery_voltage_to_level, I2cError, Model, PiSugarConfig};
@@ -28,16 +28,21 @@ pub const BATTERY_CURVE: [BatteryThreshold; 10] = [
 /// Idle intensity
 const PI_PRO_IDLE_INTENSITY: f64 = 0.2;
 
+/// Boost intensity step (A)
+const BOOST_INTENSITY_STEP: f32 = 0.05;
+
+/// Safe boost intensity range (A), lower limits may brown out the pi
+const BOOST_INTENSITY_RANGE: std::ops::RangeInclusive<f32> = 1.0..=3.15;
+
 /// IP5312, pi-3/4 bat chip
 pub struct IP5312 {
-    i2c: I2c,
+    i2c: I2cDevice,
 }
 
 impl IP5312 {
     /// Create new IP5312
     pub fn new(i2c_bus: u8, i2c_addr: u16) -> Result<Self> {
-        let mut i2c = I2c::with_bus(i2c_bus)?;
-        i2c.set_slave_address(i2c_addr)?;
+        let i2c = I2cDevice::new(i2c_bus, i2c_addr)?;
         Ok(Self { i2c })
     }
 
@@ -216,6 +221,28 @@ impl IP5312 {
         Ok(())
     }
 
+    /// Read boost intensity (A)
+    pub fn read_boost_intensity(&self) -> Result<f32> {
+        let v = self.i2c.smbus_read_byte(0x30)?;
+        Ok((v & 0x3f) as f32 * BOOST_INTENSITY_STEP)
+    }
+
+    /// Set boost intensity (A), 50ma per step
+    pub fn set_boost_intensity(&self, intensity: f32) -> Result<()> {
+        if !BOOST_INTENSITY_RANGE.contains(&intensity) {
+            return Err(Error::Other(format!(
+                "Output current limit {}A out of range {:?}",
+                intensity, BOOST_INTENSITY_RANGE
+            )));
+        }
+        let mut v = self.i2c.smbus_read_byte(0x30)?;
+        v &= 0b1100_0000;
+        v |= (intensity / BOOST_INTENSITY_STEP).round() as u8 & 0x3f;
+        self.i2c.smbus_write_byte(0x30, v)?;
+
+        Ok(())
+    }
+
     /// Read gpio tap, gpio1
     pub fn read_gpio_tap(&self) -> Result<u8> {
         let mut v = self.i2c.smbus_read_byte(0x58)?;
@@ -241,8 +268,8 @@ impl IP5312 {
 pub struct IP5312Battery {
     ip5312: IP5312,
     model: Model,
-    voltages: VecDeque<(Instant, f32)>,
-    intensities: VecDeque<(Instant, f32)>,
+    voltages: VecDeque<Timestamped<f32>>,
+    intensities: VecDeque<Timestamped<f32>>,
     levels: VecDeque<f32>,
     tap_history: String,
     cfg: PiSugarConfig,
@@ -264,6 +291,14 @@ impl IP5312Battery {
 }
 
 impl Battery for IP5312Battery {
+    fn capabilities(&self) -> BatteryCapabilities {
+        BatteryCapabilities {
+            output_current_limit: true,
+            keep_input: self.model.led_amount() == 2,
+            ..Default::default()
+        }
+    }
+
     fn init(&mut self, config: &PiSugarConfig) -> Result<()> {
         if self.model.led_amount() == 2 {
             self.ip5312.init_gpio_2led()?;
@@ -272,6 +307,11 @@ impl Battery for IP5312Battery {
             self.ip5312.init_gpio()?;
         }
         self.ip5312.init_boost_intensity()?;
+        if let Some(limit) = config.output_current_limit {
+            if let Err(e) = self.ip5312.set_boost_intensity(limit) {
+                log::warn!("Set output current limit error: {}", e);
+            }
+        }
         // NOTE: Disable auto shutdown in auto_power_on
         if config.auto_power_on == Some(true) {
             self.ip5312.disable_light_load_shutdown()?;
@@ -282,12 +322,12 @@ impl Battery for IP5312Battery {
         let v = self.voltage()?;
         let now = Instant::now();
         while self.voltages.len() < self.voltages.capacity() {
-            self.voltages.push_back((now, v));
+            self.voltages.push_back(Timestamped::new(now, v));
         }
 
         let i = self.intensity()?;
         while self.intensities.len() > self.intensities.capacity() {
-            self.intensities.push_back((now, i));
+            self.intensities.push_back(Timestamped::new(now, i));
         }
 
         Ok(())
@@ -311,7 +351,7 @@ impl Battery for IP5312Battery {
 
     fn voltage_avg(&self) -> Result<f32> {
         let mut total = 0.0;
-        self.voltages.iter().for_each(|v| total += v.1);
+        self.voltages.iter().for_each(|v| total += v.value);
         if !self.voltages.is_empty() {
             Ok(total / self.voltages.len() as f32)
         } else {
@@ -320,12 +360,7 @@ impl Battery for IP5312Battery {
     }
 
     fn level(&self) -> Result<f32> {
-        let curve = self
-            .cfg
-            .battery_curve
-            .as_ref()
-            .map(|x| &x[..])
-            .unwrap_or(BATTERY_CURVE.as_ref());
+        let curve = self.cfg.battery_curve_or(&BATTERY_CURVE);
         self.voltage_avg().map(|x| IP5312::parse_voltage_level(x, curve))
     }
 
@@ -335,7 +370,7 @@ impl Battery for IP5312Battery {
 
     fn intensity_avg(&self) -> Result<f32> {
         let mut total = 0.0;
-        self.intensities.iter().for_each(|i| total += i.1);
+        self.intensities.iter().for_each(|i| total += i.value);
         if !self.intensities.is_empty() {
             Ok(total / self.intensities.len() as f32)
         } else {
@@ -343,6 +378,14 @@ impl Battery for IP5312Battery {
         }
     }
 
+    fn voltage_history(&self) -> Vec<Timestamped<f32>> {
+        self.voltages.iter().copied().collect()
+    }
+
+    fn intensity_history(&self) -> Vec<Timestamped<f32>> {
+        self.intensities.iter().copied().collect()
+    }
+
     fn is_power_plugged(&self) -> Result<bool> {
         if self.model.led_amount() == 2 {
             self.ip5312.is_power_plugged_2led()
@@ -374,7 +417,7 @@ impl Battery for IP5312Battery {
     fn is_charging(&self) -> Result<bool> {
         if self.levels.len() > 2 {
             if let Ok(avg) = self.voltage_avg() {
-                return Ok(self.voltages[0].1 < avg && avg < self.voltages[self.voltages.len() - 1].1);
+                return Ok(self.voltages[0].value < avg && avg < self.voltages[self.voltages.len() - 1].value);
             }
         }
         Ok(false)
@@ -403,7 +446,7 @@ impl Battery for IP5312Battery {
         let voltage = self.voltage()?;
         self.voltages.pop_front();
         while self.voltages.len() < self.voltages.capacity() {
-            self.voltages.push_back((now, voltage));
+            self.voltages.push_back(Timestamped::new(now, voltage));
         }
 
         let level = self.level()?;
@@ -415,7 +458,7 @@ impl Battery for IP5312Battery {
         let intensity = self.intensity()?;
         self.intensities.pop_front();
         while self.intensities.len() < self.intensities.capacity() {
-            self.intensities.push_back((now, intensity));
+            self.intensities.push_back(Timestamped::new(now, intensity));
         }
 
         let gpio_value = self.ip5312.read_gpio_tap()?;
@@ -438,6 +481,17 @@ impl Battery for IP5312Battery {
         Ok(events)
     }
 
+    fn output_current_limit(&self) -> Result<f32> {
+        self.ip5312.read_boost_intensity()
+    }
+
+    fn set_output_current_limit(&self, limit: Option<f32>) -> Result<()> {
+        match limit {
+            Some(limit) => self.ip5312.set_boost_intensity(limit),
+            None => self.ip5312.init_boost_intensity(),
+        }
+    }
+
     fn toggle_light_load_shutdown(&self, enable: bool) -> Result<()> {
         if enable {
             self.ip5312.enable_light_load_auto_shutdown()
@@ -0,0 +1,212 @@
+use std::convert::TryInto;
+use std::fs::{read_to_string, write, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use chrono::prelude::*;
+use chrono::{DateTime, Duration, Utc};
+
+use crate::rtc::{RTCRawTime, RtcCapabilities, RTC};
+use crate::{Error, PiSugarConfig, Result};
+
+/// RTC_SET_TIME, _IOW('p', 0x0a, struct rtc_time)
+const RTC_SET_TIME: libc::c_ulong = 0x4024_700a;
+
+/// struct rtc_time of linux/rtc.h
+#[repr(C)]
+#[derive(Default)]
+struct RtcTime {
+    tm_sec: libc::c_int,
+    tm_min: libc::c_int,
+    tm_hour: libc::c_int,
+    tm_mday: libc::c_int,
+    tm_mon: libc::c_int,
+    tm_year: libc::c_int,
+    tm_wday: libc::c_int,
+    tm_yday: libc::c_int,
+    tm_isdst: libc::c_int,
+}
+
+/// Next alarm time after now, matching time of day (UTC) and weekday repeat
+pub fn next_alarm(now: DateTime<Utc>, t: RTCRawTime, weekday_repeat: u8) -> Option<DateTime<Utc>> {
+    let time = NaiveTime::from_hms_opt(t.hour() as u32, t.minute() as u32, t.second() as u32)?;
+    (0..=7)
+        .map(|d| now.date_naive().and_time(time) + Duration::days(d))
+        .map(|dt| Utc.from_utc_datetime(&dt))
+        .filter(|dt| *dt > now)
+        .find(|dt| weekday_repeat & (1 << dt.weekday().num_days_from_sunday()) != 0)
+}
+
+/// RTC owned by its kernel driver, e.g. rtc-sd3078, through /dev/rtcN and /sys/class/rtc/rtcN
+pub struct KernelRTC {
+    dev: PathBuf,
+    sysfs: PathBuf,
+}
+
+impl KernelRTC {
+    /// Create new kernel rtc, e.g. rtc0
+    pub fn new(name: &str) -> Result<Self> {
+        let rtc = Self {
+            dev: PathBuf::from("/dev").join(name),
+            sysfs: PathBuf::from("/sys/class/rtc").join(name),
+        };
+        if !rtc.sysfs.exists() {
+            return Err(Error::Other(format!("Kernel rtc not found: {}", rtc.sysfs.display())));
+        }
+        Ok(rtc)
+    }
+
+    fn read_sysfs(&self, attr: &str) -> Result<String> {
+        read_to_string(self.sysfs.join(attr))
+            .map(|s| s.trim().to_string())
+            .map_err(|e| Error::Other(format!("Read {}: {}", attr, e)))
+    }
+
+    fn write_sysfs(&self, attr: &str, value: &str) -> Result<()> {
+        write(self.sysfs.join(attr), value).map_err(|e| Error::Other(format!("Write {}: {}", attr, e)))
+    }
+
+    fn read_epoch(&self, attr: &str) -> Result<Option<DateTime<Utc>>> {
+        let s = self.read_sysfs(attr)?;
+        if s.is_empty() {
+            return Ok(None);
+        }
+        let secs = s
+            .parse::<i64>()
+            .map_err(|e| Error::Other(format!("Invalid {}: {}", attr, e)))?;
+        Ok(Utc.timestamp_opt(secs, 0).single())
+    }
+
+    /// Clear and set wakealarm, the kernel refuses to overwrite a pending alarm
+    fn write_wakealarm(&self, dt: Option<DateTime<Utc>>) -> Result<()> {
+        self.write_sysfs("wakealarm", "0")?;
+        if let Some(dt) = dt {
+            self.write_sysfs("wakealarm", &dt.timestamp().to_string())?;
+        }
+        Ok(())
+    }
+}
+
+impl RTC for KernelRTC {
+    fn capabilities(&self) -> RtcCapabilities {
+        RtcCapabilities::default()
+    }
+
+    fn init(&mut self, config: &PiSugarConfig) -> Result<()> {
+        if config.auto_power_on == Some(true) {
+            log::warn!("Auto power on is not available with kernel rtc");
+        }
+        let alarm = match config.auto_wake_time {
+            Some(t) if config.auto_wake_repeat & 0x7f != 0 => next_alarm(Utc::now(), t.into(), config.auto_wake_repeat),
+            _ => None,
+        };
+        self.write_wakealarm(alarm)
+    }
+
+    fn read_time(&self) -> Result<RTCRawTime> {
+        self.read_epoch("since_epoch")?
+            .map(RTCRawTime::from)
+            .ok_or_else(|| Error::Other("Invalid rtc time".to_string()))
+    }
+
+    fn write_time(&self, time: RTCRawTime) -> Result<()> {
+        let dt: DateTime<Utc> = time.try_into().map_err(Error::Other)?;
+        let tm = RtcTime {
+            tm_sec: dt.second() as _,
+            tm_min: dt.minute() as _,
+            tm_hour: dt.hour() as _,
+            tm_mday: dt.day() as _,
+            tm_mon: dt.month0() as _,
+            tm_year: dt.year() - 1900,
+            tm_wday: dt.weekday().num_days_from_sunday() as _,
+            ..Default::default()
+        };
+        let f = OpenOptions::new()
+            .read(true)
+            .open(&self.dev)
+            .map_err(|e| Error::Other(format!("Open {}: {}", self.dev.display(), e)))?;
+        let r = unsafe { libc::ioctl(f.as_raw_fd(), RTC_SET_TIME as _, &tm as *const RtcTime) };
+        if r < 0 {
+            return Err(Error::Other(format!(
+                "RTC_SET_TIME: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        Ok(())
+    }
+
+    fn write_adjust_ppm(&self, _ppm: f64) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn read_alarm_time(&self) -> Result<RTCRawTime> {
+        Ok(self.read_epoch("wakealarm")?.map(RTCRawTime::from).unwrap_or_default())
+    }
+
+    fn set_alarm(&self, time: RTCRawTime, weekday_repeat: u8) -> Result<()> {
+        let alarm = next_alarm(Utc::now(), time, weekday_repeat);
+        if alarm.is_none() {
+            return Err(Error::Other("Invalid alarm".to_string()));
+        }
+        self.write_wakealarm(alarm)
+    }
+
+    fn is_alarm_enable(&self) -> Result<bool> {
+        Ok(self.read_epoch("wakealarm")?.is_some())
+    }
+
+    fn toggle_alarm_enable(&self, enable: bool) -> Result<()> {
+        if enable {
+            return Err(Error::Other("Not supported".to_string()));
+        }
+        self.write_wakealarm(None)
+    }
+
+    fn read_alarm_flag(&self) -> Result<bool> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn clear_alarm_flag(&self) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn toggle_frequency_alarm(&self, enable: bool) -> Result<()> {
+        if enable {
+            return Err(Error::Other("Not supported".to_string()));
+        }
+        Ok(())
+    }
+
+    fn force_shutdown(&self) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn read_battery_low_flag(&self) -> Result<bool> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn toggle_charging(&self, _enable: bool) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn read_battery_high_flag(&self) -> Result<bool> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_alarm() {
+        // Friday
+        let now = Utc.with_ymd_and_hms(2021, 1, 1, 12, 0, 0).unwrap();
+        let t = RTCRawTime::from(Utc.with_ymd_and_hms(2000, 1, 1, 8, 30, 0).unwrap());
+        let next = next_alarm(now, t, 0b0111_1111).unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2021, 1, 2, 8, 30, 0).unwrap());
+        let next = next_alarm(now, t, 0b0000_0010).unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2021, 1, 4, 8, 30, 0).unwrap());
+        assert!(next_alarm(now, t, 0).is_none());
+    }
+}
@@ -1,3 +1,7 @@
+// Without `hw`, only config, model and protocol types are built, helpers of PiSugarCore are unused
+#![cfg_attr(not(feature = "hw"), allow(dead_code, unused_imports))]
+
+use std::collections::VecDeque;
 use std::convert::{From, TryInto};
 use std::fmt;
 use std::fmt::{Display, Formatter};
@@ -9,26 +13,73 @@ use std::thread;
 use std::time::{Duration, Instant};
 
 use battery::BatteryEvent;
-use chrono::{DateTime, Datelike, Local, Timelike, Utc};
-pub use config::{BatteryThreshold, PiSugarConfig};
-use rppal::i2c::Error as I2cError;
-
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Utc};
+pub use config::{
+    resolve_secret, AuthMode, BatteryChemistry, BatteryThreshold, DeviceConfig, GraphiteConfig, GraphiteProtocol,
+    InfluxDbConfig, LedMode, LevelSource, MissedAlarmPolicy, ModbusConfig, NotifierBackend, NotifierConfig,
+    PiSugarConfig, PowerProfile, PowerSave, ProfileBackup, RtcIntOutput, ShutdownMethod, SnmpConfig, WebhookConfig,
+    REDACTED,
+};
+#[cfg(feature = "hw")]
+use rppal::i2c::{Error as I2cError, I2c};
+use serde_json::{json, Value};
+
+#[cfg(feature = "hw")]
+pub use facade::{PiSugar, Status};
 pub use model::Model;
 use rsntp::AsyncSntpClient;
+#[cfg(feature = "hw")]
 pub use sd3078::*;
 
+use crate::adjtime::Adjtime;
 use crate::battery::Battery;
-pub use crate::rtc::RTCRawTime;
+pub use crate::battery::BatteryCapabilities;
+#[cfg(feature = "hw")]
+use crate::gpio_button::GpioButton;
+pub use crate::hook::{execute_hook, HookEnv, HookOptions, DEFAULT_HOOK_SHELL};
+#[cfg(feature = "hw")]
+use crate::i2c::I2cDevice;
+#[cfg(feature = "hw")]
+pub use crate::i2c::{i2c_retries, set_i2c_options};
+pub use crate::platform::{find_tool, run_tool, run_tool_with_input, set_realtime, tool_exists};
 use crate::rtc::RTC;
-
+pub use crate::rtc::{RTCRawTime, RtcCapabilities};
+pub use crate::sample::Timestamped;
+pub use crate::schedule::{last_alarm_occurrence, next_alarm_occurrence, Schedule};
+pub use crate::shutdown::{poweroff, DEFAULT_POWEROFF_SHELL};
+pub use crate::simulation::{set_simulation, simulation};
+pub use crate::stats::{Stats, STATS_FILE};
+pub use crate::under_voltage::UnderVoltage;
+
+mod adjtime;
 mod battery;
 mod config;
+#[cfg(feature = "hw")]
+mod facade;
+#[cfg(feature = "hw")]
+mod gpio_button;
+mod hook;
+#[cfg(feature = "hw")]
+mod i2c;
+#[cfg(feature = "hw")]
 mod ip5209;
+#[cfg(feature = "hw")]
 mod ip5312;
+#[cfg(feature = "hw")]
+mod kernel_rtc;
 mod model;
+#[cfg(feature = "hw")]
 mod pisugar3;
+mod platform;
 mod rtc;
+mod sample;
+mod schedule;
+#[cfg(feature = "hw")]
 mod sd3078;
+mod shutdown;
+mod simulation;
+mod stats;
+mod under_voltage;
 
 /// NTP addr
 pub const NTP_ADDR: &str = "pool.ntp.org";
@@ -36,9 +87,18 @@ pub const NTP_ADDR: &str = "pool.ntp.org";
 /// RTC Time record
 pub const RTC_TIME: &str = "rtc.time";
 
+/// Interval of rtc time record, for missed alarm detection
+const RTC_TIME_RECORD_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 /// I2c poll interval, no more than 1s
 pub const I2C_READ_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 
+/// Poll interval in power save (s)
+pub const POWER_SAVE_INTERVAL: u64 = 10;
+
+/// PiSugar 3 i2c addr
+const I2C_ADDR_P3: u16 = 0x57;
+
 /// RTC address, SD3078
 const I2C_ADDR_RTC: u16 = 0x32;
 
@@ -48,14 +108,40 @@ const I2C_ADDR_BAT: u16 = 0x75;
 /// Battery full charge 5min after full, 5min, should be adjust as needed
 const BAT_FULL_CHARGE_DURATION: u64 = 5 * 60;
 
+/// Wake reason of this boot, survives daemon restarts but not reboots
+pub const WAKE_REASON_FILE: &str = "/run/pisugar-wake-reason";
+
+/// Built-in longevity profile name
+pub const LONGEVITY_PROFILE: &str = "longevity";
+
+/// Longevity mode charging range, for 24/7 UPS duty
+pub const LONGEVITY_CHARGING_RANGE: (f32, f32) = (40.0, 80.0);
+
+/// Longevity mode full charge duration, 30s
+pub const LONGEVITY_FULL_CHARGE_DURATION: u64 = 30;
+
+/// Thermal protect hysteresis, 5°C
+const THERMAL_PROTECT_HYSTERESIS: f32 = 5.0;
+
+/// Poll errors in this window are reported as recent, 5min
+const POLL_ERROR_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Rtc alarm wake after the soft poweroff of a scheduled reboot, 90s, same as test wake
+const REBOOT_WAKE_DELAY: i64 = 90;
+
+/// Shutdown grace period, 5s, same as logind default InhibitDelayMaxSec
+pub const SHUTDOWN_GRACE_PERIOD: u64 = 5;
+
 /// PiSugar error
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(feature = "hw")]
     I2c(I2cError),
     Other(String),
 }
 
 /// Wrap I2cError
+#[cfg(feature = "hw")]
 impl From<I2cError> for Error {
     fn from(e: I2cError) -> Self {
         Error::I2c(e)
@@ -77,6 +163,7 @@ impl From<io::Error> for Error {
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
+            #[cfg(feature = "hw")]
             Error::I2c(e) => write!(f, "{}", e),
             Error::Other(e) => write!(f, "{}", e),
         }
@@ -105,26 +192,34 @@ fn convert_battery_voltage_to_level(voltage: f32, battery_curve: &[BatteryThresh
     0.0
 }
 
-/// Write time to system
+/// Write time to system with clock_settime(2), or `date` if it's denied, then to the hardware clock
 pub fn sys_write_time(dt: DateTime<Local>) {
-    let cmd = format!(
-        "/bin/date -s \"{}-{}-{} {}:{}:{}\"",
-        dt.year(),
-        dt.month(),
-        dt.day(),
-        dt.hour(),
-        dt.minute(),
-        dt.second()
-    );
-    if execute_shell(cmd.as_str()).is_ok() {
-        let cmd = "/sbin/hwclock -w";
-        if execute_shell(cmd).is_err() {
-            log::warn!("Failed to set hardware: {}", cmd);
-        } else {
-            log::info!("Update hardware success");
+    let utc = dt.with_timezone(&Utc);
+    if simulation() {
+        return log::info!("Simulation, system time not updated: {}", utc);
+    }
+    match set_realtime(utc) {
+        Ok(()) => log::info!("Update system time: {}", utc),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            log::warn!("clock_settime denied ({}), set system time with date", e);
+            // UTC, local time is ambiguous during DST
+            let time = utc.format("%Y-%m-%d %H:%M:%S").to_string();
+            match run_tool("date", &["-u", "-s", &time]) {
+                Ok(status) if status.success() => {}
+                Ok(status) => return log::error!("Failed to set system time: date exited with {}", status),
+                Err(e) => return log::error!("Failed to set system time: {}", e),
+            }
         }
-    } else {
-        log::error!("Failed to set system time");
+        Err(e) => return log::error!("Failed to set system time: {}", e),
+    }
+    if !tool_exists("hwclock") {
+        log::debug!("hwclock not found, hardware clock not updated");
+        return;
+    }
+    match run_tool("hwclock", &["-w"]) {
+        Ok(status) if status.success() => log::info!("Update hardware success"),
+        Ok(status) => log::warn!("Failed to set hardware: hwclock exited with {}", status),
+        Err(e) => log::warn!("Failed to set hardware: {}", e),
     }
 }
 
@@ -187,29 +282,44 @@ pub fn execute_cmd(cmd: &str, args: &[&str]) -> io::Result<ExitStatus> {
     child.wait()
 }
 
-/// Notify shutdown with message
+/// Notify shutdown with message, through `wall` on stdin, which util-linux and busybox both take
 pub fn notify_shutdown_soon(message: &str) {
-    let shell = format!("/usr/bin/wall -n '{}'", message);
-    let _ = execute_shell(shell.as_str());
+    if let Err(e) = run_tool_with_input("wall", &[], message) {
+        log::warn!("Failed to broadcast shutdown message: {}", e);
+    }
+}
+
+/// Power off through systemd-logind.
+///
+/// logind broadcasts `PrepareForShutdown`, so that other services (databases, docker) holding delay inhibitor
+/// locks could flush, and waits for them (no more than `InhibitDelayMaxSec`) before powering off.
+pub fn logind_poweroff() -> io::Result<()> {
+    if simulation() {
+        log::warn!("Simulation, logind power off skipped");
+        return Ok(());
+    }
+    shutdown::logind_poweroff_now()
 }
 
+#[cfg(feature = "hw")]
 macro_rules! call_i2c {
     ($obj:expr, $method:tt) => {
         if let Some(obj) = $obj {
-            obj.$method()
+            crate::i2c::with_bus_locked(|| obj.$method())
         } else {
             Err("I2C not connected".to_string().into())
         }
     };
     ($obj:expr, $method:tt, $($arg:tt)*) => {
         if let Some(obj) = $obj {
-            obj.$method($($arg)*)
+            crate::i2c::with_bus_locked(|| obj.$method($($arg)*))
         } else {
             Err("I2C not connected".to_string().into())
         }
     };
 }
 
+#[cfg(feature = "hw")]
 macro_rules! call_battery {
     ($battery:expr, $method:tt) => {
         call_i2c!($battery, $method)
@@ -219,6 +329,7 @@ macro_rules! call_battery {
     }
 }
 
+#[cfg(feature = "hw")]
 macro_rules! call_rtc {
     ($rtc:expr, $method:tt) => {
         call_i2c!($rtc, $method)
@@ -229,23 +340,49 @@ macro_rules! call_rtc {
 }
 
 /// Core
+#[cfg(feature = "hw")]
 pub struct PiSugarCore {
     config_path: Option<String>,
     config: PiSugarConfig,
     model: Model,
     battery: Option<Box<dyn Battery + Send>>,
+    gpio_button: Option<GpioButton>,
     battery_full_at: Option<Instant>,
     rtc: Option<Box<dyn RTC + Send>>,
     poll_check_at: Instant,
     rtc_sync_at: Instant,
+    poll_errors: VecDeque<Instant>,
+    poll_error_total: u64,
+    poll_last_error: Option<String>,
+    poll_ok_at: Option<Instant>,
+    degraded_since: Option<Instant>,
+    power_saving: bool,
+    thermal_paused: bool,
+    under_voltage: bool,
+    under_voltage_total: u64,
+    under_voltage_last: Option<UnderVoltage>,
+    throttled_sysfs: Option<bool>,
+    power_plugged_changed: Option<(bool, Instant)>,
+    charging_window_open: Option<bool>,
+    rtc_alarm_fired: Option<bool>,
+    wake_reason: Option<String>,
+    rtc_time_recorded_at: Option<Instant>,
+    test_wake_at: Option<DateTime<Local>>,
+    /// Next scheduled reboot, Some(None) caches a schedule without a next occurrence
+    reboot_at: Option<Option<DateTime<Local>>>,
+    soft_poweroff_at: Option<Instant>,
+    stats: Stats,
+    stats_at: Option<Instant>,
+    stats_saved_at: Option<Instant>,
 }
 
+#[cfg(feature = "hw")]
 impl PiSugarCore {
     fn init_battery(&mut self) -> Result<()> {
         if self.battery.is_none() {
             log::debug!("Core init battery...");
             let mut battery = self.model.bind(self.config.clone())?;
-            battery.init(&self.config)?;
+            i2c::with_bus_locked(|| battery.init(&self.config))?;
             self.battery = Some(battery);
         }
         Ok(())
@@ -255,22 +392,56 @@ impl PiSugarCore {
         if self.rtc.is_none() {
             log::debug!("Core init rtc...");
             let mut rtc = self.model.rtc(self.config.clone())?;
-            rtc.init(&self.config)?;
+            // latch alarm flag before init clears it
+            if self.rtc_alarm_fired.is_none() {
+                self.rtc_alarm_fired = rtc.read_alarm_flag().ok();
+            }
+            i2c::with_bus_locked(|| rtc.init(&self.config))?;
             self.rtc = Some(rtc);
+            // the wake alarm of a scheduled reboot is left in rtc
+            if self.config.scheduled_reboot.is_some() {
+                if let Err(e) = self.restore_alarm() {
+                    log::warn!("Restore alarm error: {}", e);
+                }
+            }
         }
         Ok(())
     }
 
     pub fn new(config: PiSugarConfig, model: Model) -> Result<Self> {
+        set_i2c_options(config.i2c_lock, config.i2c_delay.map(Duration::from_millis));
         let mut core = Self {
             config_path: None,
             config,
             model,
             battery: None,
+            gpio_button: None,
             battery_full_at: None,
             rtc: None,
             poll_check_at: Instant::now(),
             rtc_sync_at: Instant::now(),
+            poll_errors: VecDeque::new(),
+            poll_error_total: 0,
+            poll_last_error: None,
+            poll_ok_at: None,
+            degraded_since: None,
+            power_saving: false,
+            thermal_paused: false,
+            under_voltage: false,
+            under_voltage_total: 0,
+            under_voltage_last: None,
+            throttled_sysfs: None,
+            power_plugged_changed: None,
+            charging_window_open: None,
+            rtc_alarm_fired: None,
+            wake_reason: None,
+            rtc_time_recorded_at: None,
+            test_wake_at: None,
+            reboot_at: None,
+            soft_poweroff_at: None,
+            stats: Stats::default(),
+            stats_at: None,
+            stats_saved_at: None,
         };
         if let Err(e) = core.init_rtc() {
             log::warn!("Retry to init rtc, error: {}", e);
@@ -278,19 +449,188 @@ impl PiSugarCore {
         if let Err(e) = core.init_battery() {
             log::warn!("Retry to init battery later, error: {}", e);
         }
+        if let Err(e) = core.clear_thermal_pause() {
+            log::warn!("Clear thermal pause error: {}", e);
+        }
+        if let Some(pin) = core.config.gpio_button_pin {
+            let active_low = core.config.gpio_button_active_low.unwrap_or(true);
+            match GpioButton::new(pin, active_low) {
+                Ok(button) => core.gpio_button = Some(button),
+                Err(e) => log::warn!("Init gpio button error: {}", e),
+            }
+        }
+        core.latch_wake_reason();
         Ok(core)
     }
 
+    /// Latch wake reason of this boot from the rtc alarm flag: rtc_alarm, button (on battery, no alarm) or unknown
+    fn latch_wake_reason(&mut self) {
+        let reason = match std::fs::read_to_string(WAKE_REASON_FILE) {
+            Ok(reason) => reason.trim().to_string(),
+            Err(_) => {
+                let reason = match (self.rtc_alarm_fired, self.power_plugged()) {
+                    (Some(true), _) => "rtc_alarm",
+                    (Some(false), Ok(false)) => "button",
+                    _ => "unknown",
+                };
+                if let Err(e) = std::fs::write(WAKE_REASON_FILE, reason) {
+                    log::warn!("Write {} error: {}", WAKE_REASON_FILE, e);
+                }
+                reason.to_string()
+            }
+        };
+        log::info!("Wake reason: {}", reason);
+        self.wake_reason = Some(reason);
+    }
+
+    /// Re-program the alarm for its next occurrence, e.g. after it was missed, None if no alarm is set
+    pub fn reschedule_alarm(&mut self) -> Result<Option<DateTime<Local>>> {
+        let local_time = match (self.config.auto_wake_local_time, self.config.auto_wake_time) {
+            (Some(t), _) => t,
+            (None, Some(t)) => t.time(),
+            _ => return Ok(None),
+        };
+        let now = self.read_time().unwrap_or_else(|_| Local::now());
+        let next = match next_alarm_occurrence(&now, local_time, self.config.auto_wake_repeat) {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        self.write_alarm(next.into(), self.config.auto_wake_repeat)?;
+        self.config.auto_wake_time = Some(next);
+        self.save_config()?;
+        Ok(Some(next))
+    }
+
+    /// Rtc time record path, next to config file
+    fn rtc_time_path(&self) -> Option<PathBuf> {
+        self.config_path.as_ref().map(|p| Path::new(p).with_file_name(RTC_TIME))
+    }
+
+    /// Record rtc time, the last time pi was alive
+    fn record_rtc_time(&self) -> Result<()> {
+        if let Some(path) = self.rtc_time_path() {
+            let t = self.read_time().unwrap_or_else(|_| Local::now());
+            std::fs::write(path, t.to_rfc3339())?;
+        }
+        Ok(())
+    }
+
+    /// Alarm missed since the last rtc time record, if `missed_alarm_policy` is set
+    pub fn missed_alarm(&self) -> Result<Option<DateTime<Local>>> {
+        let path = match self.rtc_time_path() {
+            Some(path) if self.config.missed_alarm_policy.is_some() => path,
+            _ => return Ok(None),
+        };
+        let local_time = match (self.config.auto_wake_local_time, self.config.auto_wake_time) {
+            (Some(t), _) => t,
+            (None, Some(t)) => t.time(),
+            _ => return Ok(None),
+        };
+        if self.wake_reason.as_deref() == Some("rtc_alarm") {
+            return Ok(None);
+        }
+        let last_alive = match std::fs::read_to_string(path) {
+            Ok(s) => DateTime::parse_from_rfc3339(s.trim())
+                .map_err(|e| Error::Other(e.to_string()))?
+                .with_timezone(&Local),
+            Err(_) => return Ok(None),
+        };
+        let now = self.read_time().unwrap_or_else(|_| Local::now());
+        Ok(last_alarm_occurrence(
+            &last_alive,
+            &now,
+            local_time,
+            self.config.auto_wake_repeat,
+        ))
+    }
+
+    /// Stats file path, next to config file
+    fn stats_path(&self) -> Option<PathBuf> {
+        self.config_path
+            .as_ref()
+            .map(|p| Path::new(p).with_file_name(STATS_FILE))
+    }
+
+    /// Persistent power statistics, in memory only without a config file
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Account runtime and level, the stats are loaded and the boot is counted on the first call
+    fn update_stats(&mut self, now: Instant) {
+        let path = self.stats_path();
+        let elapsed = match self.stats_at.replace(now) {
+            Some(at) => now.duration_since(at),
+            None => {
+                if let Some(path) = &path {
+                    self.stats = match Stats::load(path) {
+                        Ok(stats) => stats,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => Stats::default(),
+                        Err(e) => {
+                            log::warn!("Load stats error, reset: {}", e);
+                            Stats::default()
+                        }
+                    };
+                }
+                if let Some(boot_id) = stats::boot_id() {
+                    if self.stats.record_boot(&boot_id) {
+                        log::info!("Boot count: {}", self.stats.boots);
+                    }
+                }
+                Duration::ZERO
+            }
+        };
+        if let Ok(power_plugged) = self.power_plugged() {
+            let date = Local::now().naive_local().date();
+            self.stats.record(elapsed, power_plugged, self.level().ok(), date);
+        }
+        let saved = matches!(self.stats_saved_at, Some(t) if t + stats::STATS_SAVE_INTERVAL > now);
+        if let (Some(path), false) = (path, saved) {
+            self.stats_saved_at = Some(now);
+            if let Err(e) = self.stats.save(&path) {
+                log::warn!("Save stats error: {}", e);
+            }
+        }
+    }
+
+    /// Wake reason of this boot
+    pub fn wake_reason(&self) -> String {
+        self.wake_reason.clone().unwrap_or_else(|| "unknown".to_string())
+    }
+
     pub fn new_without_init(config: PiSugarConfig, model: Model) -> Result<Self> {
         let mut core = Self {
             config_path: None,
             config: config.clone(),
             model,
             battery: None,
+            gpio_button: None,
             battery_full_at: None,
             rtc: None,
             poll_check_at: Instant::now(),
             rtc_sync_at: Instant::now(),
+            poll_errors: VecDeque::new(),
+            poll_error_total: 0,
+            poll_last_error: None,
+            poll_ok_at: None,
+            degraded_since: None,
+            power_saving: false,
+            thermal_paused: false,
+            under_voltage: false,
+            under_voltage_total: 0,
+            under_voltage_last: None,
+            throttled_sysfs: None,
+            power_plugged_changed: None,
+            charging_window_open: None,
+            rtc_alarm_fired: None,
+            wake_reason: None,
+            rtc_time_recorded_at: None,
+            test_wake_at: None,
+            reboot_at: None,
+            soft_poweroff_at: None,
+            stats: Stats::default(),
+            stats_at: None,
+            stats_saved_at: None,
         };
         core.battery = Some(model.bind(config.clone())?);
         core.rtc = Some(model.rtc(config.clone())?);
@@ -348,6 +688,46 @@ impl PiSugarCore {
         }
     }
 
+    /// Core of an additional device, its own config is kept in `devices/<id>/config.json` next to the main config
+    /// file, bus and address come from the device entry, i2c options from the main config
+    pub fn new_device(
+        device: &DeviceConfig,
+        main: &PiSugarConfig,
+        main_path: Option<&str>,
+        model: Model,
+    ) -> Result<Self> {
+        if !device.valid_id() {
+            return Err(Error::Other(format!("Invalid device id: {:?}", device.id)));
+        }
+        let path = main_path.map(|p| {
+            Path::new(p)
+                .with_file_name("devices")
+                .join(&device.id)
+                .join("config.json")
+        });
+        let mut config = PiSugarConfig::default();
+        if let Some(path) = path.as_ref().filter(|p| p.is_file()) {
+            if let Err(e) = config.load(path) {
+                log::warn!("Load device {} config error: {}", device.id, e);
+            }
+        }
+        config.model = Some(device.model.clone());
+        config.i2c_bus = device.i2c_bus.unwrap_or(main.i2c_bus);
+        config.i2c_addr = device.i2c_addr;
+        config.i2c_lock = main.i2c_lock;
+        config.i2c_delay = main.i2c_delay;
+        config.devices = None;
+        let mut core = Self::new(config, model)?;
+        if let Some(path) = path {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            core.config_path = Some(path.to_string_lossy().to_string());
+            core.save_config()?;
+        }
+        Ok(core)
+    }
+
     fn load_config(path: &Path, model: Model) -> Result<Self> {
         if path.exists() && path.is_file() {
             let mut config = PiSugarConfig::default();
@@ -364,6 +744,77 @@ impl PiSugarCore {
         }
     }
 
+    /// Battery i2c address in use
+    pub fn battery_i2c_addr(&self) -> u16 {
+        self.config.i2c_addr.unwrap_or(self.model.default_battery_i2c_addr())
+    }
+
+    /// RTC i2c address in use
+    pub fn rtc_i2c_addr(&self) -> u16 {
+        self.config.rtc_i2c_addr.unwrap_or(self.model.default_rtc_i2c_addr())
+    }
+
+    /// Drop battery and rtc, and init them of the config
+    fn reinit(&mut self) -> Result<()> {
+        self.battery = None;
+        self.rtc = None;
+        let rtc = self.init_rtc();
+        let battery = self.init_battery();
+        rtc.and(battery)
+    }
+
+    /// Rebind battery and rtc on new i2c bus/addresses, and persist them if both are bound, the old ones are
+    /// restored otherwise
+    pub fn rebind(&mut self, i2c_bus: u8, i2c_addr: Option<u16>, rtc_i2c_addr: Option<u16>) -> Result<()> {
+        log::info!("Rebind i2c bus={} addr={:?} rtc addr={:?}", i2c_bus, i2c_addr, rtc_i2c_addr);
+        let mut config = self.config.clone();
+        config.i2c_bus = i2c_bus;
+        config.i2c_addr = i2c_addr;
+        config.rtc_i2c_addr = rtc_i2c_addr;
+        self.apply_config(config)?;
+        self.save_config()
+    }
+
+    /// Replace the whole config, it is validated as a whole, applied by rebinding battery and rtc, and persisted once
+    /// applied, the old config is restored if it isn't
+    pub fn replace_config(&mut self, config: PiSugarConfig) -> Result<()> {
+        let issues = config.validate();
+        if !issues.is_empty() {
+            return Err(Error::Other(format!("Invalid config: {}", issues.join(", "))));
+        }
+        log::info!("Replace config");
+        self.apply_config(config)?;
+        self.save_config()
+    }
+
+    /// Rebind battery and rtc of a config, restore the old config and rebind them again if it fails
+    fn apply_config(&mut self, config: PiSugarConfig) -> Result<()> {
+        set_i2c_options(config.i2c_lock, config.i2c_delay.map(Duration::from_millis));
+        let old = std::mem::replace(&mut self.config, config);
+        if let Err(e) = self.reinit() {
+            log::warn!("Config not applied: {}, restore the old one", e);
+            set_i2c_options(old.i2c_lock, old.i2c_delay.map(Duration::from_millis));
+            self.config = old;
+            if let Err(e) = self.reinit() {
+                log::error!("Restore config error: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Reset config to defaults, except i2c bus and addresses of the wiring
+    pub fn factory_reset(&mut self) -> Result<()> {
+        log::warn!("Factory reset config");
+        let config = PiSugarConfig {
+            i2c_bus: self.config.i2c_bus,
+            i2c_addr: self.config.i2c_addr,
+            rtc_i2c_addr: self.config.rtc_i2c_addr,
+            ..Default::default()
+        };
+        self.replace_config(config)
+    }
+
     pub fn save_config(&self) -> Result<()> {
         if let Some(config_path) = &self.config_path {
             let path = Path::new(config_path);
@@ -390,7 +841,11 @@ impl PiSugarCore {
         call_battery!(&self.battery, voltage)
     }
 
+    /// Average voltage (V), the latest one in power save as samples are far apart
     pub fn voltage_avg(&self) -> Result<f32> {
+        if self.power_saving {
+            return self.voltage();
+        }
         call_battery!(&self.battery, voltage_avg)
     }
 
@@ -398,14 +853,39 @@ impl PiSugarCore {
         call_battery!(&self.battery, intensity)
     }
 
+    /// Average current intensity (A), the latest one in power save as samples are far apart
     pub fn intensity_avg(&self) -> Result<f32> {
+        if self.power_saving {
+            return self.intensity();
+        }
         call_battery!(&self.battery, intensity_avg)
     }
 
+    /// Recent voltage samples (V), oldest first
+    pub fn voltage_history(&self) -> Result<Vec<Timestamped<f32>>> {
+        self.battery
+            .as_ref()
+            .map(|b| b.voltage_history())
+            .ok_or_else(|| "I2C not connected".to_string().into())
+    }
+
+    /// Recent current intensity samples (A), oldest first
+    pub fn intensity_history(&self) -> Result<Vec<Timestamped<f32>>> {
+        self.battery
+            .as_ref()
+            .map(|b| b.intensity_history())
+            .ok_or_else(|| "I2C not connected".to_string().into())
+    }
+
     pub fn level(&self) -> Result<f32> {
         call_battery!(&self.battery, level)
     }
 
+    /// Battery level of the chip fuel gauge, newer PiSugar 3 firmware
+    pub fn chip_level(&self) -> Result<f32> {
+        call_battery!(&self.battery, chip_level)
+    }
+
     pub fn power_plugged(&self) -> Result<bool> {
         call_battery!(&self.battery, is_power_plugged)
     }
@@ -438,6 +918,90 @@ impl PiSugarCore {
         call_battery!(&self.battery, toggle_output_enabled, enable)
     }
 
+    /// Output boost current limit (A)
+    pub fn output_current_limit(&self) -> Result<f32> {
+        call_battery!(&self.battery, output_current_limit)
+    }
+
+    /// Battery LED indicators, newer PiSugar 3 firmware
+    pub fn led_mode(&self) -> Result<LedMode> {
+        call_battery!(&self.battery, led_mode)
+    }
+
+    pub fn set_led_mode(&mut self, mode: LedMode) -> Result<()> {
+        call_battery!(&self.battery, set_led_mode, mode)?;
+        self.config.led_mode = Some(mode);
+        self.save_config()
+    }
+
+    /// Power save mode of the daemon
+    pub fn power_save(&self) -> PowerSave {
+        self.config.power_save.unwrap_or(PowerSave::Off)
+    }
+
+    pub fn set_power_save(&mut self, mode: PowerSave) -> Result<()> {
+        self.config.power_save = Some(mode);
+        self.update_power_saving();
+        self.save_config()
+    }
+
+    /// Whether the daemon is saving power now
+    pub fn power_saving(&self) -> bool {
+        self.power_saving
+    }
+
+    /// Interval of polls, slower in power save
+    pub fn poll_interval(&self) -> Duration {
+        if self.power_saving {
+            Duration::from_secs(self.config.power_save_interval.unwrap_or(POWER_SAVE_INTERVAL))
+        } else {
+            I2C_READ_INTERVAL
+        }
+    }
+
+    /// Whether polls should be fast regardless of clients: taps sampled by polls, or charging under control
+    pub fn fast_poll_required(&self) -> bool {
+        // PiSugar 3 latches taps in a register, the others detect taps of sampled button states,
+        // gpio button edges are recorded by an interrupt, polls are fast only to detect a tap in progress
+        self.model != Model::PiSugar_3
+            || self.gpio_button.as_ref().is_some_and(|button| button.busy())
+            || self.config.auto_charging_range.is_some()
+            || self.config.charging_window.is_some()
+            || self.config.thermal_protect.is_some()
+    }
+
+    fn update_power_saving(&mut self) {
+        let saving = match self.power_save() {
+            PowerSave::Off => false,
+            PowerSave::On => true,
+            PowerSave::Auto => self.power_plugged().ok() == Some(false),
+        };
+        if saving != self.power_saving {
+            log::info!("Power save: {}", saving);
+            self.power_saving = saving;
+        }
+    }
+
+    /// Set output boost current limit (A), None to reset to the chip default
+    pub fn set_output_current_limit(&mut self, limit: Option<f32>) -> Result<()> {
+        if limit.is_some() || self.battery_capabilities().is_some_and(|c| c.output_current_limit) {
+            call_battery!(&self.battery, set_output_current_limit, limit)?;
+        }
+        self.config.output_current_limit = limit;
+        self.save_config()
+    }
+
+    /// Read a raw register on i2c bus, for debugging
+    pub fn read_register(&self, addr: u16, reg: u8) -> Result<u8> {
+        i2c_read_register(self.config.i2c_bus, addr, reg)
+    }
+
+    /// Write a raw register on i2c bus, for debugging
+    pub fn write_register(&self, addr: u16, reg: u8, value: u8) -> Result<()> {
+        log::warn!("Write register 0x{:02x} 0x{:02x}: 0x{:02x}", addr, reg, value);
+        i2c_write_register(self.config.i2c_bus, addr, reg, value)
+    }
+
     pub fn charging_range(&self) -> Result<Option<(f32, f32)>> {
         Ok(self.config.auto_charging_range)
     }
@@ -454,6 +1018,155 @@ impl PiSugarCore {
         self.save_config()
     }
 
+    /// Whether charging is paused by thermal protect
+    pub fn thermal_paused(&self) -> bool {
+        self.thermal_paused
+    }
+
+    /// Allow charging again at startup, a pause of thermal protect is kept in the chip but not in the daemon, so a
+    /// daemon restarted while paused would never resume it, the next poll pauses it again if it's still too hot
+    fn clear_thermal_pause(&mut self) -> Result<()> {
+        if self.config.thermal_protect.is_some() && !self.thermal_paused && !self.charging_inhibited() {
+            self.toggle_allow_charging(true)?;
+        }
+        Ok(())
+    }
+
+    /// Set max chip temperature of charging, None to disable
+    pub fn set_thermal_protect(&mut self, max: Option<f32>) -> Result<()> {
+        self.config.thermal_protect = max;
+        if max.is_none() && self.thermal_paused {
+            self.thermal_paused = false;
+            if !self.charging_inhibited() {
+                self.toggle_allow_charging(true)?;
+            }
+        }
+        self.save_config()
+    }
+
+    /// Shutdown delay (seconds) after power loss, None to disable
+    pub fn set_outage_shutdown_delay(&mut self, delay: Option<f64>) -> Result<()> {
+        if matches!(delay, Some(d) if d < 0.0) {
+            return Err(Error::Other("Invalid outage shutdown delay".to_string()));
+        }
+        self.config.outage_shutdown_delay = delay;
+        self.save_config()
+    }
+
+    /// Longevity mode: charging range 40-80%, input protect and a short full charge hold
+    pub fn set_longevity_mode(&mut self, enable: bool) -> Result<()> {
+        if enable {
+            return self.set_profile(LONGEVITY_PROFILE);
+        }
+        if self.power_profile() != LONGEVITY_PROFILE {
+            log::info!("Longevity mode is not active, settings are kept");
+            return Ok(());
+        }
+        let backup = self.config.profile_backup.take().unwrap_or_default();
+        self.config.full_charge_duration = backup.full_charge_duration;
+        self.config.bat_protect = backup.bat_protect;
+        self.config.power_profile = None;
+        if let Err(e) = self.toggle_input_protected(backup.bat_protect.unwrap_or(false)) {
+            log::warn!("Toggle input protect error: {}", e);
+        }
+        self.set_charging_range(backup.auto_charging_range)
+    }
+
+    /// Named power profile in config, or built-in longevity profile
+    pub fn profile(&self, name: &str) -> Option<PowerProfile> {
+        self.config
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .cloned()
+            .or_else(|| (name == LONGEVITY_PROFILE).then(PowerProfile::longevity))
+    }
+
+    /// Apply a named power profile, and persist it
+    pub fn set_profile(&mut self, name: &str) -> Result<()> {
+        let profile = self
+            .profile(name)
+            .ok_or_else(|| Error::Other(format!("Unknown profile: {}", name)))?;
+        log::info!("Apply power profile {}: {:?}", name, profile);
+
+        // settings of before the first profile, switching between profiles keeps them
+        let active = self.config.power_profile.as_deref() == Some(self.power_profile().as_str());
+        if !active || self.config.profile_backup.is_none() {
+            self.config.profile_backup = Some(ProfileBackup {
+                auto_charging_range: self.config.auto_charging_range,
+                full_charge_duration: self.config.full_charge_duration,
+                bat_protect: self.config.bat_protect,
+            });
+        }
+        let c = &mut self.config;
+        c.full_charge_duration = profile.full_charge_duration.or(c.full_charge_duration);
+        c.auto_shutdown_level = profile.auto_shutdown_level.or(c.auto_shutdown_level);
+        c.auto_shutdown_delay = profile.auto_shutdown_delay.or(c.auto_shutdown_delay);
+        if let Some(shell) = profile.single_tap_shell {
+            c.single_tap_enable = !shell.is_empty();
+            c.single_tap_shell = shell;
+        }
+        if let Some(shell) = profile.double_tap_shell {
+            c.double_tap_enable = !shell.is_empty();
+            c.double_tap_shell = shell;
+        }
+        if let Some(shell) = profile.long_tap_shell {
+            c.long_tap_enable = !shell.is_empty();
+            c.long_tap_shell = shell;
+        }
+        c.power_profile = Some(name.to_string());
+        if let Some(protect) = profile.bat_protect {
+            c.bat_protect = Some(protect);
+            if let Err(e) = self.toggle_input_protected(protect) {
+                log::warn!("Toggle input protect error: {}", e);
+            }
+        }
+        if let Some(auto_power_on) = profile.auto_power_on {
+            self.toggle_auto_power_on(auto_power_on)?;
+        }
+        if let Some(range) = profile.auto_charging_range {
+            self.set_charging_range(Some(range))?;
+        }
+        self.save_config()
+    }
+
+    /// Active power profile, a named profile, default or custom
+    pub fn power_profile(&self) -> String {
+        let c = &self.config;
+        if let Some(name) = &c.power_profile {
+            if let Some(profile) = self.profile(name) {
+                if profile.matches(c) {
+                    return name.clone();
+                }
+            }
+        }
+        if PowerProfile::longevity().matches(c) {
+            LONGEVITY_PROFILE.to_string()
+        } else if c.auto_charging_range.is_none() && c.full_charge_duration.is_none() {
+            "default".to_string()
+        } else {
+            "custom".to_string()
+        }
+    }
+
+    /// Set charging time window, None to disable
+    pub fn set_charging_window(&mut self, window: Option<(NaiveTime, NaiveTime)>) -> Result<()> {
+        self.config.charging_window = window;
+        if window.is_none() && self.charging_window_open == Some(false) {
+            self.charging_window_open = None;
+            if !self.charging_inhibited() {
+                self.toggle_allow_charging(true)?;
+            }
+        }
+        self.charging_window_open = window.and(self.charging_window_open);
+        self.save_config()
+    }
+
+    /// Charging is inhibited by thermal protect or charging window
+    fn charging_inhibited(&self) -> bool {
+        self.thermal_paused || self.charging_window_open == Some(false)
+    }
+
     pub fn read_time(&self) -> Result<DateTime<Local>> {
         call_rtc!(&self.rtc, read_time)
             .and_then(|t| t.try_into().map_err(|_| Error::Other("Invalid datetime".to_string())))
@@ -467,6 +1180,65 @@ impl PiSugarCore {
         call_rtc!(&self.rtc, write_time, dt.into())
     }
 
+    /// Re-program alarm if utc time of `auto_wake_local_time` changes, e.g. DST
+    fn update_local_alarm(&mut self) -> Result<()> {
+        let (wake_time, local_time) = match (self.config.auto_wake_time, self.config.auto_wake_local_time) {
+            (Some(wake_time), Some(local_time)) if self.config.auto_power_on != Some(true) => (wake_time, local_time),
+            _ => return Ok(()),
+        };
+        let today = Local::now().date_naive().and_time(local_time);
+        let alarm = match Local.from_local_datetime(&today).earliest() {
+            Some(alarm) => alarm,
+            None => return Ok(()),
+        };
+        if alarm.naive_utc().time() == wake_time.naive_utc().time() {
+            return Ok(());
+        }
+        log::info!("Local utc offset changed, re-program alarm at {}", alarm);
+        self.write_alarm(alarm.into(), self.config.auto_wake_repeat)?;
+        self.config.auto_wake_time = Some(alarm);
+        self.save_config()
+    }
+
+    /// Write ntp time to rtc, and calibrate rtc drift if `rtc_drift_file` is set
+    pub fn sync_rtc_time(&self, ntp_time: DateTime<Utc>) -> Result<()> {
+        let path = match &self.config.rtc_drift_file {
+            Some(path) => Path::new(path),
+            None => return self.write_time(ntp_time.into()),
+        };
+        let mut adjtime = Adjtime::load(path).unwrap_or_default();
+        let rtc_time = self.read_time().ok().map(DateTime::<Utc>::from);
+        if adjtime.calibrate(ntp_time, rtc_time) {
+            log::debug!("Rtc time {:?}, drift factor {}s/day", rtc_time, adjtime.drift_factor);
+            self.write_time(ntp_time.into())?;
+            adjtime.save(path)?;
+        }
+        Ok(())
+    }
+
+    /// Correct rtc and system time with predicted rtc drift, e.g. on boot before network is up
+    pub fn apply_rtc_drift(&self) -> Result<()> {
+        let path = match &self.config.rtc_drift_file {
+            Some(path) => Path::new(path),
+            None => return Ok(()),
+        };
+        let mut adjtime = match Adjtime::load(path) {
+            Ok(adjtime) => adjtime,
+            Err(_) => return Ok(()),
+        };
+        let rtc_time: DateTime<Utc> = self.read_time()?.into();
+        let correction = adjtime.correction(rtc_time);
+        if correction.num_seconds() == 0 {
+            return Ok(());
+        }
+        let t = rtc_time + correction;
+        log::info!("Correct rtc drift: {}ms", correction.num_milliseconds());
+        self.write_time(t.into())?;
+        sys_write_time(t.into());
+        adjtime.last_adjust = t.timestamp();
+        adjtime.save(path)
+    }
+
     pub fn write_alarm(&self, t: RTCRawTime, weekday_repeat: u8) -> Result<()> {
         if self.config.auto_power_on == Some(true) {
             return Err(Error::Other(
@@ -538,12 +1310,114 @@ impl PiSugarCore {
         call_battery!(&self.battery, toggle_soft_poweroff, soft_poweroff)
     }
 
+    /// Deadline of a pending soft poweroff, with `soft_poweroff_delay`
+    pub fn soft_poweroff_at(&self) -> Option<Instant> {
+        self.soft_poweroff_at
+    }
+
+    /// Cancel the pending soft poweroff
+    pub fn cancel_poweroff(&mut self) -> Result<()> {
+        match self.soft_poweroff_at.take() {
+            Some(_) => {
+                log::info!("Soft poweroff cancelled");
+                Ok(())
+            }
+            None => Err(Error::Other("No pending poweroff".to_string())),
+        }
+    }
+
     pub fn get_temperature(&self) -> Result<f32> {
         call_battery!(&self.battery, temperature)
     }
 
-    pub fn test_wake(&self) -> Result<()> {
-        call_rtc!(&self.rtc, set_test_wake)
+    /// Battery capabilities, none if battery is not ready
+    pub fn battery_capabilities(&self) -> Option<BatteryCapabilities> {
+        self.battery.as_ref().map(|battery| battery.capabilities())
+    }
+
+    /// Rtc capabilities, none if rtc is not ready
+    pub fn rtc_capabilities(&self) -> Option<RtcCapabilities> {
+        self.rtc.as_ref().map(|rtc| rtc.capabilities())
+    }
+
+    /// Rtc chip temperature (°C)
+    pub fn rtc_temperature(&self) -> Result<f32> {
+        call_rtc!(&self.rtc, read_temperature)
+    }
+
+    /// Rtc battery voltage (V)
+    pub fn rtc_battery_voltage(&self) -> Result<f32> {
+        call_rtc!(&self.rtc, read_rtc_battery_voltage)
+    }
+
+    /// Configure rtc INT pin output, None to restore alarm or auto power on
+    pub fn set_rtc_int_output(&mut self, output: Option<RtcIntOutput>) -> Result<()> {
+        match output {
+            Some(output) => call_rtc!(&self.rtc, set_int_output, output)?,
+            None => {
+                call_rtc!(&self.rtc, set_int_output, RtcIntOutput::Off)?;
+                self.restore_alarm()?;
+            }
+        }
+        self.config.rtc_int_output = output;
+        self.save_config()
+    }
+
+    /// User data in rtc battery backed ram
+    pub fn rtc_user_data(&self) -> Result<Vec<u8>> {
+        call_rtc!(&self.rtc, read_user_data)
+    }
+
+    /// Write user data in rtc battery backed ram
+    pub fn write_rtc_user_data(&self, data: &[u8]) -> Result<()> {
+        call_rtc!(&self.rtc, write_user_data, data)
+    }
+
+    /// Set a test wake up, the configured alarm is restored if the pi is still on after it
+    pub fn test_wake(&mut self) -> Result<DateTime<Local>> {
+        let t: DateTime<Local> = call_rtc!(&self.rtc, set_test_wake)?.into();
+        self.test_wake_at = Some(t);
+        Ok(t)
+    }
+
+    /// Schedule reboot of a cron expression or a datetime, None to cancel, returns the next reboot
+    pub fn set_scheduled_reboot(&mut self, schedule: Option<&str>) -> Result<Option<DateTime<Local>>> {
+        let schedule = schedule.map(|s| s.parse::<Schedule>()).transpose()?;
+        let now = self.read_time().unwrap_or_else(|_| Local::now());
+        let reboot_at = schedule.as_ref().and_then(|s| s.next_after(&now));
+        self.reboot_at = Some(reboot_at);
+        self.config.scheduled_reboot = schedule.map(|s| s.to_string());
+        self.save_config()?;
+        Ok(reboot_at)
+    }
+
+    /// Next scheduled reboot
+    pub fn next_reboot(&self) -> Option<DateTime<Local>> {
+        self.reboot_at.flatten()
+    }
+
+    /// Soft poweroff with an rtc alarm wake shortly after, returns the wake time
+    pub fn power_cycle(&mut self) -> Result<DateTime<Local>> {
+        let now = self.read_time().unwrap_or_else(|_| Local::now());
+        let wake = now + chrono::Duration::seconds(REBOOT_WAKE_DELAY);
+        self.write_alarm(wake.into(), 0b0111_1111)?;
+        // the configured alarm is restored if the pi is still on
+        self.test_wake_at = Some(wake);
+        self.spawn_poweroff("reboot");
+        Ok(wake)
+    }
+
+    /// Restore the configured alarm, or frequency alarm of auto power on
+    fn restore_alarm(&self) -> Result<()> {
+        if self.config.auto_power_on == Some(true) && self.model != Model::PiSugar_3 {
+            return call_rtc!(&self.rtc, toggle_frequency_alarm, true);
+        }
+        match self.config.auto_wake_time {
+            Some(t) if self.config.auto_wake_repeat & 0x7f != 0 => {
+                self.write_alarm(t.into(), self.config.auto_wake_repeat)
+            }
+            _ => self.disable_alarm(),
+        }
     }
 
     pub fn config(&self) -> &PiSugarConfig {
@@ -554,17 +1428,224 @@ impl PiSugarCore {
         &mut self.config
     }
 
+    /// Context of a hook of event
+    pub fn hook_env(&self, event: &str) -> HookEnv {
+        HookEnv {
+            event: event.to_string(),
+            level: self.level().ok(),
+            voltage: self.voltage_avg().ok(),
+        }
+    }
+
+    /// Run a hook script of event, until it exits
+    pub fn run_hook(&self, event: &str, script: &str) -> io::Result<ExitStatus> {
+        execute_hook(&HookOptions::new(&self.config, event), script, &self.hook_env(event))
+    }
+
+    /// Run a hook script of event in background
+    pub fn spawn_hook(&self, event: &str, script: String) {
+        let options = HookOptions::new(&self.config, event);
+        let env = self.hook_env(event);
+        thread::spawn(move || match execute_hook(&options, &script, &env) {
+            Ok(r) => log::info!("Script ok, code: {:?}", r.code()),
+            Err(e) => log::error!("{}", e),
+        });
+    }
+
+    /// Power off the system with `shutdown_method` on event, until the poweroff script exits
+    pub fn run_poweroff(&self, event: &str) -> io::Result<()> {
+        poweroff(&self.config, &self.hook_env(event))
+    }
+
+    /// Power off the system with `shutdown_method` on event, in background
+    pub fn spawn_poweroff(&self, event: &str) {
+        let config = self.config.clone();
+        let env = self.hook_env(event);
+        thread::spawn(move || {
+            if let Err(e) = poweroff(&config, &env) {
+                log::error!("Poweroff error: {}", e);
+            }
+        });
+    }
+
     pub fn force_shutdown(&self) -> Result<()> {
         // exec 30 sync before shutdown
         for _ in 0..30 {
             let _ = execute_shell("sync");
         }
 
-        call_rtc!(&self.rtc, force_shutdown)?;
+        // frequency alarm would power on again
+        if self.rtc_capabilities().is_some_and(|c| c.frequency_alarm) {
+            call_rtc!(&self.rtc, force_shutdown)?;
+        }
         call_battery!(&self.battery, shutdown)
     }
 
     pub async fn poll(&mut self, now: Instant) -> Result<Option<TapType>> {
+        let tap = self.poll_now(now)?;
+        if self.rtc_sync_due(now) {
+            if let Ok(ntp_datime) = get_ntp_datetime().await {
+                self.write_ntp_time(ntp_datime);
+            }
+        }
+        Ok(tap)
+    }
+
+    /// Poll without awaiting, ntp sync of `auto_rtc_sync` is left to the caller, see `rtc_sync_due`
+    pub fn poll_now(&mut self, now: Instant) -> Result<Option<TapType>> {
+        let r = self._poll(now);
+        match &r {
+            Ok(_) => {
+                if let Some(since) = self.degraded_since.take() {
+                    log::info!("Recovered from poll errors after {:?}", now.duration_since(since));
+                }
+                self.poll_ok_at = Some(now);
+                self.update_power_saving();
+            }
+            Err(e) => {
+                // log once, last data is served until recovered
+                if self.degraded_since.is_none() {
+                    log::warn!("Degraded, poll error: {}", e);
+                    self.degraded_since = Some(now);
+                }
+                self.poll_errors.push_back(now);
+                self.poll_error_total += 1;
+                self.poll_last_error = Some(e.to_string());
+            }
+        }
+        while let Some(t) = self.poll_errors.front() {
+            if now.duration_since(*t) <= POLL_ERROR_WINDOW {
+                break;
+            }
+            self.poll_errors.pop_front();
+        }
+        r
+    }
+
+    /// Since when polls fail, data is stale until recovered
+    pub fn degraded_since(&self) -> Option<Instant> {
+        self.degraded_since
+    }
+
+    /// Age of the data, since the last successful poll
+    pub fn data_age(&self) -> Option<Duration> {
+        self.poll_ok_at.map(|t| t.elapsed())
+    }
+
+    /// Under voltage events since startup
+    pub fn under_voltage_total(&self) -> u64 {
+        self.under_voltage_total
+    }
+
+    /// Last under voltage event
+    pub fn last_under_voltage(&self) -> Option<&UnderVoltage> {
+        self.under_voltage_last.as_ref()
+    }
+
+    /// Detect under voltage of the pi throttled flags or a rapid voltage sag, count on its rising edge.
+    /// Throttled flags are read of sysfs only, probed once, and a sag right after (un)plugging power is ignored.
+    fn check_under_voltage(&mut self, now: Instant) {
+        let throttled = match self.throttled_sysfs {
+            Some(false) => None,
+            _ => {
+                let throttled = under_voltage::read_throttled_sysfs().ok();
+                if self.throttled_sysfs.is_none() {
+                    log::info!("Throttled flags of sysfs available: {}", throttled.is_some());
+                    self.throttled_sysfs = Some(throttled.is_some());
+                }
+                throttled
+            }
+        };
+        if let Ok(plugged) = self.power_plugged() {
+            if self.power_plugged_changed.is_none_or(|(p, _)| p != plugged) {
+                self.power_plugged_changed = Some((plugged, now));
+            }
+        }
+        let plugging = self
+            .power_plugged_changed
+            .is_some_and(|(_, at)| now.duration_since(at) <= under_voltage::UNDER_VOLTAGE_SAG_WINDOW);
+        let threshold = self
+            .config
+            .under_voltage_sag
+            .unwrap_or(under_voltage::UNDER_VOLTAGE_SAG);
+        let sag = self
+            .voltage_history()
+            .ok()
+            .filter(|_| !plugging)
+            .and_then(|h| under_voltage::voltage_sag(&h, under_voltage::UNDER_VOLTAGE_SAG_WINDOW))
+            .filter(|sag| *sag >= threshold);
+        let under = matches!(throttled, Some(t) if t & under_voltage::THROTTLED_UNDER_VOLTAGE != 0) || sag.is_some();
+        if under && !self.under_voltage {
+            let event = UnderVoltage {
+                time: Local::now(),
+                voltage: self.voltage().ok(),
+                sag,
+                throttled: throttled.map(|t| format!("0x{:x}", t)),
+            };
+            log::warn!("Under voltage: {:?}", event);
+            self.under_voltage_total += 1;
+            self.under_voltage_last = Some(event);
+        }
+        self.under_voltage = under;
+    }
+
+    /// Self-test report: i2c bus scan, battery and rtc sanity, rtc clock delta, config issues, poll errors
+    pub fn diagnostics(&self) -> Value {
+        fn check<T: serde::Serialize>(r: Result<T>, valid: impl Fn(&T) -> bool) -> Value {
+            match r {
+                Ok(v) if valid(&v) => json!({ "value": v, "ok": true }),
+                Ok(v) => json!({ "value": v, "ok": false }),
+                Err(e) => json!({ "error": e.to_string(), "ok": false }),
+            }
+        }
+
+        let i2c_scan = match i2c_scan(self.config.i2c_bus) {
+            Ok(addrs) => json!(addrs.iter().map(|a| format!("0x{:02x}", a)).collect::<Vec<_>>()),
+            Err(e) => json!({ "error": e.to_string() }),
+        };
+        let rtc_delta = self
+            .read_time()
+            .map(|t| (t - Local::now()).num_milliseconds() as f64 / 1000.0);
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "model": self.model(),
+            "i2c_bus": self.config.i2c_bus,
+            "i2c_scan": i2c_scan,
+            "battery": {
+                "addr": format!("0x{:02x}", self.battery_i2c_addr()),
+                "firmware_version": check(self.version(), |_| true),
+                "voltage": check(self.voltage(), |v| (2.5..=5.5).contains(v)),
+                "level": check(self.level(), |l| (0.0..=100.0).contains(l)),
+                "temperature": check(self.get_temperature(), |t| (-40.0..=100.0).contains(t)),
+            },
+            "rtc": {
+                "addr": format!("0x{:02x}", self.rtc_i2c_addr()),
+                "time": check(self.read_time().map(|t| t.to_rfc3339()), |_| true),
+                "delta_secs": check(rtc_delta, |d| d.abs() < 60.0),
+            },
+            "config": self.config.validate(),
+            "poll_errors": {
+                "recent": self.poll_errors.len(),
+                "total": self.poll_error_total,
+                "last_error": self.poll_last_error,
+            },
+            "i2c": {
+                "retries": i2c_retries(),
+                "degraded_secs": self.degraded_since.map(|t| t.elapsed().as_secs()),
+                "data_age_secs": self.data_age().map(|age| age.as_secs_f64()),
+            },
+            "under_voltage": {
+                "now": self.under_voltage,
+                "total": self.under_voltage_total,
+                "last": self.under_voltage_last,
+                "occurred_since_boot": under_voltage::read_throttled()
+                    .ok()
+                    .map(|t| t & under_voltage::THROTTLED_UNDER_VOLTAGE_OCCURRED != 0),
+            },
+        })
+    }
+
+    fn _poll(&mut self, now: Instant) -> Result<Option<TapType>> {
         if self.rtc.is_none() {
             log::info!("Init rtc...");
             self.init_rtc()?;
@@ -577,8 +1658,14 @@ impl PiSugarCore {
         // battery events
         let mut tap = None; // tap event that returns
         let config = &self.config;
-        let events = call_battery!(&mut self.battery, poll, now, config)?;
+        let gpio_tap = self.gpio_button.as_mut().and_then(|button| button.poll(now));
+        let mut events = call_battery!(&mut self.battery, poll, now, config)?;
+        events.extend(gpio_tap.map(BatteryEvent::TapEvent));
         for event in events {
+            let hook_event = match event {
+                BatteryEvent::TapEvent(tap_type) => format!("{}_tap", tap_type),
+                BatteryEvent::SoftPowerOff => "soft_poweroff".to_string(),
+            };
             let script = match event {
                 BatteryEvent::TapEvent(tap_type) => {
                     tap = Some(tap_type);
@@ -607,27 +1694,32 @@ impl PiSugarCore {
                     }
                 }
                 BatteryEvent::SoftPowerOff => {
-                    if config.soft_poweroff == Some(true) {
-                        Some(
-                            config
-                                .soft_poweroff_shell
-                                .clone()
-                                .unwrap_or_else(|| "shutdown --poweroff 0".to_string()),
-                        )
+                    let delay = config.soft_poweroff_delay.unwrap_or(0);
+                    if config.soft_poweroff == Some(true) && delay > 0 {
+                        if self.soft_poweroff_at.is_none() {
+                            log::warn!("Soft poweroff in {}s, cancel_poweroff to abort", delay);
+                            self.soft_poweroff_at = Some(now + Duration::from_secs(delay));
+                        }
+                        None
+                    } else if config.soft_poweroff == Some(true) {
+                        self.spawn_poweroff("soft_poweroff");
+                        None
                     } else {
                         None
                     }
                 }
             };
             if let Some(script) = script {
-                log::info!("Execute script \"{}\"", script);
-                thread::spawn(move || match execute_shell(script.as_str()) {
-                    Ok(r) => log::info!("Script ok, code: {:?}", r.code()),
-                    Err(e) => log::error!("{}", e),
-                });
+                self.spawn_hook(&hook_event, script);
             }
         }
 
+        // soft poweroff after countdown
+        if matches!(self.soft_poweroff_at, Some(at) if at <= now) {
+            self.soft_poweroff_at = None;
+            self.spawn_poweroff("soft_poweroff");
+        }
+
         // slower
         if self.poll_check_at + Duration::from_secs(1) <= now {
             log::debug!("Poll slow");
@@ -638,7 +1730,7 @@ impl PiSugarCore {
                 if let Some((changing_begin, changing_end)) = &self.config.auto_charging_range {
                     let l = self.level().unwrap_or(0.0);
                     let allow_charging = self.allow_charging().unwrap_or(false);
-                    if l < *changing_begin && !allow_charging {
+                    if l < *changing_begin && !allow_charging && !self.charging_inhibited() {
                         self.battery_full_at = None;
                         let is_ok = self.toggle_allow_charging(true).map_or("fail", |_| "ok");
                         log::info!("Battery {} <= {}, enable charging: {}", l, *changing_begin, is_ok);
@@ -666,6 +1758,85 @@ impl PiSugarCore {
                 }
             }
 
+            // thermal protect, pause charging when it's too hot
+            if let Some(max) = self.config.thermal_protect {
+                if let Ok(t) = self.get_temperature() {
+                    let hysteresis = self
+                        .config
+                        .thermal_protect_hysteresis
+                        .unwrap_or(THERMAL_PROTECT_HYSTERESIS);
+                    if !self.thermal_paused && t > max {
+                        let is_ok = self.toggle_allow_charging(false).map_or("fail", |_| "ok");
+                        log::warn!("Temperature {} > {}, pause charging: {}", t, max, is_ok);
+                        self.thermal_paused = true;
+                    } else if self.thermal_paused && t < max - hysteresis {
+                        self.thermal_paused = false;
+                        if !self.charging_inhibited() {
+                            let is_ok = self.toggle_allow_charging(true).map_or("fail", |_| "ok");
+                            log::info!("Temperature {} < {}, resume charging: {}", t, max - hysteresis, is_ok);
+                        }
+                    }
+                }
+            }
+
+            // under voltage, e.g. a flaky usb supply
+            self.check_under_voltage(now);
+
+            // re-program alarm on local utc offset change, e.g. DST
+            if let Err(e) = self.update_local_alarm() {
+                log::warn!("Update local alarm error: {}", e);
+            }
+
+            // charging window
+            if let Some((begin, end)) = self.config.charging_window {
+                let t = self.read_time().unwrap_or_else(|_| Local::now()).time();
+                let open = in_time_window(t, begin, end);
+                if self.charging_window_open != Some(open) {
+                    self.charging_window_open = Some(open);
+                    if !open || !self.charging_inhibited() {
+                        let is_ok = self.toggle_allow_charging(open).map_or("fail", |_| "ok");
+                        log::info!("Charging window {}-{} open: {}, {}", begin, end, open, is_ok);
+                    }
+                }
+            }
+
+            // scheduled reboot
+            if let Some(schedule) = self.config.scheduled_reboot.clone() {
+                let t = self.read_time().unwrap_or_else(|_| Local::now());
+                match self.reboot_at {
+                    Some(Some(reboot_at)) if t >= reboot_at => {
+                        self.reboot_at = None;
+                        match self.power_cycle() {
+                            Ok(wake) => log::warn!("Scheduled reboot, wake up at {}", wake.to_rfc3339()),
+                            Err(e) => log::error!("Scheduled reboot error: {}", e),
+                        }
+                    }
+                    Some(_) => {}
+                    None => self.reboot_at = Some(schedule.parse::<Schedule>().ok().and_then(|s| s.next_after(&t))),
+                }
+            }
+
+            // restore alarm after test wake
+            if let Some(t) = self.test_wake_at {
+                if matches!(self.read_time(), Ok(now) if now > t + chrono::Duration::seconds(60)) {
+                    self.test_wake_at = None;
+                    let is_ok = self.restore_alarm().map_or("fail", |_| "ok");
+                    log::info!("Test wake missed, restore alarm: {}", is_ok);
+                }
+            }
+
+            // rtc time record
+            let recorded = matches!(self.rtc_time_recorded_at, Some(t) if t + RTC_TIME_RECORD_INTERVAL > now);
+            if self.config.missed_alarm_policy.is_some() && !recorded {
+                self.rtc_time_recorded_at = Some(now);
+                if let Err(e) = self.record_rtc_time() {
+                    log::warn!("Record rtc time error: {}", e);
+                }
+            }
+
+            // boots, runtime on battery and deepest discharge
+            self.update_stats(now);
+
             // rtc battery charging
             if let Some(rtc) = &self.rtc {
                 if rtc.read_battery_low_flag().ok() == Some(true) {
@@ -678,19 +1849,60 @@ impl PiSugarCore {
             }
         }
 
-        // much slower
+        Ok(tap)
+    }
+
+    /// Whether ntp sync of `auto_rtc_sync` is due, much slower than polling
+    pub fn rtc_sync_due(&mut self, now: Instant) -> bool {
         if self.config.auto_rtc_sync == Some(true) && self.rtc_sync_at + Duration::from_secs(10) <= now {
             self.rtc_sync_at = now;
-            if let Ok(ntp_datime) = get_ntp_datetime().await {
-                sys_write_time(ntp_datime.into());
-                let _ = self.write_time(ntp_datime.into());
-            }
+            return true;
         }
+        false
+    }
 
-        Ok(tap)
+    /// Write ntp time to system time and rtc
+    pub fn write_ntp_time(&self, ntp_time: DateTime<Utc>) {
+        sys_write_time(ntp_time.into());
+        let _ = self.sync_rtc_time(ntp_time);
     }
 }
 
+/// Whether time is in window [begin, end), the window could cross midnight
+pub fn in_time_window(t: NaiveTime, begin: NaiveTime, end: NaiveTime) -> bool {
+    if begin <= end {
+        begin <= t && t < end
+    } else {
+        t >= begin || t < end
+    }
+}
+
+/// Scan i2c bus, returns addresses that respond to a byte read
+#[cfg(feature = "hw")]
+pub fn i2c_scan(bus: u8) -> Result<Vec<u16>> {
+    let mut i2c = I2c::with_bus(bus)?;
+    let mut addrs = Vec::new();
+    for addr in 0x03..=0x77 {
+        let mut buf = [0; 1];
+        if i2c.set_slave_address(addr).is_ok() && i2c.read(&mut buf).is_ok() {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+/// Read a raw register
+#[cfg(feature = "hw")]
+pub fn i2c_read_register(bus: u8, addr: u16, reg: u8) -> Result<u8> {
+    Ok(I2cDevice::new(bus, addr)?.smbus_read_byte(reg)?)
+}
+
+/// Write a raw register
+#[cfg(feature = "hw")]
+pub fn i2c_write_register(bus: u8, addr: u16, reg: u8, value: u8) -> Result<()> {
+    Ok(I2cDevice::new(bus, addr)?.smbus_write_byte(reg, value)?)
+}
+
 /// Get ntp datetime.
 pub async fn get_ntp_datetime() -> Result<DateTime<Utc>> {
     let sntp_client = AsyncSntpClient::new();
@@ -708,11 +1920,215 @@ pub async fn get_ntp_datetime() -> Result<DateTime<Utc>> {
 
 #[cfg(test)]
 mod tests {
-    use super::PiSugarConfig;
+    use chrono::NaiveTime;
+
+    use super::{
+        in_time_window, resolve_secret, AuthMode, BatteryChemistry, NotifierBackend, NotifierConfig, PiSugarConfig,
+        PowerProfile, REDACTED,
+    };
 
     #[test]
     fn test_config() {
         let config = PiSugarConfig::default();
         assert!(serde_json::to_string(&config).is_ok())
     }
+
+    #[test]
+    fn test_battery_chemistry() {
+        let mut config = PiSugarConfig::default();
+        for chemistry in [
+            BatteryChemistry::Lipo,
+            BatteryChemistry::Lifepo4,
+            BatteryChemistry::HighCapacity,
+        ] {
+            config.battery_chemistry = Some(chemistry);
+            let curve = config.battery_curve_or(&[]);
+            assert_eq!(curve, chemistry.curve());
+            assert!(curve.windows(2).all(|w| w[0].0 > w[1].0 && w[0].1 > w[1].1));
+        }
+        config.battery_curve = Some(vec![(4.0, 100.0), (3.0, 0.0)]);
+        assert_eq!(config.battery_curve_or(&[]).len(), 2);
+    }
+
+    #[test]
+    fn test_in_time_window() {
+        let t = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        assert!(in_time_window(t(2), t(1), t(6)));
+        assert!(!in_time_window(t(6), t(1), t(6)));
+        assert!(in_time_window(t(23), t(22), t(6)));
+        assert!(in_time_window(t(3), t(22), t(6)));
+        assert!(!in_time_window(t(12), t(22), t(6)));
+    }
+
+    #[test]
+    fn test_power_profile_matches() {
+        let mut config = PiSugarConfig::default();
+        let profile = PowerProfile {
+            auto_shutdown_level: Some(10.0),
+            long_tap_shell: Some("sudo shutdown now".to_string()),
+            ..Default::default()
+        };
+        assert!(!profile.matches(&config));
+        config.auto_shutdown_level = Some(10.0);
+        config.long_tap_enable = true;
+        config.long_tap_shell = "sudo shutdown now".to_string();
+        assert!(profile.matches(&config));
+        assert!(!PowerProfile::longevity().matches(&config));
+    }
+
+    #[test]
+    fn test_config_validate() {
+        let mut config = PiSugarConfig::default();
+        assert!(config.validate().is_empty());
+        config.auto_shutdown_level = Some(120.0);
+        config.auth_user = Some("admin".to_string());
+        assert_eq!(config.validate().len(), 2);
+
+        let config: PiSugarConfig = serde_json::from_str(
+            r#"{"auth_mode": "proxy", "auth_proxy_header": "X-Forwarded User", "auth_proxy_addrs": ["10.0.0.1", "proxy"]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.auth_mode, Some(AuthMode::Proxy));
+        assert_eq!(config.validate().len(), 2);
+        let config = PiSugarConfig {
+            cors_origins: Some(vec!["*".to_string()]),
+            ..config
+        };
+        assert_eq!(config.validate().len(), 3);
+
+        let config: PiSugarConfig = serde_json::from_str(r#"{"modbus": {}}"#).unwrap();
+        let modbus = config.modbus.unwrap();
+        assert_eq!(modbus.listen, "127.0.0.1:502");
+        assert!(modbus.read_only);
+
+        let config: PiSugarConfig = serde_json::from_str(
+            r#"{"devices": [{"id": "ups-2", "model": "PiSugar 3"}, {"id": "../../tmp/x", "model": "PiSugar 3"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.validate().len(), 1);
+    }
+
+    #[cfg(feature = "hw")]
+    #[test]
+    fn test_clear_thermal_pause() {
+        use super::{set_simulation, Model, PiSugarCore};
+
+        set_simulation(true);
+        let config = PiSugarConfig {
+            thermal_protect: Some(45.0),
+            ..Default::default()
+        };
+        // paused by a previous daemon
+        let mut core = PiSugarCore::new_without_init(config, Model::PiSugar_3).unwrap();
+        core.toggle_allow_charging(false).unwrap();
+        core.clear_thermal_pause().unwrap();
+        assert!(core.allow_charging().unwrap());
+
+        core.config.thermal_protect = None;
+        core.toggle_allow_charging(false).unwrap();
+        core.clear_thermal_pause().unwrap();
+        assert!(!core.allow_charging().unwrap());
+    }
+
+    #[cfg(feature = "hw")]
+    #[test]
+    fn test_longevity_mode() {
+        use super::{set_simulation, Model, PiSugarCore, LONGEVITY_PROFILE};
+
+        set_simulation(true);
+        let config = PiSugarConfig {
+            auto_charging_range: Some((60.0, 90.0)),
+            full_charge_duration: Some(600),
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("pisugar-longevity-{}.json", std::process::id()));
+        let mut core = PiSugarCore::new_without_init(config, Model::PiSugar_3).unwrap();
+        core.config_path = Some(path.to_string_lossy().to_string());
+
+        // own settings are kept if longevity mode isn't active
+        core.set_longevity_mode(false).unwrap();
+        assert_eq!(core.config().auto_charging_range, Some((60.0, 90.0)));
+        assert_eq!(core.config().full_charge_duration, Some(600));
+
+        core.set_longevity_mode(true).unwrap();
+        assert_eq!(core.power_profile(), LONGEVITY_PROFILE);
+        core.set_longevity_mode(false).unwrap();
+        assert_eq!(core.config().auto_charging_range, Some((60.0, 90.0)));
+        assert_eq!(core.config().full_charge_duration, Some(600));
+        assert_eq!(core.config().bat_protect, None);
+        assert_eq!(core.config().profile_backup, None);
+        assert_eq!(core.power_profile(), "custom");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_resolve_secret() {
+        std::env::set_var("PISUGAR_TEST_SECRET", "s3cret");
+        assert_eq!(resolve_secret("${PISUGAR_TEST_SECRET}"), "s3cret");
+        assert_eq!(
+            resolve_secret("a${PISUGAR_TEST_SECRET}b${PISUGAR_TEST_UNSET}c"),
+            "as3cretbc"
+        );
+        assert_eq!(resolve_secret("plain ${unterminated"), "plain ${unterminated");
+
+        let config = PiSugarConfig {
+            auth_user: Some("admin".to_string()),
+            auth_password: Some("${PISUGAR_TEST_SECRET}".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(config.resolved_auth_password().unwrap().as_deref(), Some("s3cret"));
+        let config = PiSugarConfig {
+            auth_password_file: Some("/nonexistent/auth_password".to_string()),
+            ..config
+        };
+        assert!(config.resolved_auth_password().is_err());
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_config_redacted() {
+        let mut config = PiSugarConfig {
+            auth_user: Some("admin".to_string()),
+            auth_password: Some("secret".to_string()),
+            notifiers: Some(vec![NotifierConfig {
+                backend: NotifierBackend::Telegram {
+                    token: "bot-token".to_string(),
+                    chat_id: "42".to_string(),
+                },
+                template: None,
+            }]),
+            ..Default::default()
+        };
+        assert!(config.has_secrets());
+        assert!(!PiSugarConfig::default().has_secrets());
+        let redacted = config.redacted();
+        assert_eq!(redacted.auth_user.as_deref(), Some("admin"));
+        assert_eq!(redacted.auth_password.as_deref(), Some(REDACTED));
+        assert!(!serde_json::to_string(&redacted).unwrap().contains("bot-token"));
+
+        let mut pushed = redacted.clone();
+        pushed.auth_password = Some("new".to_string());
+        pushed.unredact(&config);
+        assert_eq!(pushed.auth_password.as_deref(), Some("new"));
+        assert_eq!(pushed.notifiers, config.notifiers);
+
+        // a retyped notifier doesn't get the token of another backend
+        let mut pushed = redacted.clone();
+        pushed.notifiers = Some(vec![NotifierConfig {
+            backend: NotifierBackend::Gotify {
+                url: "https://gotify.example.com".to_string(),
+                token: REDACTED.to_string(),
+            },
+            template: None,
+        }]);
+        pushed.unredact(&config);
+        assert!(!serde_json::to_string(&pushed).unwrap().contains("bot-token"));
+
+        config.notifiers = None;
+        config.auth_password = Some("${PISUGAR_AUTH_PASSWORD}".to_string());
+        assert!(!config.has_secrets());
+        let mut pushed = redacted.clone();
+        pushed.unredact(&config);
+        assert_eq!(pushed.notifiers, redacted.notifiers);
+    }
 }
@@ -4,12 +4,19 @@ use std::str::FromStr;
 use clap::builder::PossibleValue;
 use clap::ValueEnum;
 
-use crate::ip5312::IP5312Battery;
-use crate::pisugar3::{PiSugar3Battery, PiSugar3RTC, I2C_ADDR_P3};
-use crate::rtc::RTC;
-use crate::{battery::Battery, I2C_ADDR_BAT};
-use crate::{config::PiSugarConfig, ip5209::IP5209Battery};
-use crate::{Result, I2C_ADDR_RTC, SD3078};
+#[cfg(feature = "hw")]
+use crate::{
+    battery::Battery,
+    config::PiSugarConfig,
+    ip5209::{IP5209Battery, IP5209},
+    ip5312::{IP5312Battery, IP5312},
+    kernel_rtc::KernelRTC,
+    pisugar3::{PiSugar3, PiSugar3Battery, PiSugar3RTC},
+    rtc::RTC,
+    simulation::{simulation, SimulatedBattery, SimulatedRTC},
+    Error, Result, SD3078,
+};
+use crate::{I2C_ADDR_BAT, I2C_ADDR_P3, I2C_ADDR_RTC};
 
 const PISUGAR_2_4LEDS: &str = "PiSugar 2 (4-LEDs)";
 const PISUGAR_2_2LEDS: &str = "PiSugar 2 (2-LEDs)";
@@ -50,7 +57,38 @@ impl Model {
         }
     }
 
+    #[cfg(feature = "hw")]
+    /// Detect model on i2c bus, probe PiSugar 3 (0x57) and IP5209/IP5312 (0x75).
+    /// PiSugar 2 (2-LEDs) could not be told apart from PiSugar 2 (4-LEDs), the latter is returned.
+    pub fn detect(i2c_bus: u8) -> Result<Model> {
+        if simulation() {
+            return Ok(Model::PiSugar_3);
+        }
+        if let Ok(version) = PiSugar3::new(i2c_bus, I2C_ADDR_P3).and_then(|p| p.read_app_version()) {
+            log::debug!("PiSugar 3 firmware version: {}", version);
+            if !version.is_empty() && version.chars().all(|c| c.is_ascii_graphic()) {
+                return Ok(Model::PiSugar_3);
+            }
+        }
+
+        // battery voltage registers differ between IP5209 and IP5312
+        let plausible = |v: &Result<f64>| matches!(v, Ok(v) if (2.5..=4.5).contains(v));
+        let ip5312_voltage = IP5312::new(i2c_bus, I2C_ADDR_BAT).and_then(|c| c.read_voltage());
+        let ip5209_voltage = IP5209::new(i2c_bus, I2C_ADDR_BAT).and_then(|c| c.read_voltage());
+        log::debug!("IP5312 voltage: {:?}, IP5209 voltage: {:?}", ip5312_voltage, ip5209_voltage);
+        match (plausible(&ip5312_voltage), plausible(&ip5209_voltage)) {
+            (true, false) => Ok(Model::PiSugar_2_Pro),
+            (_, true) => Ok(Model::PiSugar_2_4LEDs),
+            _ if ip5209_voltage.is_ok() => Ok(Model::PiSugar_2_4LEDs),
+            _ => Err(Error::Other(format!("No PiSugar detected on i2c bus {}", i2c_bus))),
+        }
+    }
+
+    #[cfg(feature = "hw")]
     pub fn bind(&self, cfg: PiSugarConfig) -> Result<Box<dyn Battery + Send>> {
+        if simulation() {
+            return Ok(Box::new(SimulatedBattery::new(*self)));
+        }
         log::info!(
             "Binding battery i2c bus={} addr={}",
             cfg.i2c_bus,
@@ -65,12 +103,20 @@ impl Model {
         Ok(b)
     }
 
+    #[cfg(feature = "hw")]
     pub fn rtc(&self, cfg: PiSugarConfig) -> Result<Box<dyn RTC + Send>> {
+        if simulation() {
+            return Ok(Box::new(SimulatedRTC::new()));
+        }
         log::info!(
             "Bindig rtc i2c bus={} addr={}",
             cfg.i2c_bus,
-            self.default_rtc_i2c_addr()
+            cfg.rtc_i2c_addr.unwrap_or(self.default_rtc_i2c_addr())
         );
+        if let Some(name) = &cfg.rtc_kernel {
+            log::info!("Rtc is handed over to kernel driver: {}", name);
+            return Ok(Box::new(KernelRTC::new(name)?));
+        }
         let r: Box<dyn RTC + Send> = match *self {
             Model::PiSugar_3 => Box::new(PiSugar3RTC::new(cfg, *self)?),
             _ => Box::new(SD3078::new(cfg, *self)?),
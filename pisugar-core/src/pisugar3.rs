@@ -4,18 +4,15 @@ use std::collections::VecDeque;
 use std::ffi::CStr;
 use std::time::Instant;
 
-use rppal::i2c::I2c;
-
+use crate::i2c::I2cDevice;
 use crate::ip5312::IP5312;
-use crate::rtc::{bcd_to_dec, dec_to_bcd, RTC};
+use crate::rtc::{bcd_to_dec, dec_to_bcd, RtcCapabilities, RTC};
+use crate::sample::Timestamped;
 use crate::{
-    battery::{Battery, BatteryEvent},
+    battery::{Battery, BatteryCapabilities, BatteryEvent},
     ip5312::BATTERY_CURVE,
 };
-use crate::{Error, Model, PiSugarConfig, RTCRawTime, Result, TapType};
-
-/// PiSugar 3 i2c addr
-pub const I2C_ADDR_P3: u16 = 0x57;
+use crate::{Error, LedMode, LevelSource, Model, PiSugarConfig, RTCRawTime, Result, TapType};
 
 /// Global ctrl 1
 const IIC_CMD_CTR1: u8 = 0x02;
@@ -47,6 +44,9 @@ const IIC_CMD_OL: u8 = 0x27;
 
 const IIC_CMD_P: u8 = 0x2A;
 
+/// LED ctrl, newer firmware, 0 off, 1 low brightness, 2 normal
+const IIC_CMD_LED_CTR: u8 = 0x0C;
+
 /// RTC Ctrl
 const IIC_CMD_RTC_CTRL: u8 = 0x30;
 /// RTC year
@@ -85,14 +85,13 @@ const APP_VER_LEN: usize = 15;
 
 /// PiSugar 3
 pub struct PiSugar3 {
-    i2c: I2c,
+    i2c: I2cDevice,
 }
 
 impl PiSugar3 {
     pub fn new(i2c_bus: u8, i2c_addr: u16) -> Result<Self> {
         log::debug!("PiSugar3 bus 0x{:02x} addr 0x{:02x}", i2c_bus, i2c_addr);
-        let mut i2c = I2c::with_bus(i2c_bus)?;
-        i2c.set_slave_address(i2c_addr)?;
+        let i2c = I2cDevice::new(i2c_bus, i2c_addr)?;
         Ok(Self { i2c })
     }
 
@@ -265,6 +264,14 @@ impl PiSugar3 {
         Ok(oc)
     }
 
+    pub fn read_led_ctrl(&self) -> Result<u8> {
+        self.i2c_read_byte(IIC_CMD_LED_CTR)
+    }
+
+    pub fn write_led_ctrl(&self, ctrl: u8) -> Result<()> {
+        self.i2c_write_byte(IIC_CMD_LED_CTR, ctrl)
+    }
+
     pub fn get_alarm_enable(&self) -> Result<bool> {
         let ctr = self.i2c_read_byte(IIC_CMD_ALM_CTR)?;
         Ok(ctr & (0b1000_0000) != 0)
@@ -407,11 +414,12 @@ impl PiSugar3 {
 pub struct PiSugar3Battery {
     pisugar3: PiSugar3,
     model: Model,
-    voltages: VecDeque<(Instant, f32)>,
-    intensities: VecDeque<(Instant, f32)>,
+    voltages: VecDeque<Timestamped<f32>>,
+    intensities: VecDeque<Timestamped<f32>>,
     levels: VecDeque<f32>,
     poll_at: Instant,
     version: String,
+    led_ctrl: bool,
     cfg: PiSugarConfig,
 }
 
@@ -427,12 +435,27 @@ impl PiSugar3Battery {
             levels: VecDeque::with_capacity(30),
             poll_at,
             version: "".to_string(),
+            led_ctrl: false,
             cfg,
         })
     }
 }
 
 impl Battery for PiSugar3Battery {
+    fn capabilities(&self) -> BatteryCapabilities {
+        BatteryCapabilities {
+            input_protect: true,
+            output_toggle: true,
+            power_restore: true,
+            anti_mistouch: true,
+            soft_poweroff: true,
+            temperature: true,
+            led_mode: self.led_ctrl,
+            keep_input: true,
+            ..Default::default()
+        }
+    }
+
     fn init(&mut self, config: &PiSugarConfig) -> crate::Result<()> {
         log::debug!("Toggle soft poweroff");
         self.pisugar3.toggle_soft_poweroff(config.soft_poweroff == Some(true))?;
@@ -450,6 +473,15 @@ impl Battery for PiSugar3Battery {
             self.toggle_input_protected(protect)?;
         }
 
+        // led ctrl of older firmware reads out of range
+        self.led_ctrl = matches!(self.pisugar3.read_led_ctrl(), Ok(0..=2));
+        log::debug!("Led ctrl: {}", self.led_ctrl);
+        if let Some(mode) = config.led_mode {
+            if let Err(e) = self.set_led_mode(mode) {
+                log::warn!("Set led mode error: {}", e);
+            }
+        }
+
         self.version = self.pisugar3.read_app_version()?;
 
         Ok(())
@@ -474,7 +506,7 @@ impl Battery for PiSugar3Battery {
 
     fn voltage_avg(&self) -> crate::Result<f32> {
         let mut total = 0.0;
-        self.voltages.iter().for_each(|v| total += v.1);
+        self.voltages.iter().for_each(|v| total += v.value);
         if !self.voltages.is_empty() {
             Ok(total / self.voltages.len() as f32)
         } else {
@@ -483,15 +515,55 @@ impl Battery for PiSugar3Battery {
     }
 
     fn level(&self) -> crate::Result<f32> {
-        let curve = self
-            .cfg
-            .battery_curve
-            .as_ref()
-            .map(|x| &x[..])
-            .unwrap_or(BATTERY_CURVE.as_ref());
+        let source = self.cfg.level_source.unwrap_or_default();
+        if source != LevelSource::Curve {
+            match self.chip_level() {
+                Ok(level) => return Ok(level),
+                Err(e) if source == LevelSource::Chip => return Err(e),
+                Err(e) => log::debug!("Chip level unavailable, fall back to voltage curve: {}", e),
+            }
+        }
+        let curve = self.cfg.battery_curve_or(&BATTERY_CURVE);
         self.voltage_avg().map(|v| IP5312::parse_voltage_level(v, curve))
     }
 
+    fn led_mode(&self) -> crate::Result<LedMode> {
+        if !self.led_ctrl {
+            return Err(Error::Other("Not supported".to_string()));
+        }
+        match self.pisugar3.read_led_ctrl()? {
+            0 => Ok(LedMode::Off),
+            1 => Ok(LedMode::Low),
+            2 => Ok(LedMode::Normal),
+            _ => Err(Error::Other("Not supported".to_string())),
+        }
+    }
+
+    fn set_led_mode(&self, mode: LedMode) -> crate::Result<()> {
+        if !self.led_ctrl {
+            return Err(Error::Other("Not supported".to_string()));
+        }
+        let ctrl = match mode {
+            LedMode::Off => 0,
+            LedMode::Low => 1,
+            LedMode::Normal => 2,
+        };
+        self.pisugar3.write_led_ctrl(ctrl)?;
+        // firmware without led ctrl ignores the write
+        if self.pisugar3.read_led_ctrl()? != ctrl {
+            return Err(Error::Other("Not supported".to_string()));
+        }
+        Ok(())
+    }
+
+    fn chip_level(&self) -> crate::Result<f32> {
+        // older firmware reads 0 or 255
+        match self.pisugar3.read_percent()? {
+            p @ 1..=100 => Ok(p as f32),
+            p => Err(Error::Other(format!("Invalid chip level: {}", p))),
+        }
+    }
+
     fn intensity(&self) -> crate::Result<f32> {
         let c = self.pisugar3.read_output_current()?;
         Ok((c as f32) / 1000.0)
@@ -499,7 +571,7 @@ impl Battery for PiSugar3Battery {
 
     fn intensity_avg(&self) -> crate::Result<f32> {
         let mut total = 0.0;
-        self.intensities.iter().for_each(|i| total += i.1);
+        self.intensities.iter().for_each(|i| total += i.value);
         if !self.intensities.is_empty() {
             Ok(total / self.intensities.len() as f32)
         } else {
@@ -507,6 +579,14 @@ impl Battery for PiSugar3Battery {
         }
     }
 
+    fn voltage_history(&self) -> Vec<Timestamped<f32>> {
+        self.voltages.iter().copied().collect()
+    }
+
+    fn intensity_history(&self) -> Vec<Timestamped<f32>> {
+        self.intensities.iter().copied().collect()
+    }
+
     fn is_power_plugged(&self) -> crate::Result<bool> {
         let ctr1 = self.pisugar3.read_ctr1()?;
         Ok((ctr1 & (1 << 7)) != 0)
@@ -562,7 +642,7 @@ impl Battery for PiSugar3Battery {
         let voltage = self.voltage()?;
         self.voltages.pop_front();
         while self.voltages.len() < self.voltages.capacity() {
-            self.voltages.push_back((now, voltage));
+            self.voltages.push_back(Timestamped::new(now, voltage));
         }
 
         let level = self.level()?;
@@ -574,7 +654,7 @@ impl Battery for PiSugar3Battery {
         let intensity = self.intensity()?;
         self.intensities.pop_front();
         while self.intensities.len() < self.intensities.capacity() {
-            self.intensities.push_back((now, intensity));
+            self.intensities.push_back(Timestamped::new(now, intensity));
         }
 
         let tap = match self.pisugar3.read_tap()? {
@@ -648,12 +728,21 @@ pub struct PiSugar3RTC {
 
 impl PiSugar3RTC {
     pub fn new(cfg: PiSugarConfig, model: Model) -> Result<Self> {
-        let pisugar3 = PiSugar3::new(cfg.i2c_bus, model.default_rtc_i2c_addr())?;
+        let pisugar3 = PiSugar3::new(cfg.i2c_bus, cfg.rtc_i2c_addr.unwrap_or(model.default_rtc_i2c_addr()))?;
         Ok(Self { pisugar3, cfg })
     }
 }
 
 impl RTC for PiSugar3RTC {
+    fn capabilities(&self) -> RtcCapabilities {
+        RtcCapabilities {
+            adjust_ppm: true,
+            // i2c address register 0x50, see doc/pisugar3.md
+            addr_change: true,
+            ..Default::default()
+        }
+    }
+
     fn init(&mut self, config: &PiSugarConfig) -> Result<()> {
         self.pisugar3.toggle_restore(config.auto_power_on == Some(true))?;
         self.pisugar3.toggle_alarm_enable(false)?;
@@ -746,16 +835,16 @@ impl RTC for PiSugar3RTC {
 
     fn read_alarm_flag(&self) -> Result<bool> {
         // PiSugar 3 has no alarm flag
-        Ok(false)
+        Err(Error::Other("Not supported".to_string()))
     }
 
     fn clear_alarm_flag(&self) -> Result<()> {
-        Ok(())
+        Err(Error::Other("Not supported".to_string()))
     }
 
     fn toggle_frequency_alarm(&self, _enable: bool) -> Result<()> {
         // PiSugar 3 has auto power restore, so frequency alarm is deprecated
-        Ok(())
+        Err(Error::Other("Not supported".to_string()))
     }
 
     fn force_shutdown(&self) -> Result<()> {
@@ -763,14 +852,23 @@ impl RTC for PiSugar3RTC {
     }
 
     fn read_battery_low_flag(&self) -> Result<bool> {
-        Ok(false)
+        Err(Error::Other("Not supported".to_string()))
     }
 
     fn toggle_charging(&self, _enable: bool) -> Result<()> {
-        Ok(())
+        Err(Error::Other("Not supported".to_string()))
     }
 
     fn read_battery_high_flag(&self) -> Result<bool> {
-        Ok(true)
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn read_user_data(&self) -> Result<Vec<u8>> {
+        // PiSugar 3 has no battery backed user ram
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn write_user_data(&self, _data: &[u8]) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
     }
 }
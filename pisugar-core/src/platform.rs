@@ -0,0 +1,84 @@
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+
+use chrono::{DateTime, Utc};
+
+/// Directories searched after PATH, sbin is missing from PATH of some service managers and distros
+const TOOL_DIRS: &[&str] = &[
+    "/usr/local/sbin",
+    "/usr/local/bin",
+    "/usr/sbin",
+    "/usr/bin",
+    "/sbin",
+    "/bin",
+];
+
+/// Path of a tool, e.g. `hwclock`, looked up in PATH and the usual bin directories, a path is taken as is
+pub fn find_tool(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name)).filter(|p| p.is_file());
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .chain(TOOL_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|p| p.is_file())
+}
+
+fn tool_command(name: &str) -> io::Result<Command> {
+    let path = find_tool(name).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", name)))?;
+    Ok(Command::new(path))
+}
+
+/// Run a tool until it exits, NotFound if it's missing
+pub fn run_tool(name: &str, args: &[&str]) -> io::Result<ExitStatus> {
+    log::debug!("Run {} {:?}", name, args);
+    tool_command(name)?.args(args).status()
+}
+
+/// Run a tool with input on stdin, until it exits
+pub fn run_tool_with_input(name: &str, args: &[&str], input: &str) -> io::Result<ExitStatus> {
+    let mut child = tool_command(name)?.args(args).stdin(Stdio::piped()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    child.wait()
+}
+
+/// Whether a tool is found
+pub fn tool_exists(name: &str) -> bool {
+    find_tool(name).is_some()
+}
+
+/// Set the system clock with clock_settime(2), CAP_SYS_TIME is required
+pub fn set_realtime(dt: DateTime<Utc>) -> io::Result<()> {
+    // zeroed, timespec has padding on some 32-bit musl targets
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    ts.tv_sec = dt.timestamp() as libc::time_t;
+    ts.tv_nsec = dt.timestamp_subsec_nanos() as _;
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &ts) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_tool() {
+        assert!(tool_exists("sh"));
+        assert!(tool_exists("/bin/sh"));
+        assert!(!tool_exists("no-such-tool"));
+        assert!(!tool_exists("/no/such/tool"));
+        assert!(run_tool("sh", &["-c", "exit 0"]).unwrap().success());
+        assert_eq!(
+            run_tool("no-such-tool", &[]).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(run_tool_with_input("sh", &[], "exit 0").unwrap().success());
+    }
+}
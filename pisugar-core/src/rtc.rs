@@ -4,7 +4,7 @@ use std::fmt::{self, Display};
 use chrono::prelude::*;
 use chrono::{DateTime, Local, LocalResult, Utc};
 
-use crate::{PiSugarConfig, Result};
+use crate::{Error, PiSugarConfig, Result, RtcIntOutput};
 
 pub fn bcd_to_dec(bcd: u8) -> u8 {
     (bcd & 0x0F) + (((bcd & 0xF0) >> 4) * 10)
@@ -161,8 +161,55 @@ impl TryFrom<RTCRawTime> for DateTime<Local> {
     }
 }
 
+/// RTC capabilities
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RtcCapabilities {
+    /// Alarm flag, read and clear
+    pub alarm_flag: bool,
+    /// Frequency alarm, auto power on of PiSugar 2
+    pub frequency_alarm: bool,
+    /// Ppm adjust
+    pub adjust_ppm: bool,
+    /// I2c address change
+    pub addr_change: bool,
+    /// Rtc battery low/high flags and charging
+    pub battery_flags: bool,
+    /// Rtc battery voltage
+    pub battery_voltage: bool,
+    /// Chip temperature
+    pub temperature: bool,
+    /// User data in battery backed ram
+    pub user_data: bool,
+    /// INT pin output configuration
+    pub int_output: bool,
+}
+
+impl RtcCapabilities {
+    /// Names of supported capabilities
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            ("alarm_flag", self.alarm_flag),
+            ("frequency_alarm", self.frequency_alarm),
+            ("adjust_ppm", self.adjust_ppm),
+            ("addr_change", self.addr_change),
+            ("battery_flags", self.battery_flags),
+            ("battery_voltage", self.battery_voltage),
+            ("temperature", self.temperature),
+            ("user_data", self.user_data),
+            ("int_output", self.int_output),
+        ]
+        .iter()
+        .filter(|(_, supported)| *supported)
+        .map(|(name, _)| *name)
+        .collect()
+    }
+}
+
 /// RTC trait
 pub trait RTC {
+    /// Supported features
+    fn capabilities(&self) -> RtcCapabilities;
+
     /// Init
     fn init(&mut self, config: &PiSugarConfig) -> Result<()>;
 
@@ -196,18 +243,15 @@ pub trait RTC {
     /// Toggle frequency alarm (to prevent falling asleep)
     fn toggle_frequency_alarm(&self, enable: bool) -> Result<()>;
 
-    /// Set a test wake up after 1 minutes
-    fn set_test_wake(&self) -> Result<()> {
-        let now = Utc::now();
-        self.write_time(now.into())?;
-
-        let duration = chrono::Duration::seconds(90);
-        let then = now + duration;
+    /// Set a test wake up after 1min 30sec of rtc time, rtc time is kept, returns the wake up time
+    fn set_test_wake(&self) -> Result<DateTime<Utc>> {
+        let now: DateTime<Utc> = self.read_time()?.try_into().map_err(Error::Other)?;
+        let then = now + chrono::Duration::seconds(90);
         self.set_alarm(then.into(), 0b0111_1111)?;
 
-        log::error!("Will wake up after 1min 30sec, please power-off");
+        log::error!("Will wake up at {}, please power-off", then);
 
-        Ok(())
+        Ok(then)
     }
 
     /// Shutdown
@@ -221,4 +265,29 @@ pub trait RTC {
 
     /// Is battery full
     fn read_battery_high_flag(&self) -> Result<bool>;
+
+    /// Chip temperature (°C)
+    fn read_temperature(&self) -> Result<f32> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    /// Rtc battery voltage (V)
+    fn read_rtc_battery_voltage(&self) -> Result<f32> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    /// Configure INT pin output
+    fn set_int_output(&self, _output: RtcIntOutput) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    /// Read user data in battery backed ram
+    fn read_user_data(&self) -> Result<Vec<u8>> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    /// Write user data in battery backed ram, from the beginning
+    fn write_user_data(&self, _data: &[u8]) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
 }
@@ -0,0 +1,42 @@
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Local};
+use serde::Serialize;
+
+/// Sample with both monotonic and wall clock time, the instant is for intervals, the time for export
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Timestamped<T> {
+    #[serde(skip)]
+    pub instant: Instant,
+    pub time: DateTime<Local>,
+    pub value: T,
+}
+
+impl<T> Timestamped<T> {
+    /// Sample taken at instant, wall clock time is derived from now
+    pub fn new(instant: Instant, value: T) -> Self {
+        let now = Instant::now();
+        let time = if instant <= now {
+            Local::now() - Duration::from_std(now - instant).unwrap_or_else(|_| Duration::zero())
+        } else {
+            Local::now() + Duration::from_std(instant - now).unwrap_or_else(|_| Duration::zero())
+        };
+        Self { instant, time, value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamped() {
+        let instant = Instant::now() - std::time::Duration::from_secs(1);
+        let sample = Timestamped::new(instant, 3.7);
+        let age = Local::now() - sample.time;
+        assert!(age >= Duration::milliseconds(900) && age <= Duration::milliseconds(1100));
+        let json = serde_json::to_value(sample).unwrap();
+        assert_eq!(json["value"], 3.7);
+        assert!(json.get("instant").is_none());
+    }
+}
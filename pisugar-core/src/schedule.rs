@@ -0,0 +1,267 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Local, NaiveDate, NaiveTime, TimeZone, Timelike};
+
+use crate::Error;
+
+/// Range of cron fields: minute, hour, day of month, month, day of week (0 or 7 is sunday)
+const CRON_RANGES: [(u32, u32); 5] = [(0, 59), (0, 23), (1, 31), (1, 12), (0, 7)];
+
+/// Max days to search the next occurrence of a cron expression, 8 years for a Feb 29 across a skipped leap year
+const CRON_SEARCH_DAYS: u32 = 8 * 366;
+
+/// Schedule of a datetime, or a 5 field cron expression of local time, e.g. `0 3 * * *`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    At(DateTime<FixedOffset>),
+    Cron {
+        expr: String,
+        /// Bitmask of each field
+        masks: [u64; 5],
+    },
+}
+
+impl Schedule {
+    /// The first occurrence after a time
+    pub fn next_after(&self, after: &DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::At(t) => Some(t.with_timezone(&Local)).filter(|t| t > after),
+            Schedule::Cron { masks, .. } => {
+                let start = (after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1)).naive_local();
+                let mut date = start.date();
+                for _ in 0..CRON_SEARCH_DAYS {
+                    // skip the rest of a month that doesn't match
+                    if !is_set(masks, 3, date.month()) {
+                        date = first_of_next_month(date)?;
+                        continue;
+                    }
+                    if day_matches(masks, &date) {
+                        let found = field_values(masks, 1)
+                            .flat_map(|h| field_values(masks, 0).filter_map(move |m| date.and_hms_opt(h, m, 0)))
+                            .filter(|t| *t >= start)
+                            // a time in a DST gap doesn't exist
+                            .find_map(|t| Local.from_local_datetime(&t).earliest())
+                            .filter(|t| t > after);
+                        if found.is_some() {
+                            return found;
+                        }
+                    }
+                    date = date.succ_opt()?;
+                }
+                None
+            }
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Schedule::At(t));
+        }
+        let invalid = || Error::Other(format!("Invalid schedule: {:?}", s));
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != CRON_RANGES.len() {
+            return Err(invalid());
+        }
+        let mut masks = [0; 5];
+        for (i, field) in fields.iter().enumerate() {
+            let (min, max) = CRON_RANGES[i];
+            masks[i] = parse_cron_field(field, min, max).ok_or_else(invalid)?;
+        }
+        // sunday is both 0 and 7
+        if masks[4] & (1 << 7) != 0 {
+            masks[4] |= 1;
+        }
+        if !satisfiable(&masks) {
+            return Err(Error::Other(format!("Schedule never occurs: {:?}", s)));
+        }
+        Ok(Schedule::Cron {
+            expr: fields.join(" "),
+            masks,
+        })
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::At(t) => write!(f, "{}", t.to_rfc3339()),
+            Schedule::Cron { expr, .. } => write!(f, "{}", expr),
+        }
+    }
+}
+
+/// Parse a cron field of `*`, `n`, `a-b`, with `/step`, separated by `,`
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0)?),
+            None => (part, 1),
+        };
+        let (begin, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (a.parse().ok()?, b.parse().ok()?),
+                None if part.contains('/') => (range.parse().ok()?, max),
+                None => (range.parse().ok()?, range.parse().ok()?),
+            },
+        };
+        if begin < min || end > max || begin > end {
+            return None;
+        }
+        for v in (begin..=end).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+    Some(mask)
+}
+
+/// Whether a value of a cron field is set
+fn is_set(masks: &[u64; 5], i: usize, v: u32) -> bool {
+    masks[i] & (1 << v) != 0
+}
+
+/// Whether a cron field is `*`
+fn is_all(masks: &[u64; 5], i: usize) -> bool {
+    let (min, max) = CRON_RANGES[i];
+    (min..=max).all(|v| is_set(masks, i, v))
+}
+
+/// Values of a cron field, ascending
+fn field_values(masks: &[u64; 5], i: usize) -> impl Iterator<Item = u32> + '_ {
+    let (min, max) = CRON_RANGES[i];
+    (min..=max).filter(move |v| is_set(masks, i, *v))
+}
+
+/// Whether a date matches, days match either day of month or day of week if both are restricted
+fn day_matches(masks: &[u64; 5], date: &NaiveDate) -> bool {
+    let dom = is_set(masks, 2, date.day());
+    let dow = is_set(masks, 4, date.weekday().num_days_from_sunday());
+    match (is_all(masks, 2), is_all(masks, 4)) {
+        (false, false) => dom || dow,
+        _ => dom && dow,
+    }
+}
+
+/// Whether a cron expression ever occurs, e.g. `0 0 31 2 *` never does
+fn satisfiable(masks: &[u64; 5]) -> bool {
+    // a restricted day of week matches some day of every month
+    if !is_all(masks, 4) {
+        return true;
+    }
+    // 2000 is a leap year, so Feb 29 counts
+    field_values(masks, 3).any(|m| field_values(masks, 2).any(|d| NaiveDate::from_ymd_opt(2000, m, d).is_some()))
+}
+
+/// The first day of the next month
+fn first_of_next_month(date: NaiveDate) -> Option<NaiveDate> {
+    match date.month() {
+        12 => NaiveDate::from_ymd_opt(date.year() + 1, 1, 1),
+        m => NaiveDate::from_ymd_opt(date.year(), m + 1, 1),
+    }
+}
+
+/// The latest alarm occurrence in (after, now], with time of day and weekday repeat
+pub fn last_alarm_occurrence<Tz: TimeZone>(
+    after: &DateTime<Tz>,
+    now: &DateTime<Tz>,
+    time: NaiveTime,
+    weekday_repeat: u8,
+) -> Option<DateTime<Tz>> {
+    let tz = now.timezone();
+    (0..=7)
+        .filter_map(|d| {
+            let t = now.date_naive().and_time(time) - Duration::days(d);
+            tz.from_local_datetime(&t).earliest()
+        })
+        .filter(|t| t <= now && t > after)
+        .find(|t| weekday_repeat & (1 << t.weekday().num_days_from_sunday()) != 0)
+}
+
+/// The first alarm occurrence after a time, with time of day and weekday repeat
+pub fn next_alarm_occurrence<Tz: TimeZone>(
+    after: &DateTime<Tz>,
+    time: NaiveTime,
+    weekday_repeat: u8,
+) -> Option<DateTime<Tz>> {
+    let tz = after.timezone();
+    (0..=7)
+        .filter_map(|d| {
+            let t = after.date_naive().and_time(time) + Duration::days(d);
+            tz.from_local_datetime(&t).earliest()
+        })
+        .filter(|t| t > after)
+        .find(|t| weekday_repeat & (1 << t.weekday().num_days_from_sunday()) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let now = Local.with_ymd_and_hms(2024, 1, 1, 12, 0, 30).unwrap(); // monday
+
+        let daily: Schedule = "0 3 * * *".parse().unwrap();
+        assert_eq!(daily.to_string(), "0 3 * * *");
+        let next = Local.with_ymd_and_hms(2024, 1, 2, 3, 0, 0).unwrap();
+        assert_eq!(daily.next_after(&now), Some(next));
+
+        let weekly: Schedule = "30 4 * * 0".parse().unwrap();
+        let next = Local.with_ymd_and_hms(2024, 1, 7, 4, 30, 0).unwrap();
+        assert_eq!(weekly.next_after(&now), Some(next));
+
+        let steps: Schedule = "*/15 12-13 * * 1-5".parse().unwrap();
+        let next = Local.with_ymd_and_hms(2024, 1, 1, 12, 15, 0).unwrap();
+        assert_eq!(steps.next_after(&now), Some(next));
+
+        let at: Schedule = "2024-01-01T13:00:00+00:00".parse().unwrap();
+        assert!(at.next_after(&now.with_year(2023).unwrap()).is_some());
+        assert!(at.next_after(&now.with_year(2025).unwrap()).is_none());
+
+        assert!("0 3 * *".parse::<Schedule>().is_err());
+        assert!("60 3 * * *".parse::<Schedule>().is_err());
+        assert!("*/0 3 * * *".parse::<Schedule>().is_err());
+        assert!("0 0 30 2 *".parse::<Schedule>().is_err());
+        assert!("0 0 31 4,6 *".parse::<Schedule>().is_err());
+
+        let leap: Schedule = "0 0 29 2 *".parse().unwrap();
+        let next = Local.with_ymd_and_hms(2028, 2, 29, 0, 0, 0).unwrap();
+        assert_eq!(leap.next_after(&now.with_month(3).unwrap()), Some(next));
+
+        let dom_or_dow: Schedule = "0 0 13 * 5".parse().unwrap();
+        let next = Local.with_ymd_and_hms(2024, 1, 5, 0, 0, 0).unwrap();
+        assert_eq!(dom_or_dow.next_after(&now), Some(next));
+
+        let new_year: Schedule = "59 23 31 12 *".parse().unwrap();
+        let next = Local.with_ymd_and_hms(2024, 12, 31, 23, 59, 0).unwrap();
+        assert_eq!(new_year.next_after(&now), Some(next));
+    }
+
+    #[test]
+    fn test_last_alarm_occurrence() {
+        // Friday
+        let now = Utc.with_ymd_and_hms(2021, 1, 1, 12, 0, 0).unwrap();
+        let t = NaiveTime::from_hms_opt(8, 30, 0).unwrap();
+        let after = now - Duration::days(3);
+        let missed = last_alarm_occurrence(&after, &now, t, 0b0111_1111);
+        assert_eq!(missed, Some(Utc.with_ymd_and_hms(2021, 1, 1, 8, 30, 0).unwrap()));
+        let missed = last_alarm_occurrence(&after, &now, t, 0b0001_0000);
+        assert_eq!(missed, Some(Utc.with_ymd_and_hms(2020, 12, 31, 8, 30, 0).unwrap()));
+        assert_eq!(last_alarm_occurrence(&after, &now, t, 0b0000_0010), None);
+        let next = next_alarm_occurrence(&now, t, 0b0111_1111);
+        assert_eq!(next, Some(Utc.with_ymd_and_hms(2021, 1, 2, 8, 30, 0).unwrap()));
+        let next = next_alarm_occurrence(&now, t, 0b0000_0010);
+        assert_eq!(next, Some(Utc.with_ymd_and_hms(2021, 1, 4, 8, 30, 0).unwrap()));
+        assert_eq!(next_alarm_occurrence(&now, t, 0), None);
+    }
+}
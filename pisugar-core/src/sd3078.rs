@@ -1,22 +1,26 @@
-use rppal::i2c::I2c;
-
+use crate::i2c::I2cDevice;
 use crate::{
-    rtc::{bcd_to_dec, dec_to_bcd, RTCRawTime, RTC},
+    rtc::{bcd_to_dec, dec_to_bcd, RTCRawTime, RtcCapabilities, RTC},
     Model,
 };
-use crate::{PiSugarConfig, Result};
+use crate::{Error, PiSugarConfig, Result, RtcIntOutput};
+
+/// SD3078 user ram, 0x2c-0x71
+const USER_RAM_ADDR: u8 = 0x2c;
+
+/// SD3078 user ram size
+pub const USER_RAM_SIZE: usize = 70;
 
 /// SD3078, rtc chip
 pub struct SD3078 {
-    i2c: I2c,
+    i2c: I2cDevice,
     cfg: PiSugarConfig,
 }
 
 impl SD3078 {
     /// Create new SD3078
     pub fn new(cfg: PiSugarConfig, model: Model) -> Result<Self> {
-        let mut i2c = I2c::with_bus(cfg.i2c_bus)?;
-        i2c.set_slave_address(model.default_rtc_i2c_addr())?;
+        let i2c = I2cDevice::new(cfg.i2c_bus, cfg.rtc_i2c_addr.unwrap_or(model.default_rtc_i2c_addr()))?;
         Ok(Self { i2c, cfg })
     }
 
@@ -67,12 +71,18 @@ impl SD3078 {
 
     /// Set frequency alarm in auto_power_on, 1/2Hz
     pub fn enable_frequency_alarm(&self) -> Result<()> {
+        // CTR3 - 1/2Hz, FS3=1, FS2=0, FS1=1, FS0=1
+        self.enable_frequency_output(0b1011)
+    }
+
+    /// Output square wave on INT, frequency select FS3-FS0
+    pub fn enable_frequency_output(&self, fs: u8) -> Result<()> {
         self.enable_write()?;
 
-        // CTR3 - 1/2Hz, FS3=1, FS2=0, FS1=1, FS0=1
+        // CTR3 - FS3-FS0
         let mut ctr3 = self.i2c.smbus_read_byte(0x11)?;
-        ctr3 &= 0b1111_1011;
-        ctr3 |= 0b0000_1011;
+        ctr3 &= 0b1111_0000;
+        ctr3 |= fs & 0b0000_1111;
         self.i2c.smbus_write_byte(0x11, ctr3)?;
 
         // CTR2 - INTS1=1, INTS0=0, INTFE=1, and disable INTAE, INTDE
@@ -86,6 +96,43 @@ impl SD3078 {
         Ok(())
     }
 
+    /// Interrupt on INT every minute, by countdown timer
+    pub fn enable_minute_countdown(&self) -> Result<()> {
+        self.enable_write()?;
+
+        // CTR3 - countdown source 1/60Hz, TDS1=1, TDS0=1
+        let mut ctr3 = self.i2c.smbus_read_byte(0x11)?;
+        ctr3 |= 0b0011_0000;
+        self.i2c.smbus_write_byte(0x11, ctr3)?;
+
+        // countdown 1
+        self.i2c.block_write(0x13, &[1, 0, 0])?;
+
+        // CTR2 - INTS1=1, INTS0=1, INTDE=1, and disable INTAE, INTFE
+        let mut ctr2 = self.i2c.smbus_read_byte(0x10)?;
+        ctr2 &= 0b1111_1100;
+        ctr2 |= 0b0011_0100;
+        self.i2c.smbus_write_byte(0x10, ctr2)?;
+
+        self.disable_write()?;
+
+        Ok(())
+    }
+
+    /// Disable countdown interrupt
+    pub fn disable_countdown(&self) -> Result<()> {
+        self.enable_write()?;
+
+        // CTR2 - INTDE=0
+        let mut ctr2 = self.i2c.smbus_read_byte(0x10)?;
+        ctr2 &= 0b1111_1011;
+        self.i2c.smbus_write_byte(0x10, ctr2)?;
+
+        self.disable_write()?;
+
+        Ok(())
+    }
+
     pub fn enable_alarm(&self) -> Result<()> {
         self.enable_write()?;
 
@@ -144,6 +191,19 @@ impl SD3078 {
 
 impl RTC for SD3078 {
     /// Init
+    fn capabilities(&self) -> RtcCapabilities {
+        RtcCapabilities {
+            alarm_flag: true,
+            frequency_alarm: true,
+            battery_flags: true,
+            battery_voltage: true,
+            temperature: true,
+            user_data: true,
+            int_output: true,
+            ..Default::default()
+        }
+    }
+
     fn init(&mut self, config: &PiSugarConfig) -> Result<()> {
         self.clear_alarm_flag()?;
 
@@ -161,6 +221,10 @@ impl RTC for SD3078 {
             }
         }
 
+        if let Some(output) = config.rtc_int_output {
+            self.set_int_output(output)?;
+        }
+
         Ok(())
     }
 
@@ -307,4 +371,59 @@ impl RTC for SD3078 {
         let v = self.i2c.smbus_read_byte(0x1a)?;
         Ok(v & 0b0000_0010 != 0)
     }
+
+    /// Read temperature, signed
+    fn read_temperature(&self) -> Result<f32> {
+        let v = self.i2c.smbus_read_byte(0x16)?;
+        Ok(v as i8 as f32)
+    }
+
+    fn set_int_output(&self, output: RtcIntOutput) -> Result<()> {
+        self.disable_countdown()?;
+        self.disable_frequency_alarm()?;
+        self.disable_alarm()?;
+        let fs = match output {
+            RtcIntOutput::Off => return Ok(()),
+            RtcIntOutput::PerMinute => return self.enable_minute_countdown(),
+            RtcIntOutput::Hz4096 => 0b0010,
+            RtcIntOutput::Hz1024 => 0b0011,
+            RtcIntOutput::Hz64 => 0b0100,
+            RtcIntOutput::Hz32 => 0b0101,
+            RtcIntOutput::Hz16 => 0b0110,
+            RtcIntOutput::Hz8 => 0b0111,
+            RtcIntOutput::Hz4 => 0b1000,
+            RtcIntOutput::Hz2 => 0b1001,
+            RtcIntOutput::Hz1 => 0b1010,
+            RtcIntOutput::HzHalf => 0b1011,
+            RtcIntOutput::HzQuarter => 0b1100,
+            RtcIntOutput::HzEighth => 0b1101,
+            RtcIntOutput::HzSixteenth => 0b1110,
+        };
+        self.enable_frequency_output(fs)
+    }
+
+    fn read_user_data(&self) -> Result<Vec<u8>> {
+        let mut data = vec![0; USER_RAM_SIZE];
+        self.i2c.block_read(USER_RAM_ADDR, &mut data)?;
+        Ok(data)
+    }
+
+    fn write_user_data(&self, data: &[u8]) -> Result<()> {
+        if data.len() > USER_RAM_SIZE {
+            return Err(Error::Other(format!("User data exceeds {} bytes", USER_RAM_SIZE)));
+        }
+        self.enable_write()?;
+        // i2c block write, no more than 32 bytes
+        for (i, chunk) in data.chunks(16).enumerate() {
+            self.i2c.block_write(USER_RAM_ADDR + (i * 16) as u8, chunk)?;
+        }
+        self.disable_write()
+    }
+
+    /// Read battery voltage, 9 bits in 10mv
+    fn read_rtc_battery_voltage(&self) -> Result<f32> {
+        let high = (self.i2c.smbus_read_byte(0x1a)? >> 7) as u16;
+        let low = self.i2c.smbus_read_byte(0x1b)? as u16;
+        Ok(((high << 8) | low) as f32 / 100.0)
+    }
 }
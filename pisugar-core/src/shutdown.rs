@@ -0,0 +1,139 @@
+use std::io;
+
+use crate::hook::{execute_hook, HookEnv, HookOptions};
+use crate::platform::tool_exists;
+use crate::simulation::simulation;
+use crate::{execute_cmd, PiSugarConfig, ShutdownMethod};
+
+/// Poweroff script without `soft_poweroff_shell`
+pub const DEFAULT_POWEROFF_SHELL: &str = "shutdown --poweroff 0";
+
+/// Binary of a script, if it's a plain command, e.g. `shutdown` of `shutdown --poweroff 0`
+fn script_binary(script: &str) -> Option<&str> {
+    let binary = script.split_whitespace().next()?;
+    let plain = binary
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '-' | '_'));
+    Some(binary).filter(|_| plain)
+}
+
+/// PowerOff of systemd-logind over D-Bus
+pub fn logind_poweroff_now() -> io::Result<()> {
+    let args = [
+        "call",
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+        "PowerOff",
+        "b",
+        "false",
+    ];
+    let status = execute_cmd("busctl", &args)?;
+    if !status.success() {
+        return Err(io::Error::other(format!("logind PowerOff failed: {}", status)));
+    }
+    Ok(())
+}
+
+/// reboot(2) after sync, services are not stopped
+pub fn syscall_poweroff() -> io::Result<()> {
+    log::warn!("Power off through reboot(2)");
+    unsafe {
+        libc::sync();
+        if libc::reboot(libc::RB_POWER_OFF) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Logind, reboot(2) if it fails
+fn logind_or_syscall() -> io::Result<()> {
+    logind_poweroff_now().or_else(|e| {
+        log::warn!("Logind poweroff error: {}", e);
+        syscall_poweroff()
+    })
+}
+
+/// Run the poweroff script as the daemon user, `hook_user` is of tap hooks and can't power off, `fallback` if the
+/// script fails or exits non-zero
+fn script_poweroff<F>(config: &PiSugarConfig, script: &str, env: &HookEnv, fallback: F) -> io::Result<()>
+where
+    F: FnOnce() -> io::Result<()>,
+{
+    let options = HookOptions {
+        user: None,
+        group: None,
+        ..HookOptions::new(config, &env.event)
+    };
+    let result = execute_hook(&options, script, env).and_then(|status| match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("Poweroff script exited with {}", status))),
+    });
+    result.or_else(|e| {
+        log::warn!("{}, power off through logind", e);
+        fallback()
+    })
+}
+
+/// Power off the system with `shutdown_method`, the script is run as a hook of event
+pub fn poweroff(config: &PiSugarConfig, env: &HookEnv) -> io::Result<()> {
+    if simulation() {
+        log::warn!("Simulation, power off ({}) skipped", env.event);
+        return Ok(());
+    }
+    match config.shutdown_method.unwrap_or_default() {
+        ShutdownMethod::Shell => {
+            let script = config.soft_poweroff_shell.as_deref().unwrap_or(DEFAULT_POWEROFF_SHELL);
+            match script_binary(script) {
+                Some(binary) if !tool_exists(binary) => {
+                    log::warn!("{} not found, power off through logind", binary);
+                    logind_or_syscall()
+                }
+                _ => script_poweroff(config, script, env, logind_or_syscall),
+            }
+        }
+        ShutdownMethod::Logind => logind_or_syscall(),
+        ShutdownMethod::Syscall => syscall_poweroff(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_binary() {
+        assert_eq!(script_binary(DEFAULT_POWEROFF_SHELL), Some("shutdown"));
+        assert_eq!(script_binary("/usr/sbin/poweroff"), Some("/usr/sbin/poweroff"));
+        assert_eq!(script_binary("sync; poweroff"), None);
+        assert_eq!(script_binary(""), None);
+    }
+
+    #[test]
+    fn test_script_poweroff() {
+        let config = PiSugarConfig {
+            hook_user: Some("no-such-user".to_string()),
+            ..Default::default()
+        };
+        let env = HookEnv {
+            event: "soft_poweroff".to_string(),
+            ..Default::default()
+        };
+        let mut fallbacks = 0;
+        script_poweroff(&config, "true", &env, || {
+            fallbacks += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fallbacks, 0);
+        script_poweroff(&config, "exit 1", &env, || {
+            fallbacks += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fallbacks, 1);
+        let r = script_poweroff(&config, "exit 1", &env, || Err(io::Error::other("logind")));
+        assert!(r.is_err());
+    }
+}
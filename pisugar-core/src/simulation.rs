@@ -0,0 +1,393 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use crate::battery::{Battery, BatteryCapabilities, BatteryEvent};
+use crate::rtc::{RtcCapabilities, RTC};
+use crate::sample::Timestamped;
+use crate::{Error, LedMode, Model, PiSugarConfig, RTCRawTime, Result};
+
+/// Simulated seconds from empty to full while charging
+const SIMULATED_CHARGE_SECS: f32 = 15.0 * 60.0;
+
+/// Simulated seconds from full to empty on battery
+const SIMULATED_DISCHARGE_SECS: f32 = 45.0 * 60.0;
+
+/// Level of the simulated power plug, unplugged when full and plugged again below
+const SIMULATED_PLUG_LEVEL: f32 = 20.0;
+
+/// Samples of the simulated voltage and current history
+const SIMULATED_HISTORY: usize = 30;
+
+static SIMULATION: AtomicBool = AtomicBool::new(false);
+
+/// Enable simulation, drivers are simulated and system time and power are left alone
+pub fn set_simulation(enabled: bool) {
+    SIMULATION.store(enabled, Ordering::Relaxed);
+}
+
+/// Is simulation enabled
+pub fn simulation() -> bool {
+    SIMULATION.load(Ordering::Relaxed)
+}
+
+/// Battery voltage (V) of a level, linear between 3.2V and 4.2V
+fn simulated_voltage(level: f32) -> f32 {
+    3.2 + level / 100.0
+}
+
+/// Battery with synthetic data, it discharges and charges in cycles
+pub struct SimulatedBattery {
+    model: Model,
+    level: f32,
+    power_plugged: Cell<bool>,
+    allow_charging: Cell<bool>,
+    input_protected: Cell<bool>,
+    output_enabled: Cell<bool>,
+    led_mode: Cell<LedMode>,
+    voltages: VecDeque<Timestamped<f32>>,
+    intensities: VecDeque<Timestamped<f32>>,
+    poll_at: Option<Instant>,
+}
+
+impl SimulatedBattery {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            level: 80.0,
+            power_plugged: Cell::new(false),
+            allow_charging: Cell::new(true),
+            input_protected: Cell::new(false),
+            output_enabled: Cell::new(true),
+            led_mode: Cell::new(LedMode::Normal),
+            voltages: VecDeque::with_capacity(SIMULATED_HISTORY),
+            intensities: VecDeque::with_capacity(SIMULATED_HISTORY),
+            poll_at: None,
+        }
+    }
+
+    /// Advance level by the elapsed time, plug or unplug power at the end of a cycle
+    fn advance(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f32();
+        if self.power_plugged.get() {
+            if self.allow_charging.get() {
+                self.level = (self.level + secs * 100.0 / SIMULATED_CHARGE_SECS).min(100.0);
+            }
+            if self.level >= 100.0 {
+                self.power_plugged.set(false);
+            }
+        } else {
+            self.level = (self.level - secs * 100.0 / SIMULATED_DISCHARGE_SECS).max(0.0);
+            if self.level < SIMULATED_PLUG_LEVEL {
+                self.power_plugged.set(true);
+            }
+        }
+    }
+
+    fn push_sample(history: &mut VecDeque<Timestamped<f32>>, now: Instant, value: f32) {
+        if history.len() >= SIMULATED_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(Timestamped::new(now, value));
+    }
+
+    fn average(history: &VecDeque<Timestamped<f32>>) -> Result<f32> {
+        if history.is_empty() {
+            return Err(Error::Other("Require initialization".to_string()));
+        }
+        Ok(history.iter().map(|s| s.value).sum::<f32>() / history.len() as f32)
+    }
+}
+
+impl Battery for SimulatedBattery {
+    fn capabilities(&self) -> BatteryCapabilities {
+        BatteryCapabilities {
+            input_protect: true,
+            output_toggle: true,
+            temperature: true,
+            led_mode: true,
+            keep_input: true,
+            ..Default::default()
+        }
+    }
+
+    fn init(&mut self, config: &PiSugarConfig) -> Result<()> {
+        if let Some(protect) = config.bat_protect {
+            self.input_protected.set(protect);
+        }
+        if let Some(mode) = config.led_mode {
+            self.led_mode.set(mode);
+        }
+        Ok(())
+    }
+
+    fn model(&self) -> String {
+        self.model.to_string()
+    }
+
+    fn led_amount(&self) -> Result<u32> {
+        Ok(self.model.led_amount())
+    }
+
+    fn version(&self) -> Result<String> {
+        Ok("simulated".to_string())
+    }
+
+    fn voltage(&self) -> Result<f32> {
+        Ok(simulated_voltage(self.level))
+    }
+
+    fn voltage_avg(&self) -> Result<f32> {
+        Self::average(&self.voltages)
+    }
+
+    fn level(&self) -> Result<f32> {
+        Ok(self.level)
+    }
+
+    fn intensity(&self) -> Result<f32> {
+        Ok(match (self.power_plugged.get(), self.charging()) {
+            (_, true) => 1.0,
+            (true, false) => 0.0,
+            (false, false) => -0.6,
+        })
+    }
+
+    fn intensity_avg(&self) -> Result<f32> {
+        Self::average(&self.intensities)
+    }
+
+    fn voltage_history(&self) -> Vec<Timestamped<f32>> {
+        self.voltages.iter().copied().collect()
+    }
+
+    fn intensity_history(&self) -> Vec<Timestamped<f32>> {
+        self.intensities.iter().copied().collect()
+    }
+
+    fn is_power_plugged(&self) -> Result<bool> {
+        Ok(self.power_plugged.get())
+    }
+
+    fn toggle_power_restore(&self, _enable: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_allow_charging(&self) -> Result<bool> {
+        Ok(self.allow_charging.get())
+    }
+
+    fn toggle_allow_charging(&self, enable: bool) -> Result<()> {
+        self.allow_charging.set(enable);
+        Ok(())
+    }
+
+    fn is_charging(&self) -> Result<bool> {
+        Ok(self.charging())
+    }
+
+    fn is_input_protected(&self) -> Result<bool> {
+        Ok(self.input_protected.get())
+    }
+
+    fn toggle_input_protected(&self, enable: bool) -> Result<()> {
+        self.input_protected.set(enable);
+        Ok(())
+    }
+
+    fn output_enabled(&self) -> Result<bool> {
+        Ok(self.output_enabled.get())
+    }
+
+    fn toggle_output_enabled(&self, enable: bool) -> Result<()> {
+        log::info!("Simulated battery output: {}", enable);
+        self.output_enabled.set(enable);
+        Ok(())
+    }
+
+    fn poll(&mut self, now: Instant, _config: &PiSugarConfig) -> Result<Vec<BatteryEvent>> {
+        if let Some(at) = self.poll_at.replace(now) {
+            self.advance(now.saturating_duration_since(at));
+        }
+        let (voltage, intensity) = (self.voltage()?, self.intensity()?);
+        Self::push_sample(&mut self.voltages, now, voltage);
+        Self::push_sample(&mut self.intensities, now, intensity);
+        Ok(Vec::new())
+    }
+
+    fn led_mode(&self) -> Result<LedMode> {
+        Ok(self.led_mode.get())
+    }
+
+    fn set_led_mode(&self, mode: LedMode) -> Result<()> {
+        self.led_mode.set(mode);
+        Ok(())
+    }
+
+    fn toggle_light_load_shutdown(&self, _enable: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn toggle_soft_poweroff(&self, _enable: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn toggle_anti_mistouch(&self, _enable: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn temperature(&self) -> Result<f32> {
+        Ok(if self.charging() { 38.0 } else { 32.0 })
+    }
+}
+
+impl SimulatedBattery {
+    fn charging(&self) -> bool {
+        self.power_plugged.get() && self.allow_charging.get() && self.level < 100.0
+    }
+}
+
+/// Rtc with synthetic data, it runs at an offset of the system clock
+pub struct SimulatedRTC {
+    offset: Cell<chrono::Duration>,
+    alarm: Cell<RTCRawTime>,
+    alarm_enabled: Cell<bool>,
+}
+
+impl SimulatedRTC {
+    pub fn new() -> Self {
+        Self {
+            offset: Cell::new(chrono::Duration::zero()),
+            alarm: Cell::new(RTCRawTime::default()),
+            alarm_enabled: Cell::new(false),
+        }
+    }
+}
+
+impl Default for SimulatedRTC {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RTC for SimulatedRTC {
+    fn capabilities(&self) -> RtcCapabilities {
+        RtcCapabilities::default()
+    }
+
+    fn init(&mut self, config: &PiSugarConfig) -> Result<()> {
+        if let Some(wakeup_time) = config.auto_wake_time {
+            if config.auto_wake_repeat & 0x7f != 0 {
+                self.set_alarm(wakeup_time.into(), config.auto_wake_repeat)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_time(&self) -> Result<RTCRawTime> {
+        Ok((Utc::now() + self.offset.get()).into())
+    }
+
+    fn write_time(&self, time: RTCRawTime) -> Result<()> {
+        let t: DateTime<Utc> = time.try_into().map_err(Error::Other)?;
+        self.offset.set(t - Utc::now());
+        Ok(())
+    }
+
+    fn write_adjust_ppm(&self, _ppm: f64) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_alarm_time(&self) -> Result<RTCRawTime> {
+        Ok(self.alarm.get())
+    }
+
+    fn set_alarm(&self, time: RTCRawTime, weekday_repeat: u8) -> Result<()> {
+        let mut alarm = time;
+        alarm.0[3] = weekday_repeat;
+        self.alarm.set(alarm);
+        self.alarm_enabled.set(true);
+        Ok(())
+    }
+
+    fn is_alarm_enable(&self) -> Result<bool> {
+        Ok(self.alarm_enabled.get())
+    }
+
+    fn toggle_alarm_enable(&self, enable: bool) -> Result<()> {
+        self.alarm_enabled.set(enable);
+        Ok(())
+    }
+
+    fn read_alarm_flag(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn clear_alarm_flag(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn toggle_frequency_alarm(&self, _enable: bool) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn force_shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_battery_low_flag(&self) -> Result<bool> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn toggle_charging(&self, _enable: bool) -> Result<()> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+
+    fn read_battery_high_flag(&self) -> Result<bool> {
+        Err(Error::Other("Not supported".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulated_battery() {
+        let config = PiSugarConfig::default();
+        let mut battery = SimulatedBattery::new(Model::PiSugar_3);
+        let now = Instant::now();
+        battery.poll(now, &config).unwrap();
+        assert_eq!(battery.level().unwrap(), 80.0);
+        assert_eq!(battery.voltage_avg().unwrap(), 4.0);
+        assert!(!battery.is_power_plugged().unwrap());
+        assert!(battery.intensity().unwrap() < 0.0);
+
+        // discharge below the plug level, then charge until full
+        battery.poll(now + Duration::from_secs(30 * 60), &config).unwrap();
+        assert!(battery.is_power_plugged().unwrap());
+        assert!(battery.is_charging().unwrap());
+        battery.toggle_allow_charging(false).unwrap();
+        assert!(!battery.is_charging().unwrap());
+        battery.poll(now + Duration::from_secs(40 * 60), &config).unwrap();
+        assert!(battery.level().unwrap() < SIMULATED_PLUG_LEVEL);
+        battery.toggle_allow_charging(true).unwrap();
+        battery.poll(now + Duration::from_secs(60 * 60), &config).unwrap();
+        assert_eq!(battery.level().unwrap(), 100.0);
+        assert!(!battery.is_power_plugged().unwrap());
+        assert_eq!(battery.voltage_history().len(), 4);
+    }
+
+    #[test]
+    fn test_simulated_rtc() {
+        let rtc = SimulatedRTC::new();
+        let t = Utc::now() - chrono::Duration::hours(1);
+        rtc.write_time(t.into()).unwrap();
+        let read: DateTime<Utc> = rtc.read_time().unwrap().try_into().unwrap();
+        assert!((read - t).num_seconds().abs() <= 1);
+    }
+}
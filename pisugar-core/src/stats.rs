@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+/// Stats file, next to config file
+pub const STATS_FILE: &str = "stats.json";
+
+/// Interval of saving stats, not to wear out the sd card
+pub const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Days of deepest discharge kept
+pub const STATS_DAYS: usize = 366;
+
+/// Longer gaps between polls are not accounted, e.g. the pi was suspended
+pub const STATS_MAX_GAP: Duration = Duration::from_secs(60);
+
+/// Boot id of the kernel, changes on every boot
+const BOOT_ID_FILE: &str = "/proc/sys/kernel/random/boot_id";
+
+/// Persistent power statistics, e.g. for evaluating solar installations
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Boots, counted once per boot id
+    #[serde(default)]
+    pub boots: u64,
+    /// Boot id of the last counted boot
+    #[serde(default)]
+    pub boot_id: Option<String>,
+    /// Runtime on battery (s)
+    #[serde(default)]
+    pub battery_secs: f64,
+    /// Runtime on external power (s)
+    #[serde(default)]
+    pub external_secs: f64,
+    /// Lowest battery level (%) of each local day
+    #[serde(default)]
+    pub daily_min_level: BTreeMap<NaiveDate, f32>,
+}
+
+impl Stats {
+    /// Load from file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Save to file, replaced atomically so that a power loss leaves the old one
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Count a boot, unless it's counted, returns whether it's new
+    pub fn record_boot(&mut self, boot_id: &str) -> bool {
+        if self.boot_id.as_deref() == Some(boot_id) {
+            return false;
+        }
+        self.boots += 1;
+        self.boot_id = Some(boot_id.to_string());
+        true
+    }
+
+    /// Account runtime since the last record, and the battery level of the day
+    pub fn record(&mut self, elapsed: Duration, power_plugged: bool, level: Option<f32>, date: NaiveDate) {
+        if elapsed <= STATS_MAX_GAP {
+            if power_plugged {
+                self.external_secs += elapsed.as_secs_f64();
+            } else {
+                self.battery_secs += elapsed.as_secs_f64();
+            }
+        }
+        if let Some(level) = level {
+            let min = self.daily_min_level.entry(date).or_insert(level);
+            *min = min.min(level);
+            while self.daily_min_level.len() > STATS_DAYS {
+                let first = *self.daily_min_level.keys().next().unwrap();
+                self.daily_min_level.remove(&first);
+            }
+        }
+    }
+}
+
+/// Boot id of this boot
+pub fn boot_id() -> Option<String> {
+    fs::read_to_string(BOOT_ID_FILE).ok().map(|id| id.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::default();
+        assert!(stats.record_boot("a"));
+        assert!(!stats.record_boot("a"));
+        assert!(stats.record_boot("b"));
+        assert_eq!(stats.boots, 2);
+
+        let day1 = NaiveDate::from_ymd_opt(2024, 6, 1).unwrap();
+        let day2 = day1.succ_opt().unwrap();
+        stats.record(Duration::from_secs(2), false, Some(80.0), day1);
+        stats.record(Duration::from_secs(3), true, Some(60.0), day1);
+        stats.record(Duration::from_secs(3600), true, Some(70.0), day1);
+        stats.record(Duration::from_secs(1), false, Some(90.0), day2);
+        assert_eq!(stats.battery_secs, 3.0);
+        assert_eq!(stats.external_secs, 3.0);
+        assert_eq!(stats.daily_min_level[&day1], 60.0);
+        assert_eq!(stats.daily_min_level[&day2], 90.0);
+
+        let path = std::env::temp_dir().join(format!("pisugar-stats-{}.json", std::process::id()));
+        stats.save(&path).unwrap();
+        assert_eq!(Stats::load(&path).unwrap(), stats);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_stats_days() {
+        let mut stats = Stats::default();
+        let mut date = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        for _ in 0..STATS_DAYS + 10 {
+            stats.record(Duration::from_secs(1), false, Some(50.0), date);
+            date = date.succ_opt().unwrap();
+        }
+        assert_eq!(stats.daily_min_level.len(), STATS_DAYS);
+        assert_eq!(stats.daily_min_level.keys().last(), date.pred_opt().as_ref());
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs::read_to_string;
+use std::process::Command;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::{Error, Result, Timestamped};
+
+/// Throttled flags of the pi firmware, same as `vcgencmd get_throttled`
+pub const THROTTLED_FILE: &str = "/sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// Throttled flag, under voltage is detected now
+pub const THROTTLED_UNDER_VOLTAGE: u32 = 0x1;
+
+/// Throttled flag, under voltage has occurred since boot
+pub const THROTTLED_UNDER_VOLTAGE_OCCURRED: u32 = 0x10000;
+
+/// Voltage sag of an under voltage, 0.3V
+pub const UNDER_VOLTAGE_SAG: f32 = 0.3;
+
+/// Window of a voltage sag, 2s
+pub const UNDER_VOLTAGE_SAG_WINDOW: Duration = Duration::from_secs(2);
+
+/// Under voltage event, of the pi throttled flags or a rapid battery voltage sag
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnderVoltage {
+    pub time: DateTime<Local>,
+    /// Battery voltage (V)
+    pub voltage: Option<f32>,
+    /// Voltage drop in the sag window (V), if it's a sag
+    pub sag: Option<f32>,
+    /// Throttled flags of the pi, in hex
+    pub throttled: Option<String>,
+}
+
+/// Parse throttled flags, `50005` of sysfs or `throttled=0x50005` of vcgencmd
+pub fn parse_throttled(s: &str) -> Option<u32> {
+    let s = s.trim();
+    let s = s.strip_prefix("throttled=").unwrap_or(s);
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Read throttled flags of the pi from sysfs only, cheap enough for every poll
+pub fn read_throttled_sysfs() -> Result<u32> {
+    let s = read_to_string(THROTTLED_FILE)?;
+    parse_throttled(&s).ok_or_else(|| Error::Other(format!("Invalid throttled: {:?}", s)))
+}
+
+/// Read throttled flags of the pi, from sysfs or `vcgencmd get_throttled`, which spawns a process
+pub fn read_throttled() -> Result<u32> {
+    read_throttled_sysfs().or_else(|_| {
+        let output = Command::new("vcgencmd").arg("get_throttled").output()?;
+        let s = String::from_utf8_lossy(&output.stdout);
+        parse_throttled(&s).ok_or_else(|| Error::Other(format!("Invalid throttled: {:?}", s)))
+    })
+}
+
+/// Voltage drop from the max in the window to the latest sample
+pub fn voltage_sag(history: &[Timestamped<f32>], window: Duration) -> Option<f32> {
+    let latest = history.last()?;
+    let max = history
+        .iter()
+        .filter(|s| latest.instant.duration_since(s.instant) <= window)
+        .map(|s| s.value)
+        .fold(latest.value, f32::max);
+    Some(max - latest.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_under_voltage() {
+        assert_eq!(parse_throttled("50005\n"), Some(0x50005));
+        assert_eq!(parse_throttled("throttled=0x0\n"), Some(0));
+        assert_eq!(parse_throttled("error"), None);
+
+        let now = Instant::now();
+        let history: Vec<_> = [(5, 4.1), (2, 4.0), (1, 3.95), (0, 3.6)]
+            .iter()
+            .map(|(secs, v)| Timestamped::new(now - Duration::from_secs(*secs), *v))
+            .collect();
+        let sag = voltage_sag(&history, UNDER_VOLTAGE_SAG_WINDOW).unwrap();
+        assert!((sag - 0.4).abs() < 0.001);
+        assert_eq!(voltage_sag(&history[..1], UNDER_VOLTAGE_SAG_WINDOW), Some(0.0));
+        assert_eq!(voltage_sag(&[], UNDER_VOLTAGE_SAG_WINDOW), None);
+    }
+}
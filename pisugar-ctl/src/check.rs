@@ -0,0 +1,100 @@
+use pisugar_client::metrics::Metric;
+
+/// Exit status of a Nagios plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl CheckStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CheckStatus::Ok => "OK",
+            CheckStatus::Warning => "WARNING",
+            CheckStatus::Critical => "CRITICAL",
+            CheckStatus::Unknown => "UNKNOWN",
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+/// Status of battery level, warn and crit are lower thresholds in percent
+pub fn level_status(level: f64, warn: f64, crit: f64) -> CheckStatus {
+    if level < crit {
+        CheckStatus::Critical
+    } else if level < warn {
+        CheckStatus::Warning
+    } else {
+        CheckStatus::Ok
+    }
+}
+
+/// Plugin output, e.g. `PISUGAR OK - battery 80%, charging | battery=80%;30:;15:;0;100 battery_v=4.1`,
+/// battery has the thresholds in perfdata, other metrics are plain values
+pub fn check_output(status: CheckStatus, summary: &str, metrics: &[Metric], warn: f64, crit: f64) -> String {
+    let perfdata: Vec<String> = metrics
+        .iter()
+        .map(|m| match m.name {
+            "battery" => format!("battery={}%;{}:;{}:;0;100", m.value, warn, crit),
+            name => format!("{}={}", name, m.value),
+        })
+        .collect();
+    let mut output = format!("PISUGAR {} - {}", status.as_str(), summary);
+    if !perfdata.is_empty() {
+        output += &format!(" | {}", perfdata.join(" "));
+    }
+    output
+}
+
+/// Status and summary of the metrics, unknown without battery level
+pub fn check(metrics: &[Metric], warn: f64, crit: f64) -> (CheckStatus, String) {
+    let value = |name: &str| metrics.iter().find(|m| m.name == name).map(|m| m.value);
+    let level = match value("battery") {
+        Some(level) => level,
+        None => return (CheckStatus::Unknown, "battery level unavailable".to_string()),
+    };
+    let mut summary = format!("battery {:.0}%", level);
+    if let Some(v) = value("battery_v") {
+        summary += &format!(", {:.2}V", v);
+    }
+    if value("battery_charging") == Some(1.0) {
+        summary += ", charging";
+    } else if value("battery_power_plugged") == Some(1.0) {
+        summary += ", plugged";
+    }
+    (level_status(level, warn, crit), summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(level_status(80.0, 30.0, 15.0), CheckStatus::Ok);
+        assert_eq!(level_status(30.0, 30.0, 15.0), CheckStatus::Ok);
+        assert_eq!(level_status(20.0, 30.0, 15.0), CheckStatus::Warning);
+        assert_eq!(level_status(10.0, 30.0, 15.0), CheckStatus::Critical);
+
+        let metrics = [
+            Metric::new("battery", 25),
+            Metric::new("battery_v", 3.7),
+            Metric::new("battery_charging", 1),
+        ];
+        let (status, summary) = check(&metrics, 30.0, 15.0);
+        assert_eq!(status, CheckStatus::Warning);
+        assert_eq!(status.code(), 1);
+        assert_eq!(
+            check_output(status, &summary, &metrics, 30.0, 15.0),
+            "PISUGAR WARNING - battery 25%, 3.70V, charging | battery=25%;30:;15:;0;100 battery_v=3.7 battery_charging=1"
+        );
+
+        assert_eq!(check(&metrics[1..], 30.0, 15.0).0, CheckStatus::Unknown);
+    }
+}
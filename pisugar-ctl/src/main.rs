@@ -0,0 +1,299 @@
+use std::io;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::Local;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use pisugar_client::metrics::{collectd_lines, influx_line, Metric, MEASUREMENT, METRIC_FIELDS};
+use pisugar_client::Client;
+use serde_json::{Map, Value};
+
+use check::{check, check_output, CheckStatus};
+
+mod check;
+
+/// Fields of `watch` without arguments
+const WATCH_FIELDS: &[&str] = &["battery", "battery_v", "battery_charging", "battery_power_plugged"];
+
+#[derive(Parser)]
+#[command(name = "pisugar-ctl", version, author, about)]
+struct Cli {
+    /// Unix domain socket of pisugar-server
+    #[arg(short, long, default_value = "/tmp/pisugar-server.sock")]
+    uds: String,
+
+    /// Tcp address of pisugar-server, e.g. 127.0.0.1:8423, instead of uds
+    #[arg(short, long)]
+    tcp: Option<String>,
+
+    /// Response timeout in seconds
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Table)]
+    output: Format,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Table,
+    Json,
+}
+
+/// Format of `metrics`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum MetricsFormat {
+    /// InfluxDB line protocol, for telegraf exec input
+    Influx,
+    /// PUTVAL lines, for collectd exec plugin
+    Collectd,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Get fields, e.g. battery battery_v model
+    Get {
+        #[arg(required = true)]
+        fields: Vec<String>,
+    },
+    /// Print fields periodically, until interrupted
+    Watch {
+        /// Fields, default battery, battery_v, battery_charging, battery_power_plugged
+        fields: Vec<String>,
+        /// Interval in seconds
+        #[arg(short, long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Print all battery metrics once, for telegraf or collectd exec plugins
+    Metrics {
+        #[arg(short, long, value_enum, default_value_t = MetricsFormat::Influx)]
+        format: MetricsFormat,
+    },
+    /// Check battery level as a Nagios/Icinga plugin, exit 0 ok, 1 warning, 2 critical, 3 unknown
+    Check {
+        /// Warning if battery level is below, in percent
+        #[arg(short, long, default_value_t = 30.0)]
+        warn: f64,
+        /// Critical if battery level is below, in percent
+        #[arg(short, long, default_value_t = 15.0)]
+        crit: f64,
+    },
+    /// Cut power of the battery output immediately (force_shutdown), without a safe shutdown of the pi
+    ForceShutdown {
+        /// Confirm cutting power
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Send a request of the protocol as is, e.g. "rtc_alarm_set 2020-06-26T16:09:34+08:00 127"
+    Raw { request: Vec<String> },
+    /// Print shell completion script
+    Completions { shell: Shell },
+}
+
+/// Response value in json, numbers, booleans, objects and arrays are not quoted
+fn json_value(value: &str) -> Value {
+    match serde_json::from_str::<Value>(value) {
+        Ok(v @ (Value::Number(_) | Value::Bool(_) | Value::Array(_) | Value::Object(_))) => v,
+        _ => Value::String(value.to_string()),
+    }
+}
+
+fn print_json(fields: &[(String, String)], time: Option<String>) {
+    let mut object = Map::new();
+    if let Some(time) = time {
+        object.insert("time".to_string(), Value::String(time));
+    }
+    for (field, value) in fields {
+        object.insert(field.clone(), json_value(value));
+    }
+    println!("{}", Value::Object(object));
+}
+
+async fn get(client: &mut Client, fields: &[String]) -> Result<Vec<(String, String)>> {
+    let mut values = Vec::new();
+    for field in fields {
+        let resp = client.request(&format!("get {}", field)).await?;
+        values.push((field.clone(), resp.value));
+    }
+    Ok(values)
+}
+
+/// Print rows of `watch` updates, until interrupted
+async fn watch(client: &mut Client, fields: &[String], interval: u64, format: Format) -> Result<()> {
+    client
+        .request(&format!("watch {} {}", fields.join(","), interval))
+        .await?;
+    if format == Format::Table {
+        println!("{:<10} {}", "time", fields.join(" "));
+    }
+    let mut row: Vec<Option<String>> = vec![None; fields.len()];
+    loop {
+        let line = client.next_event().await?;
+        // events have no value
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name, value.trim()),
+            None => continue,
+        };
+        if let Some(i) = fields.iter().position(|f| f == name) {
+            row[i] = Some(value.to_string());
+        }
+        if row.iter().any(|v| v.is_none()) {
+            continue;
+        }
+        let values: Vec<(String, String)> = fields
+            .iter()
+            .cloned()
+            .zip(row.iter_mut().map(|v| v.take().unwrap_or_default()))
+            .collect();
+        let time = Local::now().format("%H:%M:%S").to_string();
+        match format {
+            Format::Table => {
+                let columns: Vec<String> = values
+                    .iter()
+                    .map(|(field, value)| format!("{:<width$}", value, width = field.len()))
+                    .collect();
+                println!("{:<10} {}", time, columns.join(" "));
+            }
+            Format::Json => print_json(&values, Some(Local::now().to_rfc3339())),
+        }
+    }
+}
+
+/// Battery metrics, unsupported ones (e.g. temperature of older models) are left out
+async fn collect_metrics(client: &mut Client) -> Vec<Metric> {
+    let mut metrics = Vec::new();
+    for (name, field) in METRIC_FIELDS {
+        if let Ok(resp) = client.request(&format!("get {}", field)).await {
+            metrics.extend(Metric::parse(name, &resp.value));
+        }
+    }
+    metrics
+}
+
+/// Print metrics in the format
+async fn metrics(client: &mut Client, format: MetricsFormat) -> Result<()> {
+    let metrics = collect_metrics(client).await;
+    match format {
+        MetricsFormat::Influx => {
+            let model = client.request("get model").await?.value;
+            let timestamp = Local::now().timestamp();
+            if let Some(line) = influx_line(MEASUREMENT, &[("model", &model)], &metrics, timestamp) {
+                println!("{}", line);
+            }
+        }
+        MetricsFormat::Collectd => {
+            // set by collectd for exec plugins
+            let host = std::env::var("COLLECTD_HOSTNAME")
+                .ok()
+                .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+                .map(|h| h.trim().to_string())
+                .unwrap_or_else(|| "localhost".to_string());
+            let interval = std::env::var("COLLECTD_INTERVAL").ok().and_then(|i| i.parse().ok());
+            print!("{}", collectd_lines(&host, &metrics, interval));
+        }
+    }
+    Ok(())
+}
+
+async fn connect(cli: &Cli) -> Result<Client> {
+    let mut client = match &cli.tcp {
+        Some(addr) => Client::connect_tcp(addr).await?,
+        None => Client::connect_uds(&cli.uds).await?,
+    };
+    client.set_timeout(Duration::from_secs(cli.timeout));
+    Ok(client)
+}
+
+/// Print plugin output and returns its status, server errors are unknown
+async fn run_check(cli: &Cli, warn: f64, crit: f64) -> CheckStatus {
+    let (status, output) = if crit > warn {
+        let summary = format!("critical threshold {} is above warning threshold {}", crit, warn);
+        (
+            CheckStatus::Unknown,
+            check_output(CheckStatus::Unknown, &summary, &[], warn, crit),
+        )
+    } else {
+        match connect(cli).await {
+            Ok(mut client) => {
+                let metrics = collect_metrics(&mut client).await;
+                let (status, summary) = check(&metrics, warn, crit);
+                (status, check_output(status, &summary, &metrics, warn, crit))
+            }
+            Err(e) => (
+                CheckStatus::Unknown,
+                check_output(CheckStatus::Unknown, &e.to_string(), &[], warn, crit),
+            ),
+        }
+    };
+    println!("{}", output);
+    status
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Commands::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "pisugar-ctl", &mut io::stdout());
+            return Ok(());
+        }
+        Commands::Check { warn, crit } => std::process::exit(run_check(&cli, warn, crit).await.code()),
+        Commands::ForceShutdown { yes: false } => {
+            bail!("force-shutdown cuts power immediately without a safe shutdown, add --yes to confirm")
+        }
+        _ => {}
+    }
+
+    let mut client = connect(&cli).await?;
+
+    match cli.command {
+        Commands::Get { fields } => {
+            let values = get(&mut client, &fields).await?;
+            match cli.output {
+                Format::Table => {
+                    let width = fields.iter().map(|f| f.len()).max().unwrap_or_default();
+                    for (field, value) in values {
+                        println!("{:<width$} {}", field, value, width = width);
+                    }
+                }
+                Format::Json => print_json(&values, None),
+            }
+        }
+        Commands::Watch { mut fields, interval } => {
+            if fields.is_empty() {
+                fields = WATCH_FIELDS.iter().map(|f| f.to_string()).collect();
+            }
+            watch(&mut client, &fields, interval, cli.output).await?;
+        }
+        Commands::Metrics { format } => metrics(&mut client, format).await?,
+        Commands::ForceShutdown { .. } => {
+            client.request("force_shutdown").await?;
+        }
+        Commands::Raw { request } => {
+            let value = client.request(&request.join(" ")).await?.value;
+            match cli.output {
+                Format::Table => println!("{}", value),
+                Format::Json => println!("{}", json_value(&value)),
+            }
+        }
+        Commands::Completions { .. } | Commands::Check { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+fn main() {
+    let rt = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = rt.block_on(run(Cli::parse())) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,67 @@
+/*
+ * C API of PiSugar core, link with libpisugar.so (or libpisugar.a).
+ *
+ * Functions return 0 on success, -1 on error, see pisugar_last_error().
+ * A handle is not thread safe, use it from one thread at a time.
+ * Chips are left as configured by pisugar-server, which could run meanwhile.
+ */
+
+#ifndef PISUGAR_H
+#define PISUGAR_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PISUGAR_API_VERSION 1
+
+typedef struct PiSugar PiSugar;
+
+/* Version of the C API of the library, compare with PISUGAR_API_VERSION */
+uint32_t pisugar_api_version(void);
+
+/* Message of the last error of this thread, valid until the next call */
+const char *pisugar_last_error(void);
+
+/* Open of a config file (NULL for defaults) and model (NULL or "auto" to detect), NULL on error */
+PiSugar *pisugar_open(const char *config_path, const char *model);
+
+void pisugar_close(PiSugar *pisugar);
+
+/* Read the battery, call it before getters and every 100ms-1s while in use */
+int pisugar_poll(PiSugar *pisugar);
+
+/* Model name, e.g. "PiSugar 3", truncated to len - 1 bytes and nul terminated */
+int pisugar_model(PiSugar *pisugar, char *buf, size_t len);
+
+/* Battery level (%) */
+int pisugar_battery_level(PiSugar *pisugar, float *level);
+
+/* Battery voltage (V) */
+int pisugar_battery_voltage(PiSugar *pisugar, float *voltage);
+
+/* Battery current (A) */
+int pisugar_battery_current(PiSugar *pisugar, float *current);
+
+int pisugar_power_plugged(PiSugar *pisugar, bool *plugged);
+
+int pisugar_charging(PiSugar *pisugar, bool *charging);
+
+int pisugar_allow_charging(PiSugar *pisugar, bool *allow);
+
+int pisugar_set_allow_charging(PiSugar *pisugar, bool allow);
+
+int pisugar_set_battery_output(PiSugar *pisugar, bool enable);
+
+/* Cut the power of the battery output immediately, after syncing file systems */
+int pisugar_force_shutdown(PiSugar *pisugar);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PISUGAR_H */
@@ -0,0 +1,120 @@
+"""PiSugar of libpisugar.so (pisugar-ffi), with ctypes
+
+    from pisugar import PiSugar
+
+    with PiSugar() as pisugar:
+        pisugar.poll()
+        print(pisugar.model, pisugar.battery_level, pisugar.power_plugged)
+"""
+
+import ctypes
+import ctypes.util
+import os
+
+API_VERSION = 1
+
+
+def _load(path=None):
+    path = path or os.environ.get("PISUGAR_LIB") or ctypes.util.find_library("pisugar") or "libpisugar.so"
+    lib = ctypes.CDLL(path)
+    lib.pisugar_api_version.restype = ctypes.c_uint32
+    lib.pisugar_last_error.restype = ctypes.c_char_p
+    lib.pisugar_open.argtypes = [ctypes.c_char_p, ctypes.c_char_p]
+    lib.pisugar_open.restype = ctypes.c_void_p
+    lib.pisugar_close.argtypes = [ctypes.c_void_p]
+    lib.pisugar_close.restype = None
+    for name in ["poll", "force_shutdown"]:
+        getattr(lib, "pisugar_" + name).argtypes = [ctypes.c_void_p]
+    lib.pisugar_model.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_size_t]
+    for name in ["battery_level", "battery_voltage", "battery_current"]:
+        getattr(lib, "pisugar_" + name).argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_float)]
+    for name in ["power_plugged", "charging", "allow_charging"]:
+        getattr(lib, "pisugar_" + name).argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_bool)]
+    for name in ["set_allow_charging", "set_battery_output"]:
+        getattr(lib, "pisugar_" + name).argtypes = [ctypes.c_void_p, ctypes.c_bool]
+    if lib.pisugar_api_version() != API_VERSION:
+        raise RuntimeError("Incompatible libpisugar api version: {}".format(lib.pisugar_api_version()))
+    return lib
+
+
+class PiSugarError(Exception):
+    pass
+
+
+class PiSugar:
+    """PiSugar of a config file (None for defaults) and model (None or "auto" to detect)"""
+
+    def __init__(self, config_path=None, model=None, lib_path=None):
+        self._lib = _load(lib_path)
+        encode = lambda s: s.encode() if s is not None else None
+        self._handle = self._lib.pisugar_open(encode(config_path), encode(model))
+        if not self._handle:
+            raise PiSugarError(self._error())
+
+    def _error(self):
+        return (self._lib.pisugar_last_error() or b"").decode()
+
+    def _check(self, r):
+        if r != 0:
+            raise PiSugarError(self._error())
+
+    def _get(self, name, ctype):
+        value = ctype()
+        self._check(getattr(self._lib, "pisugar_" + name)(self._handle, ctypes.byref(value)))
+        return value.value
+
+    def close(self):
+        if self._handle:
+            self._lib.pisugar_close(self._handle)
+            self._handle = None
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, *args):
+        self.close()
+
+    def poll(self):
+        """Read the battery, call it before getters and every 100ms-1s while in use"""
+        self._check(self._lib.pisugar_poll(self._handle))
+
+    @property
+    def model(self):
+        buf = ctypes.create_string_buffer(64)
+        self._check(self._lib.pisugar_model(self._handle, buf, len(buf)))
+        return buf.value.decode()
+
+    @property
+    def battery_level(self):
+        return self._get("battery_level", ctypes.c_float)
+
+    @property
+    def battery_voltage(self):
+        return self._get("battery_voltage", ctypes.c_float)
+
+    @property
+    def battery_current(self):
+        return self._get("battery_current", ctypes.c_float)
+
+    @property
+    def power_plugged(self):
+        return self._get("power_plugged", ctypes.c_bool)
+
+    @property
+    def charging(self):
+        return self._get("charging", ctypes.c_bool)
+
+    @property
+    def allow_charging(self):
+        return self._get("allow_charging", ctypes.c_bool)
+
+    @allow_charging.setter
+    def allow_charging(self, allow):
+        self._check(self._lib.pisugar_set_allow_charging(self._handle, allow))
+
+    def set_battery_output(self, enable):
+        self._check(self._lib.pisugar_set_battery_output(self._handle, enable))
+
+    def force_shutdown(self):
+        """Cut the power of the battery output immediately"""
+        self._check(self._lib.pisugar_force_shutdown(self._handle))
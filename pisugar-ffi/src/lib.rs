@@ -0,0 +1,227 @@
+//! C API of PiSugar core, see `include/pisugar.h`.
+//!
+//! Functions return 0 on success, -1 on error, with the message of `pisugar_last_error`.
+//! A handle is not thread safe, use it from one thread at a time.
+//! Pointer arguments must be NULL or valid, strings nul terminated and utf-8.
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_float, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::time::Instant;
+
+use pisugar_core::{Error, Model, PiSugarConfig, PiSugarCore, Result};
+use tokio::runtime::Runtime;
+
+/// Version of the C API, bumped on incompatible changes
+pub const PISUGAR_API_VERSION: u32 = 1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// PiSugar handle
+pub struct PiSugar {
+    core: PiSugarCore,
+    runtime: Runtime,
+}
+
+fn set_last_error(e: impl ToString) {
+    let msg = CString::new(e.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = msg);
+}
+
+/// Run f, errors and panics are kept for `pisugar_last_error`
+fn call(f: impl FnOnce() -> Result<()>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(e);
+            -1
+        }
+        Err(_) => {
+            set_last_error("panicked");
+            -1
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<Option<&'a str>> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|e| Error::Other(format!("Invalid string: {}", e)))
+}
+
+unsafe fn handle<'a>(pisugar: *mut PiSugar) -> Result<&'a mut PiSugar> {
+    pisugar.as_mut().ok_or_else(|| Error::Other("Null handle".to_string()))
+}
+
+/// Write a value of a getter to out
+unsafe fn get<T>(pisugar: *mut PiSugar, out: *mut T, f: impl FnOnce(&PiSugarCore) -> Result<T>) -> c_int {
+    call(|| {
+        let pisugar = handle(pisugar)?;
+        let out = out.as_mut().ok_or_else(|| Error::Other("Null output".to_string()))?;
+        *out = f(&pisugar.core)?;
+        Ok(())
+    })
+}
+
+/// Open PiSugar of a config file (NULL for defaults) and model (NULL or "auto" to detect), NULL on error
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_open(config_path: *const c_char, model: *const c_char) -> *mut PiSugar {
+    let mut pisugar = None;
+    let r = call(|| {
+        let mut config = PiSugarConfig::default();
+        if let Some(path) = str_arg(config_path)? {
+            config.load(Path::new(path))?;
+        }
+        let model = match str_arg(model)?.filter(|m| *m != "auto").or(config.model.as_deref()) {
+            Some(model) => model
+                .parse::<Model>()
+                .map_err(|_| Error::Other(format!("Invalid model: {}", model)))?,
+            None => Model::detect(config.i2c_bus)?,
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        // chips are left as configured by pisugar-server
+        let core = PiSugarCore::new_without_init(config, model)?;
+        pisugar = Some(Box::new(PiSugar { core, runtime }));
+        Ok(())
+    });
+    match pisugar {
+        Some(pisugar) if r == 0 => Box::into_raw(pisugar),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Close a handle of `pisugar_open`
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_close(pisugar: *mut PiSugar) {
+    if !pisugar.is_null() {
+        drop(Box::from_raw(pisugar));
+    }
+}
+
+/// Message of the last error of this thread, valid until the next call
+#[no_mangle]
+pub extern "C" fn pisugar_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Version of the C API
+#[no_mangle]
+pub extern "C" fn pisugar_api_version() -> u32 {
+    PISUGAR_API_VERSION
+}
+
+/// Read the battery, call it before getters and every 100ms-1s while in use
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_poll(pisugar: *mut PiSugar) -> c_int {
+    call(|| {
+        let pisugar = handle(pisugar)?;
+        let PiSugar { core, runtime } = pisugar;
+        runtime.block_on(core.poll(Instant::now()))?;
+        Ok(())
+    })
+}
+
+/// Model name, e.g. "PiSugar 3", truncated to len - 1 bytes and nul terminated
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_model(pisugar: *mut PiSugar, buf: *mut c_char, len: usize) -> c_int {
+    call(|| {
+        let model = handle(pisugar)?.core.model();
+        if buf.is_null() || len == 0 {
+            return Err(Error::Other("Null output".to_string()));
+        }
+        let n = model.len().min(len - 1);
+        ptr::copy_nonoverlapping(model.as_ptr() as *const c_char, buf, n);
+        *buf.add(n) = 0;
+        Ok(())
+    })
+}
+
+/// Battery level (%)
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_battery_level(pisugar: *mut PiSugar, level: *mut c_float) -> c_int {
+    get(pisugar, level, |core| core.level())
+}
+
+/// Battery voltage (V)
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_battery_voltage(pisugar: *mut PiSugar, voltage: *mut c_float) -> c_int {
+    get(pisugar, voltage, |core| core.voltage_avg())
+}
+
+/// Battery current (A)
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_battery_current(pisugar: *mut PiSugar, current: *mut c_float) -> c_int {
+    get(pisugar, current, |core| core.intensity_avg())
+}
+
+/// Whether external power is plugged
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_power_plugged(pisugar: *mut PiSugar, plugged: *mut bool) -> c_int {
+    get(pisugar, plugged, |core| core.power_plugged())
+}
+
+/// Whether the battery is charging
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_charging(pisugar: *mut PiSugar, charging: *mut bool) -> c_int {
+    get(pisugar, charging, |core| core.charging())
+}
+
+/// Whether charging is allowed
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_allow_charging(pisugar: *mut PiSugar, allow: *mut bool) -> c_int {
+    get(pisugar, allow, |core| core.allow_charging())
+}
+
+/// Allow or stop charging
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_set_allow_charging(pisugar: *mut PiSugar, allow: bool) -> c_int {
+    call(|| handle(pisugar)?.core.toggle_allow_charging(allow))
+}
+
+/// Enable or disable the battery output
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_set_battery_output(pisugar: *mut PiSugar, enable: bool) -> c_int {
+    call(|| handle(pisugar)?.core.toggle_output_enabled(enable))
+}
+
+/// Cut the power of the battery output immediately, after syncing file systems
+#[no_mangle]
+pub unsafe extern "C" fn pisugar_force_shutdown(pisugar: *mut PiSugar) -> c_int {
+    call(|| handle(pisugar)?.core.force_shutdown())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(pisugar_last_error()) }
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn test_errors() {
+        let model = CString::new("PiSugar 9").unwrap();
+        let pisugar = unsafe { pisugar_open(ptr::null(), model.as_ptr()) };
+        assert!(pisugar.is_null());
+        assert_eq!(last_error(), "Invalid model: PiSugar 9");
+
+        let mut level = 0.0;
+        assert_eq!(unsafe { pisugar_battery_level(ptr::null_mut(), &mut level) }, -1);
+        assert_eq!(last_error(), "Null handle");
+        assert_eq!(pisugar_api_version(), PISUGAR_API_VERSION);
+        unsafe { pisugar_close(ptr::null_mut()) };
+    }
+}
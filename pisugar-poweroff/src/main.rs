@@ -1,24 +1,121 @@
+use std::os::unix::process::CommandExt;
 use std::path::Path;
+use std::process::{exit, Command as ProcessCommand};
 use std::thread::sleep;
 use std::time::Duration;
 
 use clap::{Arg, Command};
 use env_logger::Env;
-use pisugar_core::{Model, PiSugarConfig, PiSugarCore, Result};
+use pisugar_core::{Error, Model, PiSugarConfig, PiSugarCore, Result};
 
+/// Exit code, shutdown sent
+const EXIT_SHUTDOWN_SENT: i32 = 0;
+
+/// Exit code, invalid arguments or config
+const EXIT_INVALID: i32 = 1;
+
+/// Exit code, battery chip or rtc unreachable
+const EXIT_UNREACHABLE: i32 = 2;
+
+/// Exit code, skipped because external power is present
+const EXIT_SKIPPED_PLUGGED: i32 = 3;
+
+/// Re-run detached with `--countdown <after>`, so that the shutdown hook returns and the pi keeps halting.
+/// argv[0] starting with '@' keeps systemd from killing it at the end of shutdown.
+fn spawn_delayed(after: u64, model: Model, matches: &clap::ArgMatches) -> std::io::Result<()> {
+    let mut cmd = ProcessCommand::new(std::env::current_exe()?);
+    cmd.arg0(format!("@{}", env!("CARGO_PKG_NAME")))
+        .args(["--countdown", &after.to_string()])
+        .args(["--model", &model.to_string()])
+        .process_group(0);
+    for (arg, name) in [("--log", "log"), ("--retries", "retries"), ("--config", "configfile")] {
+        if let Some(value) = matches.value_of(name) {
+            cmd.args([arg, value]);
+        }
+    }
+    if matches.is_present("only-if-unplugged") {
+        cmd.arg("--only-if-unplugged");
+    }
+    cmd.spawn()?;
+    Ok(())
+}
+
+/// Model of `--model`, or `model` of config file, or probed on i2c bus
+fn resolve_model(model: Option<&str>, config: &PiSugarConfig) -> Result<Model> {
+    let model = model.filter(|m| *m != "auto").or(config.model.as_deref());
+    match model {
+        Some(model) => model
+            .parse()
+            .map_err(|_| Error::Other(format!("Invalid model: {}", model))),
+        None => {
+            let model = Model::detect(config.i2c_bus)?;
+            log::info!("Detected model: {}", model);
+            Ok(model)
+        }
+    }
+}
+
+/// Registers written by shutdown of each model
+fn shutdown_registers(model: Model) -> Vec<&'static str> {
+    match model {
+        Model::PiSugar_3 => vec!["0x57 0x02 (CTR1): bit5 = 0, output off"],
+        Model::PiSugar_2_Pro => vec![
+            "0x32 0x10 (SD3078 CTR2): INTS1=0 INTS0=1 INTFE=0, frequency alarm off",
+            "0x75 0xc9: light load threshold",
+            "0x75 0x06: light load shutdown time 8s",
+            "0x75 0x03: bit5 = 1, light load shutdown on",
+            "0x75 0x13: battery low 2.76-2.84V",
+            "0x75 0x01: bit2 = 0, force shutdown",
+        ],
+        Model::PiSugar_2_2LEDs | Model::PiSugar_2_4LEDs => vec![
+            "0x32 0x10 (SD3078 CTR2): INTS1=0 INTS0=1 INTFE=0, frequency alarm off",
+            "0x75 0x0c: light load threshold",
+            "0x75 0x04: light load shutdown time 8s",
+            "0x75 0x02: bit0-1 = 1, light load shutdown on",
+            "0x75 0x01: bit2 = 0, force shutdown",
+        ],
+    }
+}
+
+/// Print current state and registers to be written, returns whether the chips are reachable
+fn dry_run(config: PiSugarConfig, model: Model) -> bool {
+    let core = match PiSugarCore::new_without_init(config, model) {
+        Ok(core) => core,
+        Err(e) => {
+            println!("{}: unreachable, {}", model, e);
+            return false;
+        }
+    };
+    let voltage = core.voltage();
+    println!("model: {}", model);
+    println!("battery_v: {:?}", voltage);
+    println!("battery_power_plugged: {:?}", core.power_plugged());
+    println!("battery_output_enabled: {:?}", core.output_enabled());
+    println!("rtc_time: {:?}", core.read_time().map(|t| t.to_rfc3339()));
+    println!("registers to write (i2c addr, reg):");
+    for reg in shutdown_registers(model) {
+        println!("  {}", reg);
+    }
+    voltage.is_ok()
+}
+
+/// Force shutdown with retries, error if no attempt reached the chips
 fn shutdown(config: PiSugarConfig, model: Model, retries: u32) -> Result<()> {
+    let mut r = Err(Error::Other("No shutdown attempt".to_string()));
     for _ in 0..retries {
         let core = PiSugarCore::new_without_init(config.clone(), model)?;
-        if let Err(e) = core.force_shutdown() {
-            log::warn!("{}", e);
+        match core.force_shutdown() {
+            Ok(_) => r = Ok(()),
+            Err(e) => log::warn!("{}", e),
         }
         sleep(Duration::from_millis(10));
     }
-    Ok(())
+    r
 }
 
 fn main() {
     let models = vec![
+        "auto".to_string(),
         Model::PiSugar_3.to_string(),
         Model::PiSugar_2_Pro.to_string(),
         Model::PiSugar_2_2LEDs.to_string(),
@@ -41,7 +138,13 @@ fn main() {
                 .short('m')
                 .long("model")
                 .value_name("MODEL")
-                .help(format!("PiSugar Model, choose from {:?}", models).as_str())
+                .help(
+                    format!(
+                        "PiSugar Model, choose from {:?}, default model of config file or auto to detect",
+                        models
+                    )
+                    .as_str(),
+                )
                 .takes_value(true)
                 .validator(move |x| {
                     if models.contains(&x.to_string()) {
@@ -49,8 +152,7 @@ fn main() {
                     } else {
                         Err("Invalid model".to_string())
                     }
-                })
-                .required(true),
+                }),
         )
         .arg(
             Arg::new("countdown")
@@ -68,6 +170,24 @@ fn main() {
                 .default_value("100")
                 .help("Retries, e.g. 100"),
         )
+        .arg(
+            Arg::new("after")
+                .long("after")
+                .value_name("SECONDS")
+                .help("Return immediately and cut power after seconds, in a detached process"),
+        )
+        .arg(
+            Arg::new("only-if-unplugged")
+                .long("only-if-unplugged")
+                .takes_value(false)
+                .help("Skip cutting power when external power is present"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .takes_value(false)
+                .help("Print current state and registers to be written, without cutting power"),
+        )
         .arg(
             Arg::new("configfile")
                 .short('f')
@@ -78,22 +198,85 @@ fn main() {
         )
         .get_matches();
 
-    let model: Model = matches.value_of("model").unwrap().parse().unwrap();
     let log_level = matches.value_of("log").unwrap();
-    let countdown: u64 = matches.value_of("countdown").unwrap().parse().unwrap();
-    let retries: u32 = matches.value_of("retries").unwrap().parse().unwrap();
+    let (countdown, retries): (u64, u32) = match (
+        matches.value_of("countdown").unwrap().parse(),
+        matches.value_of("retries").unwrap().parse(),
+    ) {
+        (Ok(countdown), Ok(retries)) => (countdown, retries),
+        _ => {
+            eprintln!("Invalid countdown or retries");
+            exit(EXIT_INVALID);
+        }
+    };
     let config_file: &str = matches.value_of("configfile").unwrap();
+    let dry_run_only = matches.is_present("dry-run");
+    let only_if_unplugged = matches.is_present("only-if-unplugged");
+    let after: Option<u64> = match matches.value_of("after").map(|s| s.parse()) {
+        Some(Ok(after)) => Some(after),
+        Some(Err(_)) => {
+            eprintln!("Invalid after");
+            exit(EXIT_INVALID);
+        }
+        None => None,
+    };
+
+    env_logger::Builder::from_env(Env::default().default_filter_or(log_level)).init();
+
+    let mut config = PiSugarConfig::default();
+    if let Err(e) = config.load(Path::new(config_file)) {
+        log::warn!("Load config file {} error: {}", config_file, e);
+    }
+
+    let model = match resolve_model(matches.value_of("model"), &config) {
+        Ok(model) => model,
+        Err(e) => {
+            log::error!("Unknown PiSugar model: {}", e);
+            exit(EXIT_UNREACHABLE);
+        }
+    };
+
+    if dry_run_only {
+        let code = if dry_run(config, model) {
+            EXIT_SHUTDOWN_SENT
+        } else {
+            EXIT_UNREACHABLE
+        };
+        exit(code);
+    }
+
+    if let Some(after) = after {
+        match spawn_delayed(after, model, &matches) {
+            Ok(_) => {
+                log::info!("Poweroff in {}s", after);
+                exit(EXIT_SHUTDOWN_SENT);
+            }
+            Err(e) => {
+                log::error!("Spawn delayed poweroff error: {}", e);
+                exit(EXIT_INVALID);
+            }
+        }
+    }
+
     for i in 0..countdown {
         eprint!("{} ", countdown - i);
         sleep(Duration::from_secs(1));
     }
     eprintln!("0...");
 
-    env_logger::Builder::from_env(Env::default().default_filter_or(log_level)).init();
+    if only_if_unplugged {
+        let plugged = PiSugarCore::new_without_init(config.clone(), model).and_then(|core| core.power_plugged());
+        if let Ok(true) = plugged {
+            log::info!("External power present, skip poweroff");
+            exit(EXIT_SKIPPED_PLUGGED);
+        }
+    }
 
-    let mut config = PiSugarConfig::default();
-    if let Err(e) = config.load(Path::new(config_file)) {
-        log::warn!("Load config file {} error: {}", config_file, e);
+    match shutdown(config, model, retries) {
+        Ok(_) => exit(EXIT_SHUTDOWN_SENT),
+        Err(e) => {
+            log::error!("Shutdown failed, chip unreachable: {}", e);
+            exit(EXIT_UNREACHABLE);
+        }
     }
-    let _ = shutdown(config, model, retries);
 }
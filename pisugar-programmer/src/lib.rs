@@ -0,0 +1,449 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rppal::i2c::I2c;
+use rppal::i2c::Result as I2cResult;
+
+pub mod online;
+
+pub const CMD_VER: u8 = 0x00;
+pub const CMD_MODE: u8 = 0x01;
+pub const CMD_APP_CTR2: u8 = 0x03;
+pub const CMD_CTRL: u8 = 0xd0;
+pub const CMD_SEG_H: u8 = 0xd1;
+pub const CMD_SEG_L: u8 = 0xd2;
+pub const CMD_POS_H: u8 = 0xd3;
+pub const CMD_POS_L: u8 = 0xd4;
+pub const CMD_DATA: u8 = 0xdd;
+pub const CMD_APPVER: u8 = 0xe2;
+pub const APP_VER_LEN: usize = 15;
+
+pub const CMD_WRITE_ENABLE: u8 = 0x0b;
+pub const ENABLE_WRITE: u8 = 0x29;
+
+pub const PISUGAR_VER: u8 = 3;
+pub const MODE_APPLICATION: u8 = 0x0f;
+pub const MODE_BOOTLOADER: u8 = 0xf0;
+pub const MODE_BOOTAPP: u8 = 0xba;
+pub const SEG_SIZE: usize = 512;
+
+/// Firmware offset is 16 bits
+pub const FIRMWARE_MAX_SIZE: usize = u16::MAX as usize;
+
+/// Programmer error
+#[derive(Debug)]
+pub enum Error {
+    I2c(rppal::i2c::Error),
+    Timeout(String),
+    InvalidFirmware(String),
+    Online(String),
+}
+
+impl From<rppal::i2c::Error> for Error {
+    fn from(e: rppal::i2c::Error) -> Self {
+        Error::I2c(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::I2c(e) => write!(f, "I2c error: {}", e),
+            Error::Timeout(s) => write!(f, "Timeout: {}", s),
+            Error::InvalidFirmware(s) => write!(f, "Invalid firmware: {}", s),
+            Error::Online(s) => write!(f, "Online: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Dotted firmware version, e.g. 1.2.6, compared numerically
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion(Vec<u32>);
+
+impl FromStr for FirmwareVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidFirmware(format!("Invalid version: {}", s));
+        let parts = s
+            .trim()
+            .trim_start_matches(['v', 'V'])
+            .split('.')
+            .map(|p| p.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+        if parts.len() < 2 {
+            return Err(invalid());
+        }
+        Ok(Self(parts))
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+/// Version string embedded in the application image, the one reported by `CMD_APPVER`.
+/// None if there is no such string, or more than one candidate.
+pub fn image_version(image: &[u8]) -> Option<FirmwareVersion> {
+    let mut candidates = image
+        .split(|&b| b == 0)
+        .filter(|s| !s.is_empty() && s.len() < APP_VER_LEN)
+        .filter_map(|s| std::str::from_utf8(s).ok())
+        .filter_map(|s| s.parse::<FirmwareVersion>().ok());
+    let version = candidates.next()?;
+    match candidates.next() {
+        None => Some(version),
+        Some(_) => None,
+    }
+}
+
+/// Check the version reported by the started application (`CMD_APPVER`) is the one of the flashed image.
+/// The bootloader has no flash read command, so the image itself can't be read back.
+pub fn verify_version(image: &FirmwareVersion, running: &str) -> Result<()> {
+    let running = running.parse::<FirmwareVersion>()?;
+    if &running != image {
+        return Err(Error::InvalidFirmware(format!(
+            "Running application {} is not the flashed {}",
+            running, image
+        )));
+    }
+    Ok(())
+}
+
+/// PiSugar 3 mcu mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Application,
+    Bootloader,
+    Bootapp,
+}
+
+/// Firmware upgrade progress
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes written
+    pub offset: usize,
+    /// Firmware size
+    pub size: usize,
+    /// I2c retries so far
+    pub retries: usize,
+}
+
+impl Progress {
+    pub fn percent(&self) -> usize {
+        if self.size == 0 {
+            return 100;
+        }
+        self.offset * 100 / self.size
+    }
+}
+
+/// PiSugar 3 firmware programmer
+pub struct Programmer {
+    i2c: I2c,
+}
+
+impl Programmer {
+    /// Open i2c bus, e.g. 1 and addr, e.g. 0x57
+    pub fn new(bus: u8, addr: u16) -> Result<Self> {
+        let mut i2c = I2c::with_bus(bus)?;
+        i2c.set_slave_address(addr)?;
+        Ok(Self { i2c })
+    }
+
+    /// Current mcu mode, none if it is not a PiSugar 3 or in an unknown mode
+    pub fn mode(&self) -> Result<Option<Mode>> {
+        let version = self.i2c.smbus_read_byte(CMD_VER)?;
+        if version != PISUGAR_VER {
+            return Ok(None);
+        }
+        log::info!("PiSugar version: {}", version);
+        let mode = self.i2c.smbus_read_byte(CMD_MODE)?;
+        let mode = match mode {
+            MODE_APPLICATION => Mode::Application,
+            MODE_BOOTLOADER => Mode::Bootloader,
+            MODE_BOOTAPP => Mode::Bootapp,
+            _ => return Ok(None),
+        };
+        log::info!("PiSugar mode: {:?}", mode);
+        Ok(Some(mode))
+    }
+
+    /// Wait for bootloader (or bootapp when upgrading application), send reset if `reset`
+    pub fn wait_bootloader(&self, bootapp: bool, reset: bool, timeout: Option<Duration>) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            match self.mode() {
+                Ok(Some(Mode::Bootloader)) => {
+                    log::info!("PiSugar bootloader mode detected");
+                    return Ok(());
+                }
+                Ok(Some(Mode::Application)) => {
+                    log::info!("PiSugar application mode detected");
+                    if reset {
+                        log::info!("Send reset to application...");
+                        let _ = self.send_reset();
+                    }
+                }
+                Ok(Some(Mode::Bootapp)) => {
+                    log::info!("PiSugar bootapp mode detected");
+                    if !bootapp && reset {
+                        log::info!("Upgrade application, send reset to bootapp and reboot to bootloader...");
+                        let _ = self.send_reset();
+                    }
+                    if bootapp {
+                        if reset {
+                            log::info!("Upgrade bootapp, send reset to bootapp and reboot to bootloader...");
+                            let _ = self.send_reset();
+                        }
+                    } else {
+                        return Ok(());
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::info!("{}", e);
+                }
+            }
+
+            if matches!(timeout, Some(timeout) if start.elapsed() > timeout) {
+                return Err(Error::Timeout("PiSugar bootloader/bootapp not ready".to_string()));
+            }
+            log::info!("PiSugar bootloader/bootapp not ready, please reset or wait, retry...");
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Wait for application mode after upgrade, returns firmware version
+    pub fn wait_application(&self, timeout: Duration) -> Result<String> {
+        let start = Instant::now();
+        loop {
+            if let Ok(Some(Mode::Application)) = self.mode() {
+                return self.read_version();
+            }
+            if start.elapsed() > timeout {
+                return Err(Error::Timeout("PiSugar application not ready".to_string()));
+            }
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Application firmware version string
+    pub fn read_version(&self) -> Result<String> {
+        let mut version = Vec::new();
+        for i in 0..APP_VER_LEN {
+            let c = self.i2c.smbus_read_byte(CMD_APPVER + i as u8)?;
+            if c == 0 {
+                break;
+            }
+            version.push(c);
+        }
+        Ok(String::from_utf8_lossy(&version).to_string())
+    }
+
+    /// Flash firmware, the mcu must be in bootloader mode
+    pub fn flash<F: FnMut(&Progress)>(&self, firmware: &[u8], mut on_progress: F) -> Result<()> {
+        let i2c = &self.i2c;
+        let fw_size = firmware.len();
+        if fw_size > FIRMWARE_MAX_SIZE {
+            return Err(Error::InvalidFirmware(format!("Firmware too large: {}", fw_size)));
+        }
+        let mut progress = Progress {
+            size: fw_size,
+            ..Default::default()
+        };
+        log::info!("");
+        log::info!("Firmware size: {}", fw_size);
+
+        let mut offset: u16 = 0;
+        for buff in firmware.chunks(SEG_SIZE) {
+            let n = buff.len();
+            log::info!("");
+            log::info!(
+                "Seg offset: {}/{}({}%), size: {}",
+                offset,
+                fw_size,
+                progress.percent(),
+                n
+            );
+            on_progress(&progress);
+
+            // Send seg
+            while self.send_seg(offset).is_err() {
+                log::info!("Send seg offset {} error, retry...", offset);
+                progress.retries += 1;
+                sleep(Duration::from_millis(50));
+            }
+
+            // Send seg pos
+            let (pos, _) = offset.overflowing_sub(1);
+            while self.send_pos(pos).is_err() {
+                log::info!("Send pos {} error, retry...", pos);
+                progress.retries += 1;
+                sleep(Duration::from_millis(50));
+            }
+
+            // Send data
+            for &data in buff {
+                while self.send_data(data).is_err() {
+                    // reset pos to offset - 1
+                    let (pos, _) = offset.overflowing_sub(1);
+                    log::info!("Send data of {} error, reset pos to {}", offset, pos);
+                    progress.retries += 1;
+                    while self.send_pos(pos).is_err() {
+                        log::info!("Send pos {} error, retry...", pos);
+                        sleep(Duration::from_millis(50));
+                    }
+                }
+                offset += 1;
+            }
+
+            // Write flash
+            log::info!("Writing flash...");
+            loop {
+                let mut ctrl;
+
+                // Read ctrl
+                loop {
+                    match i2c.smbus_read_byte(CMD_CTRL) {
+                        Ok(r) => {
+                            ctrl = r;
+                            break;
+                        }
+                        _ => {
+                            log::info!("Read upgrade ctrl error, retry...");
+                            sleep(Duration::from_millis(50));
+                        }
+                    }
+                }
+
+                // Enable write
+                ctrl |= 1 << 7;
+                ctrl |= 1 << 5;
+                while i2c.smbus_write_byte(CMD_CTRL, ctrl).is_err() {
+                    log::info!("Enable flash write error, retry...");
+                    sleep(Duration::from_millis(50));
+                }
+
+                // Wait for result
+                sleep(Duration::from_millis(50));
+                loop {
+                    match i2c.smbus_read_byte(CMD_CTRL) {
+                        Ok(r) => {
+                            ctrl = r;
+                            break;
+                        }
+                        _ => {
+                            log::info!("Read upgrade ctrl error, retry...");
+                            sleep(Duration::from_millis(50));
+                        }
+                    }
+
+                    // Not done
+                    if ctrl & (1 << 3) != 0 {
+                        log::info!("Stilling writing, retry...");
+                        sleep(Duration::from_millis(50));
+                        continue;
+                    }
+                    break;
+                }
+
+                // Write error
+                if ctrl & 1 != 0 {
+                    log::info!("Write error, retry...");
+                    progress.retries += 1;
+                    sleep(Duration::from_millis(50));
+                    continue;
+                }
+
+                log::info!("Write ok");
+                break;
+            }
+            progress.offset = offset as usize;
+        }
+        on_progress(&progress);
+
+        log::info!("");
+        log::info!("Upgrade finished!");
+        Ok(())
+    }
+
+    fn send_reset(&self) -> I2cResult<()> {
+        self.i2c.smbus_write_byte(CMD_WRITE_ENABLE, ENABLE_WRITE)?;
+        self.i2c.smbus_write_byte(CMD_APP_CTR2, 1 << 7)
+    }
+
+    fn send_seg(&self, offset: u16) -> I2cResult<()> {
+        let i2c = &self.i2c;
+        i2c.smbus_write_byte(CMD_SEG_H, (offset >> 8) as u8)?;
+        i2c.smbus_write_byte(CMD_SEG_L, (offset & 0xff) as u8)?;
+        let seg_h = i2c.smbus_read_byte(CMD_SEG_H)?;
+        let seg_l = i2c.smbus_read_byte(CMD_SEG_L)?;
+        let seg: u16 = ((seg_h as u16) << 8) | (seg_l as u16);
+        log::debug!("send_seg 0x{:x} i2c 0x{:x}", offset, seg);
+        if seg != offset {
+            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+        }
+        Ok(())
+    }
+
+    fn send_pos(&self, offset: u16) -> I2cResult<()> {
+        let i2c = &self.i2c;
+        i2c.smbus_write_byte(CMD_POS_H, (offset >> 8) as u8)?;
+        i2c.smbus_write_byte(CMD_POS_L, (offset & 0xff) as u8)?;
+        let pos_h = i2c.smbus_read_byte(CMD_POS_H)?;
+        let pos_l = i2c.smbus_read_byte(CMD_POS_L)?;
+        let pos = ((pos_h as u16) << 8) | (pos_l as u16);
+        log::debug!("send_pos 0x{:x} i2c 0x{:x}", offset, pos);
+        if pos != offset {
+            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+        }
+        Ok(())
+    }
+
+    fn send_data(&self, data: u8) -> I2cResult<()> {
+        let i2c = &self.i2c;
+        i2c.smbus_write_byte(CMD_DATA, data)?;
+        let data2 = i2c.smbus_read_byte(CMD_DATA)?;
+        log::debug!("send_data 0x{:x} i2c 0x{:x}", data, data2);
+        if data != data2 {
+            return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_version() {
+        let image = b"\x20\x00\x01\x08\x00v1.2.6\x00\xff\xfe";
+        assert_eq!(image_version(image), Some("1.2.6".parse().unwrap()));
+        assert_eq!(image_version(b"\x001.2\x001.3\x00"), None);
+        assert_eq!(image_version(b"\x00abc\x00"), None);
+
+        let v: FirmwareVersion = "1.10.0".parse().unwrap();
+        assert!(v > "1.9.3".parse().unwrap());
+        assert!("1".parse::<FirmwareVersion>().is_err());
+    }
+
+    #[test]
+    fn test_verify_version() {
+        let image: FirmwareVersion = "1.2.6".parse().unwrap();
+        assert!(verify_version(&image, "v1.2.6").is_ok());
+        assert!(verify_version(&image, "1.2.5").is_err());
+        assert!(verify_version(&image, "").is_err());
+    }
+}
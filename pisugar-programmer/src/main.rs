@@ -1,47 +1,76 @@
 use std::fs;
 use std::io;
-use std::io::Read;
-use std::process::exit;
+use std::process::{exit, Command as ProcessCommand};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::sleep;
 use std::time::Duration;
 
 use clap::Arg;
 use clap::Command;
 use env_logger::Env;
-use rppal::i2c::I2c;
-use rppal::i2c::Result as I2cResult;
+use pisugar_programmer::online::{self, Channel};
+use pisugar_programmer::{
+    image_version, verify_version, FirmwareVersion, Mode, Programmer, Progress, FIRMWARE_MAX_SIZE,
+};
 use sysinfo::ProcessRefreshKind;
 use sysinfo::{ProcessExt, RefreshKind, SystemExt};
 
-const CMD_VER: u8 = 0x00;
-const CMD_MODE: u8 = 0x01;
-const CMD_APP_CTR2: u8 = 0x03;
-const CMD_CTRL: u8 = 0xd0;
-const CMD_SEG_H: u8 = 0xd1;
-const CMD_SEG_L: u8 = 0xd2;
-const CMD_POS_H: u8 = 0xd3;
-const CMD_POS_L: u8 = 0xd4;
-const CMD_DATA: u8 = 0xdd;
+/// Application start timeout of `--wait-app` and `--verify`
+const WAIT_APP_TIMEOUT: Duration = Duration::from_secs(10);
 
-const CMD_WRITE_ENABLE: u8 = 0x0b;
-const ENABLE_WRITE: u8 = 0x29;
+/// Systemd service of pisugar-server
+const SERVICE: &str = "pisugar-server";
 
-const PISUGAR_VER: u8 = 3;
-const MODE_APPLICATION: u8 = 0x0f;
-const MODE_BOOTLOADER: u8 = 0xf0;
-const MODE_BOOTAPP: u8 = 0xba;
-const SEG_SIZE: usize = 512;
+/// Service stopped by `--manage-service`, to be started again on exit
+static SERVICE_STOPPED: AtomicBool = AtomicBool::new(false);
 
-fn show_warning() {
+fn systemctl(args: &[&str]) -> bool {
+    matches!(ProcessCommand::new("systemctl").args(args).status(), Ok(s) if s.success())
+}
+
+fn stop_service() {
+    if systemctl(&["is-active", "--quiet", SERVICE]) {
+        log::info!("Stop {}...", SERVICE);
+        if systemctl(&["stop", SERVICE]) {
+            SERVICE_STOPPED.store(true, Ordering::SeqCst);
+        } else {
+            log::warn!("Stop {} failed", SERVICE);
+        }
+    }
+}
+
+fn start_service() {
+    if SERVICE_STOPPED.swap(false, Ordering::SeqCst) {
+        log::info!("Start {}...", SERVICE);
+        if !systemctl(&["start", SERVICE]) {
+            log::error!("Start {} failed", SERVICE);
+        }
+    }
+}
+
+/// Exit, start the service again if it was stopped
+fn exit_with(code: i32) -> ! {
+    start_service();
+    exit(code)
+}
+
+fn show_warning(yes: bool, manage_service: bool) {
     log::info!("WARNING:");
     log::info!("1. PLEASE CONFIRM THAT THE BATTERY IS FULLY CHARGED");
     log::info!("2. SYSTEMD SERVICE pisugar-server MUST BE STOPPED");
     log::info!("OTHERWISE UPGRADE MAY NOT SUCCEED!!!");
-    log::info!("CONFIRM? (y or n): ");
-    let mut confirm = String::new();
-    io::stdin().read_line(&mut confirm).unwrap();
-    if !confirm.to_lowercase().trim_start().starts_with('y') {
-        exit(0);
+    if !yes {
+        log::info!("CONFIRM? (y or n): ");
+        let mut confirm = String::new();
+        io::stdin().read_line(&mut confirm).unwrap();
+        if !confirm.to_lowercase().trim_start().starts_with('y') {
+            exit(0);
+        }
+    }
+
+    if manage_service {
+        stop_service();
     }
 
     loop {
@@ -64,6 +93,48 @@ fn show_warning() {
     }
 }
 
+/// Refuse firmware of other hardware or older than the running application, fails closed if a version is unknown
+fn check_version(programmer: &Programmer, firmware: &[u8], version: Option<FirmwareVersion>) -> Result<(), String> {
+    if firmware.len() > FIRMWARE_MAX_SIZE {
+        return Err(format!("Firmware too large: {}", firmware.len()));
+    }
+    let mode = match programmer.mode() {
+        Ok(Some(mode)) => mode,
+        Ok(None) => return Err("Device is not a PiSugar 3".to_string()),
+        Err(e) => return Err(format!("Read PiSugar version error: {}", e)),
+    };
+    let image = version
+        .or_else(|| image_version(firmware))
+        .ok_or_else(|| "No version found in firmware file, not a PiSugar 3 application".to_string())?;
+    log::info!("Firmware file version: {}", image);
+    if mode != Mode::Application {
+        log::warn!("PiSugar is not running application, skip version check");
+        return Ok(());
+    }
+    let running = programmer
+        .read_version()
+        .map_err(|e| format!("Read application version error: {}", e))?;
+    log::info!("Running application version: {}", running);
+    let running = running
+        .parse::<FirmwareVersion>()
+        .map_err(|_| format!("Unknown running application version: {}", running))?;
+    if image < running {
+        return Err(format!("Firmware {} is older than running {}", image, running));
+    }
+    Ok(())
+}
+
+/// Line-delimited json progress record
+fn print_json_progress(p: &Progress) {
+    println!(
+        "{{\"offset\":{},\"size\":{},\"percent\":{},\"retries\":{}}}",
+        p.offset,
+        p.size,
+        p.percent(),
+        p.retries
+    );
+}
+
 fn to_u16(s: &str) -> u16 {
     let mut hexadecimal = false;
     let digits;
@@ -108,9 +179,71 @@ fn main() {
                 .takes_value(false)
                 .help("Automatically reset to bootloader mode"),
         )
+        .arg(
+            Arg::new("yes")
+                .short('y')
+                .long("yes")
+                .takes_value(false)
+                .help("Skip confirmation, for unattended upgrades"),
+        )
+        .arg(
+            Arg::new("progress")
+                .long("progress")
+                .takes_value(true)
+                .possible_values(["log", "json"])
+                .default_value("log")
+                .help("Progress output, json prints line-delimited records to stdout"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .takes_value(false)
+                .help("Flash without checking hardware and firmware version"),
+        )
+        .arg(
+            Arg::new("manage-service")
+                .long("manage-service")
+                .takes_value(false)
+                .help("Stop pisugar-server service during upgrade and start it again afterwards"),
+        )
+        .arg(
+            Arg::new("wait-app")
+                .long("wait-app")
+                .takes_value(false)
+                .help("Wait for the new application to start and read its version"),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .takes_value(false)
+                .help("Wait for the new application and check it reports the version of the firmware file"),
+        )
+        .arg(
+            Arg::new("online")
+                .long("online")
+                .takes_value(true)
+                .possible_values(["stable", "beta"])
+                .conflicts_with("file")
+                .help("Download the latest application firmware of channel from PiSugar CDN"),
+        )
+        .arg(
+            Arg::new("pubkey")
+                .long("pubkey")
+                .takes_value(true)
+                .requires("online")
+                .help("Hex ed25519 public key, verify signature of online firmware"),
+        )
+        .arg(
+            Arg::new("insecure")
+                .long("insecure")
+                .takes_value(false)
+                .requires("online")
+                .conflicts_with("pubkey")
+                .help("Flash online firmware without a signature check"),
+        )
         .arg(
             Arg::new("file")
-                .required(true)
+                .required_unless_present("online")
                 .help("Firmware file, e.g. pisugar-3-application.bin"),
         )
         .get_matches();
@@ -118,11 +251,19 @@ fn main() {
     let bus: u8 = to_u16(matches.value_of("bus").unwrap()) as u8;
     let addr: u16 = to_u16(matches.value_of("addr").unwrap());
     let reset: bool = matches.is_present("reset");
-    let file = matches.value_of("file").unwrap();
+    let file = matches.value_of("file");
+    let online = matches.value_of("online").map(|c| Channel::from_str(c).unwrap());
+    let pubkey = matches
+        .value_of("pubkey")
+        .map(|k| hex::decode(k).expect("Invalid public key"));
+    let insecure: bool = matches.is_present("insecure");
     let debug: bool = matches.is_present("debug");
-
-    let mut i2c = I2c::with_bus(bus).unwrap();
-    i2c.set_slave_address(addr).unwrap();
+    let wait_app: bool = matches.is_present("wait-app");
+    let verify: bool = matches.is_present("verify");
+    let yes: bool = matches.is_present("yes");
+    let force: bool = matches.is_present("force");
+    let manage_service: bool = matches.is_present("manage-service");
+    let json_progress = matches.value_of("progress") == Some("json");
 
     if debug {
         env_logger::init_from_env(Env::default().default_filter_or("DEBUG"));
@@ -130,210 +271,92 @@ fn main() {
         env_logger::init_from_env(Env::default().default_filter_or("INFO"));
     }
 
-    show_warning();
+    if online.is_some() && pubkey.is_none() && !insecure {
+        log::error!("Online firmware requires --pubkey <hex> to check its signature, or --insecure");
+        exit(1);
+    }
 
-    let mut f = fs::File::open(file).unwrap();
-    let fw_size = f.metadata().unwrap().len();
-    log::info!("");
-    log::info!("Firmware size: {}", fw_size);
+    show_warning(yes, manage_service);
 
-    // Detect pisugar bootloader
-    loop {
-        // Check pisugar version
-        let pisugar_version = i2c.smbus_read_byte(CMD_VER);
-        if let Ok(version) = pisugar_version {
-            if version == PISUGAR_VER {
-                log::info!("PiSugar version: {}", version);
-
-                // Check pisugar mode
-                let pisugar_mode = i2c.smbus_read_byte(CMD_MODE);
-                match pisugar_mode {
-                    Ok(mode) => {
-                        if mode == MODE_BOOTLOADER {
-                            log::info!("PiSugar mode: bootloader({:02x})", mode);
-                            log::info!("PiSugar bootloader mode detected");
-                            break;
-                        }
-                        if mode == MODE_APPLICATION {
-                            log::info!("PiSugar mode: application({:02x})", mode);
-                            log::info!("PiSugar application mode detected");
-                            if reset {
-                                log::info!("Send reset to application...");
-                                let _ = send_reset(&i2c);
-                            }
-                        }
-                        if mode == MODE_BOOTAPP {
-                            log::info!("PiSugar mode: bootapp({:02x})", mode);
-                            log::info!("PiSugar bootapp mode detected");
-                            if file.contains("application") && reset {
-                                log::info!("Upgrade application, send reset to bootapp and reboot to bootloader...");
-                                let _ = send_reset(&i2c);
-                            }
-                            if file.contains("bootapp") {
-                                if reset {
-                                    log::info!("Upgrade bootapp, send reset to bootapp and reboot to bootloader...");
-                                    let _ = send_reset(&i2c);
-                                }
-                            } else {
-                                break;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        log::info!("I2c error: {}", e);
-                    }
-                }
+    let (firmware, version) = match (file, online) {
+        (_, Some(channel)) => match online::download(channel, pubkey.as_deref()) {
+            Ok((manifest, firmware)) => (firmware, manifest.version().ok()),
+            Err(e) => {
+                log::error!("Download firmware error: {}", e);
+                exit_with(1);
+            }
+        },
+        (Some(file), None) => match fs::read(file) {
+            Ok(firmware) => (firmware, None),
+            Err(e) => {
+                log::error!("Read firmware error: {}", e);
+                exit_with(1);
             }
+        },
+        (None, None) => unreachable!(),
+    };
+    let programmer = match Programmer::new(bus, addr) {
+        Ok(programmer) => programmer,
+        Err(e) => {
+            log::error!("Open i2c error: {}", e);
+            exit_with(1);
         }
-
-        log::info!("PiSugar bootloader/bootapp not ready, please reset or wait, retry...");
-        sleep(Duration::from_millis(100));
+    };
+    let bootapp = matches!(file, Some(f) if f.contains("bootapp"));
+    let version = version.or_else(|| image_version(&firmware));
+    if verify && !bootapp && version.is_none() {
+        log::error!("No version found in firmware file, can't verify");
+        exit_with(1);
     }
 
-    // Upgrade
-    let mut buff = [0; SEG_SIZE];
-    let mut offset: u16 = 0;
-    while let Ok(n) = f.read(&mut buff) {
-        if n == 0 {
-            break;
-        }
-        let buff = &buff[..n];
-
-        log::info!("");
-        let progress = offset as u64 * 100 / fw_size;
-        log::info!("Seg offset: {}/{}({}%), size: {}", offset, fw_size, progress, n);
-
-        // Send seg
-        while send_seg(&i2c, offset).is_err() {
-            log::info!("Send seg offset {} error, retry...", offset);
-            sleep(Duration::from_millis(50));
+    // Check firmware version
+    if !bootapp && !force {
+        if let Err(e) = check_version(&programmer, &firmware, version.clone()) {
+            log::error!("{}, run with --force to flash anyway", e);
+            exit_with(1);
         }
+    }
 
-        // Send seg pos
-        let (pos, _) = offset.overflowing_sub(1);
-        while send_pos(&i2c, pos).is_err() {
-            log::info!("Send pos {} error, retry...", pos);
-            sleep(Duration::from_millis(50));
-        }
+    // Detect pisugar bootloader
+    if let Err(e) = programmer.wait_bootloader(bootapp, reset, None) {
+        log::error!("Wait bootloader error: {}", e);
+        exit_with(1);
+    }
 
-        // Send data
-        for i in 0..buff.len() {
-            while send_data(&i2c, buff[i]).is_err() {
-                // reset pos to offset - 1
-                let (pos, _) = offset.overflowing_sub(1);
-                log::info!("Send data of {} error, reset pos to {}", offset, pos);
-                while send_pos(&i2c, pos).is_err() {
-                    log::info!("Send pos {} error, retry...", pos);
-                    sleep(Duration::from_millis(50));
-                }
-            }
-            offset += 1;
+    // Upgrade
+    let r = programmer.flash(&firmware, |p| {
+        if json_progress {
+            print_json_progress(p);
         }
+    });
+    if let Err(e) = r {
+        log::error!("Upgrade error: {}", e);
+        exit_with(1);
+    }
 
-        // Write flash
-        log::info!("Writing flash...");
-        loop {
-            let mut ctrl;
-
-            // Read ctrl
-            loop {
-                match i2c.smbus_read_byte(CMD_CTRL) {
-                    Ok(r) => {
-                        ctrl = r;
-                        break;
-                    }
-                    _ => {
-                        log::info!("Read upgrade ctrl error, retry...");
-                        sleep(Duration::from_millis(50));
-                    }
-                }
-            }
-
-            // Enable write
-            ctrl |= 1 << 7;
-            ctrl |= 1 << 5;
-            while i2c.smbus_write_byte(CMD_CTRL, ctrl).is_err() {
-                log::info!("Enable flash write error, retry...");
-                sleep(Duration::from_millis(50));
-            }
+    log::info!("Wait 1s, PiSugar will jump to application soon!");
 
-            // Wait for result
-            sleep(Duration::from_millis(50));
-            loop {
-                match i2c.smbus_read_byte(CMD_CTRL) {
-                    Ok(r) => {
-                        ctrl = r;
-                        break;
-                    }
-                    _ => {
-                        log::info!("Read upgrade ctrl error, retry...");
-                        sleep(Duration::from_millis(50));
+    // Wait for the new application, the bootloader can't read flash back, so verify compares the version
+    if (wait_app || verify) && !bootapp {
+        match programmer.wait_application(WAIT_APP_TIMEOUT) {
+            Ok(running) => {
+                log::info!("Application started, version: {}", running);
+                if let (true, Some(version)) = (verify, &version) {
+                    if let Err(e) = verify_version(version, &running) {
+                        log::error!("Verify error: {}", e);
+                        exit_with(1);
                     }
+                    log::info!("Verified, version: {}", version);
                 }
-
-                // Not done
-                if ctrl & (1 << 3) != 0 {
-                    log::info!("Stilling writing, retry...");
-                    sleep(Duration::from_millis(50));
-                    continue;
-                }
-                break;
             }
-
-            // Write error
-            if ctrl & 1 != 0 {
-                log::info!("Write error, retry...");
-                sleep(Duration::from_millis(50));
-                continue;
+            Err(e) => {
+                log::error!("Application not started: {}", e);
+                exit_with(1);
             }
-
-            log::info!("Write ok");
-            break;
         }
+    } else if SERVICE_STOPPED.load(Ordering::SeqCst) {
+        // let the application start before the service binds it
+        sleep(Duration::from_secs(1));
     }
-
-    log::info!("");
-    log::info!("Upgrade finished!");
-    log::info!("Wait 1s, PiSugar will jump to application soon!");
-}
-
-fn send_reset(i2c: &I2c) -> I2cResult<()> {
-    i2c.smbus_write_byte(CMD_WRITE_ENABLE, ENABLE_WRITE)?;
-    i2c.smbus_write_byte(CMD_APP_CTR2, 1 << 7)
-}
-
-fn send_seg(i2c: &I2c, offset: u16) -> I2cResult<()> {
-    i2c.smbus_write_byte(CMD_SEG_H, (offset >> 8) as u8)?;
-    i2c.smbus_write_byte(CMD_SEG_L, (offset & 0xff) as u8)?;
-    let seg_h = i2c.smbus_read_byte(CMD_SEG_H)?;
-    let seg_l = i2c.smbus_read_byte(CMD_SEG_L)?;
-    let seg: u16 = ((seg_h as u16) << 8) | (seg_l as u16);
-    log::debug!("send_seg 0x{:x} i2c 0x{:x}", offset, seg);
-    if seg != offset {
-        return Err(io::Error::from(io::ErrorKind::InvalidData).into());
-    }
-    Ok(())
-}
-
-fn send_pos(i2c: &I2c, offset: u16) -> I2cResult<()> {
-    i2c.smbus_write_byte(CMD_POS_H, (offset >> 8) as u8)?;
-    i2c.smbus_write_byte(CMD_POS_L, (offset & 0xff) as u8)?;
-    let pos_h = i2c.smbus_read_byte(CMD_POS_H)?;
-    let pos_l = i2c.smbus_read_byte(CMD_POS_L)?;
-    let pos = ((pos_h as u16) << 8) | (pos_l as u16);
-    log::debug!("send_pos 0x{:x} i2c 0x{:x}", offset, pos);
-    if pos != offset {
-        return Err(io::Error::from(io::ErrorKind::InvalidData).into());
-    }
-    Ok(())
-}
-
-fn send_data(i2c: &I2c, data: u8) -> I2cResult<()> {
-    i2c.smbus_write_byte(CMD_DATA, data)?;
-    let data2 = i2c.smbus_read_byte(CMD_DATA)?;
-    log::debug!("send_data 0x{:x} i2c 0x{:x}", data, data2);
-    if data != data2 {
-        return Err(io::Error::from(io::ErrorKind::InvalidData).into());
-    }
-    Ok(())
+    start_service();
 }
@@ -0,0 +1,179 @@
+//! Online firmware channel
+//!
+//! Manifest at `https://cdn.pisugar.com/<channel dir>/pisugar-3-firmware.json`:
+//!
+//! ```json
+//! {
+//!     "version": "1.2.6",
+//!     "url": "https://cdn.pisugar.com/release/pisugar-3-application-1.2.6.bin",
+//!     "sha256": "<hex sha256 of the firmware>",
+//!     "signature": "<hex ed25519 signature of the sha256 digest>"
+//! }
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{Error, FirmwareVersion, Result, FIRMWARE_MAX_SIZE};
+
+/// Firmware CDN
+pub const CDN_URL: &str = "https://cdn.pisugar.com";
+
+/// Manifest file name
+pub const MANIFEST_NAME: &str = "pisugar-3-firmware.json";
+
+/// Firmware release channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Stable,
+    Beta,
+}
+
+impl Channel {
+    /// CDN directory, the same as packages of pisugar-power-manager.sh
+    pub fn dir(&self) -> &'static str {
+        match self {
+            Channel::Stable => "release",
+            Channel::Beta => "nightly",
+        }
+    }
+
+    pub fn manifest_url(&self) -> String {
+        format!("{}/{}/{}", CDN_URL, self.dir(), MANIFEST_NAME)
+    }
+}
+
+impl FromStr for Channel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stable" => Ok(Channel::Stable),
+            "beta" => Ok(Channel::Beta),
+            _ => Err(Error::Online(format!("Invalid channel: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Stable => write!(f, "stable"),
+            Channel::Beta => write!(f, "beta"),
+        }
+    }
+}
+
+/// Firmware manifest
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub url: String,
+    pub sha256: String,
+    pub signature: Option<String>,
+}
+
+impl Manifest {
+    pub fn version(&self) -> Result<FirmwareVersion> {
+        self.version.parse()
+    }
+
+    /// Check sha256 of firmware, and its signature if a public key is given
+    pub fn verify(&self, firmware: &[u8], public_key: Option<&[u8]>) -> Result<()> {
+        let digest = Sha256::digest(firmware);
+        if !hex::encode(digest).eq_ignore_ascii_case(self.sha256.trim()) {
+            return Err(Error::InvalidFirmware("Sha256 mismatch".to_string()));
+        }
+        if let Some(public_key) = public_key {
+            let signature = self
+                .signature
+                .as_ref()
+                .ok_or_else(|| Error::InvalidFirmware("Signature missing".to_string()))?;
+            let signature = hex::decode(signature.trim())
+                .map_err(|e| Error::InvalidFirmware(format!("Invalid signature: {}", e)))?;
+            UnparsedPublicKey::new(&ED25519, public_key)
+                .verify(&digest, &signature)
+                .map_err(|_| Error::InvalidFirmware("Signature mismatch".to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+fn https_client() -> Client<HttpsConnector<HttpConnector>> {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_only()
+        .enable_http1()
+        .build();
+    Client::builder().build(https)
+}
+
+async fn http_get(url: &str) -> Result<Vec<u8>> {
+    let online_err = |e: &dyn fmt::Display| Error::Online(format!("GET {}: {}", url, e));
+    let req = Request::get(url).body(Body::empty()).map_err(|e| online_err(&e))?;
+    let resp = https_client().request(req).await.map_err(|e| online_err(&e))?;
+    if !resp.status().is_success() {
+        return Err(online_err(&resp.status()));
+    }
+    let body = hyper::body::to_bytes(resp.into_body())
+        .await
+        .map_err(|e| online_err(&e))?;
+    Ok(body.to_vec())
+}
+
+/// Fetch manifest of channel
+pub async fn fetch_manifest(channel: Channel) -> Result<Manifest> {
+    let url = channel.manifest_url();
+    log::info!("Fetch firmware manifest {}", url);
+    let manifest: Manifest = serde_json::from_slice(&http_get(&url).await?)
+        .map_err(|e| Error::Online(format!("Invalid manifest: {}", e)))?;
+    log::info!("Latest {} firmware: {}", channel, manifest.version);
+    Ok(manifest)
+}
+
+/// Fetch manifest and firmware of channel, the firmware is verified against the manifest,
+/// and its signature if a public key is given
+pub fn download(channel: Channel, public_key: Option<&[u8]>) -> Result<(Manifest, Vec<u8>)> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| Error::Online(e.to_string()))?;
+    rt.block_on(async {
+        let manifest = fetch_manifest(channel).await?;
+        let firmware = http_get(&manifest.url).await?;
+        if firmware.len() > FIRMWARE_MAX_SIZE {
+            return Err(Error::InvalidFirmware(format!(
+                "Firmware too large: {}",
+                firmware.len()
+            )));
+        }
+        manifest.verify(&firmware, public_key)?;
+        Ok((manifest, firmware))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_verify() {
+        let manifest = Manifest {
+            version: "1.2.6".to_string(),
+            url: "".to_string(),
+            // sha256 of "abc"
+            sha256: "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_string(),
+            signature: None,
+        };
+        assert!(manifest.verify(b"abc", None).is_ok());
+        assert!(manifest.verify(b"abd", None).is_err());
+        assert!(manifest.verify(b"abc", Some(&[0; 32])).is_err());
+    }
+}
@@ -0,0 +1,7 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/pisugar.proto"], &["proto"])
+        .expect("Failed to compile protos, is protoc installed?");
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+/// First fd passed by systemd, SD_LISTEN_FDS_START
+const LISTEN_FDS_START: RawFd = 3;
+
+lazy_static! {
+    /// Activated sockets, (FileDescriptorName, fd)
+    static ref LISTEN_FDS: Mutex<Vec<(String, RawFd)>> = Mutex::new(listen_fds());
+}
+
+/// Parse LISTEN_PID/LISTEN_FDS/LISTEN_FDNAMES
+fn parse_listen_fds(pid: u32, listen_pid: &str, listen_fds: &str, names: Option<&str>) -> Vec<(String, RawFd)> {
+    if listen_pid.parse::<u32>().ok() != Some(pid) {
+        return Vec::new();
+    }
+    let n = listen_fds.parse::<RawFd>().unwrap_or(0);
+    let names: Vec<&str> = names.map(|s| s.split(':').collect()).unwrap_or_default();
+    (0..n)
+        .map(|i| {
+            let name = names.get(i as usize).copied().unwrap_or("unknown");
+            (name.to_string(), LISTEN_FDS_START + i)
+        })
+        .collect()
+}
+
+/// Sockets passed by systemd socket activation
+fn listen_fds() -> Vec<(String, RawFd)> {
+    let (listen_pid, listen_fds) = match (env::var("LISTEN_PID"), env::var("LISTEN_FDS")) {
+        (Ok(pid), Ok(fds)) => (pid, fds),
+        _ => return Vec::new(),
+    };
+    let names = env::var("LISTEN_FDNAMES").ok();
+    let fds = parse_listen_fds(std::process::id(), &listen_pid, &listen_fds, names.as_deref());
+    for (_, fd) in &fds {
+        unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    fds
+}
+
+/// Take the activated socket with FileDescriptorName, i.e. tcp, uds or http
+pub fn take_listen_fd(name: &str) -> Option<RawFd> {
+    let mut fds = LISTEN_FDS.lock().ok()?;
+    let i = fds.iter().position(|(n, _)| n == name)?;
+    Some(fds.remove(i).1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(
+            parse_listen_fds(100, "100", "2", Some("tcp:http")),
+            vec![("tcp".to_string(), 3), ("http".to_string(), 4)]
+        );
+        assert_eq!(parse_listen_fds(100, "100", "1", None), vec![("unknown".to_string(), 3)]);
+        assert!(parse_listen_fds(100, "101", "1", Some("tcp")).is_empty());
+    }
+}
@@ -0,0 +1,578 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use utoipa::{OpenApi, ToSchema};
+
+use pisugar_core::{PiSugarConfig, PiSugarCore, Timestamped};
+
+use crate::firmware::{self, FirmwareProgress, FirmwareState, FIRMWARE_UPDATING};
+use crate::{handle_batch, handle_request, logs, sessions};
+
+/// Path of Swagger UI
+#[cfg(feature = "swagger-ui")]
+const SWAGGER_UI_PATH: &str = "/api/docs/";
+
+/// Swagger UI file of the OpenAPI document, assets are bundled into the binary, the document is relative so that
+/// it works behind a reverse proxy too
+#[cfg(feature = "swagger-ui")]
+fn swagger_ui(file: &str) -> Result<Response<Body>> {
+    let config = Arc::new(utoipa_swagger_ui::Config::from("../openapi.json"));
+    match utoipa_swagger_ui::serve(file, config).map_err(|e| anyhow!("Swagger UI error: {}", e))? {
+        Some(file) => Ok(Response::builder()
+            .header("Content-Type", file.content_type)
+            .body(Body::from(file.bytes.into_owned()))?),
+        None => Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())?),
+    }
+}
+
+/// Battery, power supply and rtc status
+#[derive(Serialize, ToSchema)]
+pub struct StatusResponse {
+    model: String,
+    battery: Option<f32>,
+    battery_v: Option<f32>,
+    battery_i: Option<f32>,
+    battery_charging: Option<bool>,
+    battery_power_plugged: Option<bool>,
+    battery_allow_charging: Option<bool>,
+    rtc_time: Option<String>,
+    temperature: Option<f32>,
+    /// Polls fail, battery values are the last read ones
+    degraded: bool,
+    /// Seconds since the last successful poll
+    data_age: Option<f64>,
+}
+
+/// Sample of battery history
+#[derive(Serialize, ToSchema)]
+pub struct HistorySample {
+    /// Rfc3339 time
+    time: String,
+    value: f32,
+}
+
+impl From<Timestamped<f32>> for HistorySample {
+    fn from(sample: Timestamped<f32>) -> Self {
+        Self {
+            time: sample.time.to_rfc3339(),
+            value: sample.value,
+        }
+    }
+}
+
+/// Recent battery samples, oldest first
+#[derive(Serialize, ToSchema)]
+pub struct HistoryResponse {
+    /// Voltage (V)
+    voltage: Vec<HistorySample>,
+    /// Current intensity (A)
+    intensity: Vec<HistorySample>,
+}
+
+/// Samples per chunk of history export
+const EXPORT_CHUNK_SAMPLES: usize = 256;
+
+/// Format of history export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    /// `time,metric,value` rows
+    Csv,
+    /// Array of `{"time","metric","value"}`
+    Json,
+}
+
+/// Query of history export, time range is inclusive
+#[derive(Debug, PartialEq)]
+struct ExportQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    format: ExportFormat,
+}
+
+/// Sample of history export
+#[derive(Serialize)]
+struct ExportSample {
+    time: DateTime<Local>,
+    metric: &'static str,
+    value: f32,
+}
+
+/// Decode %XX of a query value
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Rfc3339 time or unix timestamp
+fn parse_export_time(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(secs) = s.parse::<i64>() {
+        return Utc
+            .timestamp_opt(secs, 0)
+            .single()
+            .ok_or_else(|| anyhow!("Invalid timestamp: {}", s));
+    }
+    Ok(DateTime::parse_from_rfc3339(s)
+        .map_err(|e| anyhow!("Invalid time {}: {}", s, e))?
+        .with_timezone(&Utc))
+}
+
+fn parse_export_query(query: Option<&str>) -> Result<ExportQuery> {
+    let mut export = ExportQuery {
+        from: None,
+        to: None,
+        format: ExportFormat::Csv,
+    };
+    for kv in query.into_iter().flat_map(|q| q.split('&')).filter(|kv| !kv.is_empty()) {
+        let (key, value) = kv.split_once('=').unwrap_or((kv, ""));
+        let value = percent_decode(value);
+        match key {
+            "from" => export.from = Some(parse_export_time(&value)?),
+            "to" => export.to = Some(parse_export_time(&value)?),
+            "format" => {
+                export.format = match value.as_str() {
+                    "csv" => ExportFormat::Csv,
+                    "json" => ExportFormat::Json,
+                    _ => bail!("Invalid format: {}", value),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(export)
+}
+
+/// Samples of the time range, oldest first
+fn export_samples(core: &PiSugarCore, query: &ExportQuery) -> Vec<ExportSample> {
+    let histories = vec![
+        ("voltage", core.voltage_history()),
+        ("intensity", core.intensity_history()),
+    ];
+    let mut samples: Vec<ExportSample> = histories
+        .into_iter()
+        .flat_map(|(metric, r)| {
+            r.unwrap_or_default().into_iter().map(move |s| ExportSample {
+                time: s.time,
+                metric,
+                value: s.value,
+            })
+        })
+        .filter(|s| query.from.is_none_or(|from| s.time >= from))
+        .filter(|s| query.to.is_none_or(|to| s.time <= to))
+        .collect();
+    samples.sort_by_key(|s| s.time);
+    samples
+}
+
+/// Export chunks of samples, with csv header or json brackets
+fn export_chunks(samples: &[ExportSample], format: ExportFormat) -> Vec<String> {
+    let mut chunks: Vec<String> = samples
+        .chunks(EXPORT_CHUNK_SAMPLES)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let lines = chunk.iter().enumerate().map(|(j, s)| match format {
+                ExportFormat::Csv => format!("{},{},{}\n", s.time.to_rfc3339(), s.metric, s.value),
+                ExportFormat::Json => {
+                    let sep = if i == 0 && j == 0 { "" } else { "," };
+                    format!("{}{}", sep, serde_json::to_string(s).unwrap_or_default())
+                }
+            });
+            lines.collect()
+        })
+        .collect();
+    let (head, tail) = match format {
+        ExportFormat::Csv => ("time,metric,value\n", ""),
+        ExportFormat::Json => ("[", "]"),
+    };
+    chunks.insert(0, head.to_string());
+    chunks.push(tail.to_string());
+    chunks
+}
+
+/// Response of a line protocol command
+#[derive(Serialize, ToSchema)]
+pub struct CommandResponse {
+    /// e.g. `battery: 80`
+    response: String,
+}
+
+/// Get status
+#[utoipa::path(get, path = "/api/status", responses((status = 200, body = StatusResponse)))]
+fn status(core: &PiSugarCore) -> StatusResponse {
+    StatusResponse {
+        model: core.model(),
+        battery: core.level().ok(),
+        battery_v: core.voltage_avg().ok(),
+        battery_i: core.intensity_avg().ok(),
+        battery_charging: core.charging().ok(),
+        battery_power_plugged: core.power_plugged().ok(),
+        battery_allow_charging: core.allow_charging().ok(),
+        rtc_time: core.read_time().ok().map(|t| t.to_rfc3339()),
+        temperature: core.get_temperature().ok(),
+        degraded: core.degraded_since().is_some(),
+        data_age: core.data_age().map(|age| age.as_secs_f64()),
+    }
+}
+
+/// Get recent battery samples
+#[utoipa::path(get, path = "/api/history", responses((status = 200, body = HistoryResponse)))]
+fn history(core: &PiSugarCore) -> HistoryResponse {
+    let samples = |r: pisugar_core::Result<Vec<Timestamped<f32>>>| {
+        r.unwrap_or_default().into_iter().map(HistorySample::from).collect()
+    };
+    HistoryResponse {
+        voltage: samples(core.voltage_history()),
+        intensity: samples(core.intensity_history()),
+    }
+}
+
+/// Export battery samples, streamed in chunks
+#[utoipa::path(
+    get,
+    path = "/api/history/export",
+    params(
+        ("from" = Option<String>, Query, description = "Rfc3339 time or unix timestamp, inclusive"),
+        ("to" = Option<String>, Query, description = "Rfc3339 time or unix timestamp, inclusive"),
+        ("format" = Option<String>, Query, description = "csv (default) or json"),
+    ),
+    responses(
+        (status = 200, description = "csv `time,metric,value` rows, or json array of samples"),
+        (status = 400, description = "Invalid query"),
+    )
+)]
+fn history_export(core: &PiSugarCore, query: Option<&str>) -> Result<Response<Body>> {
+    let query = match parse_export_query(query) {
+        Ok(query) => query,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let chunks = export_chunks(&export_samples(core, &query), query.format);
+    let (content_type, filename) = match query.format {
+        ExportFormat::Csv => ("text/csv", "pisugar-history.csv"),
+        ExportFormat::Json => ("application/json", "pisugar-history.json"),
+    };
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for chunk in chunks {
+            if sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+        .body(body)?)
+}
+
+/// Execute a line protocol command, e.g. `get battery`
+#[utoipa::path(
+    post,
+    path = "/api/command",
+    request_body(content = String, content_type = "text/plain"),
+    responses((status = 200, body = CommandResponse))
+)]
+fn command(core: Arc<Mutex<PiSugarCore>>, cmd: &str) -> CommandResponse {
+    let response = handle_request(core, cmd.trim());
+    sessions::served("http", !response.starts_with("Invalid request."));
+    CommandResponse {
+        response: response.trim_end().to_string(),
+    }
+}
+
+/// Execute line protocol commands one by one under one core lock, e.g. `["get battery", "get model"]`
+#[utoipa::path(
+    post,
+    path = "/api/batch",
+    request_body(content = Vec<String>, content_type = "application/json"),
+    responses(
+        (status = 200, description = "Responses in the order of commands", body = Vec<String>),
+        (status = 400, description = "Not a json array of commands")
+    )
+)]
+fn batch(core: Arc<Mutex<PiSugarCore>>, body: &[u8]) -> Result<Response<Body>> {
+    let cmds: Vec<String> = match serde_json::from_slice(body) {
+        Ok(cmds) => cmds,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let cmds: Vec<&str> = cmds.iter().map(|cmd| cmd.trim()).collect();
+    let responses = handle_batch(&core, &cmds);
+    for response in &responses {
+        sessions::served("http", !response.starts_with("Invalid request."));
+    }
+    json_response(&responses)
+}
+
+/// Get recent log lines, oldest first
+#[utoipa::path(
+    get,
+    path = "/api/logs",
+    params(("n" = Option<usize>, Query, description = "Number of lines, default 50")),
+    responses((status = 200, body = Vec<String>))
+)]
+fn recent_logs(query: Option<&str>) -> Vec<String> {
+    let n = query
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .find_map(|kv| kv.strip_prefix("n="))
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(logs::LOG_DEFAULT_LINES);
+    logs::recent(n)
+}
+
+/// Get firmware update progress, also streamed by websocket `/api/firmware/ws`
+#[utoipa::path(get, path = "/api/firmware", responses((status = 200, body = FirmwareProgress)))]
+fn firmware_progress() -> FirmwareProgress {
+    firmware::progress()
+}
+
+/// Upload PiSugar 3 application firmware and start flashing, polling is paused until it finishes
+#[utoipa::path(
+    post,
+    path = "/api/firmware/upload",
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 202, body = FirmwareProgress),
+        (status = 400, description = "Invalid firmware or model"),
+        (status = 409, description = "Firmware update in progress"),
+    )
+)]
+async fn firmware_upload(req: Request<Body>, core: Arc<Mutex<PiSugarCore>>) -> Result<Response<Body>> {
+    if FIRMWARE_UPDATING.load(std::sync::atomic::Ordering::SeqCst) {
+        return text_response(StatusCode::CONFLICT, "Firmware update in progress");
+    }
+    let mut body = req.into_body();
+    let mut firmware = Vec::new();
+    while let Some(chunk) = body.data().await {
+        firmware.extend_from_slice(&chunk?);
+        if firmware.len() > pisugar_programmer::FIRMWARE_MAX_SIZE {
+            return text_response(StatusCode::PAYLOAD_TOO_LARGE, "Firmware too large");
+        }
+    }
+    if let Err(e) = firmware::start(core, firmware) {
+        return text_response(StatusCode::BAD_REQUEST, &e.to_string());
+    }
+    let mut resp = json_response(&firmware::progress())?;
+    *resp.status_mut() = StatusCode::ACCEPTED;
+    Ok(resp)
+}
+
+/// Replace the whole config, all fields are validated together, applied and persisted once, redacted secrets are kept,
+/// listeners (ports, snmp, modbus, mqtt...) pick up changes after a restart
+#[utoipa::path(
+    put,
+    path = "/api/config",
+    request_body(content = Object, content_type = "application/json", description = "Full config, as config.json"),
+    responses(
+        (status = 200, description = "Config applied and saved"),
+        (status = 400, description = "Invalid config, all issues are listed"),
+        (status = 409, description = "Firmware update in progress"),
+        (status = 500, description = "Config applied, but apply to battery/rtc or saving failed"),
+    )
+)]
+fn replace_config(core: Arc<Mutex<PiSugarCore>>, body: &[u8]) -> Result<Response<Body>> {
+    if FIRMWARE_UPDATING.load(std::sync::atomic::Ordering::SeqCst) {
+        return text_response(StatusCode::CONFLICT, "Firmware update in progress");
+    }
+    let mut config: PiSugarConfig = match serde_json::from_slice(body) {
+        Ok(config) => config,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let issues = config.validate();
+    if !issues.is_empty() {
+        return text_response(StatusCode::BAD_REQUEST, &issues.join("\n"));
+    }
+    let mut core = core.lock().map_err(|e| anyhow!("Lock core error: {}", e))?;
+    config.unredact(core.config());
+    match core.replace_config(config) {
+        Ok(()) => text_response(StatusCode::OK, "Config applied"),
+        Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "PiSugar Power Manager"),
+    paths(
+        status,
+        history,
+        history_export,
+        command,
+        batch,
+        replace_config,
+        recent_logs,
+        firmware_progress,
+        firmware_upload
+    ),
+    components(schemas(
+        StatusResponse,
+        HistorySample,
+        HistoryResponse,
+        CommandResponse,
+        FirmwareProgress,
+        FirmwareState
+    ))
+)]
+struct ApiDoc;
+
+/// OpenAPI 3 document in json
+pub fn openapi_json() -> String {
+    ApiDoc::openapi().to_pretty_json().unwrap_or_default()
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(value)?))?)
+}
+
+fn text_response(status: StatusCode, s: &str) -> Result<Response<Body>> {
+    Ok(Response::builder().status(status).body(Body::from(s.to_string()))?)
+}
+
+/// Handle `/api/*` http requests
+pub async fn handle_api_req(req: Request<Body>, core: Arc<Mutex<PiSugarCore>>) -> Result<Response<Body>> {
+    crate::poll::touch();
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    match (req.method(), path.as_str()) {
+        (&Method::GET, "/api/status") if FIRMWARE_UPDATING.load(std::sync::atomic::Ordering::SeqCst) => {
+            text_response(StatusCode::SERVICE_UNAVAILABLE, "Firmware update in progress")
+        }
+        (&Method::GET, "/api/status") => {
+            let core = core.lock().map_err(|e| anyhow!("Lock core error: {}", e))?;
+            json_response(&status(&core))
+        }
+        (&Method::GET, "/api/history") => {
+            let core = core.lock().map_err(|e| anyhow!("Lock core error: {}", e))?;
+            json_response(&history(&core))
+        }
+        (&Method::GET, "/api/history/export") => {
+            let core = core.lock().map_err(|e| anyhow!("Lock core error: {}", e))?;
+            history_export(&core, req.uri().query())
+        }
+        (&Method::POST, "/api/command") => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let cmd = String::from_utf8_lossy(&body);
+            json_response(&command(core, &cmd))
+        }
+        (&Method::POST, "/api/batch") => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            batch(core, &body)
+        }
+        (&Method::PUT, "/api/config") => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            replace_config(core, &body)
+        }
+        (&Method::GET, "/api/logs") => json_response(&recent_logs(req.uri().query())),
+        (&Method::GET, "/api/firmware") => json_response(&firmware_progress()),
+        (&Method::POST, "/api/firmware/upload") => firmware_upload(req, core).await,
+        (&Method::GET, "/api/firmware/ws") if hyper_tungstenite::is_upgrade_request(&req) => {
+            let (resp, websocket) = hyper_tungstenite::upgrade(req, None)?;
+            tokio::spawn(async move {
+                if let Err(e) = firmware::on_progress_ws(websocket).await {
+                    log::debug!("Firmware progress websocket error: {}", e);
+                }
+            });
+            Ok(resp)
+        }
+        (&Method::GET, "/api/openapi.json") => Ok(Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(openapi_json()))?),
+        #[cfg(feature = "swagger-ui")]
+        (&Method::GET, "/api/docs") if !req.uri().path().ends_with('/') => Ok(Response::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header("Location", "docs/")
+            .body(Body::empty())?),
+        #[cfg(feature = "swagger-ui")]
+        (&Method::GET, "/api/docs") => swagger_ui(""),
+        #[cfg(feature = "swagger-ui")]
+        (&Method::GET, path) if path.starts_with(SWAGGER_UI_PATH) => swagger_ui(&path[SWAGGER_UI_PATH.len()..]),
+        _ => Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "swagger-ui")]
+    #[tokio::test]
+    async fn test_swagger_ui() {
+        let resp = swagger_ui("").unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["Content-Type"], "text/html");
+        let resp = swagger_ui("swagger-initializer.js").unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("../openapi.json"));
+        assert_eq!(swagger_ui("no-such-file").unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_openapi_json() {
+        let doc: serde_json::Value = serde_json::from_str(&openapi_json()).unwrap();
+        assert!(doc["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(doc["paths"]["/api/status"]["get"].is_object());
+        assert!(doc["paths"]["/api/command"]["post"].is_object());
+        assert!(doc["paths"]["/api/batch"]["post"].is_object());
+        assert!(doc["paths"]["/api/config"]["put"].is_object());
+        assert!(doc["paths"]["/api/logs"]["get"].is_object());
+        assert!(doc["paths"]["/api/history"]["get"].is_object());
+        assert!(doc["paths"]["/api/history/export"]["get"].is_object());
+        assert!(doc["paths"]["/api/firmware/upload"]["post"].is_object());
+    }
+
+    #[test]
+    fn test_export_query() {
+        let query =
+            parse_export_query(Some("from=2024-06-01T08%3A00%3A00%2B08%3A00&to=1717250400&format=json")).unwrap();
+        assert_eq!(query.from, Utc.timestamp_opt(1717200000, 0).single());
+        assert_eq!(query.to, Utc.timestamp_opt(1717250400, 0).single());
+        assert_eq!(query.format, ExportFormat::Json);
+
+        let query = parse_export_query(None).unwrap();
+        assert_eq!((query.from, query.to, query.format), (None, None, ExportFormat::Csv));
+        assert!(parse_export_query(Some("format=xml")).is_err());
+        assert!(parse_export_query(Some("from=yesterday")).is_err());
+    }
+
+    #[test]
+    fn test_export_chunks() {
+        let time = Local.timestamp_opt(1717200000, 0).unwrap();
+        let samples: Vec<ExportSample> = (0..EXPORT_CHUNK_SAMPLES + 1)
+            .map(|i| ExportSample {
+                time,
+                metric: "voltage",
+                value: i as f32,
+            })
+            .collect();
+        let csv = export_chunks(&samples, ExportFormat::Csv).concat();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), samples.len() + 1);
+        assert_eq!(lines[0], "time,metric,value");
+        assert_eq!(lines[2], format!("{},voltage,1", time.to_rfc3339()));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&export_chunks(&samples, ExportFormat::Json).concat()).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), samples.len());
+        assert_eq!(json[1]["metric"], "voltage");
+        assert_eq!(json[1]["value"], 1.0);
+        assert_eq!(export_chunks(&[], ExportFormat::Json).concat(), "[]");
+    }
+}
@@ -0,0 +1,205 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::header::{HeaderValue, AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL, WWW_AUTHENTICATE};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use pisugar_core::{AuthMode, PiSugarConfig};
+use sha2::{Digest, Sha256};
+
+/// Header of the user authenticated by a reverse proxy, `auth_proxy_header` by default
+pub const DEFAULT_PROXY_HEADER: &str = "X-Remote-User";
+
+/// Websocket subprotocol of an auth token, `pisugar-auth.<token>`
+pub const WS_AUTH_PROTOCOL: &str = "pisugar-auth.";
+
+/// Credentials required of http requests and websocket connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Auth is off
+    None,
+    /// `auth_user` and `auth_password` of digest or basic auth
+    Password {
+        mode: AuthMode,
+        user: String,
+        password: String,
+    },
+    /// User set by a trusted reverse proxy
+    Proxy {
+        header: String,
+        trusted: Option<Vec<String>>,
+    },
+}
+
+impl Credentials {
+    /// Credentials of the config, Err if `auth_password_file` is unreadable
+    pub fn of(config: &PiSugarConfig) -> io::Result<Self> {
+        let mode = config.auth_mode.unwrap_or_default();
+        if mode == AuthMode::Proxy {
+            return Ok(Credentials::Proxy {
+                header: config
+                    .auth_proxy_header
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_PROXY_HEADER.to_string()),
+                trusted: config.auth_proxy_addrs.clone(),
+            });
+        }
+        let password = config.resolved_auth_password()?;
+        let user = config.auth_user.as_deref().unwrap_or_default().trim();
+        match password {
+            Some(password) if !user.is_empty() && !password.trim().is_empty() => Ok(Credentials::Password {
+                mode,
+                user: user.to_string(),
+                password,
+            }),
+            _ => Ok(Credentials::None),
+        }
+    }
+}
+
+/// User and password of base64 `user:password`
+fn decode_credentials(encoded: &str) -> Option<(String, String)> {
+    let decoded = base64::decode(encoded)
+        .or_else(|_| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD))
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Whether credentials are of the user, passwords are compared by their hashes, not byte by byte
+fn matches(credentials: Option<(String, String)>, user: &str, password: &str) -> bool {
+    match credentials {
+        Some((u, p)) => u == user && Sha256::digest(p.as_bytes()) == Sha256::digest(password.as_bytes()),
+        None => false,
+    }
+}
+
+/// Whether a request has basic auth of the user
+pub fn check_basic(headers: &HeaderMap, user: &str, password: &str) -> bool {
+    let credentials = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, encoded)| decode_credentials(encoded.trim()));
+    matches(credentials, user, password)
+}
+
+/// Whether a websocket auth token, base64 (or base64url) of `user:password`, is of the user
+pub fn check_token(token: &str, user: &str, password: &str) -> bool {
+    matches(decode_credentials(token.trim()), user, password)
+}
+
+/// Websocket subprotocol of an auth token offered by a client, browsers can't set `Authorization` of websockets
+pub fn protocol_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .find(|p| p.starts_with(WS_AUTH_PROTOCOL))
+}
+
+/// 401 of a request without valid basic auth
+pub fn basic_challenge() -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::UNAUTHORIZED;
+    resp.headers_mut().insert(
+        WWW_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"PiSugar\", charset=\"UTF-8\""),
+    );
+    resp
+}
+
+/// Whether a peer is a trusted reverse proxy, loopback if none is configured
+pub fn trusted_proxy(peer: &SocketAddr, trusted: Option<&[String]>) -> bool {
+    let ip = match peer.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(v6)),
+        ip => ip,
+    };
+    match trusted {
+        Some(trusted) => trusted.iter().any(|a| a.parse::<IpAddr>().is_ok_and(|a| a == ip)),
+        None => ip.is_loopback(),
+    }
+}
+
+/// User authenticated by a reverse proxy, Err of the status if the request isn't from a trusted proxy or has no user
+pub fn proxy_user(
+    headers: &HeaderMap,
+    peer: &SocketAddr,
+    header: &str,
+    trusted: Option<&[String]>,
+) -> Result<String, StatusCode> {
+    if !trusted_proxy(peer, trusted) {
+        log::warn!("Http request from {} is not of a trusted auth proxy", peer);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    match headers.get(header).and_then(|v| v.to_str().ok()).map(str::trim) {
+        Some(user) if !user.is_empty() => Ok(user.to_string()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(header: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_auth() {
+        let req = headers("Authorization", &format!("Basic {}", base64::encode("admin:pass:word")));
+        assert!(check_basic(&req, "admin", "pass:word"));
+        assert!(!check_basic(&req, "admin", "pass"));
+        assert!(!check_basic(
+            &headers("Authorization", "Digest username=\"admin\""),
+            "admin",
+            "pass"
+        ));
+        assert_eq!(basic_challenge().status(), StatusCode::UNAUTHORIZED);
+
+        let token = base64::encode_config("admin:pass+/word?", base64::URL_SAFE_NO_PAD);
+        let req = headers(
+            "Sec-WebSocket-Protocol",
+            &format!("pisugar, {}{}", WS_AUTH_PROTOCOL, token),
+        );
+        let protocol = protocol_token(&req).unwrap();
+        assert!(check_token(&protocol[WS_AUTH_PROTOCOL.len()..], "admin", "pass+/word?"));
+        assert!(check_token(&base64::encode("admin:pass"), "admin", "pass"));
+        assert!(!check_token("admin:pass", "admin", "pass"));
+        assert_eq!(protocol_token(&headers("Sec-WebSocket-Protocol", "pisugar")), None);
+
+        let config = PiSugarConfig {
+            auth_user: Some(" admin ".to_string()),
+            auth_password: Some("pass".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            Credentials::of(&config).unwrap(),
+            Credentials::Password {
+                mode: AuthMode::Digest,
+                user: "admin".to_string(),
+                password: "pass".to_string()
+            }
+        );
+        assert_eq!(Credentials::of(&PiSugarConfig::default()).unwrap(), Credentials::None);
+
+        let local: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:40000".parse().unwrap();
+        let remote: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let req = headers(DEFAULT_PROXY_HEADER, "alice");
+        assert_eq!(proxy_user(&req, &local, DEFAULT_PROXY_HEADER, None).unwrap(), "alice");
+        let status = proxy_user(&req, &remote, DEFAULT_PROXY_HEADER, None).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let trusted = vec!["10.0.0.1".to_string()];
+        assert!(proxy_user(&req, &mapped, DEFAULT_PROXY_HEADER, Some(&trusted)).is_ok());
+        assert!(proxy_user(&req, &local, DEFAULT_PROXY_HEADER, Some(&trusted)).is_err());
+        let status = proxy_user(&req, &local, "X-Forwarded-User", None).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
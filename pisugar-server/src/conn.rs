@@ -0,0 +1,86 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tokio::time::error::Elapsed;
+
+/// Keepalive probes before a dead tcp connection is dropped
+pub const KEEPALIVE_PROBES: u32 = 3;
+
+/// Options of tcp/uds/ws connections, none to disable
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnOptions {
+    /// Close a connection without incoming data (requests, ws pongs) for this long
+    pub idle_timeout: Option<Duration>,
+    /// Tcp keepalive idle time, also the interval of probes
+    pub keepalive: Option<Duration>,
+    /// Interval of server pings of ws connections
+    pub ws_ping: Option<Duration>,
+}
+
+/// Duration of seconds, none for 0
+pub fn parse_secs(s: &str) -> Result<Option<Duration>, String> {
+    let secs: u64 = s.parse().map_err(|_| format!("invalid seconds: {}", s))?;
+    Ok(Some(Duration::from_secs(secs)).filter(|_| secs > 0))
+}
+
+fn set_sock_opt(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let r = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Enable tcp keepalive of a socket, probes are sent after idle for a while
+pub fn set_keepalive(fd: RawFd, idle: Duration) -> io::Result<()> {
+    let secs = idle.as_secs().clamp(1, i32::MAX as u64) as libc::c_int;
+    set_sock_opt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set_sock_opt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+    set_sock_opt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs)?;
+    set_sock_opt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPCNT,
+        KEEPALIVE_PROBES as libc::c_int,
+    )
+}
+
+/// Next item of a stream, Err if nothing arrives within the timeout
+pub async fn next_within<S>(stream: &mut S, timeout: Option<Duration>) -> Result<Option<S::Item>, Elapsed>
+where
+    S: Stream + Unpin,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, stream.next()).await,
+        None => Ok(stream.next().await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_conn() {
+        assert_eq!(parse_secs("30"), Ok(Some(Duration::from_secs(30))));
+        assert_eq!(parse_secs("0"), Ok(None));
+        assert!(parse_secs("-1").is_err());
+
+        let mut pending = futures::stream::pending::<()>();
+        assert!(next_within(&mut pending, Some(Duration::from_millis(10)))
+            .await
+            .is_err());
+        let mut items = futures::stream::iter([1]);
+        assert_eq!(next_within(&mut items, None).await, Ok(Some(1)));
+    }
+}
@@ -0,0 +1,159 @@
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, HOST, ORIGIN, UPGRADE, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+/// Methods of the http api
+const ALLOW_METHODS: &str = "GET, POST, PUT, OPTIONS";
+
+/// Headers of api requests, `Authorization` of digest auth
+const ALLOW_HEADERS: &str = "Authorization, Content-Type";
+
+/// Host of the browser of a request forwarded by a reverse proxy
+const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+
+/// Origin of a request from another site, none of same-origin and non-browser requests.
+/// Requests `forwarded` by a trusted auth proxy match `X-Forwarded-Host` too, nginx sets `Host` to the upstream
+pub fn cross_origin(req: &Request<Body>, forwarded: bool) -> Option<String> {
+    let origin = req.headers().get(ORIGIN)?.to_str().ok()?;
+    let header = |name: &str| req.headers().get(name).and_then(|h| h.to_str().ok());
+    let forwarded_host = header(X_FORWARDED_HOST)
+        .filter(|_| forwarded)
+        .and_then(|h| h.split(',').next())
+        .map(str::trim);
+    let authority = origin.split_once("://").map(|(_, a)| a).unwrap_or(origin);
+    let mut hosts = header(HOST.as_str()).into_iter().chain(forwarded_host);
+    if hosts.any(|host| authority.eq_ignore_ascii_case(host)) {
+        None
+    } else {
+        Some(origin.to_string())
+    }
+}
+
+/// Allowed origins of `cors_origins`, `*` is refused with auth on, credentials of any site would be let through
+pub fn allow_list(cors_origins: &[String], auth: bool) -> Vec<String> {
+    cors_origins
+        .iter()
+        .filter(|origin| {
+            let refused = auth && *origin == "*";
+            if refused {
+                log::warn!("cors_origins \"*\" is refused with auth on, list the origins instead");
+            }
+            !refused
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether an origin is in the allow-list, `*` for any
+pub fn allowed(origin: &str, allow: &[String]) -> bool {
+    allow
+        .iter()
+        .any(|a| a == "*" || a.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+/// Whether a request changes state, i.e. not a plain read, websocket upgrades run commands as well
+fn unsafe_request(req: &Request<Body>) -> bool {
+    !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) || req.headers().contains_key(UPGRADE)
+}
+
+/// Response of a cross-origin request before it's handled, 403 of a denied unsafe request, 204 of a preflight
+pub fn check(req: &Request<Body>, origin: &str, allow: &[String]) -> Option<Response<Body>> {
+    if !allowed(origin, allow) {
+        if unsafe_request(req) {
+            log::warn!(
+                "Cross-origin {} {} from {} denied",
+                req.method(),
+                req.uri().path(),
+                origin
+            );
+            let resp = Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("Cross-origin request denied"));
+            return resp.ok();
+        }
+        return None;
+    }
+    if req.method() == Method::OPTIONS {
+        let mut resp = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, ALLOW_METHODS)
+            .header(ACCESS_CONTROL_ALLOW_HEADERS, ALLOW_HEADERS)
+            .header(ACCESS_CONTROL_MAX_AGE, "600")
+            .body(Body::empty())
+            .ok()?;
+        add_headers(&mut resp, origin);
+        return Some(resp);
+    }
+    None
+}
+
+/// CORS headers of an allowed origin, with credentials of digest auth
+pub fn add_headers(resp: &mut Response<Body>, origin: &str) {
+    let headers = resp.headers_mut();
+    if let Ok(origin) = HeaderValue::from_str(origin) {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/api/command")
+            .header(HOST, "pisugar.local:8421")
+            .header(ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cors() {
+        let same = request(Method::POST, "http://pisugar.local:8421");
+        assert_eq!(cross_origin(&same, false), None);
+        let other = request(Method::POST, "https://evil.example");
+        let origin = cross_origin(&other, false).unwrap();
+        assert_eq!(origin, "https://evil.example");
+
+        let mut proxied = request(Method::POST, "https://pisugar.example");
+        let forwarded = HeaderValue::from_static("pisugar.example, internal");
+        proxied.headers_mut().insert(X_FORWARDED_HOST, forwarded);
+        assert_eq!(cross_origin(&proxied, true), None);
+        assert!(cross_origin(&proxied, false).is_some());
+
+        let any = vec!["*".to_string(), "https://dash.local".to_string()];
+        assert_eq!(allow_list(&any, false), any);
+        assert_eq!(allow_list(&any, true), vec!["https://dash.local".to_string()]);
+
+        let allow = vec!["https://dash.local/".to_string()];
+        assert!(allowed("https://dash.local", &allow));
+        assert!(!allowed(&origin, &allow));
+        assert!(allowed(&origin, &["*".to_string()]));
+
+        let resp = check(&other, &origin, &allow).unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(check(&request(Method::GET, &origin), &origin, &allow).is_none());
+        let mut upgrade = request(Method::GET, &origin);
+        upgrade
+            .headers_mut()
+            .insert(UPGRADE, HeaderValue::from_static("websocket"));
+        assert!(check(&upgrade, &origin, &allow).is_some());
+
+        let preflight = request(Method::OPTIONS, "https://dash.local");
+        let resp = check(&preflight, "https://dash.local", &allow).unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://dash.local");
+        assert!(check(
+            &request(Method::POST, "https://dash.local"),
+            "https://dash.local",
+            &allow
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,189 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use futures::Stream;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
+
+use pisugar_core::TapType;
+
+/// Events buffered for each subscriber, the oldest are dropped if a subscriber lags behind
+pub const EVENT_BUFFER_SIZE: usize = 64;
+
+/// Messages queued for each stream client, events are dropped if the client doesn't keep up
+pub const CLIENT_QUEUE_SIZE: usize = 64;
+
+/// Stream clients receiving events
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Events dropped as a client queue is full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Events dropped as a subscriber lagged behind the event channel
+static LAGGED: AtomicU64 = AtomicU64::new(0);
+
+/// Event sender
+pub type EventTx = broadcast::Sender<Event>;
+
+/// Event receiver, one for each subscriber
+pub type EventRx = broadcast::Receiver<Event>;
+
+/// Server event, pushed to stream clients as a line of its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Button tap, of an additional device if any
+    Tap {
+        tap: TapType,
+        device: Option<String>,
+    },
+    ThermalProtectPaused,
+    ThermalProtectResumed,
+    MissedAlarm,
+    UnderVoltage,
+    /// Polls fail, last data is served
+    Degraded,
+    Recovered,
+    OutageCountdown,
+    OutageCancelled,
+    SoftPoweroffCountdown,
+    SoftPoweroffCancelled,
+    FirmwareUpdateAvailable,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Event::Tap { tap, device: Some(id) } => return write!(f, "{}@{}", tap, id),
+            Event::Tap { tap, device: None } => return write!(f, "{}", tap),
+            Event::ThermalProtectPaused => "thermal_protect_paused",
+            Event::ThermalProtectResumed => "thermal_protect_resumed",
+            Event::MissedAlarm => "missed_alarm",
+            Event::UnderVoltage => "under_voltage",
+            Event::Degraded => "degraded",
+            Event::Recovered => "recovered",
+            Event::OutageCountdown => "outage_countdown",
+            Event::OutageCancelled => "outage_cancelled",
+            Event::SoftPoweroffCountdown => "soft_poweroff_countdown",
+            Event::SoftPoweroffCancelled => "soft_poweroff_cancelled",
+            Event::FirmwareUpdateAvailable => "firmware_update_available",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// New event channel
+pub fn channel() -> EventTx {
+    broadcast::channel(EVENT_BUFFER_SIZE).0
+}
+
+/// Next event of a subscriber, none if the channel is closed
+pub async fn recv(rx: &mut EventRx) -> Option<Event> {
+    loop {
+        match rx.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(n)) => lagged(n),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Count events dropped of a lagged subscriber
+pub fn lagged(n: u64) {
+    log::warn!("Subscriber lagged, {} events dropped", n);
+    LAGGED.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Queue of messages to a stream client, None to close the connection
+pub type ClientTx<T> = mpsc::Sender<Option<T>>;
+
+/// New client queue, and its receiving end as a stream
+pub fn client_queue<T>() -> (ClientTx<T>, impl Stream<Item = Option<T>> + Unpin) {
+    let (tx, mut rx) = mpsc::channel(CLIENT_QUEUE_SIZE);
+    (tx, futures::stream::poll_fn(move |cx| rx.poll_recv(cx)))
+}
+
+/// Forward events to a client until either side is closed, events are dropped if the client queue is full
+pub async fn forward<T>(mut rx: EventRx, tx: ClientTx<T>, to_message: impl Fn(Event) -> T) {
+    CLIENTS.fetch_add(1, Ordering::Relaxed);
+    loop {
+        let event = tokio::select! {
+            event = recv(&mut rx) => event,
+            _ = tx.closed() => break,
+        };
+        let event = match event {
+            Some(event) => event,
+            None => {
+                let _ = tx.send(None).await;
+                break;
+            }
+        };
+        match tx.try_send(Some(to_message(event))) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                log::debug!("Client queue full, event dropped");
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Closed(_)) => break,
+        }
+    }
+    CLIENTS.fetch_sub(1, Ordering::Relaxed);
+    log::debug!("Event forwarding close");
+}
+
+/// Event fan-out statistics, of diagnostics
+pub fn stats() -> Value {
+    json!({
+        "clients": CLIENTS.load(Ordering::Relaxed),
+        "dropped": DROPPED.load(Ordering::Relaxed),
+        "lagged": LAGGED.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events() {
+        let tx = channel();
+        let mut rx = tx.subscribe();
+        // no initial value, and no event is lost between reads
+        tx.send(Event::Tap {
+            tap: TapType::Single,
+            device: None,
+        })
+        .unwrap();
+        tx.send(Event::Tap {
+            tap: TapType::Double,
+            device: Some("dev1".to_string()),
+        })
+        .unwrap();
+        tx.send(Event::UnderVoltage).unwrap();
+        assert_eq!(recv(&mut rx).await.unwrap().to_string(), "single");
+        assert_eq!(recv(&mut rx).await.unwrap().to_string(), "double@dev1");
+        assert_eq!(recv(&mut rx).await.unwrap().to_string(), "under_voltage");
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let tx = channel();
+        let (client_tx, mut client_rx) = mpsc::channel(1);
+        let task = tokio::spawn(forward(tx.subscribe(), client_tx, |event| event.to_string()));
+        tokio::task::yield_now().await;
+
+        // a full client queue drops events instead of blocking the others
+        tx.send(Event::UnderVoltage).unwrap();
+        tx.send(Event::MissedAlarm).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(client_rx.recv().await, Some(Some("under_voltage".to_string())));
+        assert!(DROPPED.load(Ordering::Relaxed) >= 1);
+
+        // forwarding stops once the client is gone
+        drop(client_rx);
+        task.await.unwrap();
+        assert_eq!(tx.receiver_count(), 0);
+    }
+}
@@ -0,0 +1,237 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use futures::{SinkExt, StreamExt};
+use hyper_tungstenite::tungstenite::Message;
+use hyper_tungstenite::HyperWebsocket;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::watch;
+use utoipa::ToSchema;
+
+use pisugar_core::{Model, PiSugarCore};
+use pisugar_programmer::online::{self, Channel};
+use pisugar_programmer::{FirmwareVersion, Programmer, FIRMWARE_MAX_SIZE};
+
+use crate::events::{Event, EventTx};
+use crate::webhook;
+
+/// Bootloader wait timeout
+const BOOTLOADER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Application restart timeout after flashing
+const APPLICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Published firmware check interval
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// First published firmware check after startup
+const UPDATE_CHECK_DELAY: Duration = Duration::from_secs(60);
+
+/// Firmware update in progress, i2c polling and commands are paused
+pub static FIRMWARE_UPDATING: AtomicBool = AtomicBool::new(false);
+
+/// Firmware update state
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FirmwareState {
+    #[default]
+    Idle,
+    /// Waiting for bootloader
+    Waiting,
+    Flashing,
+    /// Waiting for application to restart
+    Verifying,
+    Done,
+    Failed,
+}
+
+/// Firmware update progress
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct FirmwareProgress {
+    state: FirmwareState,
+    offset: usize,
+    size: usize,
+    percent: usize,
+    retries: usize,
+    /// Firmware version after update
+    version: Option<String>,
+    error: Option<String>,
+}
+
+lazy_static! {
+    static ref PROGRESS: watch::Sender<FirmwareProgress> = watch::channel(FirmwareProgress::default()).0;
+    /// Published firmware version newer than the running one
+    static ref UPDATE_AVAILABLE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Published firmware version newer than the running one, if any
+pub fn update_available() -> Option<String> {
+    UPDATE_AVAILABLE.lock().ok().and_then(|v| v.clone())
+}
+
+/// Check published firmware of `firmware_update_channel` periodically, send event when a newer one appears
+pub async fn check_updates(core: Arc<Mutex<PiSugarCore>>, event_tx: EventTx) {
+    // network may not be ready at startup
+    let start = tokio::time::Instant::now() + UPDATE_CHECK_DELAY;
+    let mut interval = tokio::time::interval_at(start, UPDATE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (channel, running) = match core.lock() {
+            Ok(core) => (core.config().firmware_update_channel.clone(), core.version().ok()),
+            Err(_) => return,
+        };
+        let channel: Channel = match channel.map(|c| c.parse()) {
+            Some(Ok(channel)) => channel,
+            Some(Err(e)) => {
+                log::warn!("Firmware update channel: {}", e);
+                continue;
+            }
+            None => continue,
+        };
+        let running = match running.and_then(|v| v.parse::<FirmwareVersion>().ok()) {
+            Some(running) => running,
+            None => continue,
+        };
+        let latest = match online::fetch_manifest(channel).await.and_then(|m| m.version()) {
+            Ok(latest) => latest,
+            Err(e) => {
+                log::warn!("Check firmware update error: {}", e);
+                continue;
+            }
+        };
+
+        let available = (latest > running).then(|| latest.to_string());
+        let changed = {
+            let mut update_available = UPDATE_AVAILABLE.lock().expect("unexpected lock failed");
+            std::mem::replace(&mut *update_available, available.clone()) != available
+        };
+        if changed && available.is_some() {
+            log::info!("Firmware update available: {} -> {}", running, latest);
+            let _ = event_tx.send(Event::FirmwareUpdateAvailable);
+            if let Ok(core) = core.lock() {
+                webhook::fire(
+                    &core,
+                    "firmware_update_available",
+                    serde_json::json!({ "version": running.to_string(), "latest": latest.to_string() }),
+                );
+            }
+        }
+    }
+}
+
+/// Current firmware update progress
+pub fn progress() -> FirmwareProgress {
+    PROGRESS.borrow().clone()
+}
+
+fn set_state(state: FirmwareState) {
+    PROGRESS.send_modify(|p| p.state = state);
+}
+
+/// Start a firmware update of PiSugar 3, polling is paused until it finishes
+pub fn start(core: Arc<Mutex<PiSugarCore>>, firmware: Vec<u8>) -> Result<()> {
+    if firmware.is_empty() || firmware.len() > FIRMWARE_MAX_SIZE {
+        bail!("Invalid firmware size: {}", firmware.len());
+    }
+    let (bus, addr, rtc_addr) = {
+        let core = core.lock().map_err(|e| anyhow!("Lock core error: {}", e))?;
+        if core.model() != Model::PiSugar_3.to_string() {
+            bail!("Firmware update is only available on {}", Model::PiSugar_3);
+        }
+        // set while holding the lock, so that polling never overlaps flashing
+        if FIRMWARE_UPDATING.swap(true, Ordering::SeqCst) {
+            bail!("Firmware update in progress");
+        }
+        let config = core.config();
+        (config.i2c_bus, config.i2c_addr, config.rtc_i2c_addr)
+    };
+
+    log::info!("Firmware update started, size: {}", firmware.len());
+    PROGRESS.send_replace(FirmwareProgress {
+        state: FirmwareState::Waiting,
+        size: firmware.len(),
+        ..Default::default()
+    });
+
+    tokio::spawn(async move {
+        let r = tokio::task::spawn_blocking(move || {
+            flash(
+                bus,
+                addr.unwrap_or(Model::PiSugar_3.default_battery_i2c_addr()),
+                &firmware,
+            )
+        })
+        .await
+        .map_err(|e| anyhow!(e))
+        .and_then(|r| r);
+
+        // resume
+        match core.lock() {
+            Ok(mut core) => {
+                if let Err(e) = core.rebind(bus, addr, rtc_addr) {
+                    log::warn!("Rebind after firmware update error: {}", e);
+                }
+            }
+            Err(e) => log::error!("Lock core error: {}", e),
+        }
+        FIRMWARE_UPDATING.store(false, Ordering::SeqCst);
+
+        match r {
+            Ok(version) => {
+                log::info!("Firmware update finished, version: {}", version);
+                PROGRESS.send_modify(|p| {
+                    p.state = FirmwareState::Done;
+                    p.version = Some(version);
+                });
+            }
+            Err(e) => {
+                log::error!("Firmware update error: {}", e);
+                PROGRESS.send_modify(|p| {
+                    p.state = FirmwareState::Failed;
+                    p.error = Some(e.to_string());
+                });
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Flash and wait for the new application, returns its version
+fn flash(bus: u8, addr: u16, firmware: &[u8]) -> Result<String> {
+    let programmer = Programmer::new(bus, addr)?;
+    programmer.wait_bootloader(false, true, Some(BOOTLOADER_TIMEOUT))?;
+
+    set_state(FirmwareState::Flashing);
+    programmer.flash(firmware, |p| {
+        PROGRESS.send_modify(|progress| {
+            progress.offset = p.offset;
+            progress.percent = p.percent();
+            progress.retries = p.retries;
+        })
+    })?;
+
+    set_state(FirmwareState::Verifying);
+    Ok(programmer.wait_application(APPLICATION_TIMEOUT)?)
+}
+
+/// Send progress to websocket client in json, until it disconnects
+pub async fn on_progress_ws(websocket: HyperWebsocket) -> Result<()> {
+    let (mut sink, mut stream) = websocket.await?.split();
+    let mut rx = PROGRESS.subscribe();
+    loop {
+        let json = serde_json::to_string(&*rx.borrow_and_update())?;
+        sink.send(Message::text(json)).await?;
+        tokio::select! {
+            r = rx.changed() => r?,
+            msg = stream.next() => {
+                if !matches!(msg, Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Text(_)))) {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use chrono::Local;
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use pisugar_core::{PiSugarConfig, PiSugarCore};
+
+use crate::auth::{self, Credentials};
+use crate::events::{self, EventTx};
+
+use proto::pi_sugar_server::{PiSugar, PiSugarServer};
+use proto::*;
+
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("pisugar");
+}
+
+/// Merge json object of config fields into config
+pub fn merge_config(config: &PiSugarConfig, patch: &str) -> Result<PiSugarConfig, String> {
+    let patch: Value = serde_json::from_str(patch).map_err(|e| e.to_string())?;
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => return Err("Config must be a json object".to_string()),
+    };
+    let mut value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    let fields = value.as_object_mut().expect("config is a json object");
+    for (k, v) in patch {
+        if !fields.contains_key(&k) {
+            return Err(format!("Unknown config field: {}", k));
+        }
+        fields.insert(k, v);
+    }
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+/// gRPC service
+pub struct PiSugarService {
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: EventTx,
+}
+
+#[tonic::async_trait]
+#[allow(clippy::result_large_err)]
+impl PiSugar for PiSugarService {
+    async fn status(&self, _req: Request<StatusRequest>) -> Result<Response<StatusReply>, Status> {
+        let core = self.core.lock().map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(StatusReply {
+            model: core.model(),
+            battery: core.level().ok(),
+            battery_v: core.voltage_avg().ok(),
+            battery_i: core.intensity_avg().ok(),
+            battery_charging: core.charging().ok(),
+            battery_power_plugged: core.power_plugged().ok(),
+            battery_allow_charging: core.allow_charging().ok(),
+            rtc_time: core.read_time().ok().map(|t| t.to_rfc3339()),
+            temperature: core.get_temperature().ok(),
+        }))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<Event, Status>> + Send>>;
+
+    async fn watch(&self, _req: Request<WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        // lagged events are dropped
+        let stream = BroadcastStream::new(self.event_tx.subscribe()).filter_map(|event| async move {
+            match event {
+                Ok(event) => Some(Ok(Event {
+                    event: event.to_string(),
+                })),
+                Err(BroadcastStreamRecvError::Lagged(n)) => {
+                    events::lagged(n);
+                    None
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn set_config(&self, req: Request<SetConfigRequest>) -> Result<Response<SetConfigReply>, Status> {
+        let mut core = self.core.lock().map_err(|e| Status::internal(e.to_string()))?;
+        let old = core.config().clone();
+        let mut new = merge_config(&old, &req.get_ref().json).map_err(Status::invalid_argument)?;
+        new.unredact(&old);
+        let issues = new.validate();
+        if !issues.is_empty() {
+            let issues = format!("Invalid config: {}", issues.join(", "));
+            return Err(Status::invalid_argument(issues));
+        }
+        // applied and then persisted, same as `set config`
+        core.replace_config(new).map_err(|e| Status::internal(e.to_string()))?;
+
+        let json = serde_json::to_string(&core.config().redacted()).map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(SetConfigReply { json }))
+    }
+
+    async fn shutdown(&self, _req: Request<ShutdownRequest>) -> Result<Response<ShutdownReply>, Status> {
+        log::warn!("Shutdown requested through gRPC at {}", Local::now());
+        let core = self.core.lock().map_err(|e| Status::internal(e.to_string()))?;
+        core.spawn_poweroff("shutdown");
+        Ok(Response::new(ShutdownReply {}))
+    }
+}
+
+/// Check credentials of a gRPC request, basic auth in `authorization` metadata, or a user set by a trusted proxy
+#[allow(clippy::result_large_err)]
+fn check_auth(core: &Arc<Mutex<PiSugarCore>>, req: Request<()>) -> Result<Request<()>, Status> {
+    let credentials = core
+        .lock()
+        .map_err(|e| Status::internal(e.to_string()))
+        .and_then(|core| Credentials::of(core.config()).map_err(|e| Status::internal(e.to_string())))?;
+    let authed = match &credentials {
+        Credentials::None => true,
+        Credentials::Password { user, password, .. } => {
+            auth::check_basic(&req.metadata().clone().into_headers(), user, password)
+        }
+        Credentials::Proxy { header, trusted } => match req.remote_addr() {
+            Some(peer) => {
+                let headers = req.metadata().clone().into_headers();
+                auth::proxy_user(&headers, &peer, header, trusted.as_deref()).is_ok()
+            }
+            None => false,
+        },
+    };
+    if authed {
+        Ok(req)
+    } else {
+        Err(Status::unauthenticated("Unauthorized"))
+    }
+}
+
+/// Serve gRPC
+pub async fn serve_grpc(grpc_addr: SocketAddr, core: Arc<Mutex<PiSugarCore>>, event_tx: EventTx) {
+    let service = PiSugarService {
+        core: core.clone(),
+        event_tx,
+    };
+    #[allow(clippy::result_large_err)]
+    let interceptor = move |req: Request<()>| check_auth(&core, req);
+    if let Err(e) = Server::builder()
+        .add_service(PiSugarServer::with_interceptor(service, interceptor))
+        .serve(grpc_addr)
+        .await
+    {
+        log::warn!("gRPC server error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_config() {
+        let config = PiSugarConfig::default();
+        let config = merge_config(&config, r#"{"auto_shutdown_level": 10.0}"#).unwrap();
+        assert_eq!(config.auto_shutdown_level, Some(10.0));
+        assert!(merge_config(&config, r#"{"unknown": 1}"#).is_err());
+        assert!(merge_config(&config, "[]").is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use std::io;
+use std::time::Duration;
+
+/// A request without '\n' is taken as a whole after this, for clients that don't terminate requests
+pub const PARTIAL_REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Max length of a request, a longer one is refused and its connection closed
+pub const MAX_REQUEST_LEN: usize = 16 * 1024;
+
+/// Requests of a stream, split into lines regardless of how they are read, utf-8 is decoded per line so that
+/// characters split across reads are kept
+#[derive(Debug, Default)]
+pub struct RequestLines {
+    pending: Vec<u8>,
+}
+
+impl RequestLines {
+    /// Append data read from the stream, Err if a request is longer than `MAX_REQUEST_LEN`, pending data is dropped
+    pub fn push(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(buf);
+        if self
+            .pending
+            .split(|b| *b == b'\n')
+            .any(|line| line.len() > MAX_REQUEST_LEN)
+        {
+            self.pending.clear();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Request longer than {} bytes", MAX_REQUEST_LEN),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether an incomplete request is pending
+    pub fn has_partial(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Take the incomplete request as a whole
+    pub fn end_partial(&mut self) {
+        if self.has_partial() && !self.pending.ends_with(b"\n") {
+            self.pending.push(b'\n');
+        }
+    }
+
+    /// Next complete request in order, empty lines are skipped
+    pub fn next_request(&mut self) -> Option<String> {
+        while let Some(i) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=i).collect();
+            let line = String::from_utf8_lossy(&line);
+            let req = line.trim_end_matches(['\r', '\n']);
+            if !req.is_empty() {
+                return Some(req.to_string());
+            }
+        }
+        None
+    }
+}
+
+/// Response terminated with a single '\n', so that pipelined responses could be told apart
+pub fn terminate_line(mut resp: String) -> String {
+    if !resp.ends_with('\n') {
+        resp.push('\n');
+    }
+    resp
+}
+
+/// Split the `#id` prefix of a request, e.g. `#42 get battery`
+pub fn split_request_id(req: &str) -> (Option<&str>, &str) {
+    match req.strip_prefix('#').and_then(|r| r.split_once(' ')) {
+        Some((id, req)) if !id.is_empty() => (Some(id), req.trim_start()),
+        _ => (None, req),
+    }
+}
+
+/// Prefix every line of a response with `#id` of its request
+pub fn tag_response(id: Option<&str>, resp: String) -> String {
+    let id = match id {
+        Some(id) => id,
+        None => return resp,
+    };
+    let mut tagged: String = resp.lines().map(|l| format!("#{} {}\n", id, l)).collect();
+    if !resp.ends_with('\n') {
+        tagged.pop();
+    }
+    tagged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_lines() {
+        let mut lines = RequestLines::default();
+        lines.push(b"get battery\r\nget batt").unwrap();
+        assert_eq!(lines.next_request().as_deref(), Some("get battery"));
+        assert_eq!(lines.next_request(), None);
+        assert!(lines.has_partial());
+
+        lines.push(b"ery_v\n\nget model").unwrap();
+        assert_eq!(lines.next_request().as_deref(), Some("get battery_v"));
+        assert_eq!(lines.next_request(), None);
+        lines.end_partial();
+        assert_eq!(lines.next_request().as_deref(), Some("get model"));
+        assert!(!lines.has_partial());
+
+        // a character split across reads
+        let auth = "set_auth admin pässword\n".as_bytes();
+        lines.push(&auth[..17]).unwrap();
+        lines.push(&auth[17..]).unwrap();
+        assert_eq!(lines.next_request().as_deref(), Some("set_auth admin pässword"));
+
+        lines.push(&[b'x'; MAX_REQUEST_LEN]).unwrap();
+        assert!(lines.push(b"x").is_err());
+        assert!(!lines.has_partial());
+
+        assert_eq!(terminate_line("battery: 80".to_string()), "battery: 80\n");
+        assert_eq!(terminate_line("rtc_pi2rtc: done\n".to_string()), "rtc_pi2rtc: done\n");
+    }
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(split_request_id("#42 get battery"), (Some("42"), "get battery"));
+        assert_eq!(split_request_id("get battery"), (None, "get battery"));
+        assert_eq!(split_request_id("#42"), (None, "#42"));
+        assert_eq!(split_request_id("# get battery"), (None, "# get battery"));
+
+        assert_eq!(tag_response(Some("42"), "battery: 80".to_string()), "#42 battery: 80");
+        assert_eq!(
+            tag_response(Some("a"), "x: 1\ny: 2\n".to_string()),
+            "#a x: 1\n#a y: 2\n"
+        );
+        assert_eq!(tag_response(None, "battery: 80\n".to_string()), "battery: 80\n");
+    }
+}
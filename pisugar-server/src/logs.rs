@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use chrono::Local;
+use lazy_static::lazy_static;
+use log::{Log, Metadata, Record};
+
+/// Max log lines kept in memory
+pub const LOG_BUFFER_SIZE: usize = 500;
+
+/// Log lines of `get logs` without count
+pub const LOG_DEFAULT_LINES: usize = 50;
+
+lazy_static! {
+    static ref LOG_BUFFER: Mutex<VecDeque<String>> = Mutex::new(VecDeque::with_capacity(LOG_BUFFER_SIZE));
+}
+
+/// Logger that keeps recent lines in memory, besides the syslog or stderr logger
+pub struct BufferedLogger {
+    inner: Box<dyn Log>,
+}
+
+impl BufferedLogger {
+    pub fn new(inner: Box<dyn Log>) -> Self {
+        Self { inner }
+    }
+}
+
+impl Log for BufferedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} {}: {}",
+            Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if let Ok(mut buffer) = LOG_BUFFER.lock() {
+            if buffer.len() >= LOG_BUFFER_SIZE {
+                buffer.pop_front();
+            }
+            buffer.push_back(line);
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Recent log lines, oldest first
+pub fn recent(n: usize) -> Vec<String> {
+    match LOG_BUFFER.lock() {
+        Ok(buffer) => buffer.iter().skip(buffer.len().saturating_sub(n)).cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
@@ -1,11 +1,14 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::env;
 use std::fs::remove_file;
 use std::io;
 use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::Path;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::{Instant, SystemTime};
@@ -14,94 +17,272 @@ use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Result};
 use chrono::prelude::*;
-use clap::{Arg, ArgAction, Command};
-use cmds::{ButtonMode, Cmds};
+use clap::{parser::ValueSource, Arg, ArgAction, Command};
+use conn::{parse_secs, set_keepalive, ConnOptions};
 use digest_auth::{AuthContext, AuthorizationHeader, Charset, Qop, WwwAuthenticateHeader};
 use env_logger::Env;
+use events::{ClientTx, Event, EventRx, EventTx};
 use futures::prelude::*;
 use futures::SinkExt;
-use futures_channel::mpsc::unbounded;
+use hyper::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Response};
 use hyper::{Request, Server};
 use hyper_tungstenite::tungstenite::Message;
 use hyper_tungstenite::HyperWebsocket;
 use lazy_static::lazy_static;
+use lines::{split_request_id, tag_response, terminate_line, RequestLines, PARTIAL_REQUEST_TIMEOUT};
 use log::LevelFilter;
+use logs::BufferedLogger;
 use rand::RngCore;
 use syslog::{BasicLogger, Facility, Formatter3164};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request as WsRequest, Response as WsResponse};
+use tokio_tungstenite::WebSocketStream;
 use tokio_util::codec::{BytesCodec, Framed};
+use watch::Watcher;
 
+use pisugar_client::cmds::{self, ButtonMode, Cmds, Transport};
 use pisugar_core::{
-    execute_shell, get_ntp_datetime, notify_shutdown_soon, sys_write_time, Error, Model, PiSugarConfig, PiSugarCore,
-    RTCRawTime, I2C_READ_INTERVAL,
+    get_ntp_datetime, logind_poweroff, resolve_secret, set_simulation, sys_write_time, AuthMode, Error,
+    MissedAlarmPolicy, Model, PiSugarConfig, PiSugarCore, RTCRawTime, I2C_READ_INTERVAL, SHUTDOWN_GRACE_PERIOD,
 };
 
-mod cmds;
+mod activation;
+mod api;
+mod auth;
+mod conn;
+mod cors;
+mod events;
+mod firmware;
+mod mdns;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod lines;
+mod logs;
+mod modbus;
+mod notify;
+mod panic;
+mod poll;
+mod privilege;
+mod sessions;
+mod snmp;
+mod telemetry;
+mod transport;
+mod watch;
+mod web;
+mod webhook;
 
 /// Websocket info
 const WS_JSON: &str = "_ws.json";
 
+/// Timeout of the auth frame of a ws client without an auth subprotocol
+const WS_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Attempts of model detection, 3s apart, before exiting
+const MODEL_DETECT_ATTEMPTS: u32 = 10;
+
+/// Raw register commands enabled, `--enable-raw-i2c`
+static RAW_I2C_ENABLED: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     /// WS addr
     static ref WS_ADDR: Mutex<Option<SocketAddr>> = Mutex::new(None);
+    /// Additional devices
+    static ref DEVICES: Mutex<HashMap<String, Arc<Mutex<PiSugarCore>>>> = Mutex::new(HashMap::new());
+    /// Options of tcp/uds/ws connections
+    static ref CONN_OPTIONS: Mutex<ConnOptions> = Mutex::new(ConnOptions::default());
+    /// Legacy requests warned about, once for each
+    static ref WARNED_ALIASES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
 }
 
-/// Tap event tx
-type EventTx = tokio::sync::watch::Sender<String>;
+/// Options of tcp/uds/ws connections
+fn conn_options() -> ConnOptions {
+    *CONN_OPTIONS.lock().expect("unexpected lock failed")
+}
 
-/// Tap event rx
-type EventRx = tokio::sync::watch::Receiver<String>;
+/// Notify a stage of the shutdown countdown through the configured notifiers, default wall, none if it's empty
+fn notify_shutdown_stage(core: &PiSugarCore, message: String, level: f32, seconds: f64) {
+    let notifiers = core
+        .config()
+        .notifiers
+        .clone()
+        .unwrap_or_else(|| vec![Default::default()]);
+    let vars = notify::shutdown_vars(level, seconds, core.model());
+    tokio::spawn(notify::notify_all(notifiers, message, vars));
+}
 
-/// Poll pisugar status
-async fn poll_pisugar_status(core: &mut PiSugarCore, tx: &EventTx) {
+/// Poll pisugar status, events of additional devices are suffixed with `@<id>`
+fn poll_pisugar_status(shared: &Arc<Mutex<PiSugarCore>>, core: &mut PiSugarCore, device: Option<&str>, tx: &EventTx) {
     log::debug!("Polling state");
     let now = Instant::now();
-    match core.poll(now).await {
+    match core.poll_now(now) {
         Ok(Some(tap_type)) => {
-            let _ = tx.send(format!("{}\n", tap_type));
+            let _ = tx.send(Event::Tap {
+                tap: tap_type,
+                device: device.map(String::from),
+            });
+            let data = serde_json::json!({ "type": tap_type.to_string(), "device": device });
+            webhook::fire(core, "tap", data);
         }
         Err(e) => {
             log::debug!("Poll error: {}", e);
         }
         _ => {}
     }
+    if core.rtc_sync_due(now) {
+        tokio::spawn(sync_ntp_time(shared.clone()));
+    }
+}
+
+/// Sync system time and rtc from ntp, the core is not locked while waiting for ntp
+async fn sync_ntp_time(core: Arc<Mutex<PiSugarCore>>) {
+    if let Ok(ntp_time) = pisugar_core::get_ntp_datetime().await {
+        if let Ok(core) = core.lock() {
+            core.write_ntp_time(ntp_time);
+        }
+    }
 }
 
-/// Handle request
+/// Poll additional devices
+fn poll_devices(devices: &[(String, Arc<Mutex<PiSugarCore>>)], tx: &EventTx) {
+    for (id, device) in devices {
+        let mut core = device.lock().expect("unexpected lock failed");
+        poll_pisugar_status(device, &mut core, Some(id), tx);
+    }
+}
+
+/// Run a single request of `--oneshot`, returns exit code
+fn handle_oneshot(core: Arc<Mutex<PiSugarCore>>, req: &str) -> i32 {
+    // battery history needs one poll
+    if let Err(e) = core.lock().expect("unexpected lock failed").poll_now(Instant::now()) {
+        log::warn!("Poll error: {}", e);
+    }
+    let resp = handle_request(core, req);
+    println!("{}", resp.trim_end());
+    if resp.starts_with("Invalid request.") {
+        1
+    } else {
+        0
+    }
+}
+
+/// Handle request, `batch ` of `;` separated requests is handled under one core lock with a json array response
 fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
+    match cmds::split_batch(req) {
+        Some(reqs) => format!("{}\n", serde_json::json!(handle_batch(&core, &reqs))),
+        None => handle_core_request(&core, &mut core.lock().unwrap(), req),
+    }
+}
+
+/// Handle requests one by one under one core lock, responses are without the trailing `\n`
+fn handle_batch(core: &Arc<Mutex<PiSugarCore>>, reqs: &[&str]) -> Vec<String> {
+    let mut locked = core.lock().unwrap();
+    reqs.iter()
+        .map(|req| handle_core_request(core, &mut locked, req).trim_end().to_string())
+        .collect()
+}
+
+/// Handle a request of the locked core, `shared` is the core for background tasks
+fn handle_core_request(shared: &Arc<Mutex<PiSugarCore>>, core: &mut PiSugarCore, req: &str) -> String {
     let parts: Vec<String> = req.split(' ').map(|s| s.to_string()).collect();
     let err = "Invalid request.\n".to_string();
 
-    if !req.contains("set_auth") {
-        log::debug!("Request: {}", req);
-    }
+    log::debug!("Request: {}", cmds::redact_request(req));
+    poll::touch();
 
+    if req.trim() == cmds::HELP_JSON {
+        let raw_i2c = RAW_I2C_ENABLED.load(Ordering::Relaxed);
+        let catalogue: Vec<cmds::CommandSpec> = cmds::command_catalogue()
+            .into_iter()
+            .filter(|c| raw_i2c || !(c.name == "get register" || c.name == "set_register"))
+            .collect();
+        return format!("help: {}\n", serde_json::json!(catalogue));
+    }
     if req.starts_with("help") {
         let help = Cmds::from_str(req).expect_err("");
         return help.to_string();
     }
 
+    // additional device, e.g. get battery@dev1
+    if let Some((req, name, device)) = cmds::split_device(req) {
+        let device_core = DEVICES.lock().unwrap().get(device).cloned();
+        return match device_core {
+            Some(device_core) => {
+                let resp = handle_core_request(&device_core, &mut device_core.lock().unwrap(), &req);
+                resp.replacen(&format!("{}:", name), &format!("{}@{}:", name, device), 1)
+            }
+            None => {
+                log::warn!("Unknown device: {}", device);
+                err
+            }
+        };
+    }
+
+    // legacy spellings of older client scripts, the response keeps the legacy name
+    if let Some(alias) = cmds::resolve_alias(req) {
+        if WARNED_ALIASES.lock().unwrap().insert(alias.legacy_name.clone()) {
+            log::warn!("{}", alias.warning);
+        }
+        if alias.request != req.trim() {
+            let resp = handle_core_request(shared, core, &alias.request);
+            return resp.replacen(&format!("{}:", alias.name), &format!("{}:", alias.legacy_name), 1);
+        }
+    }
+
     let cmd = match Cmds::from_str(req) {
         Ok(cmd) => cmd,
         Err(e) => {
-            log::warn!("Invalid cmd: {}", e);
+            // errors of secret commands could quote their arguments
+            match cmds::redact_request(req) {
+                Cow::Owned(req) => log::warn!("Invalid cmd: {}", req),
+                Cow::Borrowed(_) => log::warn!("Invalid cmd: {}", e),
+            }
             return err;
         }
     };
 
-    let core_cloned = core.clone();
-    let mut core = core_cloned.lock().unwrap();
+    if firmware::FIRMWARE_UPDATING.load(Ordering::SeqCst) {
+        let req = cmds::redact_request(req);
+        log::warn!("Firmware update in progress, request rejected: {}", req);
+        return err;
+    }
+
+    let raw_i2c = matches!(cmd, Cmds::Get(cmds::GetCmds::Register { .. }) | Cmds::SetRegister { .. });
+    if raw_i2c && !RAW_I2C_ENABLED.load(Ordering::Relaxed) {
+        log::warn!("Raw register commands are disabled, run with --enable-raw-i2c");
+        return err;
+    }
+
+    let unsupported = core
+        .battery_capabilities()
+        .is_some_and(|caps| !cmd.battery_supported(&caps))
+        || core.rtc_capabilities().is_some_and(|caps| !cmd.rtc_supported(&caps));
+    if unsupported {
+        let name = if parts[0] == "get" { &parts[1] } else { &parts[0] };
+        return format!("{}: unsupported\n", name);
+    }
     let r = match &cmd {
         Cmds::Get(get_cmd) => {
             let r = match get_cmd {
                 cmds::GetCmds::Version => Ok(env!("CARGO_PKG_VERSION").to_string()),
+                cmds::GetCmds::ProtocolVersion => Ok(cmds::PROTOCOL_VERSION.to_string()),
+                cmds::GetCmds::Commands => {
+                    let raw_i2c = RAW_I2C_ENABLED.load(Ordering::Relaxed);
+                    let signatures: Vec<String> = cmds::command_signatures()
+                        .into_iter()
+                        .filter(|s| raw_i2c || !(s.starts_with("get register ") || s.starts_with("set_register ")))
+                        .collect();
+                    Ok(signatures.join(","))
+                }
                 cmds::GetCmds::Model => Ok(core.model()),
                 cmds::GetCmds::FirmwareVersion => core.version(),
+                cmds::GetCmds::FirmwareUpdateAvailable => Ok(firmware::update_available().unwrap_or_default()),
                 cmds::GetCmds::Battery => core.level().map(|l| l.to_string()),
+                cmds::GetCmds::BatteryChipLevel => core.chip_level().map(|l| l.to_string()),
                 cmds::GetCmds::BatteryI => core.intensity_avg().map(|i| i.to_string()),
                 cmds::GetCmds::BatteryV => core.voltage_avg().map(|v| v.to_string()),
                 cmds::GetCmds::BatteryLedAmount => core.led_amount().map(|n| n.to_string()),
@@ -138,6 +319,13 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                 cmds::GetCmds::AlarmRepeat => Ok(core.config().auto_wake_repeat.to_string()),
                 cmds::GetCmds::SafeShutdownLevel => Ok(core.config().auto_shutdown_level.unwrap_or(0.0).to_string()),
                 cmds::GetCmds::SafeShutdownDelay => Ok(core.config().auto_shutdown_delay.unwrap_or(0.0).to_string()),
+                cmds::GetCmds::OutageShutdownDelay => Ok(core
+                    .config()
+                    .outage_shutdown_delay
+                    .map(|d| d.to_string())
+                    .unwrap_or_default()),
+                cmds::GetCmds::ScheduledReboot => Ok(core.config().scheduled_reboot.clone().unwrap_or_default()),
+                cmds::GetCmds::NextReboot => Ok(core.next_reboot().map(|t| t.to_rfc3339()).unwrap_or_default()),
                 cmds::GetCmds::ButtonEnable { mode } => Ok(match mode {
                     cmds::ButtonMode::Single => core.config().single_tap_enable,
                     cmds::ButtonMode::Double => core.config().double_tap_enable,
@@ -154,8 +342,72 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                 cmds::GetCmds::AntiMistouch => Ok(core.config().anti_mistouch.unwrap_or(true).to_string()),
                 cmds::GetCmds::SoftPoweroff => Ok(core.config().soft_poweroff.unwrap_or(false).to_string()),
                 cmds::GetCmds::SoftPoweroffShell => Ok(core.config().soft_poweroff_shell.clone().unwrap_or_default()),
+                cmds::GetCmds::SoftPoweroffCountdown => Ok(core
+                    .soft_poweroff_at()
+                    .map(|at| {
+                        at.saturating_duration_since(std::time::Instant::now())
+                            .as_secs()
+                            .to_string()
+                    })
+                    .unwrap_or_default()),
                 cmds::GetCmds::Temperature => core.get_temperature().map(|x| x.to_string()),
                 cmds::GetCmds::InputProtect => core.input_protected().map(|x| x.to_string()),
+                cmds::GetCmds::DataAge => Ok(core
+                    .data_age()
+                    .map(|age| format!("{:.1}", age.as_secs_f64()))
+                    .unwrap_or_default()),
+                cmds::GetCmds::Stats => Ok(serde_json::json!(core.stats()).to_string()),
+                cmds::GetCmds::Config => Ok(serde_json::json!(core.config().redacted()).to_string()),
+                cmds::GetCmds::Transports => Ok(transport::states()),
+                cmds::GetCmds::Connections => Ok(serde_json::to_string(&sessions::stats()).unwrap_or_default()),
+                cmds::GetCmds::Sessions => {
+                    let mut sessions = sessions::connections();
+                    sessions.extend(http_sessions());
+                    sessions.sort_by_key(|s| s.id);
+                    Ok(serde_json::to_string(&sessions).unwrap_or_default())
+                }
+                cmds::GetCmds::Diagnostics => {
+                    let mut diagnostics = core.diagnostics();
+                    diagnostics["events"] = events::stats();
+                    Ok(diagnostics.to_string())
+                }
+                cmds::GetCmds::Logs { n } => {
+                    Ok(serde_json::json!(logs::recent(n.unwrap_or(logs::LOG_DEFAULT_LINES))).to_string())
+                }
+                cmds::GetCmds::ThermalProtect => Ok(core
+                    .config()
+                    .thermal_protect
+                    .map(|t| t.to_string())
+                    .unwrap_or_default()),
+                cmds::GetCmds::ChargingWindow => Ok(core
+                    .config()
+                    .charging_window
+                    .map(|(begin, end)| format!("{} {}", begin, end))
+                    .unwrap_or_default()),
+                cmds::GetCmds::Register { addr, reg } => core
+                    .read_register(*addr, *reg)
+                    .map(|v| format!("0x{:02x}", v)),
+                cmds::GetCmds::WakeReason => Ok(core.wake_reason()),
+                cmds::GetCmds::RtcBattery => core.rtc_battery_voltage().map(|v| v.to_string()),
+                cmds::GetCmds::RtcTemperature => core.rtc_temperature().map(|t| t.to_string()),
+                cmds::GetCmds::RtcUserdata => core.rtc_user_data().map(hex::encode),
+                cmds::GetCmds::Capabilities => Ok(core
+                    .battery_capabilities()
+                    .map_or("".to_string(), |caps| caps.names().join(","))),
+                cmds::GetCmds::RtcCapabilities => Ok(core
+                    .rtc_capabilities()
+                    .map_or("".to_string(), |caps| caps.names().join(","))),
+                cmds::GetCmds::RtcIntOutput => {
+                    Ok(core.config().rtc_int_output.map(|o| o.to_string()).unwrap_or_default())
+                }
+                cmds::GetCmds::OutputCurrentLimit => core.output_current_limit().map(|l| l.to_string()),
+                cmds::GetCmds::LedMode => core.led_mode().map(|m| m.to_string()),
+                cmds::GetCmds::PowerSave => Ok(core.power_save().to_string()),
+                cmds::GetCmds::PowerSaving => Ok(core.power_saving().to_string()),
+                cmds::GetCmds::PowerProfile => Ok(core.power_profile()),
+                cmds::GetCmds::I2cBus => Ok(core.config().i2c_bus.to_string()),
+                cmds::GetCmds::I2cAddr => Ok(format!("0x{:02x}", core.battery_i2c_addr())),
+                cmds::GetCmds::RtcI2cAddr => Ok(format!("0x{:02x}", core.rtc_i2c_addr())),
             };
             r.map(|x| format!("{}: {}", parts[1], x))
         }
@@ -174,6 +426,23 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
         Cmds::SetBatteryOutput(b) => core
             .toggle_output_enabled(b.value())
             .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetOutputCycle { ms } => core.toggle_output_enabled(false).map(|_| {
+            log::warn!("Output off for {}ms", ms);
+            // turn it back on without holding the core lock meanwhile
+            let core_cloned = shared.clone();
+            let off = Duration::from_millis(*ms);
+            tokio::spawn(async move {
+                tokio::time::sleep(off).await;
+                let r = match core_cloned.lock() {
+                    Ok(core) => core.toggle_output_enabled(true),
+                    Err(e) => Err(Error::Other(format!("Lock core error: {}", e))),
+                };
+                if let Err(e) = r {
+                    log::error!("Output on error: {}", e);
+                }
+            });
+            format!("{}: done\n", parts[0])
+        }),
         Cmds::SetFullChargeDuration { seconds } => {
             core.config_mut().full_charge_duration = Some(*seconds);
             core.save_config().map(|_| format!("{}: done\n", parts[0]))
@@ -188,13 +457,13 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
             format!("{}: done\n", parts[0])
         }),
         Cmds::RtcWeb => {
-            let core_cloned = core_cloned.clone();
+            let core_cloned = shared.clone();
             tokio::spawn(async move {
                 match get_ntp_datetime().await {
                     Ok(ntp_datetime) => {
                         sys_write_time(ntp_datetime.into());
                         if let Ok(core) = core_cloned.lock() {
-                            let _ = core.write_time(ntp_datetime.into());
+                            let _ = core.sync_rtc_time(ntp_datetime);
                         }
                     }
                     Err(e) => log::warn!("Sync NTP time error: {}", e),
@@ -208,6 +477,7 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
             core.write_alarm(sd3078_time, *weekdays).map(|_| {
                 core.config_mut().auto_wake_repeat = *weekdays;
                 core.config_mut().auto_wake_time = Some(datetime.clone());
+                core.config_mut().auto_wake_local_time = Some(datetime.time());
                 if let Err(e) = core.save_config() {
                     log::warn!("{}", e);
                 }
@@ -216,6 +486,7 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
         }
         Cmds::RtcAlarmDisable => core.disable_alarm().map(|_| {
             core.config_mut().auto_wake_time = None;
+            core.config_mut().auto_wake_local_time = None;
             if let Err(e) = core.save_config() {
                 log::warn!("{}", e);
             }
@@ -251,9 +522,19 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
             }
             Ok(format!("{}: done\n", parts[0]))
         }
+        Cmds::SetOutageShutdownDelay { delay } => core
+            .set_outage_shutdown_delay(*delay)
+            .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::ScheduleReboot { schedule } => {
+            let schedule = Some(schedule.join(" ")).filter(|s| !s.is_empty());
+            core.set_scheduled_reboot(schedule.as_deref()).map(|t| match t {
+                Some(t) => format!("{}: next at {}\n", parts[0], t.to_rfc3339()),
+                None => format!("{}: done\n", parts[0]),
+            })
+        }
         Cmds::RtcTestWake => core
             .test_wake()
-            .map(|_| format!("{}: wakeup after 1 min 30 sec\n", parts[0])),
+            .map(|t| format!("{}: wakeup at {}\n", parts[0], t.to_rfc3339())),
         Cmds::SetButtonEnable { mode, enable } => {
             match *mode {
                 ButtonMode::Single => core.config_mut().single_tap_enable = enable.0,
@@ -288,6 +569,7 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
                 core.config_mut().auth_user = None;
                 core.config_mut().auth_password = None;
             }
+            core.config_mut().auth_password_file = None;
             core.save_config().map(|_| format!("{}: done\n", parts[0]))
         }
         Cmds::ForceShutdown => core.force_shutdown().map(|_| format!("{}: done\n", parts[0])),
@@ -309,6 +591,63 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
         Cmds::SetInputProtect(b) => core
             .toggle_input_protected(b.value())
             .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetThermalProtect { max } => core
+            .set_thermal_protect(*max)
+            .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetLongevityMode(b) => core
+            .set_longevity_mode(b.value())
+            .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetProfile { name } => core.set_profile(name).map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetOutputCurrentLimit { limit } => core
+            .set_output_current_limit(*limit)
+            .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::CancelPoweroff => core.cancel_poweroff().map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetLedMode { mode } => core.set_led_mode(*mode).map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetPowerSave { mode } => core.set_power_save(*mode).map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetRtcIntOutput { output } => core
+            .set_rtc_int_output(*output)
+            .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetRtcUserdata { data } => core.write_rtc_user_data(data).map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetRegister { addr, reg, value } => core
+            .write_register(*addr, *reg, *value)
+            .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::SetChargingWindow { begin, end } => core
+            .set_charging_window(begin.zip(*end))
+            .map(|_| format!("{}: done\n", parts[0])),
+        Cmds::Set(cmds::SetCmds::Config { json }) => match serde_json::from_str::<PiSugarConfig>(json) {
+            Ok(mut config) => {
+                config.unredact(core.config());
+                core.replace_config(config).map(|_| "config: done\n".to_string())
+            }
+            Err(e) => Err(Error::Other(format!("Invalid config json: {}", e))),
+        },
+        Cmds::Set(cmds::SetCmds::Transport { transport, enable }) => {
+            transport::set_enabled(*transport, enable.0);
+            Ok("transport: done\n".to_string())
+        }
+        Cmds::FactoryReset => core.factory_reset().map(|_| format!("{}: done\n", parts[0])),
+        Cmds::RevokeSession { id } => {
+            if sessions::revoke(*id) || revoke_http_session(*id) {
+                Ok(format!("{}: done\n", parts[0]))
+            } else {
+                Err(Error::Other(format!("No such session: {}", id)))
+            }
+        }
+        Cmds::Watch { .. } | Cmds::Unwatch => Err(Error::Other("Only on tcp/uds/ws connections".to_string())),
+        Cmds::SetI2cBus { bus } => {
+            let (addr, rtc_addr) = (core.config().i2c_addr, core.config().rtc_i2c_addr);
+            core.rebind(*bus, addr, rtc_addr).map(|_| format!("{}: done\n", parts[0]))
+        }
+        Cmds::SetI2cAddr { addr } => {
+            let (bus, rtc_addr) = (core.config().i2c_bus, core.config().rtc_i2c_addr);
+            core.rebind(bus, Some(*addr), rtc_addr)
+                .map(|_| format!("{}: done\n", parts[0]))
+        }
+        Cmds::SetRtcI2cAddr { addr } => {
+            let (bus, battery_addr) = (core.config().i2c_bus, core.config().i2c_addr);
+            core.rebind(bus, battery_addr, Some(*addr))
+                .map(|_| format!("{}: done\n", parts[0]))
+        }
     };
 
     match r {
@@ -319,50 +658,74 @@ fn handle_request(core: Arc<Mutex<PiSugarCore>>, req: &str) -> String {
             r
         }
         Err(e) => {
-            log::warn!("Request: {}, error: {}", req, e);
+            log::warn!("Request: {}, error: {}", cmds::redact_request(req), e);
             err
         }
     }
 }
 
-async fn _handle_stream<T>(core: Arc<Mutex<PiSugarCore>>, stream: T, mut event_rx: EventRx) -> io::Result<()>
+async fn _handle_stream<T>(
+    core: Arc<Mutex<PiSugarCore>>,
+    stream: T,
+    event_rx: EventRx,
+    session: sessions::Connection,
+) -> io::Result<()>
 where
     T: 'static + AsyncRead + AsyncWrite + Send,
 {
     let framed = Framed::new(stream, BytesCodec::new());
     let (sink, mut stream) = framed.split();
-    let (mut tx, rx) = unbounded();
+    let (tx, rx) = events::client_queue();
 
-    // handle request
-    let mut tx_cloned = tx.clone();
+    // handle requests one by one, responses are sent in the order of requests
+    let tx_cloned = tx.clone();
+    let idle_timeout = conn_options().idle_timeout;
     tokio::spawn(async move {
-        while let Some(Ok(buf)) = stream.next().await {
-            let reqs = String::from_utf8_lossy(buf.as_ref());
-            let reqs = reqs.trim_end_matches('\n');
-            for req in reqs.split('\n') {
-                log::debug!("Req: {}", req);
-                let req = req.replace('\r', "");
-                let resp = handle_request(core.clone(), req.as_str());
+        let mut watcher = Watcher::default();
+        let mut lines = RequestLines::default();
+        let mut closed = false;
+        while !closed {
+            let timeout = if lines.has_partial() {
+                Some(PARTIAL_REQUEST_TIMEOUT)
+            } else {
+                idle_timeout
+            };
+            match session.next_within(&mut stream, timeout).await {
+                Ok(Some(Ok(buf))) => {
+                    if let Err(e) = lines.push(buf.as_ref()) {
+                        log::warn!("{}, close the stream", e);
+                        let _ = tx_cloned.send(Some("Invalid request.\n".to_string())).await;
+                        closed = true;
+                    }
+                }
+                Ok(_) => {
+                    closed = true;
+                    lines.end_partial();
+                }
+                Err(_) if lines.has_partial() => lines.end_partial(),
+                Err(_) => {
+                    log::info!("Stream idle timeout");
+                    closed = true;
+                }
+            }
+            while let Some(req) = lines.next_request() {
+                log::debug!("Req: {}", cmds::redact_request(&req));
+                let resp = handle_connection_request(&session, &mut watcher, &core, &req, &tx_cloned);
                 log::debug!("Resp: {}", resp);
-                tx_cloned.send(Some(resp)).await.expect("Channel failed");
+                if tx_cloned.send(Some(terminate_line(resp))).await.is_err() {
+                    log::debug!("Stream client gone");
+                    return;
+                }
             }
         }
         // delay for 100 millis
         tokio::time::sleep(Duration::from_millis(100)).await;
-        tx_cloned.send(None).await.expect("Channel failed");
+        let _ = tx_cloned.send(None).await;
         log::debug!("Stream close");
     });
 
     // button event
-    // tokio::spawn(event_rx.map(|event| Ok(Some(event))).forward(tx));
-    tokio::spawn(async move {
-        while event_rx.changed().await.is_ok() {
-            let s = event_rx.borrow().clone();
-            tx.send(Some(s)).await.expect("Channel failed");
-        }
-        log::debug!("Event watcher close");
-        tx.send(None).await.expect("Channel failed");
-    });
+    tokio::spawn(events::forward(event_rx, tx, |event| format!("{}\n", event)));
 
     // send back
     tokio::spawn(
@@ -382,61 +745,139 @@ where
     Ok(())
 }
 
+/// Handle a request of a tcp/uds/ws connection, `#id` prefix of the request is echoed in its response
+fn handle_connection_request<T>(
+    session: &sessions::Connection,
+    watcher: &mut Watcher,
+    core: &Arc<Mutex<PiSugarCore>>,
+    req: &str,
+    tx: &ClientTx<T>,
+) -> String
+where
+    T: From<String> + Send + 'static,
+{
+    let (id, req) = split_request_id(req);
+    let resp = watcher
+        .handle_request(core, req, id, tx)
+        .unwrap_or_else(|| handle_request(core.clone(), req));
+    session.served(!resp.starts_with("Invalid request."));
+    tag_response(id, resp)
+}
+
 /// Handle tcp stream
 async fn handle_tcp_stream(core: Arc<Mutex<PiSugarCore>>, stream: TcpStream, event_rx: EventRx) -> io::Result<()> {
-    log::info!("Incoming tcp connection from: {}", stream.peer_addr()?);
-    _handle_stream(core, stream, event_rx).await
+    let peer = stream.peer_addr()?;
+    log::info!("Incoming tcp connection from: {}", peer);
+    if let Some(keepalive) = conn_options().keepalive {
+        if let Err(e) = set_keepalive(stream.as_raw_fd(), keepalive) {
+            log::warn!("Set keepalive error: {}", e);
+        }
+    }
+    _handle_stream(core, stream, event_rx, sessions::connect("tcp", peer.to_string())).await
 }
 
 /// Handle websocket request
-async fn handle_ws_connection(
-    core: Arc<Mutex<PiSugarCore>>,
-    stream: TcpStream,
-    mut event_rx: EventRx,
-) -> io::Result<()> {
-    log::info!("Incoming ws connection from: {}", stream.peer_addr()?);
+async fn handle_ws_connection(core: Arc<Mutex<PiSugarCore>>, stream: TcpStream, event_rx: EventRx) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    log::info!("Incoming ws connection from: {}", peer);
+    let options = conn_options();
+    if let Some(keepalive) = options.keepalive {
+        if let Err(e) = set_keepalive(stream.as_raw_fd(), keepalive) {
+            log::warn!("Set keepalive error: {}", e);
+        }
+    }
 
-    let ws_stream = tokio_tungstenite::accept_async(stream)
+    let credentials = core
+        .lock()
+        .map_err(|e| io::Error::other(e.to_string()))
+        .and_then(|core| auth::Credentials::of(core.config()))?;
+    let mut authed = credentials == auth::Credentials::None;
+    // the callback type of tungstenite
+    #[allow(clippy::result_large_err)]
+    let handshake = |req: &WsRequest, mut resp: WsResponse| -> Result<WsResponse, ErrorResponse> {
+        let status = match &credentials {
+            auth::Credentials::None => return Ok(resp),
+            auth::Credentials::Proxy { header, trusted } => {
+                match auth::proxy_user(req.headers(), &peer, header, trusted.as_deref()) {
+                    Ok(user) => {
+                        log::debug!("WS of proxy user {}", user);
+                        authed = true;
+                        return Ok(resp);
+                    }
+                    Err(status) => status,
+                }
+            }
+            auth::Credentials::Password { user, password, .. } => match auth::protocol_token(req.headers()) {
+                // authenticated by the first frame
+                None => return Ok(resp),
+                Some(protocol) if auth::check_token(&protocol[auth::WS_AUTH_PROTOCOL.len()..], user, password) => {
+                    // browsers fail the connection without one of their protocols in the response
+                    if let Ok(protocol) = HeaderValue::from_str(protocol) {
+                        resp.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+                    }
+                    authed = true;
+                    return Ok(resp);
+                }
+                Some(_) => hyper::StatusCode::UNAUTHORIZED,
+            },
+        };
+        log::warn!("WS from {} unauthorized", peer);
+        let mut resp = ErrorResponse::new(None);
+        *resp.status_mut() = status;
+        Err(resp)
+    };
+    let mut ws_stream = tokio_tungstenite::accept_hdr_async(stream, handshake)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
         .await?;
+    if let auth::Credentials::Password { user, password, .. } = &credentials {
+        if !authed && !ws_auth_frame(&mut ws_stream, user, password).await {
+            log::warn!("WS from {} unauthorized", peer);
+            let _ = ws_stream.close(None).await;
+            return Ok(());
+        }
+    }
     log::info!("WS connection established");
 
-    let (mut tx, rx) = unbounded();
+    let (tx, rx) = events::client_queue();
     let (sink, mut stream) = ws_stream.split();
 
     // handle request
-    let mut tx_cloned = tx.clone();
+    let tx_cloned = tx.clone();
+    let tx_ping = tx.clone();
+    let session = sessions::connect("ws", peer.to_string());
     tokio::spawn(async move {
-        while let Some(Ok(msg)) = stream.next().await {
-            if let Ok(msg) = msg.to_text() {
-                let req = msg.replace('\n', "");
-                log::debug!("Req: {}", req);
-                let resp = handle_request(core.clone(), req.as_str());
+        let mut watcher = Watcher::default();
+        while let Ok(Some(Ok(msg))) = session.next_within(&mut stream, options.idle_timeout).await {
+            if msg.is_text() || msg.is_binary() {
+                let req = msg.to_text().unwrap_or_default().replace('\n', "");
+                log::debug!("Req: {}", cmds::redact_request(&req));
+                let resp = handle_connection_request(&session, &mut watcher, &core, &req, &tx_cloned);
                 log::debug!("Resp: {}", resp);
-                tx_cloned.send(Some(resp)).await.expect("Channel failed");
+                if tx_cloned.send(Some(resp.into())).await.is_err() {
+                    log::debug!("WS client gone");
+                    return;
+                }
             }
         }
         tokio::time::sleep(Duration::from_millis(100)).await;
-        tx_cloned.send(None).await.expect("Channel failed");
+        let _ = tx_cloned.send(None).await;
         log::debug!("WS stream close")
     });
 
     // button event
-    tokio::spawn(async move {
-        while event_rx.changed().await.is_ok() {
-            let s = event_rx.borrow().clone();
-            tx.send(Some(s)).await.expect("Channel failed");
-        }
-        log::debug!("Event watcher close");
-        tx.send(None).await.expect("Channel failed");
-    });
+    tokio::spawn(events::forward(event_rx, tx, |event| format!("{}\n", event).into()));
+
+    // ping
+    if let Some(interval) = options.ws_ping {
+        tokio::spawn(ws_ping(interval, tx_ping));
+    }
 
     // send back
     tokio::spawn(
-        rx.map(|s| match s {
-            Some(s) => {
-                log::debug!("WS sink send: {}", s);
-                Ok(s.into())
+        rx.map(|msg| match msg {
+            Some(msg) => {
+                log::debug!("WS sink send: {}", msg);
+                Ok(msg)
             }
             None => {
                 log::debug!("WS sink close");
@@ -449,10 +890,65 @@ async fn handle_ws_connection(
     Ok(())
 }
 
+/// Authenticate a ws client by its first frame, `auth <token>`
+async fn ws_auth_frame<S>(ws_stream: &mut WebSocketStream<S>, user: &str, password: &str) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let first = tokio::time::timeout(WS_AUTH_TIMEOUT, async {
+        loop {
+            match ws_stream.next().await {
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                msg => return msg,
+            }
+        }
+    })
+    .await;
+    let authed = match first {
+        Ok(Some(Ok(Message::Text(req)))) => req
+            .trim()
+            .strip_prefix("auth ")
+            .is_some_and(|token| auth::check_token(token, user, password)),
+        _ => false,
+    };
+    let resp = if authed { "auth: done\n" } else { "auth: unauthorized\n" };
+    let _ = ws_stream.send(Message::text(resp)).await;
+    authed
+}
+
+/// Ping a ws client periodically, until the connection is closed
+async fn ws_ping(interval: Duration, tx: ClientTx<Message>) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        interval.tick().await;
+        if tx.send(Some(Message::Ping(Vec::new()))).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Tcp listener of activated socket
+fn tcp_listener_from_fd(fd: RawFd) -> io::Result<TcpListener> {
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Unix domain socket listener of activated socket
+fn uds_listener_from_fd(fd: RawFd) -> io::Result<UnixListener> {
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
 /// Handle uds
 async fn handle_uds_stream(core: Arc<Mutex<PiSugarCore>>, stream: UnixStream, event_rx: EventRx) -> io::Result<()> {
     log::info!("Incoming uds stream: {:?}", stream.peer_addr()?);
-    _handle_stream(core, stream, event_rx).await
+    let peer = match stream.peer_cred() {
+        Ok(cred) => format!("uid={} pid={}", cred.uid(), cred.pid().unwrap_or_default()),
+        Err(_) => "unknown".to_string(),
+    };
+    _handle_stream(core, stream, event_rx, sessions::connect("uds", peer)).await
 }
 
 /// Clean up before exit
@@ -487,21 +983,25 @@ fn clean_up(uds: Option<String>, web_dir: Option<String>) {
 
 async fn on_ws_client(
     websocket: HyperWebsocket,
+    peer: SocketAddr,
     core: Arc<Mutex<PiSugarCore>>,
-    mut event_rx: EventRx,
+    event_rx: EventRx,
 ) -> Result<(), io::Error> {
     let websocket = websocket.await;
     let websocket = websocket.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-    let (mut tx, mut rx) = unbounded();
+    let (tx, mut rx) = events::client_queue();
     let (mut sink, mut s) = websocket.split();
+    let options = conn_options();
 
     // req
-    let mut tx_cloned = tx.clone();
+    let tx_cloned = tx.clone();
+    let session = sessions::connect("ws", peer.to_string());
     tokio::spawn(async move {
-        while let Some(Ok(msg)) = s.next().await {
+        let mut watcher = Watcher::default();
+        while let Ok(Some(Ok(msg))) = session.next_within(&mut s, options.idle_timeout).await {
             let resp_msg = match msg {
                 Message::Text(req) => {
-                    let resp = handle_request(core.clone(), &req);
+                    let resp = handle_connection_request(&session, &mut watcher, &core, &req, &tx_cloned);
                     Some(Message::text(resp))
                 }
                 Message::Binary(_) => Some(Message::Close(None)),
@@ -510,24 +1010,26 @@ async fn on_ws_client(
                 Message::Pong(_) => None,
                 Message::Frame(_) => None,
             };
-            if resp_msg.is_some() {
-                tx_cloned.send(resp_msg).await.expect("Channel failed");
+            if resp_msg.is_some() && tx_cloned.send(resp_msg).await.is_err() {
+                log::debug!("Websocket client gone");
+                return;
             }
         }
         tokio::time::sleep(Duration::from_millis(100)).await;
-        tx_cloned.send(None).await.expect("Channel failed");
+        let _ = tx_cloned.send(None).await;
         log::info!("Websocket closed");
     });
 
     // button event
-    tokio::spawn(async move {
-        while event_rx.changed().await.is_ok() {
-            let s = event_rx.borrow().clone();
-            tx.send(Some(Message::text(s))).await.expect("Channel failed");
-        }
-        log::debug!("Event watcher close");
-        tx.send(None).await.expect("Channel failed");
-    });
+    let tx_ping = tx.clone();
+    tokio::spawn(events::forward(event_rx, tx, |event| {
+        Message::text(format!("{}\n", event))
+    }));
+
+    // ping
+    if let Some(interval) = options.ws_ping {
+        tokio::spawn(ws_ping(interval, tx_ping));
+    }
 
     // send back
     while let Some(Some(rsp)) = rx.next().await {
@@ -539,7 +1041,13 @@ async fn on_ws_client(
     Ok(())
 }
 
-type SecurityRecord = (Option<String>, u32, SystemTime);
+/// Http auth session of an opaque
+struct SecurityRecord {
+    cnonce: Option<String>,
+    nc: u32,
+    last_time: SystemTime,
+    session: sessions::Session,
+}
 
 lazy_static! {
     static ref SECURITY_CTX: Mutex<HashMap<String, SecurityRecord>> = Mutex::new(HashMap::default());
@@ -561,7 +1069,7 @@ fn build_realm(req: &Request<Body>, user: &str) -> String {
     }
 }
 
-fn build_www_header(req: &Request<Body>, user: &str, session_timeout: Duration) -> Result<String> {
+fn build_www_header(req: &Request<Body>, peer: SocketAddr, user: &str, session_timeout: Duration) -> Result<String> {
     let now = SystemTime::now();
     let realm = build_realm(req, user);
 
@@ -582,8 +1090,8 @@ fn build_www_header(req: &Request<Body>, user: &str, session_timeout: Duration)
         .lock()
         .map_err(|e| anyhow!("Lock security ctx errro: {}", e))?;
     ctx.retain(|opaque, v| {
-        if v.2 + session_timeout < now {
-            log::debug!("opaque={}, cnonce={:?} timeout", opaque, v.0);
+        if v.last_time + session_timeout < now {
+            log::debug!("opaque={}, cnonce={:?} timeout", opaque, v.cnonce);
             false
         } else {
             true
@@ -591,7 +1099,15 @@ fn build_www_header(req: &Request<Body>, user: &str, session_timeout: Duration)
     });
 
     // new session
-    ctx.insert(opaque.clone(), (None, 0, now));
+    ctx.insert(
+        opaque.clone(),
+        SecurityRecord {
+            cnonce: None,
+            nc: 0,
+            last_time: now,
+            session: sessions::Session::new("http", peer.to_string()),
+        },
+    );
     let header = digest_auth::WwwAuthenticateHeader {
         domain: Some(vec!["/".to_string()]),
         realm,
@@ -621,12 +1137,12 @@ fn rebuild_www_header(
         .lock()
         .map_err(|e| anyhow!("Lock SECURITY_CTX error: {}", e))?;
 
-    let (cnonce, nc, last_time) = ctx
-        .get(&opaque)
+    let record = ctx
+        .get_mut(&opaque)
         .ok_or_else(|| anyhow!("Rebuild www header, server opaque not in SECURITY_CTX"))?;
 
     let now = SystemTime::now();
-    let duration = now.duration_since(*last_time)?;
+    let duration = now.duration_since(record.last_time)?;
 
     // session timeout
     if duration > session_timeout {
@@ -638,10 +1154,10 @@ fn rebuild_www_header(
         .cnonce
         .clone()
         .ok_or_else(|| anyhow!("SECURITY ERROR: empty cnonce"))?;
-    if let Some(cnonce) = cnonce {
+    if let Some(cnonce) = &record.cnonce {
         if auth_cnonce != *cnonce {
             log::debug!("SECURITY: cnonce changed, current {}", auth_cnonce);
-        } else if auth_header.nc < *nc {
+        } else if auth_header.nc < record.nc {
             bail!("SECURITY ERROR: nc replay");
         }
     }
@@ -663,27 +1179,115 @@ fn rebuild_www_header(
     };
 
     // update security ctx
-    ctx.insert(opaque, (Some(auth_cnonce), new_nc, now));
+    record.cnonce = Some(auth_cnonce);
+    record.nc = new_nc;
+    record.last_time = now;
 
     Ok(www_header)
 }
 
-/// Handle http request, /ws to websocket
+/// Http auth sessions a client has authenticated with
+fn http_sessions() -> Vec<sessions::Session> {
+    SECURITY_CTX
+        .lock()
+        .map(|ctx| {
+            ctx.values()
+                .filter(|r| r.cnonce.is_some())
+                .map(|r| r.session.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// End an http auth session, its client is challenged again
+fn revoke_http_session(id: u64) -> bool {
+    let mut ctx = match SECURITY_CTX.lock() {
+        Ok(ctx) => ctx,
+        Err(_) => return false,
+    };
+    let n = ctx.len();
+    ctx.retain(|_, r| r.session.id != id);
+    ctx.len() != n
+}
+
+/// Handle http request, cross-origin ones are checked against `cors_origins`
 async fn handle_http_req(
     req: Request<Body>,
-    static_: hyper_staticfile::Static,
+    peer: SocketAddr,
+    assets: web::WebAssets,
     core: Arc<Mutex<PiSugarCore>>,
-    event_rx: EventRx,
+    event_tx: EventTx,
+) -> Result<Response<Body>> {
+    let forwarded = {
+        let core = core.lock().map_err(|e| anyhow!("Lock core error: {}", e))?;
+        let config = core.config();
+        config.auth_mode.unwrap_or_default() == AuthMode::Proxy
+            && auth::trusted_proxy(&peer, config.auth_proxy_addrs.as_deref())
+    };
+    let origin = match cors::cross_origin(&req, forwarded) {
+        Some(origin) => origin,
+        None => return route_http_req(req, peer, assets, core, event_tx).await,
+    };
+    let allow = {
+        let core = core.lock().map_err(|e| anyhow!("Lock core error: {}", e))?;
+        let config = core.config();
+        // fail closed if auth_password_file is unreadable
+        let auth = auth::Credentials::of(config).map_or(true, |c| c != auth::Credentials::None);
+        cors::allow_list(config.cors_origins.as_deref().unwrap_or_default(), auth)
+    };
+    if let Some(resp) = cors::check(&req, &origin, &allow) {
+        return Ok(resp);
+    }
+    let mut resp = route_http_req(req, peer, assets, core, event_tx).await?;
+    if cors::allowed(&origin, &allow) {
+        cors::add_headers(&mut resp, &origin);
+    }
+    Ok(resp)
+}
+
+/// Route http request, /ws to websocket
+async fn route_http_req(
+    req: Request<Body>,
+    peer: SocketAddr,
+    assets: web::WebAssets,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: EventTx,
 ) -> Result<Response<Body>> {
     log::info!("request: {} {}", req.method(), req.uri());
     // check for http auth
     if let Ok(config) = core.lock() {
-        if let (Some(auth_user), Some(auth_pass)) =
-            (config.config().auth_user.clone(), config.config().auth_password.clone())
-        {
-            let auth_user = auth_user.trim().to_string();
-            let auth_password = auth_pass.trim().to_string();
-            if !auth_user.is_empty() && !auth_password.is_empty() {
+        let credentials = match auth::Credentials::of(config.config()) {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                // fail closed, auth is on but its password is unreadable
+                log::error!("Read auth_password_file error: {}", e);
+                return Ok(Response::builder()
+                    .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())?);
+            }
+        };
+        match credentials {
+            auth::Credentials::None => {}
+            auth::Credentials::Proxy { header, trusted } => {
+                match auth::proxy_user(req.headers(), &peer, &header, trusted.as_deref()) {
+                    Ok(user) => log::debug!("Http request of proxy user {}", user),
+                    Err(status) => return Ok(Response::builder().status(status).body(Body::empty())?),
+                }
+            }
+            auth::Credentials::Password {
+                mode: AuthMode::Basic,
+                user,
+                password,
+            } => {
+                if !auth::check_basic(req.headers(), &user, &password) {
+                    return Ok(auth::basic_challenge());
+                }
+            }
+            auth::Credentials::Password {
+                user: auth_user,
+                password: auth_pass,
+                ..
+            } => {
                 let mut auth_context = AuthContext::new(auth_user.clone(), auth_pass, req.uri().to_string());
                 let mut auth_ok = false;
                 for (name, value) in req.headers() {
@@ -713,7 +1317,8 @@ async fn handle_http_req(
                     }
                 }
                 if !auth_ok {
-                    let www_header = build_www_header(&req, &auth_user, Duration::from_secs(SECURITY_TIMEOUT_SECONDS))?;
+                    let www_header =
+                        build_www_header(&req, peer, &auth_user, Duration::from_secs(SECURITY_TIMEOUT_SECONDS))?;
                     let resp = Response::builder()
                         .status(hyper::StatusCode::UNAUTHORIZED)
                         .header(hyper::header::WWW_AUTHENTICATE, www_header) // fix chrome digest auth
@@ -734,13 +1339,17 @@ async fn handle_http_req(
             bail!("Not found");
         }
     }
+    // json api
+    if req.uri().path().starts_with("/api/") {
+        return api::handle_api_req(req, core).await;
+    }
     // websocket
     if req.uri().path().ends_with("/ws") {
         if hyper_tungstenite::is_upgrade_request(&req) {
             let (resp, websocket) =
                 hyper_tungstenite::upgrade(req, None).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
             tokio::spawn(async move {
-                if let Err(e) = on_ws_client(websocket, core, event_rx).await {
+                if let Err(e) = on_ws_client(websocket, peer, core, event_tx.subscribe()).await {
                     log::debug!("Serving websocket error: {}", e);
                 }
             });
@@ -749,22 +1358,34 @@ async fn handle_http_req(
             bail!("/ws only serve websocket");
         }
     } else {
-        let resp = static_.clone().serve(req).await?;
-        Ok(resp)
+        // web UI is suspended in power save, api and websocket still work
+        if core.lock().map(|core| core.power_saving()).unwrap_or(false) {
+            return Ok(Response::builder()
+                .status(hyper::StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from("Web UI is suspended in power save"))?);
+        }
+        web::serve(assets, req).await
     }
 }
 
 /// Serve http
-async fn serve_http(http_addr: SocketAddr, web_dir: String, core: Arc<Mutex<PiSugarCore>>, event_rx: EventRx) {
-    let static_ = hyper_staticfile::Static::new(web_dir);
-
-    let make_service = make_service_fn(move |_| {
-        let static_ = static_.clone();
+async fn serve_http(
+    listener: std::net::TcpListener,
+    assets: web::WebAssets,
+    core: Arc<Mutex<PiSugarCore>>,
+    event_tx: EventTx,
+) {
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let peer = conn.remote_addr();
+        let assets = assets.clone();
         let core = core.clone();
-        let event_rx = event_rx.clone();
-        async {
+        let event_tx = event_tx.clone();
+        // dropped with the service when the connection is closed
+        let activity = sessions::Activity::new("http");
+        async move {
             Ok::<_, anyhow::Error>(service_fn(move |req| {
-                handle_http_req(req, static_.clone(), core.clone(), event_rx.clone()).map_err(|e| {
+                let _ = &activity;
+                handle_http_req(req, peer, assets.clone(), core.clone(), event_tx.clone()).map_err(|e| {
                     log::error!("Handle http req error: {}", e);
                     e
                 })
@@ -772,13 +1393,45 @@ async fn serve_http(http_addr: SocketAddr, web_dir: String, core: Arc<Mutex<PiSu
         }
     });
 
-    let server = Server::bind(&http_addr).serve(make_service);
+    let builder = match Server::from_tcp(listener) {
+        Ok(builder) => builder,
+        Err(e) => {
+            log::error!("Http web server socket error: {}", e);
+            return;
+        }
+    };
+    let server = builder
+        .tcp_keepalive(conn_options().keepalive)
+        .serve(make_service)
+        .with_graceful_shutdown(transport::wait(Transport::Http, false));
 
     if let Err(e) = server.await {
         log::error!("Http web server error: {}", e);
     }
 }
 
+/// Accept a connection until the listener is turned off by `set transport`
+async fn accept_enabled(listener: &TcpListener, transport: Transport) -> Option<(TcpStream, SocketAddr)> {
+    tokio::select! {
+        accepted = listener.accept() => accepted.ok(),
+        _ = transport::wait(transport, false) => None,
+    }
+}
+
+/// Parse model, `auto` to detect
+fn parse_model(s: &str) -> std::result::Result<Option<Model>, String> {
+    if s == "auto" {
+        return Ok(None);
+    }
+    s.parse().map(Some).map_err(|_| {
+        let models: Vec<String> = <Model as clap::ValueEnum>::value_variants()
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+        format!("choose from auto, {}", models.join(", "))
+    })
+}
+
 /// Init logging
 fn init_logging(debug: bool, syslog: bool) {
     if syslog {
@@ -791,22 +1444,25 @@ fn init_logging(debug: bool, syslog: bool) {
             pid: pid as u32,
         };
         let logger = syslog::unix(formatter).expect("Could not connect to syslog");
-        log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
+        log::set_boxed_logger(Box::new(BufferedLogger::new(Box::new(BasicLogger::new(logger)))))
             .map(|_| match debug {
                 true => log::set_max_level(LevelFilter::Debug),
                 false => log::set_max_level(LevelFilter::Info),
             })
             .expect("Failed to init syslog");
-    } else if debug {
-        env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
     } else {
-        env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+        let level = if debug { "debug" } else { "info" };
+        let logger = env_logger::Builder::from_env(Env::default().default_filter_or(level)).build();
+        let max_level = logger.filter();
+        log::set_boxed_logger(Box::new(BufferedLogger::new(Box::new(logger))))
+            .map(|_| log::set_max_level(max_level))
+            .expect("Failed to init logger");
     }
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let matches = Command::new(env!("CARGO_PKG_NAME"))
+    let app = Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
@@ -832,6 +1488,26 @@ async fn main() -> std::io::Result<()> {
                 .default_value("/tmp/pisugar-server.sock")
                 .help("Unix domain socket file, e.g. /tmp/pisugar-server.sock"),
         )
+        .arg(
+            Arg::new("no-chown")
+                .long("no-chown")
+                .action(ArgAction::SetTrue)
+                .help("Leave the owner of uds file alone, by default it is chowned to the owner of its directory"),
+        )
+        .arg(
+            Arg::new("user")
+                .long("user")
+                .value_name("USER")
+                .value_parser(privilege::lookup_user)
+                .help("Drop root to USER after i2c is opened and listeners are bound"),
+        )
+        .arg(
+            Arg::new("i2c-dev")
+                .long("i2c-dev")
+                .value_name("DEV")
+                .value_parser(privilege::parse_i2c_dev)
+                .help("I2C device, e.g. /dev/i2c-1, overrides i2c_bus of config"),
+        )
         .arg(
             Arg::new("ws")
                 .short('w')
@@ -844,8 +1520,8 @@ async fn main() -> std::io::Result<()> {
                 .requires_all(&["http"])
                 .long("web")
                 .value_name("DIR")
-                .default_value("/usr/share/pisugar-server/web")
-                .help("Web content directory, e.g. web"),
+                .default_value(web::DEFAULT_WEB_DIR)
+                .help("Web content directory, e.g. web, embedded web UI of embed-web builds by default"),
         )
         .arg(
             Arg::new("http")
@@ -868,22 +1544,140 @@ async fn main() -> std::io::Result<()> {
                 .action(ArgAction::SetTrue)
                 .help("Log to syslog"),
         )
+        .arg(
+            Arg::new("no-mdns")
+                .long("no-mdns")
+                .action(ArgAction::SetTrue)
+                .help("Do not advertise _pisugar._tcp via mDNS"),
+        )
+        .arg(
+            Arg::new("enable-raw-i2c")
+                .long("enable-raw-i2c")
+                .action(ArgAction::SetTrue)
+                .help("Enable raw register commands, get register and set_register"),
+        )
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .action(ArgAction::SetTrue)
+                .help("Serve synthetic battery and rtc data without i2c, for development without a PiSugar"),
+        )
+        .arg(
+            Arg::new("oneshot")
+                .long("oneshot")
+                .value_name("REQUEST")
+                .help("Run a single request, e.g. \"get battery\", print the response and exit without listening"),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECS")
+                .default_value("0")
+                .value_parser(parse_secs)
+                .help("Close tcp/uds/ws connections without incoming data for SECS, 0 to disable"),
+        )
+        .arg(
+            Arg::new("keepalive")
+                .long("keepalive")
+                .value_name("SECS")
+                .default_value("60")
+                .value_parser(parse_secs)
+                .help("Tcp keepalive of tcp/ws/http connections after idle for SECS, 0 to disable"),
+        )
+        .arg(
+            Arg::new("ws-ping")
+                .long("ws-ping")
+                .value_name("SECS")
+                .default_value("30")
+                .value_parser(parse_secs)
+                .help("Ping websocket clients every SECS, 0 to disable"),
+        )
         .arg(Arg::new("led").long("led").default_value("4").help("2-led or 4-led"))
         .arg(
             Arg::new("model")
                 .long("model")
                 .required(true)
-                .value_parser(clap::value_parser!(Model)),
-        )
-        .get_matches();
+                .help("PiSugar model, or auto to detect")
+                .value_parser(parse_model),
+        );
+    #[cfg(feature = "grpc")]
+    let app = app.arg(
+        Arg::new("grpc")
+            .long("grpc")
+            .value_name("ADDR")
+            .help("gRPC listen address, e.g. 0.0.0.0:8424"),
+    );
+    let matches = app.get_matches();
 
     // init logging
     let debug = matches.get_flag("debug");
     let syslog = matches.get_flag("syslog");
     init_logging(debug, syslog);
 
+    let i2c_dev = matches.get_one::<u8>("i2c-dev").copied();
+    if matches.get_flag("simulate") {
+        log::warn!("Simulation, battery and rtc data are synthetic, system time and power are left alone");
+        set_simulation(true);
+    } else {
+        privilege::check_capabilities();
+        if let Some(bus) = i2c_dev {
+            if let Err(e) = privilege::check_i2c_dev(bus) {
+                log::error!("{}", e);
+            }
+        }
+    }
+
+    if matches.get_flag("enable-raw-i2c") {
+        log::warn!("Raw register commands are enabled");
+        RAW_I2C_ENABLED.store(true, Ordering::Relaxed);
+    }
+
+    *CONN_OPTIONS.lock().expect("unexpected lock failed") = ConnOptions {
+        idle_timeout: matches.get_one::<Option<Duration>>("idle-timeout").copied().flatten(),
+        keepalive: matches.get_one::<Option<Duration>>("keepalive").copied().flatten(),
+        ws_ping: matches.get_one::<Option<Duration>>("ws-ping").copied().flatten(),
+    };
+
+    let oneshot = matches.get_one::<String>("oneshot").cloned();
+
     // model
-    let model = matches.get_one::<Model>("model").unwrap();
+    let model = match matches.get_one::<Option<Model>>("model").unwrap() {
+        Some(model) => *model,
+        None => {
+            let mut config = PiSugarConfig::default();
+            if let Some(c) = matches.get_one::<String>("config") {
+                let _ = config.load(Path::new(c));
+            }
+            match config.model.as_ref().and_then(|m| m.parse::<Model>().ok()) {
+                Some(model) => {
+                    log::info!("Configured model: {}", model);
+                    model
+                }
+                None => {
+                    let attempts = if oneshot.is_some() { 1 } else { MODEL_DETECT_ATTEMPTS };
+                    let mut detected = None;
+                    for _ in 0..attempts {
+                        match Model::detect(i2c_dev.unwrap_or(config.i2c_bus)) {
+                            Ok(model) => {
+                                log::info!("Detected model: {}", model);
+                                detected = Some(model);
+                                break;
+                            }
+                            Err(e) => log::error!("PiSugar model detection failed: {}", e),
+                        }
+                        tokio::time::sleep(Duration::from_secs(3)).await;
+                    }
+                    match detected {
+                        Some(model) => model,
+                        None => {
+                            log::error!("No PiSugar detected, set the model with --model");
+                            exit(1);
+                        }
+                    }
+                }
+            }
+        }
+    };
     log::debug!("Running with model: {}", model);
 
     // core
@@ -897,20 +1691,59 @@ async fn main() -> std::io::Result<()> {
                 PiSugarCore::new(config, model.clone())
             });
         match c {
-            Ok(c) => {
+            Ok(mut c) => {
+                if let Some(bus) = i2c_dev.filter(|bus| *bus != c.config().i2c_bus) {
+                    let (i2c_addr, rtc_i2c_addr) = (c.config().i2c_addr, c.config().rtc_i2c_addr);
+                    if let Err(e) = c.rebind(bus, i2c_addr, rtc_i2c_addr) {
+                        log::warn!("Rebind i2c bus {} error: {}", bus, e);
+                    }
+                }
+                if let Err(e) = c.apply_rtc_drift() {
+                    log::warn!("Apply rtc drift error: {}", e);
+                }
                 core = Arc::new(Mutex::new(c));
                 break;
             }
             Err(e) => log::error!("PiSugar init failed: {}", e),
         }
+        if oneshot.is_some() {
+            exit(1);
+        }
         sleep(Duration::from_secs(3));
     }
 
-    // event watch
-    let (event_tx, event_rx) = tokio::sync::watch::channel("".to_string());
+    panic::install_hook(core.clone(), model);
+
+    // secrets readable by others
+    let (has_secrets, password_file) = {
+        let core = core.lock().expect("unexpected lock failed");
+        (core.config().has_secrets(), core.config().auth_password_file.clone())
+    };
+    if let Some(config) = matches.get_one::<String>("config").filter(|_| has_secrets) {
+        if let Err(e) = privilege::check_secret_file(Path::new(config)) {
+            log::warn!("Config has secrets: {}", e);
+        }
+    }
+    if let Some(password_file) = password_file {
+        if let Err(e) = privilege::check_secret_file(Path::new(&resolve_secret(&password_file))) {
+            log::warn!("Auth password file: {}", e);
+        }
+    }
+
+    if let Some(req) = oneshot {
+        exit(handle_oneshot(core, &req));
+    }
+
+    // events
+    let event_tx = events::channel();
 
-    // CTRL+C signal handling
-    let uds = matches.get_one::<String>("uds").cloned();
+    // systemd socket activation, FileDescriptorName=tcp|uds|http
+    let mut tcp_fd = activation::take_listen_fd("tcp");
+    let mut uds_fd = activation::take_listen_fd("uds");
+    let mut http_fd = activation::take_listen_fd("http");
+
+    // CTRL+C signal handling, activated uds is owned by systemd
+    let uds = matches.get_one::<String>("uds").cloned().filter(|_| uds_fd.is_none());
     let web_dir = matches.get_one::<String>("web").cloned();
     ctrlc::set_handler(move || {
         clean_up(uds.clone(), web_dir.clone());
@@ -918,18 +1751,27 @@ async fn main() -> std::io::Result<()> {
     .expect("Failed to setup ctrl+c");
 
     // tcp
-    if let Some(tcp_addr) = matches.get_one::<String>("tcp").cloned() {
+    let tcp_addr = matches.get_one::<String>("tcp").cloned();
+    if tcp_addr.is_some() || tcp_fd.is_some() {
         let core_cloned = core.clone();
-        let event_rx_cloned = event_rx.clone();
+        let event_tx_cloned = event_tx.clone();
+        let mut bind = privilege::pending_bind();
         tokio::spawn(async move {
             loop {
-                match TcpListener::bind(&tcp_addr).await {
+                transport::wait(Transport::Tcp, true).await;
+                let tcp_listener = match (tcp_fd.take(), &tcp_addr) {
+                    (Some(fd), _) => tcp_listener_from_fd(fd),
+                    (None, Some(tcp_addr)) => TcpListener::bind(tcp_addr).await,
+                    (None, None) => break,
+                };
+                bind.done();
+                match tcp_listener {
                     Ok(tcp_listener) => {
                         log::info!("TCP listening...");
-                        while let Ok((stream, addr)) = tcp_listener.accept().await {
+                        while let Some((stream, addr)) = accept_enabled(&tcp_listener, Transport::Tcp).await {
                             log::info!("TCP from {}", addr);
                             let core = core_cloned.clone();
-                            if let Err(e) = handle_tcp_stream(core, stream, event_rx_cloned.clone()).await {
+                            if let Err(e) = handle_tcp_stream(core, stream, event_tx_cloned.subscribe()).await {
                                 log::error!("Handle tcp error: {}", e);
                             }
                         }
@@ -939,7 +1781,9 @@ async fn main() -> std::io::Result<()> {
                         log::warn!("TCP bind error: {}", e);
                     }
                 }
-                tokio::time::sleep(Duration::from_secs(3)).await;
+                if transport::enabled(Transport::Tcp) {
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                }
             }
         });
     }
@@ -947,18 +1791,26 @@ async fn main() -> std::io::Result<()> {
     // ws
     if let Some(ws_addr) = matches.get_one::<String>("ws").cloned() {
         let core_cloned = core.clone();
-        let event_rx_cloned = event_rx.clone();
+        let event_tx_cloned = event_tx.clone();
+        let mut bind = privilege::pending_bind();
         tokio::spawn(async move {
             loop {
-                match tokio::net::TcpListener::bind(&ws_addr).await {
+                transport::wait(Transport::Ws, true).await;
+                let ws_listener = tokio::net::TcpListener::bind(&ws_addr).await;
+                bind.done();
+                match ws_listener {
                     Ok(ws_listener) => {
                         log::info!("WS listening...");
-                        while let Ok((stream, addr)) = ws_listener.accept().await {
+                        while let Some((stream, addr)) = accept_enabled(&ws_listener, Transport::Ws).await {
                             log::info!("WS from {}", addr);
                             let core = core_cloned.clone();
-                            if let Err(e) = handle_ws_connection(core, stream, event_rx_cloned.clone()).await {
-                                log::warn!("Handle ws error: {}", e);
-                            }
+                            let event_rx = event_tx_cloned.subscribe();
+                            // handshake and auth frame of a client don't hold up others
+                            tokio::spawn(async move {
+                                if let Err(e) = handle_ws_connection(core, stream, event_rx).await {
+                                    log::warn!("Handle ws error: {}", e);
+                                }
+                            });
                         }
                         log::info!("WS stopped");
                     }
@@ -966,24 +1818,42 @@ async fn main() -> std::io::Result<()> {
                         log::warn!("WS bind error: {}", e);
                     }
                 }
-                tokio::time::sleep(Duration::from_secs(3)).await;
+                if transport::enabled(Transport::Ws) {
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                }
             }
         });
     }
 
     // uds
-    if let Some(uds_addr) = matches.get_one::<String>("uds").cloned() {
+    let uds_addr = matches.get_one::<String>("uds").cloned();
+    let no_chown = matches.get_flag("no-chown");
+    let user = matches.get_one::<privilege::User>("user").cloned();
+    if uds_addr.is_some() || uds_fd.is_some() {
         let core_cloned = core.clone();
-        let event_rx_cloned = event_rx.clone();
+        let event_tx_cloned = event_tx.clone();
+        let user = user.clone();
+        let mut bind = privilege::pending_bind();
         tokio::spawn(async move {
             loop {
-                match tokio::net::UnixListener::bind(&uds_addr) {
+                let (uds_listener, bound) = match (uds_fd.take(), &uds_addr) {
+                    (Some(fd), _) => (uds_listener_from_fd(fd), None),
+                    (None, Some(uds_addr)) => (UnixListener::bind(uds_addr), Some(uds_addr)),
+                    (None, None) => break,
+                };
+                match uds_listener {
                     Ok(uds_listener) => {
                         log::info!("UDS listening...");
+                        if let Some(uds_addr) = bound.filter(|_| !no_chown) {
+                            if let Err(e) = privilege::chown_socket(Path::new(uds_addr), user.as_ref()) {
+                                log::warn!("Failed to chown uds file: {}, pass --no-chown to leave it alone", e);
+                            }
+                        }
+                        bind.done();
                         while let Ok((stream, addr)) = uds_listener.accept().await {
                             log::info!("UDS from {:?}", addr);
                             let core = core_cloned.clone();
-                            if let Err(e) = handle_uds_stream(core, stream, event_rx_cloned.clone()).await {
+                            if let Err(e) = handle_uds_stream(core, stream, event_tx_cloned.subscribe()).await {
                                 log::error!("Handle uds error: {}", e);
                             }
                         }
@@ -991,6 +1861,7 @@ async fn main() -> std::io::Result<()> {
                     }
                     Err(e) => {
                         log::warn!("UDS bind error: {}", e);
+                        bind.done();
                     }
                 }
                 tokio::time::sleep(Duration::from_secs(3)).await;
@@ -1004,20 +1875,30 @@ async fn main() -> std::io::Result<()> {
         matches.get_one::<String>("web").cloned(),
     ) {
         let core_cloned = core.clone();
-        let event_rx = event_rx.clone();
-        let _web_dir_cloned = web_dir.clone();
+        let event_tx = event_tx.clone();
+        // the default dir is left to embedded web UI of `embed-web` builds
+        let web_dir = Some(web_dir).filter(|_| matches.value_source("web") != Some(ValueSource::DefaultValue));
+        let assets = web::WebAssets::new(web_dir);
+        let mut bind = privilege::pending_bind();
         tokio::spawn(async move {
             loop {
-                log::info!("Http web server listening...");
-                serve_http(
-                    http_addr.parse().unwrap(),
-                    web_dir.clone(),
-                    core_cloned.clone(),
-                    event_rx.clone(),
-                )
-                .await;
-                log::info!("Http web server stopped");
-                tokio::time::sleep(Duration::from_secs(3)).await;
+                transport::wait(Transport::Http, true).await;
+                let listener = match http_fd.take() {
+                    Some(fd) => Ok(unsafe { std::net::TcpListener::from_raw_fd(fd) }),
+                    None => std::net::TcpListener::bind(&http_addr),
+                };
+                bind.done();
+                match listener {
+                    Ok(listener) => {
+                        log::info!("Http web server listening...");
+                        serve_http(listener, assets.clone(), core_cloned.clone(), event_tx.clone()).await;
+                        log::info!("Http web server stopped");
+                    }
+                    Err(e) => log::warn!("Http bind error: {}", e),
+                }
+                if transport::enabled(Transport::Http) {
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                }
             }
         });
 
@@ -1028,41 +1909,349 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
+    // mdns
+    let _mdns = if !matches.get_flag("no-mdns") {
+        let port_of = |name: &str| {
+            matches
+                .get_one::<String>(name)
+                .and_then(|addr| addr.parse::<SocketAddr>().ok())
+                .map(|addr| addr.port())
+        };
+        let ports = [("http", port_of("http")), ("tcp", port_of("tcp")), ("ws", port_of("ws"))];
+        let port = ports.iter().find_map(|(_, port)| *port).unwrap_or(8421);
+        let model = core.lock().expect("unexpected lock failed").model();
+        match mdns::advertise(port, &model, &ports) {
+            Ok(daemon) => Some(daemon),
+            Err(e) => {
+                log::warn!("mDNS advertise error: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // grpc
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = matches.get_one::<String>("grpc").cloned() {
+        let core_cloned = core.clone();
+        let event_tx_cloned = event_tx.clone();
+        tokio::spawn(async move {
+            match grpc_addr.parse() {
+                Ok(grpc_addr) => loop {
+                    log::info!("gRPC listening...");
+                    grpc::serve_grpc(grpc_addr, core_cloned.clone(), event_tx_cloned.clone()).await;
+                    log::info!("gRPC stopped");
+                    tokio::time::sleep(Duration::from_secs(3)).await;
+                },
+                Err(e) => log::error!("Invalid gRPC address {}: {}", grpc_addr, e),
+            }
+        });
+    }
+
+    // snmp agent
+    let snmp = core.lock().expect("unexpected lock failed").config().snmp.clone();
+    if let Some(snmp) = snmp {
+        let core_cloned = core.clone();
+        let bind = privilege::pending_bind();
+        tokio::spawn(async move {
+            if let Err(e) = snmp::serve(snmp, core_cloned, bind).await {
+                log::error!("SNMP agent error: {}", e);
+            }
+        });
+    }
+
+    // modbus tcp
+    let modbus = core.lock().expect("unexpected lock failed").config().modbus.clone();
+    if let Some(modbus) = modbus {
+        let core_cloned = core.clone();
+        let bind = privilege::pending_bind();
+        tokio::spawn(async move {
+            if let Err(e) = modbus::serve(modbus, core_cloned, bind).await {
+                log::error!("Modbus TCP error: {}", e);
+            }
+        });
+    }
+
+    // telemetry push, e.g. influxdb
+    tokio::spawn(telemetry::run(core.clone()));
+
+    // firmware update check
+    if core.lock().expect("unexpected lock failed").model() == Model::PiSugar_3.to_string() {
+        tokio::spawn(firmware::check_updates(core.clone(), event_tx.clone()));
+    }
+
+    // additional devices
+    let devices = core.lock().expect("unexpected lock failed").config().devices.clone();
+    for device in devices.unwrap_or_default() {
+        let model: Model = match device.model.parse() {
+            Ok(model) => model,
+            Err(_) => {
+                log::error!("Device {} invalid model: {}", device.id, device.model);
+                continue;
+            }
+        };
+        let main_path = matches.get_one::<String>("config").map(String::as_str);
+        let main_config = core.lock().expect("unexpected lock failed").config().clone();
+        match PiSugarCore::new_device(&device, &main_config, main_path, model) {
+            Ok(c) => {
+                log::info!("Device {}: {}", device.id, model);
+                DEVICES.lock().unwrap().insert(device.id, Arc::new(Mutex::new(c)));
+            }
+            Err(e) => log::error!("Device {} init failed: {}", device.id, e),
+        }
+    }
+    let devices: Vec<(String, Arc<Mutex<PiSugarCore>>)> =
+        DEVICES.lock().unwrap().iter().map(|(id, c)| (id.clone(), c.clone())).collect();
+
+    // missed alarm
+    {
+        let mut core = core.lock().expect("unexpected lock failed");
+        match core.missed_alarm() {
+            Ok(Some(missed_at)) => {
+                log::warn!("Missed alarm at {}", missed_at);
+                let _ = event_tx.send(Event::MissedAlarm);
+                webhook::fire(
+                    &core,
+                    "missed_alarm",
+                    serde_json::json!({ "time": missed_at.to_rfc3339() }),
+                );
+                match core.config().missed_alarm_policy {
+                    Some(MissedAlarmPolicy::Run) => {
+                        if let Some(shell) = core.config().missed_alarm_shell.clone() {
+                            core.spawn_hook("missed_alarm", shell);
+                        }
+                    }
+                    Some(MissedAlarmPolicy::Reschedule) => match core.reschedule_alarm() {
+                        Ok(Some(t)) => log::info!("Alarm rescheduled at {}", t),
+                        Ok(None) => log::warn!("No alarm to reschedule"),
+                        Err(e) => log::warn!("Reschedule alarm error: {}", e),
+                    },
+                    None => {}
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Missed alarm detection error: {}", e),
+        }
+    }
+
+    // drop root after listeners are bound, i2c of the core is open already
+    if let Some(user) = &user {
+        privilege::binds_done().await;
+        if let Err(e) = privilege::drop_to(user) {
+            log::error!("Failed to drop root to {}: {}", user.name, e);
+            exit(1);
+        }
+        if !matches.get_flag("simulate") {
+            privilege::check_capabilities();
+            let bus = core.lock().expect("unexpected lock failed").config().i2c_bus;
+            if let Err(e) = privilege::check_i2c_dev(bus) {
+                log::warn!("Reopening i2c will fail: {}", e);
+            }
+        }
+        if let Some(config) = matches.get_one::<String>("config") {
+            if !privilege::accessible(Path::new(config), libc::W_OK) {
+                log::warn!(
+                    "Config {} is not writable by {}, settings will not be saved",
+                    config,
+                    user.name
+                );
+            }
+        }
+        let password_file = core
+            .lock()
+            .expect("unexpected lock failed")
+            .config()
+            .auth_password_file
+            .clone();
+        if let Some(password_file) = password_file {
+            if !privilege::accessible(Path::new(&password_file), libc::R_OK) {
+                log::warn!(
+                    "{} is not readable by {}, http auth will fail",
+                    password_file,
+                    user.name
+                );
+            }
+        }
+    }
+
     // polling
     let core_cloned = core.clone();
     let mut interval = tokio::time::interval(I2C_READ_INTERVAL);
     let mut notify_at = tokio::time::Instant::now();
     let mut battery_high_at = tokio::time::Instant::now(); // last battery high timestamp
+    let mut logind_poweroff_at = None; // logind poweroff requested timestamp
+    let mut power_plugged = None; // last power plugged state
+    let mut thermal_paused = false;
+    let mut under_voltage_total = 0;
+    let mut degraded = false;
+    let mut soft_poweroff_at = None; // pending soft poweroff deadline
+    let mut battery_low_fired = false;
+    let mut outage_at = None; // power loss timestamp of outage policy
+    let mut shutdown_fired = false;
     loop {
         interval.tick().await;
         log::debug!("Polling");
+        poll_devices(&devices, &event_tx);
         let mut core = core_cloned.lock().expect("unexpected lock failed");
-        poll_pisugar_status(&mut core, &event_tx).await;
-
-        // auto shutdown at battery low
-        let mut battery_high = true;
+        if firmware::FIRMWARE_UPDATING.load(Ordering::SeqCst) {
+            continue;
+        }
+        poll_pisugar_status(&core_cloned, &mut core, None, &event_tx);
         let level = core.level().unwrap_or(100.0);
-        let auto_shutdown_level = core.config().auto_shutdown_level.unwrap_or(0.0);
 
-        // check battery level
-        if auto_shutdown_level > 0.0 && auto_shutdown_level > (level as f64) {
-            battery_high = false;
+        // slower polls in power save or when idle, but not during a shutdown countdown
+        let countdown = battery_low_fired || outage_at.is_some() || core.soft_poweroff_at().is_some();
+        let power_save = core.power_saving().then(|| core.poll_interval());
+        let active =
+            core.fast_poll_required() || watch::watching() || event_tx.receiver_count() > 0 || poll::recently_active();
+        let period = poll::adaptive_interval(countdown, power_save, active);
+        if period != interval.period() {
+            log::debug!("Poll interval {:?}", period);
+            interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
         }
 
-        // skip if battery high
-        if battery_high {
-            battery_high_at = tokio::time::Instant::now();
-            continue;
+        // power loss/restore
+        if core.config().webhooks.is_some() {
+            if let Ok(plugged) = core.power_plugged() {
+                if power_plugged.is_some() && power_plugged != Some(plugged) {
+                    let event = if plugged { "power_restore" } else { "power_loss" };
+                    webhook::fire(&core, event, serde_json::json!({ "level": level }));
+                }
+                power_plugged = Some(plugged);
+            }
+        }
+
+        // thermal protect
+        if core.thermal_paused() != thermal_paused {
+            thermal_paused = core.thermal_paused();
+            let event = if thermal_paused {
+                Event::ThermalProtectPaused
+            } else {
+                Event::ThermalProtectResumed
+            };
+            let temperature = core.get_temperature().ok();
+            webhook::fire(
+                &core,
+                &event.to_string(),
+                serde_json::json!({ "temperature": temperature }),
+            );
+            let _ = event_tx.send(event);
+        }
+
+        // under voltage
+        if core.under_voltage_total() != under_voltage_total {
+            under_voltage_total = core.under_voltage_total();
+            let _ = event_tx.send(Event::UnderVoltage);
+            let data = serde_json::to_value(core.last_under_voltage()).unwrap_or_default();
+            webhook::fire(&core, "under_voltage", data);
+        }
+
+        // degraded by poll errors, and recovered
+        if core.degraded_since().is_some() != degraded {
+            degraded = !degraded;
+            let (event, name) = if degraded {
+                (Event::Degraded, "degraded")
+            } else {
+                (Event::Recovered, "recovered")
+            };
+            let _ = event_tx.send(event);
+            let data = serde_json::json!({ "data_age": core.data_age().map(|age| age.as_secs_f64()) });
+            webhook::fire(&core, name, data);
         }
 
-        // battery low
-        log::debug!("Battery low: {}", level);
-        let auto_shutdown_delay = core.config().auto_shutdown_delay.unwrap_or(0.0);
+        // soft poweroff countdown
+        if core.soft_poweroff_at() != soft_poweroff_at {
+            let now = std::time::Instant::now();
+            match (soft_poweroff_at, core.soft_poweroff_at()) {
+                (_, Some(at)) => {
+                    let seconds = at.saturating_duration_since(now).as_secs();
+                    let _ = event_tx.send(Event::SoftPoweroffCountdown);
+                    webhook::fire(
+                        &core,
+                        "soft_poweroff_countdown",
+                        serde_json::json!({ "seconds": seconds }),
+                    );
+                }
+                // cancelled before its deadline
+                (Some(at), None) if now < at => {
+                    let _ = event_tx.send(Event::SoftPoweroffCancelled);
+                    webhook::fire(&core, "soft_poweroff_cancelled", serde_json::json!({}));
+                }
+                _ => {}
+            }
+            soft_poweroff_at = core.soft_poweroff_at();
+        }
+
+        // auto shutdown at battery low
         let now = tokio::time::Instant::now();
-        let battery_low_secs = now.duration_since(battery_high_at).as_secs() as f64;
-        let shutdown_remain_secs = auto_shutdown_delay - battery_low_secs;
+        let auto_shutdown_level = core.config().auto_shutdown_level.unwrap_or(0.0);
+        let battery_low = auto_shutdown_level > 0.0 && auto_shutdown_level > (level as f64);
+        if battery_low {
+            log::debug!("Battery low: {}", level);
+            if !battery_low_fired {
+                webhook::fire(&core, "low_battery", serde_json::json!({ "level": level }));
+                battery_low_fired = true;
+            }
+        } else {
+            battery_high_at = now;
+            battery_low_fired = false;
+        }
+
+        // outage policy, shutdown after power loss, cancelled when power returns
+        let outage_delay = core.config().outage_shutdown_delay;
+        let plugged = if outage_delay.is_some() || outage_at.is_some() {
+            core.power_plugged().unwrap_or(true)
+        } else {
+            true
+        };
+        match (outage_delay, outage_at) {
+            (Some(delay), None) if !plugged => {
+                let message = format!("Power lost, will power off after {} seconds", delay);
+                log::warn!("{}", message);
+                notify_shutdown_stage(&core, message, level, delay);
+                let _ = event_tx.send(Event::OutageCountdown);
+                webhook::fire(
+                    &core,
+                    "outage_countdown",
+                    serde_json::json!({ "level": level, "seconds": delay }),
+                );
+                outage_at = Some(now);
+            }
+            (_, Some(_)) if plugged || outage_delay.is_none() => {
+                let message = "Power restored, power off cancelled".to_string();
+                log::info!("{}", message);
+                notify_shutdown_stage(&core, message, level, 0.0);
+                let _ = event_tx.send(Event::OutageCancelled);
+                webhook::fire(&core, "outage_cancelled", serde_json::json!({ "level": level }));
+                outage_at = None;
+            }
+            _ => {}
+        }
+
+        // the earlier of battery low and outage
+        let battery_remain_secs = if battery_low {
+            let auto_shutdown_delay = core.config().auto_shutdown_delay.unwrap_or(0.0);
+            Some(auto_shutdown_delay - now.duration_since(battery_high_at).as_secs() as f64)
+        } else {
+            None
+        };
+        let outage_remain_secs = outage_at
+            .zip(outage_delay)
+            .map(|(at, delay)| delay - now.duration_since(at).as_secs() as f64);
+        let (reason, shutdown_remain_secs) = match (battery_remain_secs, outage_remain_secs) {
+            (Some(b), Some(o)) if o < b => ("outage", o),
+            (Some(b), _) => ("low_battery", b),
+            (None, Some(o)) => ("outage", o),
+            (None, None) => continue,
+        };
+        let reason_message = if reason == "outage" {
+            "Power lost"
+        } else {
+            "Low battery"
+        };
 
-        // notify battery low
+        // notify shutdown
         let should_notify = if shutdown_remain_secs > 0.0 {
             if shutdown_remain_secs < 10.0 {
                 notify_at + Duration::from_secs(1) < now // every 1s
@@ -1077,20 +2266,50 @@ async fn main() -> std::io::Result<()> {
             false
         };
         if should_notify {
-            let message = format!("Low battery, will power off after {} seconds", shutdown_remain_secs);
+            let message = format!(
+                "{}, will power off after {} seconds",
+                reason_message, shutdown_remain_secs
+            );
             log::warn!("{}", message);
-            notify_shutdown_soon(message.as_str());
+            notify_shutdown_stage(&core, message, level, shutdown_remain_secs);
             notify_at = now;
         }
 
         // shutdown
         if shutdown_remain_secs <= 0.0 {
-            let shell = core
-                .config()
-                .soft_poweroff_shell
-                .clone()
-                .unwrap_or_else(|| "shutdown --poweroff 0".to_string());
-            let _ = execute_shell(&shell);
+            if !shutdown_fired {
+                webhook::fire(
+                    &core,
+                    "shutdown",
+                    serde_json::json!({ "level": level, "reason": reason }),
+                );
+                shutdown_fired = true;
+            }
+
+            // power off through logind first, give other services a grace period
+            if core.config().shutdown_logind == Some(true) {
+                let grace = Duration::from_secs(core.config().shutdown_grace_period.unwrap_or(SHUTDOWN_GRACE_PERIOD));
+                let requested_at = *logind_poweroff_at.get_or_insert_with(|| {
+                    log::warn!(
+                        "{}, power off through logind, grace period {}s",
+                        reason_message,
+                        grace.as_secs()
+                    );
+                    std::thread::spawn(|| {
+                        if let Err(e) = logind_poweroff() {
+                            log::error!("Logind poweroff error: {}", e);
+                        }
+                    });
+                    now
+                });
+                if now < requested_at + grace {
+                    continue;
+                }
+            }
+
+            if let Err(e) = core.run_poweroff("shutdown") {
+                log::error!("Poweroff error: {}", e);
+            }
             tokio::time::sleep(Duration::from_secs(1)).await;
         }
     }
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+/// mDNS service type
+const SERVICE_TYPE: &str = "_pisugar._tcp.local.";
+
+/// Host name of the system
+pub fn hostname() -> String {
+    let mut buf = [0_u8; 256];
+    let r = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if r != 0 {
+        return "pisugar".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+/// Advertise `_pisugar._tcp` via mDNS, ports and model in TXT records.
+/// The returned daemon must be kept alive.
+pub fn advertise(port: u16, model: &str, ports: &[(&str, Option<u16>)]) -> Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()?;
+    let hostname = hostname();
+    let mut properties = HashMap::new();
+    properties.insert("model".to_string(), model.to_string());
+    properties.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+    for (name, port) in ports {
+        if let Some(port) = port {
+            properties.insert(name.to_string(), port.to_string());
+        }
+    }
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &hostname,
+        &format!("{}.local.", hostname),
+        "",
+        port,
+        properties,
+    )?
+    .enable_addr_auto();
+    daemon.register(service)?;
+    Ok(daemon)
+}
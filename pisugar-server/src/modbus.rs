@@ -0,0 +1,294 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use pisugar_core::{ModbusConfig, PiSugarCore};
+
+use crate::privilege::PendingBind;
+
+/// Register value of unsupported readings, the min of int16
+pub const MODBUS_UNSUPPORTED: u16 = 0x8000;
+
+/// Input and holding registers, both read-only
+pub const REGISTER_COUNT: u16 = 5;
+/// Battery level (0.1%)
+pub const REG_LEVEL: u16 = 0;
+/// Battery voltage (mV)
+pub const REG_VOLTAGE: u16 = 1;
+/// Battery current (mA), signed
+pub const REG_CURRENT: u16 = 2;
+/// Temperature (°C), signed
+pub const REG_TEMPERATURE: u16 = 3;
+/// Flags, bit 0 charging, 1 power plugged, 2 allow charging, 3 battery output
+pub const REG_FLAGS: u16 = 4;
+
+/// Discrete inputs: charging, power plugged
+pub const DISCRETE_INPUT_COUNT: u16 = 2;
+
+/// Coils: allow charging, battery output
+pub const COIL_COUNT: u16 = 2;
+pub const COIL_ALLOW_CHARGING: u16 = 0;
+pub const COIL_BATTERY_OUTPUT: u16 = 1;
+
+const FC_READ_COILS: u8 = 0x01;
+const FC_READ_DISCRETE_INPUTS: u8 = 0x02;
+const FC_READ_HOLDING_REGISTERS: u8 = 0x03;
+const FC_READ_INPUT_REGISTERS: u8 = 0x04;
+const FC_WRITE_SINGLE_COIL: u8 = 0x05;
+const FC_WRITE_MULTIPLE_COILS: u8 = 0x0f;
+
+const EX_ILLEGAL_FUNCTION: u8 = 0x01;
+const EX_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const EX_ILLEGAL_DATA_VALUE: u8 = 0x03;
+const EX_SERVER_DEVICE_FAILURE: u8 = 0x04;
+
+/// MBAP header: transaction id, protocol id, length, unit id
+const MBAP_HEADER_LEN: usize = 7;
+
+/// Readings of a request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub registers: [u16; REGISTER_COUNT as usize],
+    pub discrete_inputs: [bool; DISCRETE_INPUT_COUNT as usize],
+    pub coils: [bool; COIL_COUNT as usize],
+}
+
+impl Snapshot {
+    pub fn of(core: &PiSugarCore) -> Self {
+        let scaled = |r: pisugar_core::Result<f32>, scale: f32| {
+            r.map(|v| (v * scale).round() as i16 as u16)
+                .unwrap_or(MODBUS_UNSUPPORTED)
+        };
+        let charging = core.charging().unwrap_or_default();
+        let power_plugged = core.power_plugged().unwrap_or_default();
+        let allow_charging = core.allow_charging().unwrap_or_default();
+        let output = core.output_enabled().unwrap_or_default();
+        let flags = [charging, power_plugged, allow_charging, output]
+            .iter()
+            .enumerate()
+            .fold(0, |flags, (i, b)| flags | ((*b as u16) << i));
+        let mut registers = [MODBUS_UNSUPPORTED; REGISTER_COUNT as usize];
+        registers[REG_LEVEL as usize] = scaled(core.level(), 10.0);
+        registers[REG_VOLTAGE as usize] = scaled(core.voltage_avg(), 1000.0);
+        registers[REG_CURRENT as usize] = scaled(core.intensity_avg(), 1000.0);
+        registers[REG_TEMPERATURE as usize] = scaled(core.get_temperature(), 1.0);
+        registers[REG_FLAGS as usize] = flags;
+        Self {
+            registers,
+            discrete_inputs: [charging, power_plugged],
+            coils: [allow_charging, output],
+        }
+    }
+}
+
+/// Pack bits, lsb first
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| chunk.iter().enumerate().fold(0, |b, (i, bit)| b | ((*bit as u8) << i)))
+        .collect()
+}
+
+fn u16_at(pdu: &[u8], i: usize) -> Option<u16> {
+    pdu.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// Address range of a read, exception if it's out of the table
+fn read_range(pdu: &[u8], count: u16, max_quantity: u16) -> Result<std::ops::Range<usize>, u8> {
+    let (start, quantity) = match (u16_at(pdu, 1), u16_at(pdu, 3)) {
+        (Some(start), Some(quantity)) => (start, quantity),
+        _ => return Err(EX_ILLEGAL_DATA_VALUE),
+    };
+    if quantity == 0 || quantity > max_quantity {
+        return Err(EX_ILLEGAL_DATA_VALUE);
+    }
+    if start as u32 + quantity as u32 > count as u32 {
+        return Err(EX_ILLEGAL_DATA_ADDRESS);
+    }
+    Ok(start as usize..(start + quantity) as usize)
+}
+
+/// Response pdu and coil writes (address, on) of a request pdu, or an exception code
+type PduResult = Result<(Vec<u8>, Vec<(u16, bool)>), u8>;
+
+/// Handle a request pdu of the snapshot, writes are rejected if read-only
+pub fn handle_pdu(pdu: &[u8], snapshot: &Snapshot, read_only: bool) -> PduResult {
+    let fc = *pdu.first().ok_or(EX_ILLEGAL_FUNCTION)?;
+    let response = |data: Vec<u8>| [vec![fc, data.len() as u8], data].concat();
+    match fc {
+        FC_READ_COILS => {
+            let range = read_range(pdu, COIL_COUNT, 2000)?;
+            Ok((response(pack_bits(&snapshot.coils[range])), vec![]))
+        }
+        FC_READ_DISCRETE_INPUTS => {
+            let range = read_range(pdu, DISCRETE_INPUT_COUNT, 2000)?;
+            Ok((response(pack_bits(&snapshot.discrete_inputs[range])), vec![]))
+        }
+        FC_READ_HOLDING_REGISTERS | FC_READ_INPUT_REGISTERS => {
+            let range = read_range(pdu, REGISTER_COUNT, 125)?;
+            let data = snapshot.registers[range].iter().flat_map(|r| r.to_be_bytes()).collect();
+            Ok((response(data), vec![]))
+        }
+        FC_WRITE_SINGLE_COIL if !read_only => {
+            let (addr, value) = match (u16_at(pdu, 1), u16_at(pdu, 3)) {
+                (Some(addr), Some(value)) => (addr, value),
+                _ => return Err(EX_ILLEGAL_DATA_VALUE),
+            };
+            let on = match value {
+                0xff00 => true,
+                0x0000 => false,
+                _ => return Err(EX_ILLEGAL_DATA_VALUE),
+            };
+            if addr >= COIL_COUNT {
+                return Err(EX_ILLEGAL_DATA_ADDRESS);
+            }
+            Ok((pdu[..5].to_vec(), vec![(addr, on)]))
+        }
+        FC_WRITE_MULTIPLE_COILS if !read_only => {
+            let range = read_range(pdu, COIL_COUNT, 0x07b0)?;
+            let bytes = pdu.get(6..6 + *pdu.get(5).ok_or(EX_ILLEGAL_DATA_VALUE)? as usize);
+            let bytes = bytes.ok_or(EX_ILLEGAL_DATA_VALUE)?;
+            if bytes.len() != range.len().div_ceil(8) {
+                return Err(EX_ILLEGAL_DATA_VALUE);
+            }
+            let writes = range
+                .clone()
+                .enumerate()
+                .map(|(i, addr)| (addr as u16, bytes[i / 8] & (1 << (i % 8)) != 0))
+                .collect();
+            Ok((pdu[..5].to_vec(), writes))
+        }
+        _ => Err(EX_ILLEGAL_FUNCTION),
+    }
+}
+
+/// Response pdu of a request pdu, coil writes applied to core
+fn handle_request(pdu: &[u8], core: &Mutex<PiSugarCore>, read_only: bool) -> Vec<u8> {
+    let fc = pdu.first().copied().unwrap_or_default();
+    let core = match core.lock() {
+        Ok(core) => core,
+        Err(_) => return vec![fc | 0x80, EX_SERVER_DEVICE_FAILURE],
+    };
+    let r = handle_pdu(pdu, &Snapshot::of(&core), read_only).and_then(|(response, writes)| {
+        for (addr, on) in writes {
+            let r = match addr {
+                COIL_ALLOW_CHARGING => core.toggle_allow_charging(on),
+                COIL_BATTERY_OUTPUT => core.toggle_output_enabled(on),
+                _ => return Err(EX_ILLEGAL_DATA_ADDRESS),
+            };
+            if let Err(e) = r {
+                log::warn!("Modbus write coil {} error: {}", addr, e);
+                return Err(EX_SERVER_DEVICE_FAILURE);
+            }
+            log::info!("Modbus write coil {}: {}", addr, on);
+        }
+        Ok(response)
+    });
+    r.unwrap_or_else(|exception| vec![fc | 0x80, exception])
+}
+
+/// Serve frames of a connection, until closed
+async fn handle_stream(mut stream: TcpStream, core: Arc<Mutex<PiSugarCore>>, config: &ModbusConfig) -> Result<()> {
+    let mut header = [0; MBAP_HEADER_LEN];
+    loop {
+        if stream.read_exact(&mut header).await.is_err() {
+            return Ok(());
+        }
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if !(2..=254).contains(&len) {
+            anyhow::bail!("Invalid MBAP length: {}", len);
+        }
+        let mut pdu = vec![0; len - 1];
+        stream.read_exact(&mut pdu).await?;
+        let protocol_id = u16::from_be_bytes([header[2], header[3]]);
+        if protocol_id != 0 || matches!(config.unit_id, Some(unit_id) if unit_id != header[6]) {
+            continue;
+        }
+        let response = handle_request(&pdu, &core, config.read_only);
+        let mut frame = header[..4].to_vec();
+        frame.extend(((response.len() + 1) as u16).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend(response);
+        stream.write_all(&frame).await?;
+    }
+}
+
+/// Serve Modbus TCP, until the listener fails
+pub async fn serve(config: ModbusConfig, core: Arc<Mutex<PiSugarCore>>, mut bind: PendingBind) -> Result<()> {
+    let listener = TcpListener::bind(&config.listen).await?;
+    bind.done();
+    log::info!("Modbus TCP listening on {}", config.listen);
+    if !config.read_only && !listener.local_addr()?.ip().is_loopback() {
+        log::warn!("Modbus coil writes are enabled without auth, anyone on the network could cut the battery output");
+    }
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        log::debug!("Modbus TCP from {}", addr);
+        let (core, config) = (core.clone(), config.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(stream, core, &config).await {
+                log::debug!("Modbus TCP {} error: {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            registers: [805, 4012, (-350i16) as u16, MODBUS_UNSUPPORTED, 0b0110],
+            discrete_inputs: [false, true],
+            coils: [true, false],
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let s = snapshot();
+        let (response, writes) = handle_pdu(&[FC_READ_HOLDING_REGISTERS, 0, 1, 0, 2], &s, false).unwrap();
+        assert_eq!(response, vec![FC_READ_HOLDING_REGISTERS, 4, 0x0f, 0xac, 0xfe, 0xa2]);
+        assert!(writes.is_empty());
+        let (response, _) = handle_pdu(&[FC_READ_INPUT_REGISTERS, 0, 4, 0, 1], &s, false).unwrap();
+        assert_eq!(response, vec![FC_READ_INPUT_REGISTERS, 2, 0, 0b0110]);
+        let (response, _) = handle_pdu(&[FC_READ_COILS, 0, 0, 0, 2], &s, false).unwrap();
+        assert_eq!(response, vec![FC_READ_COILS, 1, 0b01]);
+        let (response, _) = handle_pdu(&[FC_READ_DISCRETE_INPUTS, 0, 1, 0, 1], &s, false).unwrap();
+        assert_eq!(response, vec![FC_READ_DISCRETE_INPUTS, 1, 0b1]);
+
+        assert_eq!(
+            handle_pdu(&[FC_READ_HOLDING_REGISTERS, 0, 4, 0, 2], &s, false),
+            Err(EX_ILLEGAL_DATA_ADDRESS)
+        );
+        assert_eq!(
+            handle_pdu(&[FC_READ_COILS, 0, 0, 0, 0], &s, false),
+            Err(EX_ILLEGAL_DATA_VALUE)
+        );
+        assert_eq!(handle_pdu(&[0x2b, 0x0e], &s, false), Err(EX_ILLEGAL_FUNCTION));
+    }
+
+    #[test]
+    fn test_write() {
+        let s = snapshot();
+        let pdu = [FC_WRITE_SINGLE_COIL, 0, 1, 0xff, 0];
+        assert_eq!(handle_pdu(&pdu, &s, false), Ok((pdu.to_vec(), vec![(1, true)])));
+        assert_eq!(handle_pdu(&pdu, &s, true), Err(EX_ILLEGAL_FUNCTION));
+        assert_eq!(
+            handle_pdu(&[FC_WRITE_SINGLE_COIL, 0, 1, 0x12, 0], &s, false),
+            Err(EX_ILLEGAL_DATA_VALUE)
+        );
+        assert_eq!(
+            handle_pdu(&[FC_WRITE_SINGLE_COIL, 0, 2, 0xff, 0], &s, false),
+            Err(EX_ILLEGAL_DATA_ADDRESS)
+        );
+
+        let pdu = [FC_WRITE_MULTIPLE_COILS, 0, 0, 0, 2, 1, 0b10];
+        assert_eq!(
+            handle_pdu(&pdu, &s, false),
+            Ok((pdu[..5].to_vec(), vec![(0, false), (1, true)]))
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::{bail, Result};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use pisugar_core::{notify_shutdown_soon, resolve_secret, NotifierBackend, NotifierConfig};
+
+use crate::mdns::hostname;
+
+/// Notification title
+const NOTIFY_TITLE: &str = "PiSugar";
+
+/// Template placeholders and values, e.g. ("level", "10")
+pub type NotifyVars = Vec<(&'static str, String)>;
+
+/// Render template, replace `{name}` with its value
+pub fn render_template(template: &str, vars: &[(&str, String)]) -> String {
+    let mut s = template.to_string();
+    for (name, value) in vars {
+        s = s.replace(&format!("{{{}}}", name), value);
+    }
+    s
+}
+
+/// Placeholders of shutdown notifications, besides {message}
+pub fn shutdown_vars(level: f32, seconds: f64, model: String) -> NotifyVars {
+    vec![
+        ("level", level.to_string()),
+        ("seconds", seconds.to_string()),
+        ("model", model),
+        ("hostname", hostname()),
+    ]
+}
+
+fn https_client() -> Client<HttpsConnector<HttpConnector>> {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(https)
+}
+
+/// Host of a url, paths and queries of webhook urls or bot tokens are secrets, so only the host is logged
+pub fn url_host(url: &str) -> String {
+    url.parse::<hyper::Uri>()
+        .ok()
+        .and_then(|uri| uri.host().map(str::to_string))
+        .unwrap_or_default()
+}
+
+pub async fn http_post(url: &str, headers: &[(&str, &str)], body: String) -> Result<()> {
+    let mut builder = Request::builder().method(Method::POST).uri(url);
+    for (name, value) in headers {
+        builder = builder.header(*name, *value);
+    }
+    let req = builder.body(Body::from(body))?;
+    let resp = https_client().request(req).await?;
+    if !resp.status().is_success() {
+        bail!("Http POST to {} error: {}", url_host(url), resp.status());
+    }
+    Ok(())
+}
+
+/// Write MQTT utf-8 string, 2 bytes length prefixed
+fn mqtt_write_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s.as_bytes());
+}
+
+/// Build MQTT packet, fixed header with variable length encoded remaining length
+fn mqtt_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut b = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            b |= 0x80;
+        }
+        packet.push(b);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+/// Publish a MQTT 3.1.1 message with QoS 0
+async fn mqtt_publish(
+    host: &str,
+    port: u16,
+    topic: &str,
+    username: Option<&str>,
+    password: Option<&str>,
+    payload: &str,
+) -> Result<()> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+
+    // CONNECT, clean session
+    let mut flags = 0b0000_0010;
+    let mut body = Vec::new();
+    mqtt_write_str(&mut body, "MQTT");
+    body.push(4);
+    if username.is_some() {
+        flags |= 0b1000_0000;
+    }
+    if password.is_some() {
+        flags |= 0b0100_0000;
+    }
+    body.push(flags);
+    body.extend(60_u16.to_be_bytes());
+    mqtt_write_str(&mut body, &format!("pisugar-{}", std::process::id()));
+    if let Some(username) = username {
+        mqtt_write_str(&mut body, username);
+    }
+    if let Some(password) = password {
+        mqtt_write_str(&mut body, password);
+    }
+    stream.write_all(&mqtt_packet(0x10, &body)).await?;
+
+    // CONNACK
+    let mut connack = [0; 4];
+    stream.read_exact(&mut connack).await?;
+    if connack[0] != 0x20 || connack[3] != 0 {
+        bail!("MQTT connection refused, code: {}", connack[3]);
+    }
+
+    // PUBLISH
+    let mut body = Vec::new();
+    mqtt_write_str(&mut body, topic);
+    body.extend(payload.as_bytes());
+    stream.write_all(&mqtt_packet(0x30, &body)).await?;
+
+    // DISCONNECT
+    stream.write_all(&[0xe0, 0]).await?;
+    Ok(())
+}
+
+/// Send message with a notifier
+pub async fn notify(notifier: &NotifierConfig, message: &str) -> Result<()> {
+    match &notifier.backend {
+        NotifierBackend::Wall => {
+            let message = message.to_string();
+            tokio::task::spawn_blocking(move || notify_shutdown_soon(&message)).await?;
+        }
+        NotifierBackend::NotifySend => {
+            let status = tokio::process::Command::new("notify-send")
+                .args([NOTIFY_TITLE, message])
+                .status()
+                .await?;
+            if !status.success() {
+                bail!("notify-send exit with {}", status);
+            }
+        }
+        NotifierBackend::Webhook { url } => {
+            let body = serde_json::json!({ "title": NOTIFY_TITLE, "message": message });
+            http_post(url, &[("Content-Type", "application/json")], body.to_string()).await?;
+        }
+        NotifierBackend::Mqtt {
+            host,
+            port,
+            topic,
+            username,
+            password,
+        } => {
+            let password = password.as_deref().map(resolve_secret);
+            mqtt_publish(host, *port, topic, username.as_deref(), password.as_deref(), message).await?;
+        }
+        NotifierBackend::Telegram { token, chat_id } => {
+            let url = format!("https://api.telegram.org/bot{}/sendMessage", resolve_secret(token));
+            let body = serde_json::json!({ "chat_id": chat_id, "text": message });
+            http_post(&url, &[("Content-Type", "application/json")], body.to_string()).await?;
+        }
+        NotifierBackend::Gotify { url, token } => {
+            let url = format!("{}/message", url.trim_end_matches('/'));
+            let body = serde_json::json!({ "title": NOTIFY_TITLE, "message": message, "priority": 8 });
+            let token = resolve_secret(token);
+            let headers = [("Content-Type", "application/json"), ("X-Gotify-Key", token.as_str())];
+            http_post(&url, &headers, body.to_string()).await?;
+        }
+        NotifierBackend::Ntfy { url } => {
+            http_post(url, &[("Title", NOTIFY_TITLE)], message.to_string()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Backend type and host of a notifier for logs, without its secrets
+fn notifier_label(backend: &NotifierBackend) -> String {
+    match backend {
+        NotifierBackend::Wall => "wall".to_string(),
+        NotifierBackend::NotifySend => "notify_send".to_string(),
+        NotifierBackend::Webhook { url } => format!("webhook {}", url_host(url)),
+        NotifierBackend::Mqtt { host, port, .. } => format!("mqtt {}:{}", host, port),
+        NotifierBackend::Telegram { .. } => "telegram".to_string(),
+        NotifierBackend::Gotify { url, .. } => format!("gotify {}", url_host(url)),
+        NotifierBackend::Ntfy { url } => format!("ntfy {}", url_host(url)),
+    }
+}
+
+/// Log line of a notifier error
+fn notify_error(backend: &NotifierBackend, e: &anyhow::Error) -> String {
+    format!("Notify error, {}: {}", notifier_label(backend), e)
+}
+
+/// Send message with all notifiers, the message is rendered with notifier's template
+pub async fn notify_all(notifiers: Vec<NotifierConfig>, message: String, mut vars: NotifyVars) {
+    vars.push(("message", message.clone()));
+    for notifier in notifiers {
+        let message = notifier
+            .template
+            .as_ref()
+            .map(|t| render_template(t, &vars))
+            .unwrap_or_else(|| message.clone());
+        if let Err(e) = notify(&notifier, &message).await {
+            log::warn!("{}", notify_error(&notifier.backend, &e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let vars = vec![("level", "9.5".to_string()), ("seconds", "30".to_string())];
+        let s = render_template("Battery {level}%, poweroff in {seconds}s {unknown}", &vars);
+        assert_eq!(s, "Battery 9.5%, poweroff in 30s {unknown}");
+
+        let vars = shutdown_vars(9.5, 30.0, "PiSugar 3".to_string());
+        let s = render_template("{hostname}: {model} {level}% {seconds}s", &vars);
+        assert_eq!(s, format!("{}: PiSugar 3 9.5% 30s", hostname()));
+    }
+
+    #[test]
+    fn test_mqtt_packet() {
+        assert_eq!(mqtt_packet(0xe0, &[]), vec![0xe0, 0]);
+        let packet = mqtt_packet(0x30, &[0; 200]);
+        assert_eq!(&packet[..3], &[0x30, 0xc8, 0x01]);
+        assert_eq!(packet.len(), 203);
+    }
+
+    #[tokio::test]
+    async fn test_notify_error_without_secrets() {
+        let backends = vec![
+            NotifierBackend::Telegram {
+                token: "123:telegram-secret".to_string(),
+                chat_id: "42".to_string(),
+            },
+            NotifierBackend::Gotify {
+                url: "http://127.0.0.1:1/gotify".to_string(),
+                token: "gotify-secret".to_string(),
+            },
+            NotifierBackend::Webhook {
+                url: "http://127.0.0.1:1/hooks/webhook-secret?key=webhook-secret".to_string(),
+            },
+            NotifierBackend::Mqtt {
+                host: "127.0.0.1".to_string(),
+                port: 1,
+                topic: "pisugar".to_string(),
+                username: Some("pi".to_string()),
+                password: Some("mqtt-secret".to_string()),
+            },
+        ];
+        for backend in backends {
+            let notifier = NotifierConfig {
+                backend,
+                template: None,
+            };
+            let label = notifier_label(&notifier.backend);
+            assert!(!label.contains("secret"), "{}", label);
+            if let NotifierBackend::Telegram { .. } = notifier.backend {
+                continue;
+            }
+            let e = notify(&notifier, "message").await.unwrap_err();
+            let line = notify_error(&notifier.backend, &e);
+            assert!(!line.contains("secret"), "{}", line);
+        }
+        let host = url_host("https://api.telegram.org/bot123:secret/sendMessage");
+        assert_eq!(host, "api.telegram.org");
+    }
+}
@@ -0,0 +1,73 @@
+use std::backtrace::Backtrace;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::panic;
+use std::sync::{Arc, Mutex, TryLockError};
+
+use chrono::Local;
+
+use pisugar_core::{Model, PiSugarConfig, PiSugarCore};
+
+/// Exit code after a panic, systemd restarts the service
+const EXIT_PANIC: i32 = 101;
+
+/// Append a panic to `audit_file`. A panic of the main thread, which polls the core, also restores safe defaults
+/// of the battery, i.e. charging allowed and output on, and exits. Panics of other threads only fail their task,
+/// a panic holding the core lock poisons it, and the next poll panics on the main thread.
+pub fn install_hook(core: Arc<Mutex<PiSugarCore>>, model: Model) {
+    let startup_config = core.lock().map(|c| c.config().clone()).ok();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let backtrace = Backtrace::force_capture();
+        log::error!("Panic: {}\n{}", info, backtrace);
+        let main_thread = std::thread::current().name() == Some("main");
+
+        // the lock is still held if the panic happens inside
+        let config = match core.try_lock() {
+            Ok(core) => Some(core.config().clone()),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner().config().clone()),
+            Err(TryLockError::WouldBlock) => startup_config.clone(),
+        };
+        if let Some(config) = config {
+            audit(&config, &info, &backtrace);
+            if main_thread {
+                restore(config, model);
+            }
+        }
+        if main_thread {
+            std::process::exit(EXIT_PANIC);
+        }
+    }));
+}
+
+fn audit(config: &PiSugarConfig, info: &dyn Display, backtrace: &Backtrace) {
+    let path = match &config.audit_file {
+        Some(path) => path,
+        None => return,
+    };
+    let r = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| writeln!(f, "{} panic: {}\n{}", Local::now().to_rfc3339(), info, backtrace));
+    if let Err(e) = r {
+        log::error!("Write audit file {} error: {}", path, e);
+    }
+}
+
+/// Allow charging and turn output on, through a new i2c binding
+fn restore(config: PiSugarConfig, model: Model) {
+    match PiSugarCore::new_without_init(config, model) {
+        Ok(core) => {
+            if let Err(e) = core.toggle_allow_charging(true) {
+                log::warn!("Restore allow charging error: {}", e);
+            }
+            if let Err(e) = core.toggle_output_enabled(true) {
+                log::warn!("Restore output error: {}", e);
+            }
+        }
+        Err(e) => log::error!("Restore battery error: {}", e),
+    }
+}
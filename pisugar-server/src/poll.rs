@@ -0,0 +1,52 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use pisugar_core::I2C_READ_INTERVAL;
+
+/// Poll interval when idle: nothing subscribed, watched or requested recently
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Clients are taken as active for a while after a request
+pub const ACTIVITY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Time of the last client request
+static LAST_REQUEST: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Record a client request
+pub fn touch() {
+    *LAST_REQUEST.lock().expect("unexpected lock failed") = Some(Instant::now());
+}
+
+/// Whether a client requested something recently
+pub fn recently_active() -> bool {
+    let last = *LAST_REQUEST.lock().expect("unexpected lock failed");
+    matches!(last, Some(t) if t.elapsed() <= ACTIVITY_WINDOW)
+}
+
+/// Interval of the next poll: fast during a shutdown countdown, slow in power save,
+/// fast when clients or policies need fresh data, otherwise idle
+pub fn adaptive_interval(countdown: bool, power_save: Option<Duration>, active: bool) -> Duration {
+    match (countdown, power_save) {
+        (true, _) => I2C_READ_INTERVAL,
+        (false, Some(interval)) => interval,
+        (false, None) if active => I2C_READ_INTERVAL,
+        _ => IDLE_POLL_INTERVAL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_interval() {
+        let power_save = Some(Duration::from_secs(10));
+        assert_eq!(adaptive_interval(true, power_save, false), I2C_READ_INTERVAL);
+        assert_eq!(adaptive_interval(false, power_save, true), Duration::from_secs(10));
+        assert_eq!(adaptive_interval(false, None, true), I2C_READ_INTERVAL);
+        assert_eq!(adaptive_interval(false, None, false), IDLE_POLL_INTERVAL);
+
+        touch();
+        assert!(recently_active());
+    }
+}
@@ -0,0 +1,250 @@
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use tokio::sync::watch;
+
+/// Capabilities the server uses when not root, (bit, name, what fails without it)
+const CAPABILITIES: [(u32, &str, &str); 2] = [
+    (22, "SYS_BOOT", "power off and reboot of the system"),
+    (25, "SYS_TIME", "syncing system time from rtc or ntp"),
+];
+
+lazy_static! {
+    /// Listeners yet to bind, root is dropped when there is none
+    static ref PENDING_BINDS: watch::Sender<usize> = watch::channel(0).0;
+}
+
+/// User to run as after root is dropped, `--user`
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// Look up a user in passwd
+pub fn lookup_user(name: &str) -> Result<User, String> {
+    let c_name = CString::new(name).map_err(|_| format!("invalid user: {}", name))?;
+    let pw = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if pw.is_null() {
+        return Err(format!("unknown user: {}", name));
+    }
+    let (uid, gid) = unsafe { ((*pw).pw_uid, (*pw).pw_gid) };
+    Ok(User {
+        name: name.to_string(),
+        uid,
+        gid,
+    })
+}
+
+/// A listener to bind before root is dropped, done on its first bind attempt, or when dropped
+pub struct PendingBind(bool);
+
+impl PendingBind {
+    pub fn done(&mut self) {
+        if std::mem::take(&mut self.0) {
+            PENDING_BINDS.send_modify(|n| *n -= 1);
+        }
+    }
+}
+
+impl Drop for PendingBind {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+/// Register a listener to bind before root is dropped
+pub fn pending_bind() -> PendingBind {
+    PENDING_BINDS.send_modify(|n| *n += 1);
+    PendingBind(true)
+}
+
+/// Wait for pending listeners to bind
+pub async fn binds_done() {
+    let mut rx = PENDING_BINDS.subscribe();
+    while *rx.borrow_and_update() > 0 {
+        if rx.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Drop root to a user, with its groups, for all threads
+pub fn drop_to(user: &User) -> io::Result<()> {
+    let c_name = CString::new(user.name.as_str()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    unsafe {
+        if libc::initgroups(c_name.as_ptr(), user.gid as _) != 0
+            || libc::setgid(user.gid) != 0
+            || libc::setuid(user.uid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::getuid() != user.uid || libc::geteuid() != user.uid || libc::setuid(0) == 0 {
+            return Err(io::Error::other("root is not dropped"));
+        }
+    }
+    log::info!("Dropped root, running as {} ({}:{})", user.name, user.uid, user.gid);
+    Ok(())
+}
+
+/// Whether the current user has access to a file, `libc::R_OK` or `libc::W_OK`
+pub fn accessible(path: &Path, mode: libc::c_int) -> bool {
+    CString::new(path.as_os_str().as_bytes())
+        .map(|c_path| unsafe { libc::access(c_path.as_ptr(), mode) } == 0)
+        .unwrap_or(false)
+}
+
+/// Check a file of secrets is not accessible to group and others
+pub fn check_secret_file(path: &Path) -> Result<(), String> {
+    let mode = fs::metadata(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "{} is accessible to others (mode {:o}), `chmod 600 {}`",
+            path.display(),
+            mode & 0o777,
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Bus of an i2c device path, `/dev/i2c-1` is bus 1
+pub fn parse_i2c_dev(s: &str) -> Result<u8, String> {
+    s.strip_prefix("/dev/i2c-")
+        .and_then(|bus| bus.parse().ok())
+        .ok_or_else(|| format!("invalid i2c device: {}, expect /dev/i2c-N", s))
+}
+
+/// Check an i2c device is accessible, Err with a hint of how to pass it through otherwise
+pub fn check_i2c_dev(bus: u8) -> Result<(), String> {
+    let dev = format!("/dev/i2c-{}", bus);
+    match OpenOptions::new().read(true).write(true).open(&dev) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(format!(
+            "{} not found, enable i2c on the host (raspi-config), in a container pass it through, e.g. `docker run --device {}`",
+            dev, dev
+        )),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(format!(
+            "{} permission denied, run as root or in the group of the device, in a container e.g. `docker run --group-add $(stat -c %g {})`",
+            dev, dev
+        )),
+        Err(e) => Err(format!("{}: {}", dev, e)),
+    }
+}
+
+/// Effective capabilities, CapEff of /proc/self/status
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+}
+
+/// Capabilities missing from the effective set, (name, what fails without it)
+fn missing_capabilities(cap_eff: u64) -> Vec<(&'static str, &'static str)> {
+    CAPABILITIES
+        .iter()
+        .filter(|(bit, _, _)| cap_eff & (1 << bit) == 0)
+        .map(|(_, name, usage)| (*name, *usage))
+        .collect()
+}
+
+/// Warn about capabilities missing for system time and power off, e.g. of a non-root user or a container
+pub fn check_capabilities() {
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 {
+        log::info!("Running as uid {}", euid);
+    }
+    let cap_eff = match fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|s| parse_cap_eff(&s))
+    {
+        Some(cap_eff) => cap_eff,
+        None => return,
+    };
+    for (name, usage) in missing_capabilities(cap_eff) {
+        log::warn!(
+            "Missing CAP_{}, {} will fail, run as root or grant it, e.g. `docker run --cap-add {}`",
+            name,
+            usage,
+            name
+        );
+    }
+}
+
+/// Chown a file, if it's owned by someone else
+pub fn chown(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    let meta = fs::metadata(path)?;
+    if (meta.uid(), meta.gid()) == (uid, gid) {
+        return Ok(());
+    }
+    let c_path =
+        CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    log::info!("Chown {} to {}:{}", path.display(), uid, gid);
+    Ok(())
+}
+
+/// Chown a socket file to the user root is dropped to, or to the owner of its directory, e.g. a volume shared with
+/// clients of another user, only as root
+pub fn chown_socket(path: &Path, user: Option<&User>) -> io::Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+    if let Some(user) = user {
+        return chown(path, user.uid, user.gid);
+    }
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let dir_meta = fs::metadata(dir)?;
+    chown(path, dir_meta.uid(), dir_meta.gid())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn test_privilege_checks() {
+        assert_eq!(parse_i2c_dev("/dev/i2c-1"), Ok(1));
+        assert_eq!(parse_i2c_dev("/dev/i2c-22"), Ok(22));
+        assert!(parse_i2c_dev("/dev/i2c1").is_err());
+        assert!(parse_i2c_dev("/host/dev/i2c-1").is_err());
+
+        let status = "Name:\tpisugar-server\nCapInh:\t0000000000000000\nCapEff:\t0000000002000000\n";
+        let cap_eff = parse_cap_eff(status).unwrap();
+        assert_eq!(cap_eff, 1 << 25);
+        assert_eq!(
+            missing_capabilities(cap_eff),
+            vec![("SYS_BOOT", "power off and reboot of the system")]
+        );
+        assert!(missing_capabilities(0x3f_ffff_ffff).is_empty());
+        assert_eq!(parse_cap_eff("Name:\tx\n"), None);
+
+        let secret = std::env::temp_dir().join(format!("pisugar-secret-{}", std::process::id()));
+        fs::write(&secret, "secret").unwrap();
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(check_secret_file(&secret).unwrap_err().contains("mode 644"));
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(check_secret_file(&secret), Ok(()));
+        fs::remove_file(&secret).unwrap();
+
+        let root = lookup_user("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(lookup_user("no-such-user-of-pisugar").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use futures::Stream;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio::time::error::Elapsed;
+
+use crate::conn::next_within;
+
+/// Last id of sessions, connections and http auth sessions share it
+static LAST_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Transports of connection stats
+const TRANSPORTS: [&str; 4] = ["tcp", "uds", "ws", "http"];
+
+lazy_static! {
+    /// Open tcp/uds/ws connections, and their revoke signals
+    static ref CONNECTIONS: Mutex<BTreeMap<u64, (Session, Arc<Notify>)>> = Mutex::new(BTreeMap::new());
+    /// Connection stats of transports
+    static ref STATS: Mutex<BTreeMap<&'static str, TransportStats>> =
+        Mutex::new(TRANSPORTS.iter().map(|t| (*t, TransportStats::default())).collect());
+}
+
+/// Connection stats of a transport, of `get connections`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TransportStats {
+    /// Open connections
+    pub active: u64,
+    /// Connections since start
+    pub total: u64,
+    /// Commands served
+    pub commands: u64,
+    /// Commands answered with `Invalid request.`
+    pub errors: u64,
+    /// Last connection or command
+    pub last_activity: Option<DateTime<Local>>,
+}
+
+fn update_stats(transport: &'static str, f: impl FnOnce(&mut TransportStats)) {
+    if let Ok(mut stats) = STATS.lock() {
+        f(stats.entry(transport).or_default());
+    }
+}
+
+/// Count a command served by a transport
+pub fn served(transport: &'static str, ok: bool) {
+    update_stats(transport, |stats| {
+        stats.commands += 1;
+        stats.errors += u64::from(!ok);
+        stats.last_activity = Some(Local::now());
+    });
+}
+
+/// Connection stats of transports
+pub fn stats() -> BTreeMap<&'static str, TransportStats> {
+    STATS.lock().map(|stats| stats.clone()).unwrap_or_default()
+}
+
+/// An open connection of a transport, it's counted active until dropped
+pub struct Activity {
+    transport: &'static str,
+}
+
+impl Activity {
+    pub fn new(transport: &'static str) -> Self {
+        update_stats(transport, |stats| {
+            stats.active += 1;
+            stats.total += 1;
+            stats.last_activity = Some(Local::now());
+        });
+        Self { transport }
+    }
+
+    /// Count a command served by the connection
+    pub fn served(&self, ok: bool) {
+        served(self.transport, ok);
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        update_stats(self.transport, |stats| stats.active -= 1);
+    }
+}
+
+/// A connection or an http auth session, of `get sessions`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Session {
+    pub id: u64,
+    /// tcp, uds, ws or http
+    pub transport: &'static str,
+    /// Peer address, or credentials of a uds peer
+    pub peer: String,
+    pub issued_at: DateTime<Local>,
+}
+
+impl Session {
+    pub fn new(transport: &'static str, peer: String) -> Self {
+        Self {
+            id: LAST_ID.fetch_add(1, Ordering::Relaxed) + 1,
+            transport,
+            peer,
+            issued_at: Local::now(),
+        }
+    }
+}
+
+/// An open connection, it's listed until dropped
+pub struct Connection {
+    id: u64,
+    revoke: Arc<Notify>,
+    activity: Activity,
+}
+
+impl Connection {
+    /// Next request of the connection like `next_within`, none if it's revoked
+    pub async fn next_within<S>(&self, stream: &mut S, timeout: Option<Duration>) -> Result<Option<S::Item>, Elapsed>
+    where
+        S: Stream + Unpin,
+    {
+        tokio::select! {
+            next = next_within(stream, timeout) => next,
+            _ = self.revoke.notified() => {
+                log::info!("Session {} revoked", self.id);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Count a command served by the connection
+    pub fn served(&self, ok: bool) {
+        self.activity.served(ok);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = CONNECTIONS.lock() {
+            connections.remove(&self.id);
+        }
+    }
+}
+
+/// Register an open connection
+pub fn connect(transport: &'static str, peer: String) -> Connection {
+    let session = Session::new(transport, peer);
+    let (id, revoke, activity) = (session.id, Arc::new(Notify::new()), Activity::new(transport));
+    log::debug!("Session {} of {} {}", id, session.transport, session.peer);
+    if let Ok(mut connections) = CONNECTIONS.lock() {
+        connections.insert(id, (session, revoke.clone()));
+    }
+    Connection { id, revoke, activity }
+}
+
+/// Open connections
+pub fn connections() -> Vec<Session> {
+    CONNECTIONS
+        .lock()
+        .map(|connections| connections.values().map(|(s, _)| s.clone()).collect())
+        .unwrap_or_default()
+}
+
+/// Close a connection, false if there isn't such one
+pub fn revoke(id: u64) -> bool {
+    match CONNECTIONS.lock().ok().and_then(|c| c.get(&id).map(|(_, r)| r.clone())) {
+        Some(revoke) => {
+            // a permit is stored if the connection is busy with a request
+            revoke.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let conn = connect("tcp", "127.0.0.1:40000".to_string());
+        let session = connections().into_iter().find(|s| s.id == conn.id).unwrap();
+        assert_eq!(session.transport, "tcp");
+        assert_eq!(session.peer, "127.0.0.1:40000");
+        conn.served(true);
+        conn.served(false);
+        let tcp = stats()["tcp"].clone();
+        assert!(tcp.active >= 1 && tcp.total >= 1 && tcp.commands >= 2 && tcp.errors >= 1);
+        assert!(tcp.last_activity.is_some());
+        assert!(stats().contains_key("uds"));
+
+        let mut pending = futures::stream::pending::<()>();
+        assert!(revoke(conn.id));
+        assert_eq!(conn.next_within(&mut pending, None).await, Ok(None));
+
+        let id = conn.id;
+        drop(conn);
+        assert!(connections().iter().all(|s| s.id != id));
+        assert!(!revoke(id));
+    }
+}
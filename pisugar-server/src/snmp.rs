@@ -0,0 +1,517 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::net::UdpSocket;
+
+use pisugar_core::{resolve_secret, PiSugarCore, SnmpConfig};
+
+use crate::privilege::PendingBind;
+
+/// Base oid of PISUGAR-MIB, netSnmpPlaypen.1 of NET-SNMP-MIB
+pub const SNMP_BASE_OID: &[u32] = &[1, 3, 6, 1, 4, 1, 8072, 9999, 9999, 1];
+
+/// Max varbinds of a GetBulk response
+const SNMP_MAX_VARBINDS: usize = 64;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+const PDU_GET: u8 = 0xa0;
+const PDU_GET_NEXT: u8 = 0xa1;
+const PDU_RESPONSE: u8 = 0xa2;
+const PDU_SET: u8 = 0xa3;
+const PDU_GET_BULK: u8 = 0xa5;
+
+const VERSION_1: i64 = 0;
+
+/// Error status of v1 for unknown or read-only objects
+const ERROR_NO_SUCH_NAME: i64 = 2;
+/// Error status of v2c for sets
+const ERROR_NOT_WRITABLE: i64 = 17;
+
+/// TruthValue of SNMPv2-TC
+const TRUE: i64 = 1;
+const FALSE: i64 = 2;
+
+/// Value of a varbind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnmpValue {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    Gauge32(u32),
+    Null,
+    NoSuchObject,
+    EndOfMibView,
+}
+
+type Oid = Vec<u32>;
+
+fn encode_len(len: usize, buf: &mut Vec<u8>) {
+    if len < 0x80 {
+        buf.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().copied().skip_while(|b| *b == 0).collect();
+        buf.push(0x80 | bytes.len() as u8);
+        buf.extend(bytes);
+    }
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut buf = vec![tag];
+    encode_len(content.len(), &mut buf);
+    buf.extend_from_slice(content);
+    buf
+}
+
+/// Minimal two's complement
+fn encode_integer(tag: u8, v: i64) -> Vec<u8> {
+    let bytes = v.to_be_bytes();
+    let mut start = 0;
+    while start < 7 {
+        let (b, next) = (bytes[start], bytes[start + 1]);
+        if (b == 0 && next & 0x80 == 0) || (b == 0xff && next & 0x80 != 0) {
+            start += 1;
+        } else {
+            break;
+        }
+    }
+    tlv(tag, &bytes[start..])
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut content = Vec::new();
+    if oid.len() >= 2 {
+        content.push((oid[0] * 40 + oid[1]) as u8);
+    }
+    for n in oid.iter().skip(2) {
+        let mut n = *n;
+        let mut bytes = vec![(n & 0x7f) as u8];
+        n >>= 7;
+        while n > 0 {
+            bytes.push(0x80 | (n & 0x7f) as u8);
+            n >>= 7;
+        }
+        content.extend(bytes.iter().rev());
+    }
+    tlv(TAG_OID, &content)
+}
+
+fn encode_value(value: &SnmpValue) -> Vec<u8> {
+    match value {
+        SnmpValue::Integer(v) => encode_integer(TAG_INTEGER, *v),
+        SnmpValue::OctetString(s) => tlv(TAG_OCTET_STRING, s),
+        SnmpValue::Gauge32(v) => encode_integer(TAG_GAUGE32, *v as i64),
+        SnmpValue::Null => tlv(TAG_NULL, &[]),
+        SnmpValue::NoSuchObject => tlv(TAG_NO_SUCH_OBJECT, &[]),
+        SnmpValue::EndOfMibView => tlv(TAG_END_OF_MIB_VIEW, &[]),
+    }
+}
+
+/// Two's complement, up to 8 bytes
+fn decode_integer(content: &[u8]) -> Result<i64> {
+    if content.is_empty() || content.len() > 8 {
+        bail!("Invalid integer");
+    }
+    let init = if content[0] & 0x80 != 0 { -1 } else { 0 };
+    Ok(content.iter().fold(init, |v, b| (v << 8) | *b as i64))
+}
+
+/// Reader of BER TLVs
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self) -> Result<(u8, &'a [u8])> {
+        let invalid = || anyhow!("Invalid BER");
+        let tag = *self.buf.first().ok_or_else(invalid)?;
+        let first = *self.buf.get(1).ok_or_else(invalid)?;
+        let (len, header) = if first & 0x80 == 0 {
+            (first as usize, 2)
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 {
+                bail!("Invalid BER length");
+            }
+            let bytes = self.buf.get(2..2 + n).ok_or_else(invalid)?;
+            (bytes.iter().fold(0, |len, b| (len << 8) | *b as usize), 2 + n)
+        };
+        // a peer-controlled length overflows usize of 32-bit targets
+        let end = header.checked_add(len).ok_or_else(invalid)?;
+        let content = self.buf.get(header..end).ok_or_else(invalid)?;
+        self.buf = &self.buf[end..];
+        Ok((tag, content))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8]> {
+        match self.read()? {
+            (t, content) if t == tag => Ok(content),
+            (t, _) => bail!("Unexpected tag 0x{:02x}, expect 0x{:02x}", t, tag),
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        decode_integer(self.expect(TAG_INTEGER)?)
+    }
+
+    #[cfg(test)]
+    fn value(&mut self) -> Result<SnmpValue> {
+        let (tag, content) = self.read()?;
+        Ok(match tag {
+            TAG_INTEGER => SnmpValue::Integer(decode_integer(content)?),
+            TAG_OCTET_STRING => SnmpValue::OctetString(content.to_vec()),
+            TAG_GAUGE32 => SnmpValue::Gauge32(decode_integer(content)? as u32),
+            TAG_NO_SUCH_OBJECT => SnmpValue::NoSuchObject,
+            TAG_END_OF_MIB_VIEW => SnmpValue::EndOfMibView,
+            _ => SnmpValue::Null,
+        })
+    }
+
+    fn oid(&mut self) -> Result<Oid> {
+        let content = self.expect(TAG_OID)?;
+        let (first, rest) = content.split_first().ok_or_else(|| anyhow!("Empty oid"))?;
+        let mut oid = vec![(*first / 40) as u32, (*first % 40) as u32];
+        let mut n: u32 = 0;
+        for b in rest {
+            n = n.checked_shl(7).ok_or_else(|| anyhow!("Invalid oid"))? | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                oid.push(n);
+                n = 0;
+            }
+        }
+        Ok(oid)
+    }
+}
+
+/// Request of a message
+#[derive(Debug, PartialEq)]
+struct SnmpRequest {
+    version: i64,
+    community: Vec<u8>,
+    pdu: u8,
+    request_id: i64,
+    /// non-repeaters of GetBulk
+    non_repeaters: i64,
+    /// max-repetitions of GetBulk
+    max_repetitions: i64,
+    oids: Vec<Oid>,
+}
+
+fn parse_request(packet: &[u8]) -> Result<SnmpRequest> {
+    let mut message = Reader {
+        buf: Reader { buf: packet }.expect(TAG_SEQUENCE)?,
+    };
+    let version = message.integer()?;
+    let community = message.expect(TAG_OCTET_STRING)?.to_vec();
+    let (pdu, content) = message.read()?;
+    let mut pdu_reader = Reader { buf: content };
+    let request_id = pdu_reader.integer()?;
+    let non_repeaters = pdu_reader.integer()?;
+    let max_repetitions = pdu_reader.integer()?;
+    let mut varbinds = Reader {
+        buf: pdu_reader.expect(TAG_SEQUENCE)?,
+    };
+    let mut oids = Vec::new();
+    while !varbinds.buf.is_empty() {
+        let mut varbind = Reader {
+            buf: varbinds.expect(TAG_SEQUENCE)?,
+        };
+        oids.push(varbind.oid()?);
+    }
+    Ok(SnmpRequest {
+        version,
+        community,
+        pdu,
+        request_id,
+        non_repeaters,
+        max_repetitions,
+        oids,
+    })
+}
+
+fn encode_response(request: &SnmpRequest, error: (i64, i64), varbinds: &[(Oid, SnmpValue)]) -> Vec<u8> {
+    let varbinds: Vec<u8> = varbinds
+        .iter()
+        .flat_map(|(oid, value)| tlv(TAG_SEQUENCE, &[encode_oid(oid), encode_value(value)].concat()))
+        .collect();
+    let pdu = [
+        encode_integer(TAG_INTEGER, request.request_id),
+        encode_integer(TAG_INTEGER, error.0),
+        encode_integer(TAG_INTEGER, error.1),
+        tlv(TAG_SEQUENCE, &varbinds),
+    ]
+    .concat();
+    let message = [
+        encode_integer(TAG_INTEGER, request.version),
+        tlv(TAG_OCTET_STRING, &request.community),
+        tlv(PDU_RESPONSE, &pdu),
+    ]
+    .concat();
+    tlv(TAG_SEQUENCE, &message)
+}
+
+/// Objects of PISUGAR-MIB, sorted by oid, unreadable ones are left out
+pub fn mib(core: &PiSugarCore, base: &[u32]) -> Vec<(Oid, SnmpValue)> {
+    let truth = |b: bool| SnmpValue::Integer(if b { TRUE } else { FALSE });
+    let milli = |v: f32| SnmpValue::Integer((v * 1000.0).round() as i64);
+    let objects = vec![
+        (1, Some(SnmpValue::OctetString(core.model().into_bytes()))),
+        (
+            2,
+            core.level()
+                .ok()
+                .map(|l| SnmpValue::Gauge32(l.round().clamp(0.0, 100.0) as u32)),
+        ),
+        (3, core.voltage_avg().ok().map(milli)),
+        (4, core.intensity_avg().ok().map(milli)),
+        (5, core.charging().ok().map(truth)),
+        (6, core.power_plugged().ok().map(truth)),
+        (7, core.allow_charging().ok().map(truth)),
+        (
+            8,
+            core.get_temperature()
+                .ok()
+                .map(|t| SnmpValue::Integer(t.round() as i64)),
+        ),
+    ];
+    objects
+        .into_iter()
+        .filter_map(|(n, value)| value.map(|v| ([base, &[n, 0]].concat(), v)))
+        .collect()
+}
+
+/// Response of a request, None if it should be dropped
+fn respond(request: &SnmpRequest, community: &str, mib: &[(Oid, SnmpValue)]) -> Option<Vec<u8>> {
+    if request.community != community.as_bytes() || request.version > 1 {
+        return None;
+    }
+    let v1 = request.version == VERSION_1;
+    let get = |oid: &Oid| mib.iter().find(|(o, _)| o == oid).map(|(_, v)| v.clone());
+    let next = |oid: &Oid| mib.iter().find(|(o, _)| o > oid).cloned();
+    let varbinds: Vec<(Oid, SnmpValue)> = request.oids.iter().map(|oid| (oid.clone(), SnmpValue::Null)).collect();
+    // v1 has no exceptions in varbinds, but an error of the first failed one
+    let v1_error = |i: usize| Some(encode_response(request, (ERROR_NO_SUCH_NAME, i as i64 + 1), &varbinds));
+
+    let mut response = Vec::new();
+    match request.pdu {
+        PDU_GET => {
+            for (i, oid) in request.oids.iter().enumerate() {
+                match get(oid) {
+                    Some(value) => response.push((oid.clone(), value)),
+                    None if v1 => return v1_error(i),
+                    None => response.push((oid.clone(), SnmpValue::NoSuchObject)),
+                }
+            }
+        }
+        PDU_GET_NEXT => {
+            for (i, oid) in request.oids.iter().enumerate() {
+                match next(oid) {
+                    Some(varbind) => response.push(varbind),
+                    None if v1 => return v1_error(i),
+                    None => response.push((oid.clone(), SnmpValue::EndOfMibView)),
+                }
+            }
+        }
+        PDU_GET_BULK if !v1 => {
+            let non_repeaters = (request.non_repeaters.max(0) as usize).min(request.oids.len());
+            let (singles, repeaters) = request.oids.split_at(non_repeaters);
+            for oid in singles {
+                response.push(next(oid).unwrap_or_else(|| (oid.clone(), SnmpValue::EndOfMibView)));
+            }
+            let mut cursors: Vec<Oid> = repeaters.to_vec();
+            for _ in 0..request.max_repetitions.max(0) {
+                if cursors.is_empty() || response.len() >= SNMP_MAX_VARBINDS {
+                    break;
+                }
+                for cursor in cursors.iter_mut() {
+                    let varbind = next(cursor).unwrap_or_else(|| (cursor.clone(), SnmpValue::EndOfMibView));
+                    *cursor = varbind.0.clone();
+                    response.push(varbind);
+                }
+                if response
+                    .iter()
+                    .rev()
+                    .take(cursors.len())
+                    .all(|(_, v)| *v == SnmpValue::EndOfMibView)
+                {
+                    break;
+                }
+            }
+            response.truncate(SNMP_MAX_VARBINDS);
+        }
+        PDU_SET if v1 => return v1_error(0),
+        PDU_SET => return Some(encode_response(request, (ERROR_NOT_WRITABLE, 1), &varbinds)),
+        _ => return None,
+    }
+    Some(encode_response(request, (0, 0), &response))
+}
+
+/// Parse a dotted oid, e.g. 1.3.6.1.4.1
+fn parse_oid(s: &str) -> Result<Oid> {
+    s.trim_start_matches('.')
+        .split('.')
+        .map(|n| n.parse().map_err(|_| anyhow!("Invalid oid: {}", s)))
+        .collect()
+}
+
+/// Serve SNMP requests, until the socket fails
+pub async fn serve(config: SnmpConfig, core: Arc<Mutex<PiSugarCore>>, mut bind: PendingBind) -> Result<()> {
+    let base = match &config.base_oid {
+        Some(oid) => parse_oid(oid)?,
+        None => SNMP_BASE_OID.to_vec(),
+    };
+    let community = resolve_secret(&config.community);
+    let socket = UdpSocket::bind(&config.listen).await?;
+    bind.done();
+    log::info!("SNMP listening on {}", config.listen);
+    let mut buf = vec![0; 65535];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        let request = match parse_request(&buf[..n]) {
+            Ok(request) => request,
+            Err(e) => {
+                log::debug!("Invalid SNMP request from {}: {}", peer, e);
+                continue;
+            }
+        };
+        let mib = match core.lock() {
+            Ok(core) => mib(&core, &base),
+            Err(_) => continue,
+        };
+        if let Some(response) = respond(&request, &community, &mib) {
+            socket.send_to(&response, peer).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(version: i64, pdu: u8, fields: (i64, i64), oids: &[Oid]) -> Vec<u8> {
+        let varbinds: Vec<(Oid, SnmpValue)> = oids.iter().map(|o| (o.clone(), SnmpValue::Null)).collect();
+        let mut packet = encode_response(
+            &SnmpRequest {
+                version,
+                community: b"public".to_vec(),
+                pdu,
+                request_id: 1234,
+                non_repeaters: 0,
+                max_repetitions: 0,
+                oids: vec![],
+            },
+            fields,
+            &varbinds,
+        );
+        // encoded as a response, switch the pdu tag
+        let pos = packet.iter().position(|b| *b == PDU_RESPONSE).unwrap();
+        packet[pos] = pdu;
+        packet
+    }
+
+    fn oid(s: &str) -> Oid {
+        parse_oid(s).unwrap()
+    }
+
+    fn test_mib() -> Vec<(Oid, SnmpValue)> {
+        vec![
+            (
+                oid("1.3.6.1.4.1.8072.9999.9999.1.1.0"),
+                SnmpValue::OctetString(b"PiSugar 3".to_vec()),
+            ),
+            (oid("1.3.6.1.4.1.8072.9999.9999.1.2.0"), SnmpValue::Gauge32(80)),
+            (oid("1.3.6.1.4.1.8072.9999.9999.1.4.0"), SnmpValue::Integer(-350)),
+        ]
+    }
+
+    fn varbinds(response: &[u8]) -> (i64, Vec<(Oid, SnmpValue)>) {
+        let mut message = Reader {
+            buf: Reader { buf: response }.expect(TAG_SEQUENCE).unwrap(),
+        };
+        message.integer().unwrap();
+        message.expect(TAG_OCTET_STRING).unwrap();
+        let mut pdu = Reader {
+            buf: message.expect(PDU_RESPONSE).unwrap(),
+        };
+        assert_eq!(pdu.integer().unwrap(), 1234);
+        let error = pdu.integer().unwrap();
+        pdu.integer().unwrap();
+        let mut list = Reader {
+            buf: pdu.expect(TAG_SEQUENCE).unwrap(),
+        };
+        let mut varbinds = Vec::new();
+        while !list.buf.is_empty() {
+            let mut varbind = Reader {
+                buf: list.expect(TAG_SEQUENCE).unwrap(),
+            };
+            varbinds.push((varbind.oid().unwrap(), varbind.value().unwrap()));
+        }
+        (error, varbinds)
+    }
+
+    #[test]
+    fn test_ber() {
+        for v in [0, 127, 128, -1, -129, 65535, i64::MIN] {
+            assert_eq!(
+                Reader {
+                    buf: &encode_integer(TAG_INTEGER, v)
+                }
+                .integer()
+                .unwrap(),
+                v
+            );
+        }
+        assert_eq!(encode_integer(TAG_GAUGE32, 200), vec![TAG_GAUGE32, 2, 0, 200]);
+        let o = oid("1.3.6.1.4.1.8072.9999.9999.1.2.0");
+        assert_eq!(Reader { buf: &encode_oid(&o) }.oid().unwrap(), o);
+        let long = tlv(TAG_OCTET_STRING, &[b'x'; 300]);
+        assert_eq!(&long[..4], &[TAG_OCTET_STRING, 0x82, 0x01, 0x2c]);
+        assert_eq!(
+            Reader { buf: &long }.value().unwrap(),
+            SnmpValue::OctetString(vec![b'x'; 300])
+        );
+        assert!(parse_request(&[TAG_SEQUENCE, 0x10, 0x02]).is_err());
+        assert!(parse_request(&[TAG_SEQUENCE, 0x84, 0xff, 0xff, 0xff, 0xff, 0x02]).is_err());
+    }
+
+    #[test]
+    fn test_respond() {
+        let mib = test_mib();
+        let level = oid("1.3.6.1.4.1.8072.9999.9999.1.2.0");
+        let unknown = oid("1.3.6.1.4.1.8072.9999.9999.1.3.0");
+
+        let get = parse_request(&request(1, PDU_GET, (0, 0), &[level.clone(), unknown.clone()])).unwrap();
+        assert_eq!(get.oids, vec![level.clone(), unknown.clone()]);
+        let (error, values) = varbinds(&respond(&get, "public", &mib).unwrap());
+        assert_eq!(error, 0);
+        assert_eq!(values[0], (level.clone(), SnmpValue::Gauge32(80)));
+        assert_eq!(values[1], (unknown.clone(), SnmpValue::NoSuchObject));
+        assert_eq!(respond(&get, "private", &mib), None);
+
+        let get_v1 = parse_request(&request(0, PDU_GET, (0, 0), std::slice::from_ref(&unknown))).unwrap();
+        assert_eq!(
+            varbinds(&respond(&get_v1, "public", &mib).unwrap()).0,
+            ERROR_NO_SUCH_NAME
+        );
+
+        // walk
+        let next = parse_request(&request(1, PDU_GET_NEXT, (0, 0), std::slice::from_ref(&level))).unwrap();
+        let (_, values) = varbinds(&respond(&next, "public", &mib).unwrap());
+        assert_eq!(values[0], mib[2]);
+
+        let bulk = parse_request(&request(1, PDU_GET_BULK, (0, 10), &[oid("1.3.6.1.4.1.8072")])).unwrap();
+        let (_, values) = varbinds(&respond(&bulk, "public", &mib).unwrap());
+        assert_eq!(&values[..3], &mib[..]);
+        assert_eq!(values[3].1, SnmpValue::EndOfMibView);
+        assert_eq!(values.len(), 4);
+
+        let set = parse_request(&request(1, PDU_SET, (0, 0), &[level])).unwrap();
+        assert_eq!(varbinds(&respond(&set, "public", &mib).unwrap()).0, ERROR_NOT_WRITABLE);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+
+use pisugar_client::metrics::{graphite_lines, influx_line, statsd_lines, Metric, MEASUREMENT};
+use pisugar_core::{resolve_secret, GraphiteConfig, GraphiteProtocol, InfluxDbConfig, PiSugarConfig, PiSugarCore};
+
+use crate::notify::http_post;
+use crate::sessions;
+
+/// Tick of the telemetry scheduler, exporters are due at their own intervals
+const TELEMETRY_TICK: Duration = Duration::from_secs(1);
+
+/// Timeout of graphite connections and writes
+const GRAPHITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Battery metrics of core, unreadable ones are left out
+pub fn collect(core: &PiSugarCore) -> Vec<Metric> {
+    let bool_value = |b: bool| if b { 1 } else { 0 };
+    let mut metrics = Vec::new();
+    metrics.extend(core.level().ok().map(|v| Metric::new("battery", v)));
+    metrics.extend(core.voltage_avg().ok().map(|v| Metric::new("battery_v", v)));
+    metrics.extend(core.intensity_avg().ok().map(|v| Metric::new("battery_i", v)));
+    metrics.extend(
+        core.charging()
+            .ok()
+            .map(|v| Metric::new("battery_charging", bool_value(v))),
+    );
+    metrics.extend(
+        core.power_plugged()
+            .ok()
+            .map(|v| Metric::new("battery_power_plugged", bool_value(v))),
+    );
+    metrics.extend(
+        core.allow_charging()
+            .ok()
+            .map(|v| Metric::new("battery_allow_charging", bool_value(v))),
+    );
+    metrics.extend(core.get_temperature().ok().map(|v| Metric::new("temperature", v)));
+    metrics
+}
+
+/// Metric names of connection stats of transports, (transport, open connections, commands, command errors)
+const CONNECTION_METRICS: [(&str, &str, &str, &str); 4] = [
+    ("tcp", "connections_tcp", "commands_tcp", "command_errors_tcp"),
+    ("uds", "connections_uds", "commands_uds", "command_errors_uds"),
+    ("ws", "connections_ws", "commands_ws", "command_errors_ws"),
+    ("http", "connections_http", "commands_http", "command_errors_http"),
+];
+
+/// Connection metrics of transports, `get connections`
+fn connection_metrics() -> Vec<Metric> {
+    let stats = sessions::stats();
+    let mut metrics = Vec::new();
+    for (transport, active, commands, errors) in CONNECTION_METRICS {
+        if let Some(stats) = stats.get(transport) {
+            metrics.push(Metric::new(active, stats.active as f64));
+            metrics.push(Metric::new(commands, stats.commands as f64));
+            metrics.push(Metric::new(errors, stats.errors as f64));
+        }
+    }
+    metrics
+}
+
+/// Percent-encode a query value
+fn query_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Exporter of the telemetry scheduler
+#[derive(Debug, Clone, PartialEq)]
+enum Exporter {
+    InfluxDb(InfluxDbConfig),
+    Graphite(GraphiteConfig),
+}
+
+impl Exporter {
+    /// Name, for logs and scheduling
+    fn name(&self) -> &'static str {
+        match self {
+            Exporter::InfluxDb(_) => "influxdb",
+            Exporter::Graphite(_) => "graphite",
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        let secs = match self {
+            Exporter::InfluxDb(influxdb) => influxdb.interval,
+            Exporter::Graphite(graphite) => graphite.interval,
+        };
+        Duration::from_secs(secs.max(1))
+    }
+
+    async fn export(&self, model: &str, metrics: &[Metric]) -> Result<()> {
+        match self {
+            Exporter::InfluxDb(influxdb) => {
+                let line = influx_line(MEASUREMENT, &[("model", model)], metrics, Utc::now().timestamp())
+                    .ok_or_else(|| anyhow!("No metrics"))?;
+                let mut url = format!(
+                    "{}/api/v2/write?bucket={}&precision=s",
+                    influxdb.url.trim_end_matches('/'),
+                    query_encode(&influxdb.bucket)
+                );
+                if let Some(org) = &influxdb.org {
+                    url += &format!("&org={}", query_encode(org));
+                }
+                let authorization = influxdb
+                    .token
+                    .as_deref()
+                    .map(|token| format!("Token {}", resolve_secret(token)));
+                let mut headers = vec![("Content-Type", "text/plain; charset=utf-8")];
+                if let Some(authorization) = &authorization {
+                    headers.push(("Authorization", authorization.as_str()));
+                }
+                http_post(&url, &headers, line).await
+            }
+            Exporter::Graphite(graphite) => {
+                let prefix = graphite.prefix.as_deref().unwrap_or(MEASUREMENT);
+                let addr = (
+                    graphite.host.as_str(),
+                    graphite.port.unwrap_or_else(|| graphite.protocol.default_port()),
+                );
+                match graphite.protocol {
+                    GraphiteProtocol::Graphite => {
+                        let lines = graphite_lines(prefix, metrics, Utc::now().timestamp());
+                        tokio::time::timeout(GRAPHITE_TIMEOUT, async {
+                            let mut stream = TcpStream::connect(addr).await?;
+                            stream.write_all(lines.as_bytes()).await?;
+                            stream.shutdown().await
+                        })
+                        .await??;
+                    }
+                    GraphiteProtocol::Statsd => {
+                        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                        socket.send_to(statsd_lines(prefix, metrics).as_bytes(), addr).await?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Exporters of config
+fn exporters(config: &PiSugarConfig) -> Vec<Exporter> {
+    let mut exporters = Vec::new();
+    exporters.extend(config.influxdb.clone().map(Exporter::InfluxDb));
+    exporters.extend(config.graphite.clone().map(Exporter::Graphite));
+    exporters
+}
+
+/// Export battery metrics with the configured exporters, config changes are picked up on the next tick
+pub async fn run(core: Arc<Mutex<PiSugarCore>>) {
+    let mut exported_at: BTreeMap<&'static str, Instant> = BTreeMap::new();
+    loop {
+        tokio::time::sleep(TELEMETRY_TICK).await;
+        let now = Instant::now();
+        let (model, metrics, due) = {
+            let core = match core.lock() {
+                Ok(core) => core,
+                Err(_) => continue,
+            };
+            let due: Vec<Exporter> = exporters(core.config())
+                .into_iter()
+                .filter(|e| !matches!(exported_at.get(e.name()), Some(t) if *t + e.interval() > now))
+                .collect();
+            if due.is_empty() {
+                continue;
+            }
+            let mut metrics = collect(&core);
+            metrics.extend(connection_metrics());
+            (core.model(), metrics, due)
+        };
+        for exporter in due {
+            exported_at.insert(exporter.name(), now);
+            let (model, metrics) = (model.clone(), metrics.clone());
+            tokio::spawn(async move {
+                if let Err(e) = exporter.export(&model, &metrics).await {
+                    log::warn!("Telemetry {} error: {}", exporter.name(), e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exporters() {
+        assert_eq!(query_encode("my bucket/1"), "my%20bucket%2F1");
+
+        let mut config = PiSugarConfig::default();
+        assert!(exporters(&config).is_empty());
+        config.influxdb = serde_json::from_str(r#"{"url": "http://127.0.0.1:8086", "bucket": "pisugar"}"#).unwrap();
+        let exporters = exporters(&config);
+        assert_eq!(exporters.len(), 1);
+        assert_eq!(exporters[0].name(), "influxdb");
+        assert_eq!(exporters[0].interval(), Duration::from_secs(60));
+
+        let metrics = connection_metrics();
+        assert_eq!(metrics.len(), 12);
+        assert_eq!(metrics[0].name, "connections_tcp");
+    }
+
+    #[tokio::test]
+    async fn test_statsd_export() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let exporter = Exporter::Graphite(GraphiteConfig {
+            protocol: GraphiteProtocol::Statsd,
+            host: "127.0.0.1".to_string(),
+            port: Some(server.local_addr().unwrap().port()),
+            prefix: Some("pi".to_string()),
+            interval: 10,
+        });
+        exporter
+            .export("PiSugar 3", &[Metric::new("battery", 80)])
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"pi.battery:80|g\n");
+        assert_eq!(exporter.interval(), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,70 @@
+use lazy_static::lazy_static;
+use tokio::sync::watch;
+
+use pisugar_client::cmds::Transport;
+
+lazy_static! {
+    /// Whether listeners are started, indexed by `Transport`, all on at startup
+    static ref TRANSPORTS: Vec<watch::Sender<bool>> = TRANSPORT_LIST.iter().map(|_| watch::channel(true).0).collect();
+}
+
+/// Transports in the order of `TRANSPORTS`
+const TRANSPORT_LIST: [Transport; 3] = [Transport::Tcp, Transport::Ws, Transport::Http];
+
+fn sender(transport: Transport) -> &'static watch::Sender<bool> {
+    &TRANSPORTS[transport as usize]
+}
+
+/// Start or stop a listener, returns whether it is changed
+pub fn set_enabled(transport: Transport, enable: bool) -> bool {
+    let changed = sender(transport).send_replace(enable) != enable;
+    if changed {
+        log::warn!("Transport {} turned {}", transport, if enable { "on" } else { "off" });
+    }
+    changed
+}
+
+/// Whether a listener is started
+pub fn enabled(transport: Transport) -> bool {
+    *sender(transport).borrow()
+}
+
+/// Wait until a listener is turned on (or off)
+pub async fn wait(transport: Transport, enable: bool) {
+    let mut rx = sender(transport).subscribe();
+    while *rx.borrow() != enable {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Listeners and their states, e.g. `tcp=on,ws=off,http=on`
+pub fn states() -> String {
+    let states: Vec<String> = TRANSPORT_LIST
+        .iter()
+        .map(|t| format!("{}={}", t, if enabled(*t) { "on" } else { "off" }))
+        .collect();
+    states.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transport() {
+        assert!(enabled(Transport::Ws));
+        assert!(set_enabled(Transport::Ws, false));
+        assert!(!set_enabled(Transport::Ws, false));
+        assert_eq!(states(), "tcp=on,ws=off,http=on");
+        wait(Transport::Ws, false).await;
+
+        let on = tokio::spawn(wait(Transport::Ws, true));
+        set_enabled(Transport::Ws, true);
+        tokio::time::timeout(std::time::Duration::from_secs(1), on)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use pisugar_client::cmds::{split_device, Cmds};
+use pisugar_core::PiSugarCore;
+
+use crate::events::ClientTx;
+use crate::lines::tag_response;
+
+/// Fields of `watch` without fields
+pub const WATCH_DEFAULT_FIELDS: &[&str] = &["battery", "battery_v", "battery_charging", "battery_power_plugged"];
+
+/// Interval of `watch` without interval
+pub const WATCH_DEFAULT_INTERVAL: u64 = 1;
+
+/// Running watches of all connections
+static WATCHING: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether any connection is watching
+pub fn watching() -> bool {
+    WATCHING.load(Ordering::Relaxed) > 0
+}
+
+/// Periodic `get` of a tcp/uds/ws connection, started by `watch`, stopped by `unwatch` or disconnection
+#[derive(Default)]
+pub struct Watcher {
+    task: Option<JoinHandle<()>>,
+}
+
+/// Fields and interval of `watch [field...] [interval]`
+pub fn parse_watch_args(args: &[String]) -> Option<(Vec<String>, Duration)> {
+    let (interval, fields) = match args.split_last() {
+        Some((last, fields)) if last.chars().all(|c| c.is_ascii_digit()) => (last.parse().ok()?, fields),
+        _ => (WATCH_DEFAULT_INTERVAL, args),
+    };
+    if interval == 0 {
+        return None;
+    }
+    let mut fields: Vec<String> = fields
+        .iter()
+        .flat_map(|f| f.split(','))
+        .filter(|f| !f.is_empty())
+        .map(|f| f.to_string())
+        .collect();
+    if fields.is_empty() {
+        fields = WATCH_DEFAULT_FIELDS.iter().map(|f| f.to_string()).collect();
+    }
+    let valid = fields.iter().all(|f| {
+        let req = format!("get {}", f);
+        let req = split_device(&req).map(|(req, _, _)| req).unwrap_or(req);
+        matches!(Cmds::from_str(&req), Ok(Cmds::Get(_)))
+    });
+    valid.then(|| (fields, Duration::from_secs(interval)))
+}
+
+impl Watcher {
+    /// Handle `watch` and `unwatch`, None for other requests, updates are tagged with `#id` of the request
+    pub fn handle_request<T>(
+        &mut self,
+        core: &Arc<Mutex<PiSugarCore>>,
+        req: &str,
+        id: Option<&str>,
+        tx: &ClientTx<T>,
+    ) -> Option<String>
+    where
+        T: From<String> + Send + 'static,
+    {
+        if !matches!(req.split(' ').next(), Some("watch" | "unwatch")) {
+            return None;
+        }
+        let resp = match Cmds::from_str(req) {
+            Ok(Cmds::Watch { args }) => match parse_watch_args(&args) {
+                Some((fields, interval)) => {
+                    self.stop();
+                    WATCHING.fetch_add(1, Ordering::Relaxed);
+                    self.task = Some(tokio::spawn(watch(
+                        core.clone(),
+                        fields,
+                        interval,
+                        id.map(str::to_string),
+                        tx.clone(),
+                    )));
+                    "watch: done\n".to_string()
+                }
+                None => {
+                    log::warn!("Invalid watch: {}", req);
+                    "Invalid request.\n".to_string()
+                }
+            },
+            Ok(Cmds::Unwatch) => {
+                self.stop();
+                "unwatch: done\n".to_string()
+            }
+            _ => "Invalid request.\n".to_string(),
+        };
+        Some(resp)
+    }
+
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            WATCHING.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+async fn watch<T>(
+    core: Arc<Mutex<PiSugarCore>>,
+    fields: Vec<String>,
+    interval: Duration,
+    id: Option<String>,
+    tx: ClientTx<T>,
+) where
+    T: From<String>,
+{
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        for field in &fields {
+            let resp = crate::handle_request(core.clone(), &format!("get {}", field));
+            // get responses are not terminated, one line per field
+            let resp = tag_response(id.as_deref(), format!("{}\n", resp.trim_end()));
+            if tx.send(Some(resp.into())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
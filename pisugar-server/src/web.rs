@@ -0,0 +1,246 @@
+use std::fs::Metadata;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use hyper::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+/// Web UI directory of `--web`
+pub const DEFAULT_WEB_DIR: &str = "/usr/share/pisugar-server/web";
+
+/// Max age (s) of assets other than html, assets of the web UI build have hashed names
+pub const ASSET_MAX_AGE: u32 = 86400;
+
+/// Precompressed siblings of assets in order of preference, e.g. `app.js.br`
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Web UI built into the binary by `embed-web` feature, from pisugar-webui/dist
+#[cfg(feature = "embed-web")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "$CARGO_MANIFEST_DIR/../pisugar-webui/dist/"]
+struct EmbeddedWeb;
+
+/// Web UI assets, of a directory or embedded
+#[derive(Clone)]
+pub enum WebAssets {
+    Dir(hyper_staticfile::Static),
+    #[cfg(feature = "embed-web")]
+    Embedded,
+}
+
+impl WebAssets {
+    /// Assets of web dir, embedded ones of `embed-web` builds without a web dir
+    pub fn new(web_dir: Option<String>) -> Self {
+        match web_dir {
+            Some(web_dir) => WebAssets::Dir(hyper_staticfile::Static::new(web_dir)),
+            #[cfg(feature = "embed-web")]
+            None => WebAssets::Embedded,
+            #[cfg(not(feature = "embed-web"))]
+            None => WebAssets::Dir(hyper_staticfile::Static::new(DEFAULT_WEB_DIR)),
+        }
+    }
+}
+
+/// Content type of web assets by extension
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).unwrap_or_default() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "application/javascript",
+        "css" => "text/css",
+        "json" | "map" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Cache-Control of a file, html is revalidated every time, so that an upgraded web UI is picked up at once
+fn cache_control(path: &Path) -> String {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") | None => "no-cache".to_string(),
+        _ => format!("public, max-age={}", ASSET_MAX_AGE),
+    }
+}
+
+/// File of a request path in web dir, `/` is `index.html`, none if it escapes web dir
+fn file_path(web_dir: &Path, uri_path: &str) -> Option<PathBuf> {
+    let relative = Path::new(uri_path.trim_start_matches('/'));
+    if uri_path.contains('%') || relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    let path = web_dir.join(relative);
+    if uri_path.ends_with('/') || path.is_dir() {
+        Some(path.join("index.html"))
+    } else {
+        Some(path)
+    }
+}
+
+/// Weak ETag of a file, of its size and modification time
+fn etag(meta: &Metadata) -> String {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format!("W/\"{:x}-{:x}\"", meta.len(), mtime)
+}
+
+/// Whether the request has a matching `If-None-Match`
+fn not_modified(req: &Request<Body>, etag: &str) -> bool {
+    req.headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == etag))
+}
+
+/// Serve the precompressed sibling of a file the client accepts, none if there isn't any
+async fn serve_precompressed(path: &Path, req: &Request<Body>) -> Result<Option<Response<Body>>> {
+    let accepted = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let accepts = |encoding: &str| {
+        accepted
+            .split(',')
+            .any(|e| e.split(';').next().unwrap_or_default().trim() == encoding)
+    };
+    for (encoding, ext) in PRECOMPRESSED {
+        if !accepts(encoding) {
+            continue;
+        }
+        let mut compressed = path.as_os_str().to_owned();
+        compressed.push(".");
+        compressed.push(ext);
+        let meta = match tokio::fs::metadata(&compressed).await {
+            Ok(meta) if meta.is_file() => meta,
+            _ => continue,
+        };
+        let etag = etag(&meta);
+        let builder = Response::builder()
+            .header(ETAG, &etag)
+            .header(CACHE_CONTROL, cache_control(path))
+            .header(VARY, "Accept-Encoding");
+        if not_modified(req, &etag) {
+            return Ok(Some(builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())?));
+        }
+        let body = if req.method() == Method::HEAD {
+            Body::empty()
+        } else {
+            Body::from(tokio::fs::read(&compressed).await?)
+        };
+        return Ok(Some(
+            builder
+                .header(CONTENT_TYPE, content_type(path))
+                .header(CONTENT_ENCODING, encoding)
+                .body(body)?,
+        ));
+    }
+    Ok(None)
+}
+
+/// Serve embedded web UI, ETag is of the content
+#[cfg(feature = "embed-web")]
+fn serve_embedded(req: &Request<Body>) -> Result<Response<Body>> {
+    let mut name = req.uri().path().trim_start_matches('/').to_string();
+    if name.is_empty() || name.ends_with('/') {
+        name += "index.html";
+    }
+    let file = match EmbeddedWeb::get(&name) {
+        Some(file) => file,
+        None => return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())?),
+    };
+    let path = Path::new(&name);
+    let etag = format!("\"{}\"", hex::encode(file.metadata.sha256_hash()));
+    let builder = Response::builder()
+        .header(ETAG, &etag)
+        .header(CACHE_CONTROL, cache_control(path));
+    if not_modified(req, &etag) {
+        return Ok(builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())?);
+    }
+    let body = if req.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::from(file.data.into_owned())
+    };
+    Ok(builder.header(CONTENT_TYPE, content_type(path)).body(body)?)
+}
+
+/// Serve web UI, html is revalidated and other assets are cached
+pub async fn serve(assets: WebAssets, req: Request<Body>) -> Result<Response<Body>> {
+    match assets {
+        WebAssets::Dir(static_) => serve_dir(static_, req).await,
+        #[cfg(feature = "embed-web")]
+        WebAssets::Embedded => serve_embedded(&req),
+    }
+}
+
+/// Serve web dir, precompressed `.br`/`.gz` siblings first
+async fn serve_dir(static_: hyper_staticfile::Static, req: Request<Body>) -> Result<Response<Body>> {
+    let path = match file_path(&static_.root, req.uri().path()) {
+        Some(path) if req.method() == Method::GET || req.method() == Method::HEAD => path,
+        _ => return Ok(static_.serve(req).await?),
+    };
+    if let Some(resp) = serve_precompressed(&path, &req).await? {
+        return Ok(resp);
+    }
+    let mut resp = static_.serve(req).await?;
+    if resp.status().is_success() || resp.status() == StatusCode::NOT_MODIFIED {
+        let headers = resp.headers_mut();
+        headers.insert(CACHE_CONTROL, cache_control(&path).parse()?);
+        headers.insert(VARY, "Accept-Encoding".parse()?);
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve_precompressed() {
+        let web_dir = std::env::temp_dir().join(format!("pisugar-web-{}", std::process::id()));
+        std::fs::create_dir_all(&web_dir).unwrap();
+        std::fs::write(web_dir.join("app.js"), "plain").unwrap();
+        std::fs::write(web_dir.join("app.js.gz"), "gzipped").unwrap();
+
+        assert_eq!(file_path(&web_dir, "/"), Some(web_dir.join("index.html")));
+        assert_eq!(file_path(&web_dir, "/../etc/passwd"), None);
+        assert_eq!(cache_control(&web_dir.join("index.html")), "no-cache");
+
+        let path = file_path(&web_dir, "/app.js").unwrap();
+        let req = Request::get("/app.js")
+            .header(ACCEPT_ENCODING, "gzip, deflate, br")
+            .body(Body::empty())
+            .unwrap();
+        let resp = serve_precompressed(&path, &req).await.unwrap().unwrap();
+        assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/javascript");
+        assert_eq!(resp.headers()[CACHE_CONTROL], "public, max-age=86400");
+        let etag = resp.headers()[ETAG].clone();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"gzipped");
+
+        let req = Request::get("/app.js")
+            .header(ACCEPT_ENCODING, "gzip")
+            .header(IF_NONE_MATCH, etag)
+            .body(Body::empty())
+            .unwrap();
+        let resp = serve_precompressed(&path, &req).await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        let req = Request::get("/app.js").body(Body::empty()).unwrap();
+        assert!(serve_precompressed(&path, &req).await.unwrap().is_none());
+
+        std::fs::remove_dir_all(&web_dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::time::Duration;
+
+use pisugar_core::{resolve_secret, PiSugarCore, WebhookConfig};
+
+use crate::notify::{http_post, url_host};
+
+/// Signature header
+const SIGNATURE_HEADER: &str = "X-PiSugar-Signature";
+
+/// HMAC-SHA256 signature of body, `sha256=<hex>`
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POST body to webhook, retry with exponential backoff
+async fn post(webhook: WebhookConfig, body: String) {
+    let signature = webhook
+        .secret
+        .as_deref()
+        .map(|secret| sign(&resolve_secret(secret), &body));
+    let mut headers = vec![("Content-Type", "application/json")];
+    if let Some(signature) = &signature {
+        headers.push((SIGNATURE_HEADER, signature.as_str()));
+    }
+    for i in 0..=webhook.retries {
+        match http_post(&webhook.url, &headers, body.clone()).await {
+            Ok(_) => return,
+            Err(e) => log::warn!("Webhook {} error ({}/{}): {}", url_host(&webhook.url), i + 1, webhook.retries + 1, e),
+        }
+        if i < webhook.retries {
+            tokio::time::sleep(Duration::from_secs(1 << i.min(6))).await;
+        }
+    }
+}
+
+/// Fire event to all subscribed webhooks
+pub fn fire(core: &PiSugarCore, event: &str, data: Value) {
+    let webhooks = match &core.config().webhooks {
+        Some(webhooks) => webhooks,
+        None => return,
+    };
+    let body = json!({
+        "event": event,
+        "time": Local::now().to_rfc3339(),
+        "model": core.model(),
+        "data": data,
+    })
+    .to_string();
+    for webhook in webhooks.iter().filter(|w| w.subscribed(event)) {
+        tokio::spawn(post(webhook.clone(), body.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+}